@@ -0,0 +1,148 @@
+use std::io::Write;
+use std::sync::atomic::Ordering;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{config::Config, index::IndexPool};
+
+/// インデックスのデフォルトの保存先
+pub const DEFAULT_INDEX_DIR: &str = "./index_data";
+
+/// コマンドライン引数
+/// サブコマンドなしは serve
+#[derive(Debug, Parser)]
+#[command(name = "wk-search", version, about = "wk-371tti-net search engine")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// HTTP サーバを起動する (デフォルト)
+    Serve,
+    /// インデックスの統計を JSON で出力する
+    Stats(IndexArgs),
+    /// コーパスの不要な語と削除済みドキュメントの meta を消して保存する
+    Compact(IndexArgs),
+    /// 登録中のドキュメントの meta を JSON Lines で出力する
+    Export(IndexArgs),
+    /// url_map と IDF を作り直し、現在の形式で保存し直す
+    Reindex(IndexArgs),
+}
+
+/// オフライン操作の共通引数
+#[derive(Debug, Clone, Args)]
+pub struct IndexArgs {
+    /// インデックスのディレクトリ
+    #[arg(long, default_value = DEFAULT_INDEX_DIR)]
+    pub index_dir: String,
+}
+
+/// サーバを起動せずにインデックスを操作する (serve 以外)
+/// 結果は out に書き、ログは stderr に出す
+pub fn run(command: &Command, config: &Config, out: &mut dyn Write) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Serve => Err("serve is not an offline command".into()),
+        Command::Stats(args) => {
+            let pool = open_pool(args, config)?;
+            let shards: Vec<_> = (0..pool.indexes.len())
+                .filter_map(|i| pool.dump_shard(i, 0..0))
+                .collect();
+            let stats = serde_json::json!({
+                "index_dir": args.index_dir,
+                "documents": pool.counter.load(Ordering::SeqCst),
+                "generation": pool.generation.load(Ordering::SeqCst),
+                "vocab_size": pool.corpus.load().vocab_size(),
+                "shards": shards,
+            });
+            writeln!(out, "{}", serde_json::to_string_pretty(&stats)?)?;
+            Ok(())
+        }
+        Command::Compact(args) => {
+            let pool = open_pool(args, config)?;
+            let prune = pool.prune_corpus()?;
+            let removed_meta = pool.compact_meta();
+            save(&pool, args)?;
+            let result = serde_json::json!({
+                "removed_tokens": prune.removed,
+                "corrected_tokens": prune.corrected,
+                "vocab_size": prune.vocab_size,
+                "removed_meta": removed_meta,
+            });
+            writeln!(out, "{}", serde_json::to_string_pretty(&result)?)?;
+            Ok(())
+        }
+        Command::Export(args) => {
+            let pool = open_pool(args, config)?;
+            for (shard_id, index) in pool.shards().iter().enumerate() {
+                let idx = index.read().map_err(|_| format!("RwLock poisoned for index id {}", shard_id))?;
+                for meta in idx.meta.iter().filter(|m| idx.vectorizer.contains_doc(&m.id)) {
+                    let mut value = serde_json::to_value(meta)?;
+                    if let Some(obj) = value.as_object_mut() {
+                        obj.insert("index_id".to_string(), shard_id.into());
+                    }
+                    writeln!(out, "{}", value)?;
+                }
+            }
+            Ok(())
+        }
+        Command::Reindex(args) => {
+            let pool = open_pool(args, config)?;
+            let documents = pool.rebuild();
+            save(&pool, args)?;
+            writeln!(out, "{}", serde_json::to_string_pretty(&serde_json::json!({ "documents": documents }))?)?;
+            Ok(())
+        }
+    }
+}
+
+/// 既存のインデックスを読む (なければエラー、新規作成はしない)
+fn open_pool(args: &IndexArgs, config: &Config) -> Result<IndexPool, Box<dyn std::error::Error>> {
+    let mut pool = IndexPool::load(&args.index_dir, config.corpus_file.as_deref())?;
+    pool.apply_config(config);
+    Ok(pool)
+}
+
+fn save(pool: &IndexPool, args: &IndexArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let summary = pool.save(&args.index_dir)?;
+    if !summary.skipped.is_empty() {
+        return Err(format!("Index saved partially, skipped shards: {:?}", summary.skipped).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::add_test_docs;
+
+    fn prepared_index(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("wk-cli-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dir = dir.to_string_lossy().into_owned();
+        let pool = IndexPool::new(&dir);
+        add_test_docs(&pool, &[("https://example.com/1", &["東京", "天気"]), ("https://example.com/2", &["大阪", "天気"])]);
+        pool.save(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_stats_command() {
+        assert!(Cli::try_parse_from(["wk-search"]).unwrap().command.is_none());
+
+        let dir = prepared_index("stats");
+        let cli = Cli::try_parse_from(["wk-search", "stats", "--index-dir", &dir]).unwrap();
+        let mut out = Vec::new();
+        run(cli.command.as_ref().unwrap(), &Config::default(), &mut out).unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(stats["documents"], 2);
+        assert_eq!(stats["vocab_size"], 3);
+        assert_eq!(stats["shards"].as_array().unwrap().len(), IndexPool::new(&dir).indexes.len());
+
+        // 存在しないディレクトリは新規作成せずエラー
+        let missing = Cli::try_parse_from(["wk-search", "stats", "--index-dir", "/nonexistent/wk-cli-test"]).unwrap();
+        assert!(run(missing.command.as_ref().unwrap(), &Config::default(), &mut Vec::new()).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,7 +1,48 @@
+use std::collections::BTreeMap;
 use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use chrono::{DateTime, Utc};
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Serializer, Deserialize};
+
+/// スコアを JSON に書くときの有効桁数のデフォルト
+pub const DEFAULT_SCORE_DIGITS: usize = 4;
+
+/// スコアを JSON に書くときの有効桁数 (0 で丸めない)
+/// 起動時に set_score_digits で設定する
+static SCORE_DIGITS: AtomicUsize = AtomicUsize::new(DEFAULT_SCORE_DIGITS);
+
+pub fn set_score_digits(digits: usize) {
+    SCORE_DIGITS.store(digits, Ordering::Relaxed);
+}
+
+/// 有効桁数 digits に丸める (0 / 非有限値はそのまま)
+pub fn round_significant(value: f64, digits: usize) -> f64 {
+    if digits == 0 || value == 0.0 || !value.is_finite() {
+        return value;
+    }
+    let magnitude = value.abs().log10().floor() as i32;
+    let shift = digits as i32 - 1 - magnitude;
+    // 10^負 は誤差が出るので割り算にする
+    let rounded = if shift >= 0 {
+        let factor = 10f64.powi(shift);
+        (value * factor).round() / factor
+    } else {
+        let factor = 10f64.powi(-shift);
+        (value / factor).round() * factor
+    };
+    if rounded.is_finite() { rounded } else { value }
+}
+
+/// スコアを有効桁数で丸めて書く (内部では丸めない値でソートする)
+fn serialize_score<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(round_significant(*value, SCORE_DIGITS.load(Ordering::Relaxed)))
+}
+
+/// Option 版 (skip_serializing_if で None は書かない)
+fn serialize_opt_score<S: Serializer>(value: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+    serialize_score(&value.unwrap_or_default(), serializer)
+}
 
 pub struct ScoredEntry {
     pub score: f64,
@@ -10,6 +51,30 @@ pub struct ScoredEntry {
     pub index_id: usize,
 }
 
+/// 検索でシャードが出した候補の数 (debug_shards)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ShardCandidates {
+    pub index_id: usize,
+    /// スコア計算とフィルタの後、range で切り出す前の件数
+    pub candidates: usize,
+    /// 書き込み中で read lock を取れず検索しなかった
+    pub skipped: bool,
+}
+
+/// scored をシャードごとに数える
+/// skipped はそのリクエストで飛ばしたシャード ID (重複可)
+pub fn shard_candidates(shards: usize, scored: &[ScoredEntry], skipped: &[usize]) -> Vec<ShardCandidates> {
+    let mut counts = vec![0; shards];
+    for e in scored {
+        if let Some(c) = counts.get_mut(e.index_id) {
+            *c += 1;
+        }
+    }
+    counts.into_iter().enumerate()
+        .map(|(index_id, candidates)| ShardCandidates { index_id, candidates, skipped: skipped.contains(&index_id) })
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResEntry {
     pub url: Box<str>,
@@ -17,12 +82,66 @@ pub struct ResEntry {
     pub favicon: Option<Box<str>>,
     pub tags: Vec<Box<str>>,
     pub descriptions: Box<str>,
+    #[serde(serialize_with = "serialize_score")]
     pub score: f64,
+    #[serde(serialize_with = "serialize_score")]
     pub point: f64,
     pub length: u64,
     pub id: usize,
     pub index_id: usize,
     pub time: DateTime<Utc>,
+    pub author: Option<Box<str>>,
+    pub published: Option<DateTime<Utc>>,
+    pub modified: Option<DateTime<Utc>>,
+    /// クエリの語のうちドキュメントに含まれる語の数 (match_info 指定時のみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matched_terms: Option<usize>,
+    /// クエリの語のドキュメント内での出現回数の合計 (match_info 指定時のみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub term_freq: Option<u64>,
+    /// descriptions 中のクエリの語の位置 (文字単位、highlight 指定時のみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub highlights: Option<Vec<Range<usize>>>,
+    /// descriptions のうちクエリの語が密集している部分 (snippet_count 指定時のみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snippets: Option<Vec<String>>,
+    /// 正規化する前のスコア (normalize_score 指定時のみ)
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "serialize_opt_score")]
+    pub raw_score: Option<f64>,
+    /// タイトルに含まれるクエリの語 (matched_tokens 指定時のみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matched_title: Option<Vec<String>>,
+    /// descriptions に含まれるクエリの語 (matched_tokens 指定時のみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matched_desc: Option<Vec<String>>,
+    /// 同じホストの後続の結果 (collapse_by=host 指定時の代表のみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub others: Option<Vec<ResEntry>>,
+}
+
+/// ResEntry のフィールド名
+/// fields= による投影で指定可能な名前
+pub const RES_ENTRY_FIELDS: &[&str] = &[
+    "url", "title", "favicon", "tags", "descriptions", "score", "point", "length", "id", "index_id", "time",
+    "author", "published", "modified", "matched_terms", "term_freq", "highlights", "snippets", "raw_score",
+    "matched_title", "matched_desc", "others",
+];
+
+/// lite=true で結果から除くフィールド
+pub const LITE_OMITTED_FIELDS: &[&str] = &["descriptions", "favicon"];
+
+/// fields パラメータをパース
+/// 例: "url,title,score"
+/// 未知のフィールド名があれば Err(そのフィールド名)
+pub fn parse_fields(s: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    for f in s.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()) {
+        if !RES_ENTRY_FIELDS.contains(&f) {
+            return Err(f.to_string());
+        }
+        fields.push(f.to_string());
+    }
+    Ok(fields)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,16 +151,129 @@ pub enum SearchRes {
     Success {
         query: String,
         tokenize_query: Vec<String>,
+        /// スコア計算に使った語ごとの出現回数 (show_query_tf 指定時)
+        /// tokenize_query の重複をまとめたもの
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        query_tf: Option<Box<BTreeMap<String, u64>>>,
         algorithm: String,
         range: Range<usize>,
+        /// パラメータを補正した場合などの注意
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hint: Option<String>,
+        /// ヒットがないときのクエリ候補 (did you mean)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        suggestion: Option<String>,
+        /// 無視した入力 (未知のタグなど)
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+        /// タグごとのヒット件数 (facets 指定時)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        facets: Option<Box<BTreeMap<String, usize>>>,
+        /// range で切り出す前の件数 (タグなどで絞り込んだ後、SCORE_TOP_K の上位に入らなかった分も含む)
+        total: usize,
+        /// range の開始位置が total 以上 (ページが結果の末尾を越えている)
+        out_of_range: bool,
+        /// シャードごとの候補数 (debug_shards 指定時)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        debug_shards: Option<Vec<ShardCandidates>>,
         results: Vec<ResEntry>,
     },
     #[serde(rename = "false")]
     Failed {
         error: String,
+        /// エラーの種類 (invalid_request / tokenizer_unavailable / tokenizer_error)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
+        /// 受け取ったクエリ (読めた場合)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        query: Option<String>,
     },
 }
 
+impl SearchRes {
+    pub fn failed(code: &str, error: String, query: Option<String>) -> Self {
+        SearchRes::Failed { error, code: Some(code.to_string()), query }
+    }
+
+    /// JSON に変換し results の各要素 (others の中も) を fields のみに絞る
+    /// fields が None または空なら全フィールド
+    pub fn to_value_with_fields(&self, fields: Option<&[String]>) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        let Some(fields) = fields.filter(|f| !f.is_empty()) else { return value; };
+        if let Some(results) = value.get_mut("results").and_then(|r| r.as_array_mut()) {
+            project_entries(results, fields);
+        }
+        value
+    }
+}
+
+/// 結果の各要素を fields のみに絞る (others の中も同じフィールドに)
+fn project_entries(entries: &mut [serde_json::Value], fields: &[String]) {
+    for entry in entries.iter_mut() {
+        if let Some(obj) = entry.as_object_mut() {
+            obj.retain(|k, _| fields.iter().any(|f| f == k));
+            if let Some(others) = obj.get_mut("others").and_then(|o| o.as_array_mut()) {
+                project_entries(others, fields);
+            }
+        }
+    }
+}
+
+/// /debug/shard/:id のレスポンス
+#[derive(Debug, Clone, Serialize)]
+pub struct ShardDump {
+    pub id: usize,
+    /// vectorizer 上のドキュメント数
+    pub documents: usize,
+    /// meta の件数 (削除済みドキュメントの meta も含む)
+    pub meta_count: usize,
+    pub update_count: usize,
+    pub vectorizer_bin_size: u64,
+    pub meta_bin_size: u64,
+    pub range: Range<usize>,
+    pub entries: Vec<ShardDumpEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShardDumpEntry {
+    pub id: usize,
+    pub url: Box<str>,
+    pub title: Box<str>,
+}
+
+/// /doc/vector のレスポンス
+#[derive(Debug, Clone, Serialize)]
+pub struct DocVector {
+    pub url: Box<str>,
+    pub index_id: usize,
+    pub id: usize,
+    /// インデックス時のトークン数
+    pub token_sum: u64,
+    /// トークン → 出現回数 (量子化された TF から復元した値)
+    pub tokens: BTreeMap<Box<str>, u64>,
+}
+
+/// /indexed のリクエスト
+#[derive(Debug, Clone, Deserialize)]
+pub struct IndexedReq {
+    /// 確認する URL (別名も可、最大 MAX_BULK_ITEMS 件)
+    pub urls: Vec<String>,
+}
+
+/// /indexed の URL ごとの結果 (リクエストの順)
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct IndexedEntry {
+    pub url: String,
+    pub indexed: bool,
+    /// 最後にインデックスした時刻
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index_id: Option<usize>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct IndexReq {
     pub url: String,
@@ -60,6 +292,44 @@ pub struct IndexReq {
     /// - "tools": ツール系サイト
     pub tags: Vec<String>,
     pub descriptions: Option<String>,
+    /// インデックスに使う品詞 (前方一致)
+    /// 例: ["名詞", "動詞"]
+    /// 省略時は全トークン
+    #[serde(default)]
+    pub pos: Option<Vec<String>>,
+    /// 未知のタグ名を 400 にする (false なら無視して warnings に載せる)
+    #[serde(default)]
+    pub strict_tags: bool,
+    /// 同じページを指す別の URL (削除 / 再登録でも URL と同じように使える)
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// 本文の言語 ("ja" / "en" など)
+    /// 省略時と "ja" は sudachi、それ以外は単語分割でトークン化する
+    #[serde(default)]
+    pub lang: Option<String>,
+    /// スクレイパ API の URL (SCRAPER_URL_ALLOWLIST のホストのみ、省略時は SCRAPER_API_URL)
+    #[serde(default)]
+    pub scraper_url: Option<String>,
+    /// 登録済みで最後のインデックスからこの秒数が経っていなければ、スクレイプせず既存の内容を返す (skipped)
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+/// /clear のリクエスト
+/// 誤って全件消さないように confirm: "yes" を必須にする
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClearReq {
+    pub confirm: String,
+}
+
+/// /bulk_add のリクエスト
+#[derive(Debug, Clone, Deserialize)]
+pub struct BulkAddReq {
+    /// /add と同じ形式のドキュメント (最大 MAX_BULK_ITEMS 件)
+    pub items: Vec<IndexReq>,
+    /// true ならジョブ ID をすぐ返し、バックグラウンドで処理する (進捗は GET /jobs/:id)
+    #[serde(default, rename = "async")]
+    pub background: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -67,15 +337,27 @@ pub struct IndexReq {
 pub enum IndexRes {
     #[serde(rename = "true")]
     Success {
+        /// 保存された URL (canonical があればそちら)
         url: Box<str>,
+        /// リクエストされた URL
+        requested_url: Box<str>,
         title: Box<str>,
         favicon: Option<Box<str>>,
         tags: Vec<Box<str>>,
         descriptions: Box<str>,
+        /// 無視した入力 (未知のタグなど)
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+        /// max_age 以内に登録済みだったので更新しなかった (既存の内容を返す)
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        skipped: bool,
     },
     #[serde(rename = "false")]
     Failed {
         error: String,
+        /// リクエストボディのどこが悪いか (デシリアライズ失敗時)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
     },
 }
 
@@ -108,10 +390,107 @@ pub enum ScraperResult {
         success: bool, // 常に true を想定
         status: u16,
         url: String,
-        results: ScrapeResults,
+        results: Box<ScrapeResults>,
     },
     Failed {
         success: bool, // 常に false を想定
         error: String,
+        /// 対象ページの HTTP ステータス (スクレイパが返す場合のみ)
+        #[serde(default)]
+        status: Option<u16>,
     },
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_entry() -> ResEntry {
+        ResEntry {
+            url: "https://example.com/".into(),
+            title: "Example".into(),
+            favicon: Some("https://example.com/favicon.ico".into()),
+            tags: vec!["WIKI".into()],
+            descriptions: "description".into(),
+            score: 1.5,
+            point: 0.0,
+            length: 10,
+            id: 0,
+            index_id: 0,
+            time: Utc::now(),
+            author: None,
+            published: None,
+            modified: None,
+            matched_terms: None,
+            term_freq: None,
+            highlights: None,
+            snippets: None,
+            raw_score: None,
+            matched_title: None,
+            matched_desc: None,
+            others: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        assert_eq!(parse_fields("url, title,score").unwrap(), vec!["url", "title", "score"]);
+        assert_eq!(parse_fields("url,bogus").unwrap_err(), "bogus");
+    }
+
+    #[test]
+    fn test_field_projection_omits_fields() {
+        let res = SearchRes::Success {
+            query: "q".to_string(),
+            tokenize_query: vec!["q".to_string()],
+            query_tf: None,
+            algorithm: "BM25(1.2,0.75)".to_string(),
+            range: 0..20,
+            hint: None,
+            suggestion: None,
+            warnings: Vec::new(),
+            facets: None,
+            total: 1,
+            out_of_range: false,
+            debug_shards: None,
+            results: vec![test_entry()],
+        };
+        let fields = parse_fields("url,title,score").unwrap();
+        let value = res.to_value_with_fields(Some(&fields));
+        let entry = value["results"][0].as_object().unwrap();
+        assert_eq!(entry.len(), 3);
+        assert!(entry.contains_key("url") && entry.contains_key("title") && entry.contains_key("score"));
+        assert!(!entry.contains_key("descriptions"));
+        assert!(!entry.contains_key("favicon"));
+        assert!(!entry.contains_key("tags"));
+        // 結果以外のフィールドは残る
+        assert_eq!(value["query"], "q");
+    }
+
+    #[test]
+    fn test_score_precision() {
+        assert_eq!(round_significant(3.45678912, 4), 3.457);
+        assert_eq!(round_significant(0.000123456, 4), 0.0001235);
+        assert_eq!(round_significant(123456.0, 4), 123500.0);
+        assert_eq!(round_significant(-2.34567, 3), -2.35);
+        assert_eq!(round_significant(1.23456, 0), 1.23456);
+
+        // JSON は丸め、値そのものは丸めない
+        let mut a = test_entry();
+        a.score = 1.234567891234;
+        let mut b = test_entry();
+        b.score = 1.234567891233;
+        let json = serde_json::to_string(&a).unwrap();
+        assert!(json.contains(r#""score":1.235"#), "{}", json);
+        assert!(a.score > b.score);
+    }
+
+    #[test]
+    fn test_scraper_failed_status() {
+        let failed: ScraperResult = serde_json::from_str(r#"{"success":false,"error":"not found","status":404}"#).unwrap();
+        assert!(matches!(failed, ScraperResult::Failed { status: Some(404), .. }));
+        // status のない従来のレスポンス
+        let legacy: ScraperResult = serde_json::from_str(r#"{"success":false,"error":"timeout"}"#).unwrap();
+        assert!(matches!(legacy, ScraperResult::Failed { status: None, .. }));
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+
+use crate::{collect::{set_score_digits, DEFAULT_SCORE_DIGITS}, index::{MetaFormat, SaveOptions, ScoreMerge, TagRegistry, DEFAULT_SAVE_BUFFER_BYTES, DEFAULT_SCORE_FLOOR}, search::{validate_scoring, DEFAULT_ALGORITHM, DEFAULT_SEARCH_RESULTS, MAX_SEARCH_RESULTS}, ingest::{DescriptionSource, EnrichOptions, DEFAULT_DESCRIPTION_SOURCES, DEFAULT_TITLE_WEIGHT}, tokenize::{SudachiCommand, TokenizeOptions, DEFAULT_NORMALIZED_COLUMN, DEFAULT_SUDACHI_BIN}};
+
+/// 内容語の品詞
+/// POS_FILTER=1 のときこれらのみインデックス/検索に使う (助詞・助動詞などを除外)
+pub const CONTENT_POS: &[&str] = &["名詞", "動詞", "形容詞", "副詞"];
+
+/// スクレイパのレスポンスキャッシュの TTL のデフォルト (秒)
+pub const DEFAULT_SCRAPE_CACHE_TTL_SECS: u64 = 600;
+
+/// リクエストボディの最大サイズのデフォルト (1MiB)
+pub const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// 検索クエリの最大文字数のデフォルト
+pub const DEFAULT_MAX_QUERY_CHARS: usize = 2000;
+
+/// 実行時設定
+/// 環境変数から読み込み、未設定の項目はデフォルト値
+#[derive(Clone)]
+pub struct Config {
+    /// インデックスと検索で共通のトークン化設定
+    pub tokenize: TokenizeOptions,
+    /// /debug/* エンドポイントを有効化
+    pub debug_endpoints: bool,
+    /// /add でのスクレイパ結果によるメタデータ補完
+    pub enrich: EnrichOptions,
+    /// インデックスディレクトリ内のコーパスファイル名 (未設定なら唯一の .corpus)
+    pub corpus_file: Option<String>,
+    /// 起動時にインデックスをウォームアップ
+    pub warmup: bool,
+    /// POST のリクエストボディの最大バイト数 (超えたら 413)
+    pub max_body_bytes: usize,
+    /// 検索クエリの最大文字数 (超えたらトークン化せずに 400)
+    pub max_query_chars: usize,
+    /// インデックス時に description のトークン位置を保存 (ハイライト用)
+    pub store_desc_tokens: bool,
+    /// スコア計算後にシャードごとに保持する上位件数 (None なら全件)
+    pub score_top_k: Option<usize>,
+    /// score_top_k 有効時のシャード間のまとめ方
+    pub score_merge: ScoreMerge,
+    /// タグ定義ファイル (JSON, 名前 -> ビット番号)
+    pub tags_file: Option<String>,
+    /// sudachi の実行ファイル / 設定 / 辞書
+    pub sudachi: SudachiCommand,
+    /// 新規インデックスでシャードごとのサブディレクトリを使う (既存のインデックスは保存済みの配置)
+    pub shard_dirs: bool,
+    /// スクレイパが対象ページの一時的な失敗を返したとき、タイトルのみでインデックスする
+    pub index_soft_failures: bool,
+    /// スクレイパのレスポンスキャッシュの件数 (0 で無効)
+    pub scrape_cache_size: usize,
+    /// スクレイパのレスポンスキャッシュの TTL (秒)
+    pub scrape_cache_ttl_secs: u64,
+    /// レスポンスの score / point の有効桁数 (0 で丸めない)
+    pub score_digits: usize,
+    /// これ以下のスコアのドキュメントを検索結果から除く
+    pub score_floor: f64,
+    /// 検索で書き込み中のシャードを待つ時間 (ミリ秒、None なら待たずに飛ばす)
+    pub search_lock_timeout_ms: Option<u64>,
+    /// 起動時の自己診断で問題があれば起動しない (false なら警告のみ)
+    pub strict_startup: bool,
+    /// /add で description を取る優先順
+    pub description_sources: Vec<DescriptionSource>,
+    /// 1 ドキュメントの異なり語数の上限 (None なら無制限)
+    pub max_doc_tokens: Option<usize>,
+    /// range 省略時などの検索結果の件数
+    pub default_search_results: usize,
+    /// 1 リクエストで生成する検索結果の上限 (range の幅をこれに切り詰める、MAX_SEARCH_RESULTS 以下)
+    pub max_results_per_request: usize,
+    /// algo 省略時のアルゴリズム (algo パラメータと同じ形式)
+    pub default_algorithm: String,
+    /// 管理系 (/reload, /save, /split/:id, /clear, /recalc_sizes, /fsck, /debug/prune_corpus) に必要なトークン (None ならこれらは無効)
+    pub reload_token: Option<String>,
+    /// 削除時にドキュメントの meta も消す (メモリを返す代わりに write lock が少し長くなる)
+    pub del_removes_meta: bool,
+    /// インデックスのファイル保存の設定 (DURABLE_SAVE / SAVE_BUFFER_BYTES)
+    pub save_options: SaveOptions,
+    /// 文字 3-gram インデックスを作る (substring 検索用、メモリとディスクを多く使う)
+    pub trigram_index: bool,
+    /// スクレイパを使わない (/add は 501)
+    pub scraper_disabled: bool,
+    /// リクエストごとにアクセスログ (メソッド・パス・ステータス・処理時間) を出す
+    pub log_requests: bool,
+    /// /add の scraper_url で指定できるホスト ("host" か "host:port"、空なら指定できない)
+    pub scraper_url_allowlist: Vec<String>,
+    /// 更新系 (/add, /bulk_add, /del/*, /debug/shard/:id) に必要な API キー (空なら認証しない)
+    pub api_keys: Vec<String>,
+    /// 検索などの読み取り系にも API キーを必要にする
+    pub api_key_reads: bool,
+}
+
+/// 起動時のログなどに出すので、秘密の値 (reload_token / api_keys) は伏せる
+/// 分割代入しているので、フィールドを足すとここも直すまでコンパイルが通らない
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const REDACTED: &str = "<redacted>";
+        let Config {
+            tokenize, debug_endpoints, enrich, corpus_file, warmup, max_body_bytes, max_query_chars,
+            store_desc_tokens, score_top_k, score_merge, tags_file, sudachi, shard_dirs,
+            index_soft_failures, scrape_cache_size, scrape_cache_ttl_secs, score_digits,
+            score_floor, search_lock_timeout_ms, strict_startup, description_sources,
+            max_doc_tokens, default_search_results, max_results_per_request, default_algorithm,
+            reload_token, del_removes_meta, save_options, trigram_index, scraper_disabled,
+            log_requests, scraper_url_allowlist, api_keys, api_key_reads,
+        } = self;
+        f.debug_struct("Config")
+            .field("tokenize", tokenize)
+            .field("debug_endpoints", debug_endpoints)
+            .field("enrich", enrich)
+            .field("corpus_file", corpus_file)
+            .field("warmup", warmup)
+            .field("max_body_bytes", max_body_bytes)
+            .field("max_query_chars", max_query_chars)
+            .field("store_desc_tokens", store_desc_tokens)
+            .field("score_top_k", score_top_k)
+            .field("score_merge", score_merge)
+            .field("tags_file", tags_file)
+            .field("sudachi", sudachi)
+            .field("shard_dirs", shard_dirs)
+            .field("index_soft_failures", index_soft_failures)
+            .field("scrape_cache_size", scrape_cache_size)
+            .field("scrape_cache_ttl_secs", scrape_cache_ttl_secs)
+            .field("score_digits", score_digits)
+            .field("score_floor", score_floor)
+            .field("search_lock_timeout_ms", search_lock_timeout_ms)
+            .field("strict_startup", strict_startup)
+            .field("description_sources", description_sources)
+            .field("max_doc_tokens", max_doc_tokens)
+            .field("default_search_results", default_search_results)
+            .field("max_results_per_request", max_results_per_request)
+            .field("default_algorithm", default_algorithm)
+            .field("reload_token", &reload_token.as_ref().map(|_| REDACTED))
+            .field("del_removes_meta", del_removes_meta)
+            .field("save_options", save_options)
+            .field("trigram_index", trigram_index)
+            .field("scraper_disabled", scraper_disabled)
+            .field("log_requests", log_requests)
+            .field("scraper_url_allowlist", scraper_url_allowlist)
+            .field("api_keys", &vec![REDACTED; api_keys.len()])
+            .field("api_key_reads", api_key_reads)
+            .finish()
+    }
+}
+
+impl Default for Config {
+    /// 環境変数が全て未設定の場合と同じ
+    fn default() -> Self {
+        Self::from_lookup(|_| None)
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self::from_lookup(|key| std::env::var(key).ok())
+    }
+
+    /// CONFIG_FILE (KEY=VALUE 形式) があればその値を環境変数より優先して読み込む
+    /// 実行中は環境変数を変えられないので、/reload で設定を変えるときはファイルを使う
+    pub fn load() -> Result<Self, String> {
+        let file = match std::env::var("CONFIG_FILE").ok().filter(|p| !p.trim().is_empty()) {
+            Some(path) => {
+                let text = std::fs::read_to_string(path.trim()).map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+                parse_config_file(&text)
+            }
+            None => HashMap::new(),
+        };
+        Ok(Self::from_lookup(|key| file.get(key).cloned().or_else(|| std::env::var(key).ok())))
+    }
+
+    /// sudachi / タグ定義 / スコアの桁数などプロセス全体の設定に反映する
+    /// 起動時と /reload で使う 失敗したら何も変更しない
+    pub fn install_globals(&self) -> Result<(), String> {
+        validate_scoring(&self.default_algorithm).map_err(|e| format!("DEFAULT_ALGORITHM: {}", e))?;
+        self.sudachi.validate()?;
+        let registry = match &self.tags_file {
+            Some(path) => {
+                let registry = TagRegistry::from_file(path)?;
+                log::info!("Loaded {} tags from {}", registry.len(), path);
+                registry
+            }
+            None => TagRegistry::default(),
+        };
+        registry.install();
+        self.sudachi.clone().install();
+        set_score_digits(self.score_digits);
+        Ok(())
+    }
+
+    /// 起動時にしか反映されない設定のうち self から変わったものの名前
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.corpus_file != new.corpus_file {
+            changed.push("CORPUS_FILE");
+        }
+        if self.shard_dirs != new.shard_dirs {
+            changed.push("SHARD_DIRS");
+        }
+        if self.score_floor != new.score_floor {
+            changed.push("SCORE_FLOOR");
+        }
+        if self.search_lock_timeout_ms != new.search_lock_timeout_ms {
+            changed.push("SEARCH_LOCK_TIMEOUT_MS");
+        }
+        if self.del_removes_meta != new.del_removes_meta {
+            changed.push("DEL_REMOVES_META");
+        }
+        if self.save_options.durable != new.save_options.durable {
+            changed.push("DURABLE_SAVE");
+        }
+        if self.save_options.buffer_capacity != new.save_options.buffer_capacity {
+            changed.push("SAVE_BUFFER_BYTES");
+        }
+        if self.save_options.meta_format != new.save_options.meta_format {
+            changed.push("META_FORMAT");
+        }
+        if self.trigram_index != new.trigram_index {
+            changed.push("TRIGRAM_INDEX");
+        }
+        if self.scrape_cache_size != new.scrape_cache_size || self.scrape_cache_ttl_secs != new.scrape_cache_ttl_secs {
+            changed.push("SCRAPE_CACHE_SIZE / SCRAPE_CACHE_TTL");
+        }
+        changed
+    }
+
+    /// インデックス済みのドキュメントとトークン化が変わる設定のうち self から変わったものの名前
+    /// /reload では変えられない (既存のドキュメントとクエリのトークンが合わなくなる)
+    pub fn tokenization_changes(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.tokenize.pos_filter != new.tokenize.pos_filter {
+            changed.push("POS_FILTER");
+        }
+        if self.tokenize.min_token_len != new.tokenize.min_token_len {
+            changed.push("MIN_TOKEN_LEN");
+        }
+        if self.sudachi.bin != new.sudachi.bin {
+            changed.push("SUDACHI_BIN");
+        }
+        if self.sudachi.config != new.sudachi.config {
+            changed.push("SUDACHI_CONFIG");
+        }
+        if self.sudachi.dict != new.sudachi.dict {
+            changed.push("SUDACHI_DICT");
+        }
+        if self.sudachi.user_dict != new.sudachi.user_dict {
+            changed.push("SUDACHI_USER_DICT");
+        }
+        if self.sudachi.normalized_column != new.sudachi.normalized_column {
+            changed.push("SUDACHI_NORMALIZED_COLUMN");
+        }
+        if self.max_doc_tokens != new.max_doc_tokens {
+            changed.push("MAX_DOC_TOKENS");
+        }
+        if self.enrich.title_weight != new.enrich.title_weight {
+            changed.push("TITLE_WEIGHT");
+        }
+        if self.enrich.heading_weight != new.enrich.heading_weight {
+            changed.push("HEADING_WEIGHT");
+        }
+        changed
+    }
+
+    /// 任意のキー参照関数から構築 (テスト用に環境変数を差し替え可能)
+    ///
+    /// - POS_FILTER: "1"/"true" で CONTENT_POS、カンマ区切りで任意の品詞、未設定/"0" で無効
+    /// - DEBUG_ENDPOINTS: "1"/"true" で /debug/* を有効化
+    /// - ENRICH_TAGS / ENRICH_AUTHOR / ENRICH_PUBLISHED: "1"/"true" でそれぞれ有効化
+    /// - HEADING_WEIGHT: 見出しトークンの重み (未設定/0 で見出しを使わない)
+    /// - TITLE_WEIGHT: タイトルトークンの重み (デフォルト 1、0 でタイトルを使わない)
+    /// - CORPUS_FILE: コーパスのファイル名
+    /// - WARMUP: "1"/"true" で起動時にウォームアップ
+    /// - MAX_BODY_BYTES: リクエストボディの最大バイト数 (デフォルト 1MiB)
+    /// - MAX_QUERY_CHARS: 検索クエリの最大文字数 (デフォルト 2000)
+    /// - STORE_DESC_TOKENS: "1"/"true" で description のトークン位置を保存
+    /// - SCORE_TOP_K: シャードごとに保持するスコア上位件数 (未設定/0 で全件)
+    /// - SCORE_MERGE: SCORE_TOP_K のシャード間のまとめ方 "flat" (デフォルト) / "kway"
+    /// - TAGS_FILE: タグ定義ファイルのパス (未設定なら組み込みのタグ)
+    /// - MIN_TOKEN_LEN: これより短い (文字数) トークンを捨てる (デフォルト 1 = フィルタなし)
+    /// - SUDACHI_BIN / SUDACHI_CONFIG / SUDACHI_DICT: sudachi の実行ファイル (デフォルト "sudachi") / 設定ファイル / 辞書
+    /// - SUDACHI_USER_DICT: ビルド済みのユーザ辞書
+    /// - SUDACHI_NORMALIZED_COLUMN: sudachi -a の出力で正規化形がある列 (0 始まり、デフォルト 2、2 未満なら起動しない)
+    /// - SHARD_DIRS: "1"/"true" で新規インデックスのシャードを shard_N/ に分けて保存
+    /// - INDEX_SOFT_FAILURES: "1"/"true" でスクレイパの一時的な失敗時もタイトルのみでインデックス
+    /// - SCORE_DIGITS: レスポンスの score / point の有効桁数 (デフォルト 4, 0 で丸めない)
+    /// - SCORE_FLOOR: これ以下のスコアを結果から除く (デフォルト 0.0 = 語が一致しないものを除く)
+    /// - SEARCH_LOCK_TIMEOUT_MS: 検索で書き込み中のシャードを待つミリ秒 (超えたら 503、未設定なら待たずに飛ばす)
+    /// - SCRAPE_CACHE_SIZE / SCRAPE_CACHE_TTL: スクレイパのレスポンスキャッシュの件数 (未設定/0 で無効) / TTL 秒 (デフォルト 600)
+    /// - STRICT_STARTUP: "1"/"true" で起動時の自己診断の失敗を致命的にする
+    /// - DESC_SOURCES: description の取得元の優先順 (カンマ区切り、デフォルト "request,scraper_meta,body_head")
+    /// - MAX_DOC_TOKENS: 1 ドキュメントの異なり語数の上限 (未設定/0 で無制限)
+    /// - DEFAULT_SEARCH_RESULTS: range 省略時の検索結果の件数 (デフォルト 20)
+    /// - MAX_RESULTS_PER_REQUEST: 1 リクエストで返す検索結果の上限 (デフォルト/最大 MAX_SEARCH_RESULTS = 1000)
+    /// - DEFAULT_ALGORITHM: algo 省略時のアルゴリズム (デフォルト BM25(1.2,0.75)、読めなければ起動しない)
+    /// - RELOAD_TOKEN: 管理系 (/reload /save /split/:id /clear /recalc_sizes /fsck /debug/prune_corpus) の Bearer トークン (未設定ならこれらは無効)
+    /// - DEL_REMOVES_META: "1"/"true" で削除時に meta も消す
+    /// - DURABLE_SAVE: "1"/"true" でインデックスの各ファイルを書いた後に fsync する
+    /// - SAVE_BUFFER_BYTES: 保存時の BufWriter の容量 (デフォルト 8192)
+    /// - META_FORMAT: .meta の保存形式 "bincode" (デフォルト) / "json"
+    /// - TRIGRAM_INDEX: "1"/"true" で文字 3-gram インデックスを作る (substring 検索)
+    /// - SCRAPER_DISABLED: "1"/"true" でスクレイパを使わない (/add は 501)
+    /// - LOG_REQUESTS: "1"/"true" でリクエストごとにアクセスログを出す
+    /// - SCRAPER_URL_ALLOWLIST: /add の scraper_url で使えるホスト (カンマ区切り、未設定なら scraper_url は 400)
+    /// - API_KEYS: /add /bulk_add /del/* /debug/shard/:id に必要な API キー (カンマ区切り、未設定なら認証しない、管理系は RELOAD_TOKEN で守る)
+    /// - API_KEY_READS: "1"/"true" で検索などの読み取り系にも API キーを必要にする
+    pub fn from_lookup<F>(lookup: F) -> Self
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let pos_filter = lookup("POS_FILTER").and_then(|v| {
+            let v = v.trim();
+            match v.to_ascii_lowercase().as_str() {
+                "" | "0" | "false" => None,
+                "1" | "true" => Some(CONTENT_POS.iter().map(|p| p.to_string()).collect()),
+                _ => Some(v.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect()),
+            }
+        });
+        Self {
+            tokenize: TokenizeOptions {
+                pos_filter,
+                min_token_len: lookup("MIN_TOKEN_LEN").and_then(|v| v.trim().parse().ok()).unwrap_or(1),
+            },
+            debug_endpoints: flag(&lookup, "DEBUG_ENDPOINTS"),
+            enrich: EnrichOptions {
+                scraper_tags: flag(&lookup, "ENRICH_TAGS"),
+                author: flag(&lookup, "ENRICH_AUTHOR"),
+                published: flag(&lookup, "ENRICH_PUBLISHED"),
+                heading_weight: lookup("HEADING_WEIGHT").and_then(|v| v.trim().parse().ok()).unwrap_or(0),
+                title_weight: lookup("TITLE_WEIGHT").and_then(|v| v.trim().parse().ok()).unwrap_or(DEFAULT_TITLE_WEIGHT),
+            },
+            corpus_file: lookup("CORPUS_FILE").map(|v| v.trim().to_string()).filter(|v| !v.is_empty()),
+            warmup: flag(&lookup, "WARMUP"),
+            max_body_bytes: lookup("MAX_BODY_BYTES").and_then(|v| v.trim().parse().ok()).unwrap_or(DEFAULT_MAX_BODY_BYTES),
+            max_query_chars: lookup("MAX_QUERY_CHARS").and_then(|v| v.trim().parse().ok()).unwrap_or(DEFAULT_MAX_QUERY_CHARS),
+            store_desc_tokens: flag(&lookup, "STORE_DESC_TOKENS"),
+            score_top_k: lookup("SCORE_TOP_K").and_then(|v| v.trim().parse().ok()).filter(|&k| k > 0),
+            score_merge: lookup("SCORE_MERGE").and_then(|v| ScoreMerge::parse(&v)).unwrap_or_default(),
+            tags_file: lookup("TAGS_FILE").map(|v| v.trim().to_string()).filter(|v| !v.is_empty()),
+            sudachi: SudachiCommand {
+                bin: non_empty(&lookup, "SUDACHI_BIN").unwrap_or_else(|| DEFAULT_SUDACHI_BIN.to_string()),
+                config: non_empty(&lookup, "SUDACHI_CONFIG"),
+                dict: non_empty(&lookup, "SUDACHI_DICT"),
+                user_dict: non_empty(&lookup, "SUDACHI_USER_DICT"),
+                normalized_column: lookup("SUDACHI_NORMALIZED_COLUMN").and_then(|v| v.trim().parse().ok()).unwrap_or(DEFAULT_NORMALIZED_COLUMN),
+            },
+            shard_dirs: flag(&lookup, "SHARD_DIRS"),
+            index_soft_failures: flag(&lookup, "INDEX_SOFT_FAILURES"),
+            scrape_cache_size: lookup("SCRAPE_CACHE_SIZE").and_then(|v| v.trim().parse().ok()).unwrap_or(0),
+            score_digits: lookup("SCORE_DIGITS").and_then(|v| v.trim().parse().ok()).unwrap_or(DEFAULT_SCORE_DIGITS),
+            score_floor: lookup("SCORE_FLOOR").and_then(|v| v.trim().parse().ok()).unwrap_or(DEFAULT_SCORE_FLOOR),
+            search_lock_timeout_ms: lookup("SEARCH_LOCK_TIMEOUT_MS").and_then(|v| v.trim().parse().ok()),
+            scrape_cache_ttl_secs: lookup("SCRAPE_CACHE_TTL").and_then(|v| v.trim().parse().ok()).unwrap_or(DEFAULT_SCRAPE_CACHE_TTL_SECS),
+            strict_startup: flag(&lookup, "STRICT_STARTUP"),
+            description_sources: lookup("DESC_SOURCES")
+                .and_then(|v| DescriptionSource::parse_list(&v))
+                .unwrap_or_else(|| DEFAULT_DESCRIPTION_SOURCES.to_vec()),
+            max_doc_tokens: lookup("MAX_DOC_TOKENS").and_then(|v| v.trim().parse().ok()).filter(|&n| n > 0),
+            default_search_results: lookup("DEFAULT_SEARCH_RESULTS").and_then(|v| v.trim().parse().ok()).filter(|&n| n > 0).unwrap_or(DEFAULT_SEARCH_RESULTS),
+            max_results_per_request: lookup("MAX_RESULTS_PER_REQUEST").and_then(|v| v.trim().parse().ok()).filter(|&n| n > 0).map_or(MAX_SEARCH_RESULTS, |n: usize| n.min(MAX_SEARCH_RESULTS)),
+            default_algorithm: non_empty(&lookup, "DEFAULT_ALGORITHM").unwrap_or_else(|| DEFAULT_ALGORITHM.to_string()),
+            reload_token: non_empty(&lookup, "RELOAD_TOKEN"),
+            del_removes_meta: flag(&lookup, "DEL_REMOVES_META"),
+            save_options: SaveOptions {
+                durable: flag(&lookup, "DURABLE_SAVE"),
+                buffer_capacity: lookup("SAVE_BUFFER_BYTES").and_then(|v| v.trim().parse().ok()).filter(|&n| n > 0).unwrap_or(DEFAULT_SAVE_BUFFER_BYTES),
+                meta_format: lookup("META_FORMAT").and_then(|v| MetaFormat::parse(&v)).unwrap_or_default(),
+            },
+            trigram_index: flag(&lookup, "TRIGRAM_INDEX"),
+            scraper_disabled: flag(&lookup, "SCRAPER_DISABLED"),
+            log_requests: flag(&lookup, "LOG_REQUESTS"),
+            scraper_url_allowlist: lookup("SCRAPER_URL_ALLOWLIST")
+                .map(|v| v.split(',').map(|h| h.trim().to_ascii_lowercase()).filter(|h| !h.is_empty()).collect())
+                .unwrap_or_default(),
+            api_keys: lookup("API_KEYS")
+                .map(|v| v.split(',').map(|k| k.trim().to_string()).filter(|k| !k.is_empty()).collect())
+                .unwrap_or_default(),
+            api_key_reads: flag(&lookup, "API_KEY_READS"),
+        }
+    }
+}
+
+/// KEY=VALUE の行をパース (空行と # で始まる行は無視、値の前後の空白と引用符は除く)
+pub fn parse_config_file(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().trim_matches('"').to_string()))
+        .collect()
+}
+
+/// 前後の空白を除いた値 (未設定/空は None)
+fn non_empty<F>(lookup: &F, key: &str) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    lookup(key).map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+/// "1" / "true" を真とみなす (未設定は偽)
+fn flag<F>(lookup: &F, key: &str) -> bool
+where
+    F: Fn(&str) -> Option<String>,
+{
+    lookup(key)
+        .map(|v| {
+            let v = v.trim().to_ascii_lowercase();
+            v == "1" || v == "true"
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup<'a>(pairs: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |key| pairs.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string())
+    }
+
+    #[test]
+    fn test_pos_filter_from_env() {
+        assert!(Config::from_lookup(lookup(&[])).tokenize.pos_filter.is_none());
+        assert!(Config::from_lookup(lookup(&[("POS_FILTER", "0")])).tokenize.pos_filter.is_none());
+        let content = Config::from_lookup(lookup(&[("POS_FILTER", "1")]));
+        assert_eq!(content.tokenize.pos_filter.unwrap(), CONTENT_POS);
+        let custom = Config::from_lookup(lookup(&[("POS_FILTER", "名詞, 動詞")]));
+        assert_eq!(custom.tokenize.pos_filter.unwrap(), vec!["名詞", "動詞"]);
+    }
+
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let config = Config::from_lookup(lookup(&[("RELOAD_TOKEN", "reload-secret"), ("API_KEYS", "key-one,key-two")]));
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("reload-secret"), "{}", debug);
+        assert!(!debug.contains("key-one") && !debug.contains("key-two"), "{}", debug);
+        assert!(debug.contains("<redacted>"));
+        assert!(debug.contains("max_body_bytes"));
+    }
+
+    #[test]
+    fn test_debug_endpoints_flag() {
+        assert!(!Config::from_lookup(lookup(&[])).debug_endpoints);
+        assert!(Config::from_lookup(lookup(&[("DEBUG_ENDPOINTS", "1")])).debug_endpoints);
+        assert!(Config::from_lookup(lookup(&[("DEBUG_ENDPOINTS", "TRUE")])).debug_endpoints);
+        assert!(!Config::from_lookup(lookup(&[("DEBUG_ENDPOINTS", "no")])).debug_endpoints);
+    }
+
+    #[test]
+    fn test_enrich_flags() {
+        let none = Config::from_lookup(lookup(&[]));
+        assert!(!none.enrich.scraper_tags && !none.enrich.author && !none.enrich.published);
+        assert_eq!(none.enrich.heading_weight, 0);
+        assert_eq!(none.enrich.title_weight, DEFAULT_TITLE_WEIGHT);
+        let some = Config::from_lookup(lookup(&[("ENRICH_AUTHOR", "1"), ("HEADING_WEIGHT", "3"), ("TITLE_WEIGHT", "0")]));
+        assert!(some.enrich.author && !some.enrich.published && !some.enrich.scraper_tags);
+        assert_eq!(some.enrich.heading_weight, 3);
+        assert_eq!(some.enrich.title_weight, 0);
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let file = parse_config_file("# comment\nDEFAULT_SEARCH_RESULTS = 5\n\nTAGS_FILE=\"tags.json\"\nbroken line\n");
+        assert_eq!(file.len(), 2);
+        let config = Config::from_lookup(|key| file.get(key).cloned());
+        assert_eq!(config.default_search_results, 5);
+        assert_eq!(config.tags_file.as_deref(), Some("tags.json"));
+        assert_eq!(Config::default().default_search_results, DEFAULT_SEARCH_RESULTS);
+        assert_eq!(Config::default().restart_required(&config), Vec::<&str>::new());
+        assert_eq!(Config::default().restart_required(&Config::from_lookup(lookup(&[("SHARD_DIRS", "1")]))), vec!["SHARD_DIRS"]);
+        assert!(Config::default().tokenization_changes(&config).is_empty());
+        let changed = Config::from_lookup(lookup(&[("POS_FILTER", "1"), ("SUDACHI_USER_DICT", "user.dic"), ("MAX_DOC_TOKENS", "100"), ("TITLE_WEIGHT", "0")]));
+        assert_eq!(Config::default().tokenization_changes(&changed), vec!["POS_FILTER", "SUDACHI_USER_DICT", "MAX_DOC_TOKENS", "TITLE_WEIGHT"]);
+    }
+
+    #[test]
+    fn test_sudachi_command() {
+        assert_eq!(Config::from_lookup(lookup(&[])).sudachi, SudachiCommand::default());
+        let custom = Config::from_lookup(lookup(&[("SUDACHI_BIN", "/opt/sudachi"), ("SUDACHI_DICT", " user.dic "), ("SUDACHI_CONFIG", "")]));
+        assert_eq!(custom.sudachi.bin, "/opt/sudachi");
+        assert_eq!(custom.sudachi.dict.as_deref(), Some("user.dic"));
+        assert!(custom.sudachi.config.is_none());
+        assert_eq!(custom.sudachi.normalized_column, DEFAULT_NORMALIZED_COLUMN);
+        let column = Config::from_lookup(lookup(&[("SUDACHI_NORMALIZED_COLUMN", "3")]));
+        assert_eq!(column.sudachi.normalized_column, 3);
+    }
+}
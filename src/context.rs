@@ -1,28 +1,550 @@
 use std::sync::Arc;
 
-use kurosabi::context::ContextMiddleware;
+use arc_swap::ArcSwap;
+use kurosabi::{context::ContextMiddleware, kurosabi::Context, response::Res};
+use serde::de::DeserializeOwned;
 
-use crate::index::IndexPool;
+use crate::{config::Config, http_client::ScrapeCache, index::{IndexPool, ShardLayout}, jobs::JobStore};
+
+/// リクエストID のヘッダ名
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// API キーを渡すヘッダ (Authorization: Bearer でもよい)
+pub const API_KEY_HEADER: &str = "X-API-Key";
+/// 受け取ったリクエストID を採用する最大長
+pub const MAX_REQUEST_ID_LENGTH: usize = 128;
+/// アクセスログ (LOG_REQUESTS) のログターゲット
+pub const ACCESS_LOG_TARGET: &str = "access";
 
 #[derive(Clone)]
 pub struct SearchContext {
     pub index_pool: Arc<IndexPool>,
+    /// このリクエストで使う設定 (before_handle で最新のものに差し替える)
+    pub config: Arc<Config>,
+    /// /reload で差し替える設定 (全リクエストで共有)
+    /// リクエストごとに読むのでロックを取らない ArcSwap
+    live_config: Arc<ArcSwap<Config>>,
+    /// スクレイパのレスポンスキャッシュ (SCRAPE_CACHE_SIZE 未設定なら None)
+    pub scrape_cache: Option<Arc<ScrapeCache>>,
+    /// /bulk_add のバックグラウンドジョブ (全リクエストで共有)
+    pub jobs: Arc<JobStore>,
+    /// リクエストごとの ID
+    /// コンテキストはリクエストごとに clone されるので before_handle で設定する
+    pub request_id: String,
+    /// リクエストの開始時刻 (before_handle で設定する)
+    pub started: std::time::Instant,
+    /// API キーの確認結果 (before_handle で設定し、ハンドラの先頭で見る)
+    pub auth_error: Option<(u16, String)>,
 }
 
 impl SearchContext {
-    pub fn new(index_dir: &str) -> Self {
-        let index_pool = match IndexPool::load_or_new(index_dir) {
-            Ok(pool) => {
+    pub fn new(index_dir: &str, config: Config) -> Self {
+        let layout = if config.shard_dirs { ShardLayout::Nested } else { ShardLayout::Flat };
+        let index_pool = match IndexPool::load_or_new(index_dir, config.corpus_file.as_deref(), layout) {
+            Ok(mut pool) => {
                 log::info!("Index pool loaded successfully");
+                pool.apply_config(&config);
                 Arc::new(pool)
             },
             Err(e) => {
                 panic!("Failed to load or create index pool: {}", e);
             }
         };
-        Self { index_pool }
+        let scrape_cache = (config.scrape_cache_size > 0).then(|| {
+            Arc::new(ScrapeCache::new(config.scrape_cache_size, std::time::Duration::from_secs(config.scrape_cache_ttl_secs)))
+        });
+        let config = Arc::new(config);
+        Self { index_pool, live_config: Arc::new(ArcSwap::new(config.clone())), config, scrape_cache, jobs: Arc::new(JobStore::default()), request_id: String::new(), started: std::time::Instant::now(), auth_error: None }
+    }
+
+    /// 最新の設定
+    pub fn current_config(&self) -> Arc<Config> {
+        self.live_config.load_full()
+    }
+
+    /// config を最新にする (リクエストの開始時)
+    pub fn refresh_config(&mut self) {
+        self.config = self.current_config();
+    }
+
+    /// 設定を差し替える インデックスはそのまま
+    /// 起動時にしか反映されない設定が変わっていればその名前を返す (新しい値は再起動まで使われない)
+    /// トークン化が変わる設定を変えようとしたときは何も変えずにエラー
+    pub fn reload(&self, config: Config) -> Result<Vec<&'static str>, String> {
+        let tokenization = self.current_config().tokenization_changes(&config);
+        if !tokenization.is_empty() {
+            return Err(format!("{} cannot be changed by reload because indexed documents were tokenized with the current values; restart and reindex instead", tokenization.join(", ")));
+        }
+        config.install_globals()?;
+        let restart_required = self.current_config().restart_required(&config);
+        self.live_config.store(Arc::new(config));
+        Ok(restart_required)
+    }
+}
+
+/// 管理系 (/reload, /save, /split/:id, /clear, /recalc_sizes, /fsck, /debug/prune_corpus) の Authorization ヘッダを確認
+/// # Returns
+/// Err((HTTP ステータス, エラーメッセージ))
+pub fn check_admin_auth(token: Option<&str>, authorization: Option<&str>) -> Result<(), (u16, String)> {
+    let Some(token) = token else { return Err((403, "admin endpoints disabled".to_string())); };
+    if token_matches(bearer_token(authorization).unwrap_or(""), token) { Ok(()) } else { Err((401, "invalid admin token".to_string())) }
+}
+
+fn bearer_token(authorization: Option<&str>) -> Option<&str> {
+    authorization.and_then(|h| h.trim().strip_prefix("Bearer ")).map(|t| t.trim())
+}
+
+/// 長さ以外で時間差が出ないように全バイト比較する
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given.bytes().zip(token.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// API キーの確認でのエンドポイントの分類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteAccess {
+    /// 検索・ステータスなど (API_KEY_READS のときのみ API キーが必要)
+    Read,
+    /// インデックスを変更する (API_KEYS があれば API キーが必要)
+    Write,
+    /// RELOAD_TOKEN で守られている (API キーは見ない)
+    Admin,
+}
+
+impl RouteAccess {
+    /// リクエストのパスから分類する
+    /// ルーターと同じく ?/# 以降と余分な / を無視してから見る
+    /// /del/* は GET でも更新系、一覧にないパスは Write 扱い
+    pub fn of(raw_path: &str) -> Self {
+        let path = route_path(raw_path);
+        match path.as_str() {
+            "/status" | "/openapi.json" | "/version" | "/search" | "/trending" | "/idf" | "/doc/vector" | "/indexed" => Self::Read,
+            p if p.starts_with("/jobs/") => Self::Read,
+            "/reload" | "/save" | "/clear" | "/recalc_sizes" | "/fsck" | "/debug/prune_corpus" => Self::Admin,
+            p if p.starts_with("/split/") => Self::Admin,
+            _ => Self::Write,
+        }
+    }
+}
+
+/// kurosabi のルーターと同じ正規化をしたパス
+/// ?/# 以降を落とし、空のセグメントを詰めて先頭に / を付ける
+pub fn route_path(raw_path: &str) -> String {
+    let path = raw_path.split(['?', '#']).next().unwrap_or_default();
+    let segs: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    format!("/{}", segs.join("/"))
+}
+
+/// API キーを確認 (X-API-Key ヘッダか Authorization: Bearer)
+/// # Returns
+/// Err((HTTP ステータス, エラーメッセージ))
+pub fn check_api_key(config: &Config, access: RouteAccess, api_key: Option<&str>, authorization: Option<&str>) -> Result<(), (u16, String)> {
+    let required = match access {
+        RouteAccess::Write => true,
+        RouteAccess::Read => config.api_key_reads,
+        RouteAccess::Admin => false,
+    };
+    if !required || config.api_keys.is_empty() {
+        return Ok(());
+    }
+    let given = api_key.map(|k| k.trim()).or_else(|| bearer_token(authorization)).unwrap_or("");
+    // どのキーと一致したかで時間差が出ないように全て比較する
+    let matched = config.api_keys.iter().fold(false, |acc, key| token_matches(given, key) | acc);
+    if matched { Ok(()) } else { Err((401, "Missing or invalid API key".to_string())) }
+}
+
+/// スクレイパを使う /add が使えるか確認
+/// # Returns
+/// Err((HTTP ステータス, エラーメッセージ))
+pub fn check_scraper_enabled(config: &Config) -> Result<(), (u16, String)> {
+    if config.scraper_disabled {
+        return Err((501, "Scraper is disabled (SCRAPER_DISABLED), /add is not available".to_string()));
     }
+    Ok(())
 }
 
+/// /add で使うスクレイパ API の URL (この後ろに対象ページの URL を付ける)
+/// scraper_url の指定がなければ default、あればホストが allowlist にある http(s) の URL のみ認める (SSRF 対策)
+/// allowlist は "host" (ポートを問わない) か "host:port"
+/// # Returns
+/// Err((HTTP ステータス, エラーメッセージ))
+pub fn resolve_scraper_url(scraper_url: Option<&str>, default: &str, allowlist: &[String]) -> Result<String, (u16, String)> {
+    let Some(scraper_url) = scraper_url.map(|u| u.trim()) else { return Ok(default.to_string()); };
+    let rejected = || (400, format!("scraper_url is not allowed: {}", scraper_url));
+    let parsed = reqwest::Url::parse(scraper_url).map_err(|_| rejected())?;
+    if !matches!(parsed.scheme(), "http" | "https") || !parsed.username().is_empty() || parsed.password().is_some() {
+        return Err(rejected());
+    }
+    let host = parsed.host_str().ok_or_else(rejected)?.to_ascii_lowercase();
+    let host_port = parsed.port_or_known_default().map(|port| format!("{}:{}", host, port));
+    if allowlist.iter().any(|allowed| *allowed == host || Some(allowed) == host_port.as_ref()) {
+        Ok(scraper_url.to_string())
+    } else {
+        Err(rejected())
+    }
+}
+
+/// リクエストID を決定
+/// ヘッダで渡された ID が妥当ならそのまま使い、なければ UUID を生成する
+/// ヘッダインジェクション対策で印字可能 ASCII のみ受け付ける
+pub fn request_id_from_header(header: Option<&str>) -> String {
+    match header.map(|h| h.trim()) {
+        Some(h) if !h.is_empty()
+            && h.len() <= MAX_REQUEST_ID_LENGTH
+            && h.chars().all(|c| c.is_ascii_graphic()) => h.to_string(),
+        _ => uuid::Uuid::new_v4().to_string(),
+    }
+}
+
+/// アクセスログを 1 行出す (LOG_REQUESTS)
+/// path はクエリを除いたもの
+pub fn log_request(method: &str, path: &str, status: u16, elapsed: std::time::Duration, request_id: &str) {
+    log::info!(
+        target: ACCESS_LOG_TARGET,
+        "method={} path={} status={} latency_ms={:.3} request_id={}",
+        method, path, status, elapsed.as_secs_f64() * 1000.0, request_id,
+    );
+}
+
+/// Content-Length を上限と比較
+/// kurosabi は Content-Length 分をそのまま確保して読むので、読む前に弾く
+/// Content-Length がなければ kurosabi は空ボディとして扱うので通す
+/// # Returns
+/// Err((HTTP ステータス, エラーメッセージ))
+pub fn check_content_length(header: Option<&str>, max: usize) -> Result<(), (u16, String)> {
+    let Some(header) = header else { return Ok(()); };
+    match header.trim().parse::<usize>() {
+        Ok(len) if len > max => Err((413, format!("Request body too large ({} > {} bytes)", len, max))),
+        Ok(_) => Ok(()),
+        Err(_) => Err((400, "Invalid Content-Length".to_string())),
+    }
+}
+
+/// テキスト系の Content-Type に charset=utf-8 を付ける
+/// kurosabi の json_value / text は charset を付けないので、クライアントによっては日本語が文字化けする
+pub fn set_utf8_charset(res: &mut Res) {
+    let Some(content_type) = res.header.get("Content-Type") else { return; };
+    let lower = content_type.to_ascii_lowercase();
+    let textual = lower.starts_with("text/") || lower.starts_with("application/json");
+    if textual && !lower.contains("charset=") {
+        let with_charset = format!("{}; charset=utf-8", content_type);
+        // set は既存の値を置き換えずに追加するので先に消す
+        res.header.del("Content-Type");
+        res.header.set("Content-Type", &with_charset);
+    }
+}
+
+/// どのルートにも一致しなかったときのレスポンス
+/// 他のエンドポイントと同じく JSON で返す
+pub fn not_found(res: &mut Res) {
+    res.json_value(&serde_json::json!({ "success": false, "error": "not found", "code": "NOT_FOUND" }));
+    res.set_status(404);
+    set_utf8_charset(res);
+}
+
+/// リクエストボディを読めなかった理由
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyError {
+    pub status: u16,
+    pub error: String,
+    /// どのフィールドが悪いか (serde のエラーメッセージ)
+    pub detail: Option<String>,
+}
+
+impl From<(u16, String)> for BodyError {
+    fn from((status, error): (u16, String)) -> Self {
+        Self { status, error, detail: None }
+    }
+}
+
+/// JSON ボディをデシリアライズ
+/// 失敗したら serde のメッセージ (missing field `url` など) を detail に入れる
+pub fn parse_json_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, BodyError> {
+    serde_json::from_slice(body).map_err(|e| BodyError {
+        status: 400,
+        error: "Invalid request body".to_string(),
+        detail: Some(e.to_string()),
+    })
+}
+
+/// サイズ上限を確認してから JSON ボディをデシリアライズ
+pub async fn read_json_body<T: DeserializeOwned>(ctx: &mut Context<SearchContext>) -> Result<T, BodyError> {
+    check_content_length(ctx.req.header.get("Content-Length"), ctx.c.config.max_body_bytes)?;
+    let body = ctx.req.body().await.map_err(|_| BodyError::from((400, "Invalid request body".to_string())))?;
+    parse_json_body(&body)
+}
+
+#[async_trait::async_trait]
 impl ContextMiddleware<SearchContext> for SearchContext {
+    async fn before_handle(mut ctx: Context<SearchContext>) -> Context<SearchContext> {
+        ctx.c.started = std::time::Instant::now();
+        ctx.c.request_id = request_id_from_header(ctx.req.header.get(REQUEST_ID_HEADER));
+        ctx.c.refresh_config();
+        // kurosabi のミドルウェアはハンドラを止められないので結果だけ持たせる
+        ctx.c.auth_error = check_api_key(&ctx.c.config, RouteAccess::of(ctx.req.path.get_raw_path()), ctx.req.header.get(API_KEY_HEADER), ctx.req.header.get("Authorization")).err();
+        ctx
+    }
+
+    async fn after_handle(mut ctx: Context<SearchContext>) -> Context<SearchContext> {
+        let request_id = ctx.c.request_id.clone();
+        ctx.res.header.set(REQUEST_ID_HEADER, &request_id);
+        set_utf8_charset(&mut ctx.res);
+        if ctx.c.config.log_requests {
+            let method = ctx.req.method.to_string();
+            let path = ctx.req.path.get_raw_path().split('?').next().unwrap_or_default();
+            log_request(&method, path, ctx.res.code, ctx.c.started.elapsed(), &request_id);
+        }
+        ctx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use crate::search::{SearchParams, SearchReq, DEFAULT_SEARCH_RESULTS};
+
+    #[test]
+    fn test_check_content_length() {
+        let max = 1024;
+        assert!(check_content_length(None, max).is_ok());
+        assert!(check_content_length(Some("1024"), max).is_ok());
+        assert_eq!(check_content_length(Some("1025"), max).unwrap_err().0, 413);
+        assert_eq!(check_content_length(Some("abc"), max).unwrap_err().0, 400);
+    }
+
+    #[test]
+    fn test_parse_json_body_detail() {
+        use crate::collect::IndexReq;
+        let ok: IndexReq = parse_json_body(br#"{"url": "https://example.com/", "tags": ["wiki"]}"#).unwrap();
+        assert_eq!(ok.url, "https://example.com/");
+
+        let missing = parse_json_body::<IndexReq>(br#"{"tags": []}"#).unwrap_err();
+        assert_eq!(missing.status, 400);
+        assert_eq!(missing.error, "Invalid request body");
+        assert!(missing.detail.unwrap().starts_with("missing field `url`"));
+
+        let wrong_type = parse_json_body::<IndexReq>(br#"{"url": "https://example.com/", "tags": "wiki"}"#).unwrap_err();
+        let detail = wrong_type.detail.unwrap();
+        assert!(detail.starts_with("invalid type: string \"wiki\", expected a sequence"), "{}", detail);
+    }
+
+    struct CaptureLogger(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.target() == ACCESS_LOG_TARGET {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE_LOGGER: CaptureLogger = CaptureLogger(std::sync::Mutex::new(Vec::new()));
+
+    #[test]
+    fn test_log_request() {
+        let _ = log::set_logger(&CAPTURE_LOGGER);
+        log::set_max_level(log::LevelFilter::Info);
+        log_request("GET", "/search", 200, std::time::Duration::from_micros(1500), "req-1");
+        let lines = CAPTURE_LOGGER.0.lock().unwrap();
+        assert_eq!(lines.iter().filter(|l| l.contains("request_id=req-1")).collect::<Vec<_>>(),
+            vec!["method=GET path=/search status=200 latency_ms=1.500 request_id=req-1"]);
+    }
+
+    #[test]
+    fn test_not_found() {
+        let mut res = Res::new();
+        not_found(&mut res);
+        assert_eq!(res.code, 404);
+        assert_eq!(res.header.gets("Content-Type"), vec!["application/json; charset=utf-8"]);
+        let kurosabi::response::body::Body::Text(body) = &res.body else { panic!("not a text body"); };
+        let value: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(value, serde_json::json!({ "success": false, "error": "not found", "code": "NOT_FOUND" }));
+    }
+
+    #[test]
+    fn test_utf8_charset() {
+        let mut json = Res::new();
+        json.json_value(&serde_json::json!({"title": "東京"}));
+        set_utf8_charset(&mut json);
+        assert_eq!(json.header.gets("Content-Type"), vec!["application/json; charset=utf-8"]);
+        // 二重に付けない
+        set_utf8_charset(&mut json);
+        assert_eq!(json.header.gets("Content-Type"), vec!["application/json; charset=utf-8"]);
+
+        let mut text = Res::new();
+        text.text("Not Found");
+        set_utf8_charset(&mut text);
+        assert_eq!(text.header.get("Content-Type"), Some("text/plain; charset=utf-8"));
+
+        let mut binary = Res::new();
+        binary.binary(&[0u8]);
+        set_utf8_charset(&mut binary);
+        assert_eq!(binary.header.get("Content-Type"), Some("application/octet-stream"));
+    }
+
+    #[test]
+    fn test_reload_changes_default_search_results() {
+        let dir = std::env::temp_dir().join(format!("wk-context-test-reload-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let context = SearchContext::new(&dir.to_string_lossy(), Config::default());
+        let req = || SearchReq { query: "rust".to_string(), ..Default::default() };
+        assert_eq!(SearchParams::new(req(), &context.config).unwrap().range, 0..DEFAULT_SEARCH_RESULTS);
+
+        let reloaded = Config::from_lookup(|key| (key == "DEFAULT_SEARCH_RESULTS").then(|| "5".to_string()));
+        assert!(context.reload(reloaded).unwrap().is_empty());
+        // 次のリクエスト
+        let mut next = context.clone();
+        next.refresh_config();
+        assert_eq!(SearchParams::new(req(), &next.config).unwrap().range, 0..5);
+        assert_eq!(next.index_pool.indexes.len(), context.index_pool.indexes.len());
+
+        // トークン化が変わる設定は変えずにエラー
+        let err = context.reload(Config::from_lookup(|key| (key == "MIN_TOKEN_LEN").then(|| "2".to_string()))).unwrap_err();
+        assert!(err.contains("MIN_TOKEN_LEN"));
+        assert_eq!(context.current_config().default_search_results, 5);
+        assert_eq!(context.current_config().tokenize.min_token_len, 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_concurrent_reads_during_reload() {
+        let dir = std::env::temp_dir().join(format!("wk-context-test-swap-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let context = SearchContext::new(&dir.to_string_lossy(), Config::default());
+        let with_results = |n: usize| Config::from_lookup(move |key| (key == "DEFAULT_SEARCH_RESULTS").then(|| n.to_string()));
+        let stop = AtomicBool::new(false);
+        let reads = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !stop.load(Ordering::Relaxed) {
+                        let mut request = context.clone();
+                        request.refresh_config();
+                        // 差し替え途中の値は見えない
+                        assert!(matches!(request.config.default_search_results, 20 | 7 | 9));
+                        reads.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+            // 読み込みと重なるまで差し替え続ける
+            let mut i = 0;
+            while i < 200 || reads.load(Ordering::Relaxed) < 1000 {
+                context.reload(with_results(if i % 2 == 0 { 7 } else { 9 })).unwrap();
+                i += 1;
+            }
+            if i % 2 == 1 {
+                context.reload(with_results(9)).unwrap();
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(context.current_config().default_search_results, 9);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_check_admin_auth() {
+        assert_eq!(check_admin_auth(None, Some("Bearer secret")).unwrap_err(), (403, "admin endpoints disabled".to_string()));
+        assert!(check_admin_auth(Some("secret"), Some("Bearer secret")).is_ok());
+        assert_eq!(check_admin_auth(Some("secret"), Some("Bearer secreT")).unwrap_err(), (401, "invalid admin token".to_string()));
+        assert_eq!(check_admin_auth(Some("secret"), None).unwrap_err().0, 401);
+
+        // /debug/prune_corpus は API キーではなく管理トークンで守る (API_KEYS 未設定でもトークンなしは 401)
+        assert_eq!(RouteAccess::of("/debug/prune_corpus"), RouteAccess::Admin);
+        assert!(check_api_key(&Config::default(), RouteAccess::of("/debug/prune_corpus"), None, None).is_ok());
+        assert_eq!(check_admin_auth(Some("secret"), None).unwrap_err(), (401, "invalid admin token".to_string()));
+    }
+
+    #[test]
+    fn test_check_api_key() {
+        use RouteAccess::*;
+        assert_eq!(RouteAccess::of("/add"), Write);
+        assert_eq!(RouteAccess::of("/del/https://example.com/"), Write);
+        assert_eq!(RouteAccess::of("/debug/shard/0"), Write);
+        assert_eq!(RouteAccess::of("//debug/prune_corpus?x=1"), Admin);
+        assert_eq!(RouteAccess::of("/reload"), Admin);
+        assert_eq!(RouteAccess::of("/split/1"), Admin);
+        assert_eq!(RouteAccess::of("/fsck"), Admin);
+        assert_eq!(RouteAccess::of("/search"), Read);
+        assert_eq!(RouteAccess::of("/search?q=a"), Read);
+        assert_eq!(RouteAccess::of("/jobs/abc"), Read);
+        // ルーターは先頭の / と ?/# 以降を無視するので同じ分類にする
+        assert_eq!(RouteAccess::of("//add"), Write);
+        assert_eq!(RouteAccess::of("/add#x"), Write);
+        assert_eq!(RouteAccess::of("/add?x=1"), Write);
+        assert_eq!(RouteAccess::of("//del/https://example.com/"), Write);
+        assert_eq!(RouteAccess::of("//reload"), Admin);
+        // 知らないパスは Write 扱い
+        assert_eq!(RouteAccess::of("/unknown"), Write);
+        assert_eq!(RouteAccess::of("/"), Write);
+
+        // キー未設定なら全て通す
+        assert!(check_api_key(&Config::default(), Write, None, None).is_ok());
+
+        let config = Config::from_lookup(|key| (key == "API_KEYS").then(|| " key1, key2 ,".to_string()));
+        assert!(check_api_key(&config, Write, Some("key2"), None).is_ok());
+        assert!(check_api_key(&config, Write, None, Some("Bearer key1")).is_ok());
+        assert_eq!(check_api_key(&config, Write, Some("key3"), None).unwrap_err().0, 401);
+        assert_eq!(check_api_key(&config, Write, None, None).unwrap_err().0, 401);
+        assert!(check_api_key(&config, Read, None, None).is_ok());
+        assert!(check_api_key(&config, Admin, None, None).is_ok());
+
+        let reads = Config::from_lookup(|key| match key {
+            "API_KEYS" => Some("key1".to_string()),
+            "API_KEY_READS" => Some("1".to_string()),
+            _ => None,
+        });
+        assert_eq!(check_api_key(&reads, Read, None, None).unwrap_err().0, 401);
+        assert!(check_api_key(&reads, Read, Some("key1"), None).is_ok());
+    }
+
+    #[test]
+    fn test_check_scraper_enabled() {
+        assert!(check_scraper_enabled(&Config::default()).is_ok());
+        let disabled = Config::from_lookup(|key| (key == "SCRAPER_DISABLED").then(|| "1".to_string()));
+        let (status, message) = check_scraper_enabled(&disabled).unwrap_err();
+        assert_eq!(status, 501);
+        assert!(message.contains("SCRAPER_DISABLED"));
+    }
+
+    #[test]
+    fn test_resolve_scraper_url() {
+        let default = "http://localhost:88/url/";
+        let allowlist = vec!["staging.example.com".to_string(), "10.0.0.5:8080".to_string()];
+        assert_eq!(resolve_scraper_url(None, default, &allowlist).unwrap(), default);
+        // 許可したホストなら指定した URL を使う
+        assert_eq!(resolve_scraper_url(Some("https://Staging.example.com/url/"), default, &allowlist).unwrap(), "https://Staging.example.com/url/");
+        assert!(resolve_scraper_url(Some("http://10.0.0.5:8080/url/"), default, &allowlist).is_ok());
+        for rejected in [
+            "http://evil.example.com/url/",
+            "http://10.0.0.5:9090/url/",
+            "http://169.254.169.254/latest/",
+            "file:///etc/passwd",
+            "http://user@staging.example.com/url/",
+            "not a url",
+        ] {
+            assert_eq!(resolve_scraper_url(Some(rejected), default, &allowlist).unwrap_err().0, 400, "{}", rejected);
+        }
+        // allowlist が空なら指定できない
+        assert_eq!(resolve_scraper_url(Some("https://staging.example.com/url/"), default, &[]).unwrap_err().0, 400);
+        let config = Config::from_lookup(|key| (key == "SCRAPER_URL_ALLOWLIST").then(|| " Staging.example.com, ,10.0.0.5:8080".to_string()));
+        assert_eq!(config.scraper_url_allowlist, allowlist);
+    }
+
+    #[test]
+    fn test_request_id_from_header() {
+        assert_eq!(request_id_from_header(Some("abc-123")), "abc-123");
+        let generated = request_id_from_header(None);
+        assert!(uuid::Uuid::parse_str(&generated).is_ok());
+        // 改行などを含む値は採用しない
+        let injected = request_id_from_header(Some("abc\r\nSet-Cookie: x"));
+        assert!(uuid::Uuid::parse_str(&injected).is_ok());
+        let too_long = "a".repeat(MAX_REQUEST_ID_LENGTH + 1);
+        assert_ne!(request_id_from_header(Some(&too_long)), too_long);
+    }
 }
@@ -1,11 +1,180 @@
-use reqwest::Client;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::{Client, Url};
 use crate::collect::ScraperResult;
 
-// ScraperResult を直接返す
+// (スクレイパの HTTP ステータス, ScraperResult) を返す
+// ステータスは失敗の分類 (ScrapeFailure::classify) に使う
 pub async fn fetch_scraper_api(
     api_url: &str,
-) -> Result<ScraperResult, Box<dyn std::error::Error>> {
+) -> Result<(u16, ScraperResult), Box<dyn std::error::Error>> {
     let client = Client::new();
-    let resp = client.get(api_url).send().await?.json::<ScraperResult>().await?;
-    Ok(resp)
-}
\ No newline at end of file
+    let resp = client.get(api_url).send().await?;
+    let status = resp.status().as_u16();
+    let result = resp.json::<ScraperResult>().await?;
+    Ok((status, result))
+}
+
+/// スクレイパのレスポンスのキャッシュ (LRU + TTL)
+/// 同じ URL を繰り返し /add するときにスクレイパへのリクエストを省く
+/// 成功したレスポンスのみ保持する (失敗は一時的なことが多いので毎回問い合わせる)
+pub struct ScrapeCache {
+    capacity: usize,
+    ttl: Duration,
+    /// 正規化した URL -> エントリ
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+struct CacheEntry {
+    inserted: Instant,
+    last_used: Instant,
+    value: (u16, ScraperResult),
+}
+
+impl ScrapeCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self { capacity, ttl, entries: Mutex::new(HashMap::with_capacity(capacity)) }
+    }
+
+    /// キャッシュにあればそれを返し、なければ fetch を呼んで成功したら保存する
+    pub async fn get_or_fetch<F, Fut>(&self, url: &str, fetch: F) -> Result<(u16, ScraperResult), Box<dyn std::error::Error>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(u16, ScraperResult), Box<dyn std::error::Error>>>,
+    {
+        let key = normalize_url(url);
+        if let Some(hit) = self.get(&key) {
+            log::debug!("Scrape cache hit: {}", key);
+            return Ok(hit);
+        }
+        let fetched = fetch().await?;
+        if matches!(fetched.1, ScraperResult::Success { .. }) {
+            self.insert(key, fetched.clone());
+        }
+        Ok(fetched)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, CacheEntry>> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poison) => poison.into_inner(),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<(u16, ScraperResult)> {
+        let mut entries = self.lock();
+        let now = Instant::now();
+        match entries.get_mut(key) {
+            Some(entry) if now.duration_since(entry.inserted) < self.ttl => {
+                entry.last_used = now;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// 容量を超える場合は最も長く使われていないものを捨てる
+    /// 容量は小さい前提なので線形探索
+    fn insert(&self, key: String, value: (u16, ScraperResult)) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.lock();
+        if !entries.contains_key(&key) && entries.len() >= self.capacity {
+            let oldest = entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let now = Instant::now();
+        entries.insert(key, CacheEntry { inserted: now, last_used: now, value });
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// キャッシュのキー
+/// スキーム / ホストの大文字小文字とフラグメントの違いを吸収する (パースできなければそのまま)
+pub fn normalize_url(url: &str) -> String {
+    match Url::parse(url.trim()) {
+        Ok(mut parsed) => {
+            parsed.set_fragment(None);
+            parsed.to_string()
+        }
+        Err(_) => url.trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn success(url: &str) -> ScraperResult {
+        serde_json::from_value(serde_json::json!({
+            "success": true,
+            "status": 200,
+            "url": url,
+            "results": {
+                "author": [], "base": [], "canonical": [], "content_html": [], "descriptions": ["body"],
+                "favicon": [], "headings": [], "lang": [], "links": [], "modified": [], "next": [],
+                "prev": [], "published": [], "rss": [], "site_name": [], "tags": [], "title": ["title"]
+            }
+        })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_scrape_cache_skips_second_fetch() {
+        let cache = ScrapeCache::new(2, Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        let mock = |url: &'static str| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async move { Ok::<_, Box<dyn std::error::Error>>((200, success(url))) }
+        };
+        cache.get_or_fetch("https://Example.com/a", || mock("https://example.com/a")).await.unwrap();
+        // 正規化で同じキー
+        cache.get_or_fetch("https://example.com/a#top", || mock("https://example.com/a")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // 容量を超えると最も古いものから捨てる
+        cache.get_or_fetch("https://example.com/b", || mock("https://example.com/b")).await.unwrap();
+        cache.get_or_fetch("https://example.com/a", || mock("https://example.com/a")).await.unwrap();
+        cache.get_or_fetch("https://example.com/c", || mock("https://example.com/c")).await.unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        cache.get_or_fetch("https://example.com/b", || mock("https://example.com/b")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_scrape_cache_ttl_and_failures() {
+        let calls = AtomicUsize::new(0);
+        let expired = ScrapeCache::new(4, Duration::ZERO);
+        for _ in 0..2 {
+            expired.get_or_fetch("https://example.com/", || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { Ok::<_, Box<dyn std::error::Error>>((200, success("https://example.com/"))) }
+            }).await.unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // 失敗は保存しない
+        let cache = ScrapeCache::new(4, Duration::from_secs(60));
+        let failed: ScraperResult = serde_json::from_str(r#"{"success":false,"error":"timeout"}"#).unwrap();
+        cache.get_or_fetch("https://example.com/", || async { Ok::<_, Box<dyn std::error::Error>>((200, failed)) }).await.unwrap();
+        assert!(cache.is_empty());
+    }
+}
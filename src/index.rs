@@ -1,45 +1,278 @@
-use std::collections::HashMap;
-use std::io::Error;
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::io::{Error, Write};
 use std::ops::Range;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
+
+use arc_swap::ArcSwap;
+use std::time::{Duration, Instant};
 
 use log::{error, warn};
 use rayon::prelude::*;
 use chrono::{DateTime, Utc};
-use tf_idf_vectorizer::{Corpus, SimilarityAlgorithm, TFIDFData, TFIDFVectorizer, TokenFrequency};
+use tf_idf_vectorizer::{utils::math::vector::{ZeroSpVec, ZeroSpVecTrait}, Corpus, SimilarityAlgorithm, TFIDFData, TFIDFVectorizer, TokenFrequency};
 use serde::{Serialize, Deserialize};
 
-use crate::collect::{ResEntry, ScoredEntry};
+use crate::collect::{DocVector, IndexedEntry, ResEntry, ScoredEntry, ShardDump, ShardDumpEntry};
+use crate::tokenize::{SudachiError, TokenSpan};
+use crate::trigram::{Trigram, TrigramIndex};
 
 
 pub struct IndexPool {
-    pub corpus: Arc<Corpus>,
+    /// 全シャードが共有するコーパス (prune_corpus で作り直したものに入れ替わる)
+    pub corpus: ArcSwap<Corpus>,
     /// Index shards
     /// idと対応を絶対強制
-    pub indexes: Vec<Arc<RwLock<Index>>>,
+    /// rebuild_shards で Arc ごと入れ替わるので、ロックを取るときは shard / shards で取り出した Arc を持っておく
+    pub indexes: Vec<ArcSwap<RwLock<Index>>>,
     pub index_dir: String,
+    /// コーパスのファイル名 (index_dir 内)
+    pub corpus_file: String,
     pub counter: AtomicU64,
+    /// url_hash -> (shard_id, doc_id)
+    /// 既存 URL の検索で全シャードを走査しないためのマップ
+    /// 登録中 (vectorizer に存在する) ドキュメントのみ保持
+    pub url_map: RwLock<HashMap<u64, (usize, usize)>>,
+    /// シャードファイルの配置
+    pub layout: ShardLayout,
+    /// インデックスの世代番号 add / del / prune_corpus が成功するたびに増える
+    /// キャッシュ (ETag など) のキーに使う 再起動しても戻らない
+    pub generation: AtomicU64,
+    /// 永続化済みの世代番号の上限 (これを超えたら次の上限をファイルに書く)
+    generation_ceiling: Mutex<u64>,
+    /// シャードのロック競合の計測
+    pub lock_metrics: LockMetrics,
+    /// per_similarity でこれ以下のスコアのドキュメントを捨てる
+    /// デフォルト 0.0 でクエリの語を含まないドキュメント (スコア 0) を除く
+    pub score_floor: f64,
+    /// del_document で meta も消す (false なら残す)
+    pub remove_meta_on_delete: bool,
+    /// ファイル保存時の書き込み方
+    pub save_options: SaveOptions,
+    /// SAVE_FILE_INTERVAL ごとの自動保存の回数
+    pub auto_saves: AtomicU64,
+    /// 追加時に文字 3-gram インデックスも作って保存する (substring 検索用)
+    pub trigram_index: bool,
+    /// save / save_shard を同時に走らせないためのロック
+    save_lock: Mutex<()>,
+    /// 前回コーパスを書いてから語彙が変わったか (false なら save_shard はコーパスを書き直さない)
+    corpus_dirty: AtomicBool,
+    /// シャードの .index / .meta のサイズの上限 (超えたシャードには新規ドキュメントを入れない)
+    pub max_shard_bytes: u64,
+    /// 検索で書き込み中のシャードの read lock を待つ時間
+    /// None なら待たずに飛ばす (その分ヒットが欠ける)、Some なら待っても取れなければ検索を 503 にする
+    pub search_lock_timeout: Option<Duration>,
+    /// add / del などの更新は read、rebuild_shards (コピーオンライトの作り直し) は write で取る
+    /// 複製してから入れ替えるまでの間の更新が消えないように
+    mutation_lock: RwLock<()>,
 }
 
 pub const DEFAULT_INDEX_SHARD_NUM: usize = 16;
-pub const MAX_FILE_SIZE: usize = 200 * 1024 * 1024; // 200MB
+pub const MAX_FILE_SIZE: usize = 200 * 1024 * 1024; // 200MB シャードのファイルサイズの上限
 pub const CALCULATE_BIN_SIZE_INTERVAL: usize = 20; // 20回更新ごとにバイナリサイズを再計算
 pub const SAVE_FILE_INTERVAL: usize = 100; // 100回更新ごとにディスクに保存
+pub const URL_MAP_FILE: &str = "url.map";
+pub const DEFAULT_CORPUS_FILE: &str = "global.corpus";
+/// 世代番号をまとめて予約する幅
+/// 上限だけを保存するので、保存前に落ちても再起動後の番号は落ちる前より大きくなる
+pub const GENERATION_RESERVE: u64 = 1000;
+/// score_floor のデフォルト
+pub const DEFAULT_SCORE_FLOOR: f64 = 0.0;
+/// 保存時の BufWriter の容量のデフォルト (std と同じ)
+pub const DEFAULT_SAVE_BUFFER_BYTES: usize = 8 * 1024;
+/// search_lock_timeout の間に try_read をやり直す間隔
+pub const SEARCH_LOCK_POLL: Duration = Duration::from_millis(1);
+/// スニペット 1 つの長さ (文字数)
+pub const SNIPPET_WINDOW: usize = 40;
+/// snippet_count の上限
+pub const MAX_SNIPPET_COUNT: usize = 5;
 
 impl IndexPool {
     pub fn new(index_dir: &str) -> Self {
         let corpus = Arc::new(Corpus::new());
         // Create index shards
-        let indexes: Vec<Arc<RwLock<Index>>> = (0..DEFAULT_INDEX_SHARD_NUM).map(|i| {
-            Arc::new(RwLock::new(Index::new(i, Arc::clone(&corpus))))
+        let indexes: Vec<ArcSwap<RwLock<Index>>> = (0..DEFAULT_INDEX_SHARD_NUM).map(|i| {
+            ArcSwap::from_pointee(RwLock::new(Index::new(i, Arc::clone(&corpus))))
         }).collect();
         Self {
-            corpus,
+            corpus: ArcSwap::new(corpus),
             indexes,
             index_dir: index_dir.to_string(),
+            corpus_file: DEFAULT_CORPUS_FILE.to_string(),
             counter: AtomicU64::new(0),
+            url_map: RwLock::new(HashMap::new()),
+            layout: ShardLayout::Flat,
+            generation: AtomicU64::new(0),
+            generation_ceiling: Mutex::new(0),
+            save_lock: Mutex::new(()),
+            lock_metrics: LockMetrics::new(DEFAULT_INDEX_SHARD_NUM),
+            score_floor: DEFAULT_SCORE_FLOOR,
+            remove_meta_on_delete: false,
+            save_options: SaveOptions::default(),
+            auto_saves: AtomicU64::new(0),
+            trigram_index: false,
+            corpus_dirty: AtomicBool::new(true),
+            max_shard_bytes: MAX_FILE_SIZE as u64,
+            search_lock_timeout: None,
+            mutation_lock: RwLock::new(()),
+        }
+    }
+
+    /// 今のシャード
+    pub fn shard(&self, shard_id: usize) -> Option<Arc<RwLock<Index>>> {
+        self.indexes.get(shard_id).map(|index| index.load_full())
+    }
+
+    /// 今の全シャード (id 順)
+    pub fn shards(&self) -> Vec<Arc<RwLock<Index>>> {
+        self.indexes.iter().map(|index| index.load_full()).collect()
+    }
+
+    /// 検索 1 回ぶんの read lock の取り方 (期限は search_lock_timeout から今作る)
+    /// スコア計算から結果の作成まで、そのリクエストのシャードの読み取りは全てこれを使う
+    pub fn search_reads(&self) -> SearchReads {
+        SearchReads {
+            deadline: self.search_lock_timeout.map(|timeout| Instant::now() + timeout),
+            skipped: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 検索用に try_read する
+    /// 書き込み中のシャードは reads の期限まで取り直し、取れなければ飛ばす (その分ヒットが欠ける) ので回数を記録して reads に載せる
+    /// ロックが壊れていれば待たない
+    fn try_read_for_search<'a>(&self, shard_id: usize, index: &'a RwLock<Index>, reads: &SearchReads) -> Option<std::sync::RwLockReadGuard<'a, Index>> {
+        loop {
+            match index.try_read() {
+                Ok(idx) => return Some(idx),
+                Err(TryLockError::WouldBlock) if reads.deadline.is_some_and(|d| Instant::now() < d) => {
+                    std::thread::sleep(SEARCH_LOCK_POLL);
+                }
+                Err(_) => {
+                    self.lock_metrics.record_search_skip(shard_id);
+                    reads.skip(shard_id);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// スコア計算の後 (絞り込み・並べ替え・結果の作成) の read lock
+    /// search_lock_timeout がなければ従来どおり待ち、あれば try_read_for_search と同じく reads の期限までで諦める
+    /// None ならロックが壊れているか期限切れ (期限切れは reads に載る)
+    fn read_for_results<'a>(&self, shard_id: usize, index: &'a RwLock<Index>, reads: &SearchReads) -> Option<std::sync::RwLockReadGuard<'a, Index>> {
+        if reads.deadline.is_none() {
+            return index.read().ok();
+        }
+        self.try_read_for_search(shard_id, index, reads)
+    }
+
+    /// 検索に使うシャードの read lock をまとめて取る
+    /// 書き込み中で飛ばしたシャードの ID は reads に載る
+    fn readable_shards<'a>(&self, shards: &'a [Arc<RwLock<Index>>], reads: &SearchReads) -> Vec<std::sync::RwLockReadGuard<'a, Index>> {
+        shards.iter().enumerate()
+            .filter_map(|(shard_id, e)| self.try_read_for_search(shard_id, e, reads))
+            .collect()
+    }
+
+    /// scored のドキュメントをシャードごとにまとめ、候補のあるシャードだけ lock で read lock を取って f(シャード ID, シャード, そのシャードの key) を呼ぶ
+    /// lock はスコア計算中なら try_read_for_search、その後なら read_for_results
+    /// 読めなかったシャードは飛ばす (期限切れは reads に載る)
+    fn for_each_shard_keys<'s>(&self, scored: impl IntoIterator<Item = &'s ScoredEntry>, reads: &SearchReads, lock: ShardRead, mut f: impl FnMut(usize, &Index, &HashSet<usize>)) {
+        let mut per_shard: Vec<HashSet<usize>> = vec![HashSet::new(); self.indexes.len()];
+        for e in scored {
+            if let Some(keys) = per_shard.get_mut(e.index_id) {
+                keys.insert(e.key);
+            }
+        }
+        for (index_id, (index, keys)) in self.shards().iter().zip(per_shard).enumerate() {
+            if keys.is_empty() {
+                continue;
+            }
+            let Some(idx) = lock(self, index_id, index, reads) else {
+                warn!("Index id {} is not readable, skipping", index_id);
+                continue;
+            };
+            f(index_id, &idx, &keys);
+        }
+    }
+
+    /// 世代番号を進める
+    /// 予約済みの上限に達したら次の上限をコーパスの隣のファイルに保存する
+    /// # Returns
+    /// 新しい世代番号
+    pub fn bump_generation(&self) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut ceiling = match self.generation_ceiling.lock() {
+            Ok(c) => c,
+            Err(poison) => poison.into_inner(),
+        };
+        if generation >= *ceiling {
+            let next = generation + GENERATION_RESERVE;
+            if let Err(e) = save_generation(&self.index_dir, &self.corpus_file, next, self.save_options) {
+                warn!("Failed to save generation: {}", e);
+            }
+            *ceiling = next;
+        }
+        generation
+    }
+
+    /// URL (別名を含む) から (shard_id, doc_id) を取得
+    /// url_map で対象シャードを特定し、そのシャードの meta で URL を確認する (ハッシュ衝突対策)
+    pub fn locate(&self, url: &str) -> Option<(usize, usize)> {
+        let (shard_id, doc_id) = *self.url_map.read().ok()?.get(&url_hash(url))?;
+        let index = self.shard(shard_id)?;
+        let idx = index.read().ok()?;
+        match idx.meta_from_id(doc_id) {
+            Some(m) if m.matches_url(url) => Some((shard_id, doc_id)),
+            _ => None,
+        }
+    }
+
+    /// URL (別名を含む) が登録済みで、最後のインデックスから max_age 経っていなければその meta を返す (/add の max_age)
+    /// time が now より後なら経過 0 とみなす
+    pub fn fresh_meta(&self, url: &str, max_age: Duration, now: DateTime<Utc>) -> Option<IndexMeta> {
+        let (shard_id, doc_id) = self.locate(url)?;
+        let index = self.shard(shard_id)?;
+        let idx = index.read().ok()?;
+        let meta = idx.meta_from_id(doc_id)?;
+        let age = now.signed_duration_since(meta.time).to_std().unwrap_or(Duration::ZERO);
+        (age < max_age).then(|| meta.clone())
+    }
+
+    /// 複数の URL (別名を含む) が登録済みか調べる (POST /indexed)
+    /// url_map を一度だけ引いてシャードごとにまとめ、シャードの read lock は一度ずつ取る
+    /// 確認の方法は locate と同じ 結果は urls の順
+    pub fn indexed_status<S: AsRef<str>>(&self, urls: &[S]) -> Vec<IndexedEntry> {
+        let mut entries: Vec<IndexedEntry> = urls.iter()
+            .map(|url| IndexedEntry { url: url.as_ref().to_string(), indexed: false, time: None, id: None, index_id: None })
+            .collect();
+        // shard_id -> (urls の位置, doc_id)
+        let mut by_shard: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+        if let Ok(map) = self.url_map.read() {
+            for (i, url) in urls.iter().enumerate() {
+                if let Some(&(shard_id, doc_id)) = map.get(&url_hash(url.as_ref())) {
+                    by_shard.entry(shard_id).or_default().push((i, doc_id));
+                }
+            }
+        }
+        for (shard_id, found) in by_shard {
+            let index = self.shard(shard_id);
+            let Some(Ok(idx)) = index.as_ref().map(|index| index.read()) else {
+                warn!("Index id {} is not readable, reporting its URLs as not indexed", shard_id);
+                continue;
+            };
+            for (i, doc_id) in found {
+                let entry = &mut entries[i];
+                if let Some(m) = idx.meta_from_id(doc_id).filter(|m| m.matches_url(&entry.url)) {
+                    entry.indexed = true;
+                    entry.time = Some(m.time);
+                    entry.id = Some(doc_id);
+                    entry.index_id = Some(shard_id);
+                }
+            }
         }
+        entries
     }
 
     /// Calculate similarity for all indexes in parallel
@@ -54,20 +287,25 @@ impl IndexPool {
     /// Vector of (Hits<IndexMeta>, usize) tuples
     /// 
     /// # Example
-    /// ```
+    /// ```ignore
     /// let results = index_pool.per_similarity(&token_fq, &SimilarityAlgorithm::CosineSimilarity);
     /// for (hits, index_id) in results.0 {
     ///     println!("Index ID: {}, Hits: {:?}", index_id, hits);
     /// }
     /// ```
     pub fn per_similarity(&self, token_fq: &TokenFrequency, algorithm: &SimilarityAlgorithm) -> Vec<ScoredEntry> {
-        let result: Vec<ScoredEntry> = self.indexes
-            .iter().filter_map(|e| e.try_read().ok())
-            .collect::<Vec<_>>()
+        self.per_similarity_with_skips(token_fq, algorithm, &SearchReads::default())
+    }
+
+    /// per_similarity で書き込み中で飛ばしたシャードの ID を reads に載せる
+    pub fn per_similarity_with_skips(&self, token_fq: &TokenFrequency, algorithm: &SimilarityAlgorithm, reads: &SearchReads) -> Vec<ScoredEntry> {
+        let shards = self.shards();
+        let result: Vec<ScoredEntry> = self.readable_shards(&shards, reads)
             .par_iter().flat_map(|idx| {
                 let mut result = Vec::new();
                 let hits = idx.vectorizer.similarity_uncheck_idf(token_fq, algorithm);
-                hits.list.iter().for_each(|h| {
+                // ソート前に捨てて件数を減らす (NaN も落ちる)
+                hits.list.iter().filter(|h| h.1 > self.score_floor).for_each(|h| {
                     result.push(ScoredEntry {
                         score: h.1,
                         key: h.0,
@@ -80,84 +318,385 @@ impl IndexPool {
         result
     }
 
-    pub fn sort_by_score(&self, mut results: Vec<ScoredEntry>) -> Vec<ScoredEntry> {
-        results
-            .par_iter_mut()
-            .for_each(|h| {
-                // Calculate total score for each hit
-                h.score = h.score; // Placeholder for actual score calculation if needed
+    /// per_similarity の top-K 版
+    /// シャードごとにサイズ k の最小ヒープでスコア上位 k 件だけを保持し、それを結合して返す
+    /// 全体の上位 k 件は必ず含まれるが、k 件目より下の順位は失われる
+    pub fn per_similarity_top_k(&self, token_fq: &TokenFrequency, algorithm: &SimilarityAlgorithm, k: usize) -> Vec<ScoredEntry> {
+        self.per_similarity_top_k_with_skips(token_fq, algorithm, k, &SearchReads::default()).0
+    }
+
+    /// per_similarity_top_k で書き込み中で飛ばしたシャードの ID を reads に載せる
+    /// # Returns
+    /// (上位の結果, 上位に入らなかった分も含めて score_floor を超えた件数)
+    pub fn per_similarity_top_k_with_skips(&self, token_fq: &TokenFrequency, algorithm: &SimilarityAlgorithm, k: usize, reads: &SearchReads) -> (Vec<ScoredEntry>, usize) {
+        let shards = self.shards();
+        let per_shard: Vec<(Vec<ScoredEntry>, usize)> = self.readable_shards(&shards, reads)
+            .par_iter().map(|idx| self.shard_top_k(idx, token_fq, algorithm, k))
+            .collect();
+        let matched = per_shard.iter().map(|(_, hits)| hits).sum();
+        (per_shard.into_iter().flat_map(|(top, _)| top).collect(), matched)
+    }
+
+    /// per_similarity_top_k の k-way マージ版
+    /// シャードごとのスコア順の上位 k 件を先頭から突き合わせ、全体の上位 k 件だけを返す (スコア順)
+    /// シャード数 × k 件を結合して並べ直さず、k 件取り出した時点で打ち切る
+    /// 件数は per_similarity_top_k_with_skips と同じ
+    pub fn per_similarity_merged_top_k_with_skips(&self, token_fq: &TokenFrequency, algorithm: &SimilarityAlgorithm, k: usize, reads: &SearchReads) -> (Vec<ScoredEntry>, usize) {
+        let shards = self.shards();
+        let (per_shard, hits): (Vec<Vec<ScoredEntry>>, Vec<usize>) = self.readable_shards(&shards, reads)
+            .par_iter().map(|idx| self.shard_top_k(idx, token_fq, algorithm, k))
+            .unzip();
+        (merge_top_k(per_shard, k), hits.into_iter().sum())
+    }
+
+    /// 1 シャードのスコア上位 k 件 (サイズ k の最小ヒープで保持、スコア順で返す) と score_floor を超えた件数
+    fn shard_top_k(&self, idx: &Index, token_fq: &TokenFrequency, algorithm: &SimilarityAlgorithm, k: usize) -> (Vec<ScoredEntry>, usize) {
+        let hits = idx.vectorizer.similarity_uncheck_idf(token_fq, algorithm);
+        let matched = hits.list.iter().filter(|h| h.1 > self.score_floor).count();
+        if k == 0 {
+            return (Vec::new(), matched);
+        }
+        let mut heap: BinaryHeap<Reverse<HeapEntry>> = BinaryHeap::with_capacity(k + 1);
+        for h in hits.list.iter().filter(|h| h.1 > self.score_floor) {
+            let entry = HeapEntry(ScoredEntry {
+                score: h.1,
+                key: h.0,
+                length: h.2,
+                index_id: idx.id,
             });
+            if heap.len() < k {
+                heap.push(Reverse(entry));
+            } else if heap.peek().is_some_and(|min| entry > min.0) {
+                heap.pop();
+                heap.push(Reverse(entry));
+            }
+        }
+        // Reverse の昇順 = スコアの降順
+        (heap.into_sorted_vec().into_iter().map(|Reverse(e)| e.0).collect(), matched)
+    }
+
+    /// 複数アルゴリズムのスコアを重み付きで合成
+    /// アルゴリズムごとに全シャード横断で min-max 正規化 ([0,1]) してから
+    /// (index_id, key) 単位で weight * score を合計する
+    /// 結果は (index_id, key) 順 (同点時の順位を実行ごとに揃えるため)
+    pub fn mixed_similarity(&self, token_fq: &TokenFrequency, algorithms: &[(SimilarityAlgorithm, f64)]) -> Vec<ScoredEntry> {
+        self.mixed_similarity_with_skips(token_fq, algorithms, &SearchReads::default())
+    }
+
+    /// mixed_similarity で書き込み中で飛ばしたシャードの ID を reads に載せる (アルゴリズムごとに重複しうる)
+    pub fn mixed_similarity_with_skips(&self, token_fq: &TokenFrequency, algorithms: &[(SimilarityAlgorithm, f64)], reads: &SearchReads) -> Vec<ScoredEntry> {
+        let mut combined: HashMap<(usize, usize), ScoredEntry> = HashMap::new();
+        for (algorithm, weight) in algorithms {
+            let mut scored = self.per_similarity_with_skips(token_fq, algorithm, reads);
+            normalize_scores(&mut scored);
+            for entry in scored {
+                combined
+                    .entry((entry.index_id, entry.key))
+                    .and_modify(|e| e.score += weight * entry.score)
+                    .or_insert(ScoredEntry { score: weight * entry.score, ..entry });
+            }
+        }
+        let mut result: Vec<ScoredEntry> = combined.into_values().collect();
+        result.sort_by_key(|e| (e.index_id, e.key));
+        result
+    }
+
+    pub fn sort_by_score(&self, mut results: Vec<ScoredEntry>) -> Vec<ScoredEntry> {
         results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
         results
     }
 
+    /// scored を grams を全て含むドキュメントに絞る (substring 検索、トークンでのヒットとの積)
+    /// 3-gram のないドキュメント (TRIGRAM_INDEX を有効にする前に登録したもの) は確かめられないので除き、その数を返す
+    /// 書き込み中で読めなかったシャードのドキュメントも除き、そのシャードの ID を reads に載せる
+    pub fn apply_substring(&self, scored: &mut Vec<ScoredEntry>, grams: &std::collections::HashSet<Trigram>, reads: &SearchReads) -> usize {
+        let mut matched: HashSet<(usize, usize)> = HashSet::new();
+        let mut unindexed = 0;
+        self.for_each_shard_keys(scored.iter(), reads, Self::try_read_for_search, |index_id, idx, keys| {
+            for &key in keys {
+                if !idx.trigrams.contains(key) {
+                    unindexed += 1;
+                } else if idx.trigrams.contains_all(key, grams) {
+                    matched.insert((index_id, key));
+                }
+            }
+        });
+        scored.retain(|e| matched.contains(&(e.index_id, e.key)));
+        unindexed
+    }
+
+    /// タグフィルタを通らないドキュメントを取り除く (range で切り出す前に total を数えるため)
+    /// meta のないものも除く ロックが壊れたシャードの分は generate_results に任せて残す
+    pub fn retain_tags(&self, scored: &mut Vec<ScoredEntry>, tag: Tags, tag_exclusive: bool, exclude_tag: Tags, reads: &SearchReads) {
+        if tag.is_empty() && exclude_tag.is_empty() {
+            return;
+        }
+        let mut dropped: HashSet<(usize, usize)> = HashSet::new();
+        self.for_each_shard_keys(scored.iter(), reads, Self::read_for_results, |index_id, idx, keys| {
+            for &key in keys {
+                if !idx.meta_from_id(key).is_some_and(|m| m.tags.matches_filter(tag, tag_exclusive, exclude_tag)) {
+                    dropped.insert((index_id, key));
+                }
+            }
+        });
+        if !dropped.is_empty() {
+            scored.retain(|e| !dropped.contains(&(e.index_id, e.key)));
+        }
+    }
+
+    /// 除外語を含むドキュメントを取り除く
+    /// exclude は除外語ごとのトークン列で、そのトークンを全て含むドキュメントを除外する
+    /// (1 語が複数トークンに分かれる場合に一部のトークンだけで除外しないため)
+    pub fn retain_without_terms(&self, scored: &mut Vec<ScoredEntry>, exclude: &[Vec<String>], reads: &SearchReads) {
+        if exclude.is_empty() {
+            return;
+        }
+        // 候補だけを調べる (候補のないシャードは読まない)
+        let mut dropped: HashSet<(usize, usize)> = HashSet::new();
+        self.for_each_shard_keys(scored.iter(), reads, Self::read_for_results, |index_id, idx, keys| {
+            // 除外語の次元はシャードごとに一度だけ引く
+            // このシャードの語彙にない語を含むものは一致しない
+            let groups: Vec<Vec<usize>> = exclude.iter()
+                .filter_map(|tokens| tokens.iter().map(|t| idx.vectorizer.token_dim_sample.get_index_of(t.as_str())).collect())
+                .filter(|dims: &Vec<usize>| !dims.is_empty())
+                .collect();
+            if groups.is_empty() {
+                return;
+            }
+            // TF ベクトルの次元は昇順なので二分探索で引く
+            for doc in idx.vectorizer.documents.iter().filter(|doc| keys.contains(&doc.key)) {
+                if groups.iter().any(|group| group.iter().all(|d| doc.tf_vec.ind_binary_search(d).is_ok())) {
+                    dropped.insert((index_id, doc.key));
+                }
+            }
+        });
+        scored.retain(|e| !dropped.contains(&(e.index_id, e.key)));
+    }
+
+    /// now から window 以内にインデックスしたドキュメントのみ残し、スコアを関連度と新しさの混合にする
+    /// 関連度は min-max 正規化、新しさは window / 2 で半減する指数減衰 (どちらも 0〜1)
+    /// score = (1 - recency_weight) * 関連度 + recency_weight * 新しさ
+    pub fn apply_freshness(&self, scored: &mut Vec<ScoredEntry>, now: DateTime<Utc>, window: chrono::Duration, recency_weight: f64, reads: &SearchReads) {
+        let since = now - window;
+        let half_life = window.num_seconds().max(1) as f64 / 2.0;
+        let mut freshness: HashMap<(usize, usize), f64> = HashMap::new();
+        self.for_each_shard_keys(scored.iter(), reads, Self::read_for_results, |index_id, idx, keys| {
+            for &key in keys {
+                let Some(meta) = idx.meta_from_id(key) else { continue; };
+                if meta.time < since {
+                    continue;
+                }
+                let age = (now - meta.time).num_seconds().max(0) as f64;
+                freshness.insert((index_id, key), 0.5f64.powf(age / half_life));
+            }
+        });
+        scored.retain(|e| freshness.contains_key(&(e.index_id, e.key)));
+        normalize_scores(scored);
+        for e in scored.iter_mut() {
+            let fresh = freshness[&(e.index_id, e.key)];
+            e.score = (1.0 - recency_weight) * e.score + recency_weight * fresh;
+        }
+    }
+
+    /// scored の各ドキュメントの field の日付 (IndexMeta::date、meta を引けないものは含めない)
+    fn document_dates(&self, scored: &[ScoredEntry], field: DateField, reads: &SearchReads) -> HashMap<(usize, usize), DateTime<Utc>> {
+        let mut dates = HashMap::new();
+        self.for_each_shard_keys(scored, reads, Self::read_for_results, |index_id, idx, keys| {
+            for &key in keys {
+                if let Some(meta) = idx.meta_from_id(key) {
+                    dates.insert((index_id, key), meta.date(field));
+                }
+            }
+        });
+        dates
+    }
+
+    /// field の日付が after 以上 before 未満のドキュメントのみ残す
+    pub fn retain_dates(&self, scored: &mut Vec<ScoredEntry>, field: DateField, after: Option<DateTime<Utc>>, before: Option<DateTime<Utc>>, reads: &SearchReads) {
+        if after.is_none() && before.is_none() {
+            return;
+        }
+        let dates = self.document_dates(scored, field, reads);
+        scored.retain(|e| dates.get(&(e.index_id, e.key))
+            .is_some_and(|d| after.is_none_or(|a| *d >= a) && before.is_none_or(|b| *d < b)));
+    }
+
+    /// field の日付の新しい順に並べ替える (同じ日付なら元の順 = スコア順)
+    pub fn sort_by_date(&self, mut results: Vec<ScoredEntry>, field: DateField, reads: &SearchReads) -> Vec<ScoredEntry> {
+        let dates = self.document_dates(&results, field, reads);
+        results.sort_by_key(|e| Reverse(dates.get(&(e.index_id, e.key)).copied()));
+        results
+    }
+
+    /// タグごとの件数 (ファセット)
+    /// range で切り出す前の全件のうち、スコアが正でタグフィルタを通るものを数える
+    /// キーはタグ名 (TagRegistry の定義のまま)、0 件のタグは含めない
+    pub fn tag_facets(&self, results: &[ScoredEntry], tag: Tags, tag_exclusive: bool, exclude_tag: Tags, reads: &SearchReads) -> BTreeMap<String, usize> {
+        // シャードごとにまとめて lock を取る回数を減らす
+        let mut bits: HashMap<u64, usize> = HashMap::new();
+        self.for_each_shard_keys(results.iter().filter(|s| s.score > 0.0), reads, Self::read_for_results, |_, idx, keys| {
+            for &key in keys {
+                let Some(meta) = idx.meta_from_id(key) else { continue; };
+                if meta.tags.matches_filter(tag, tag_exclusive, exclude_tag) {
+                    *bits.entry(meta.tags.0).or_insert(0) += 1;
+                }
+            }
+        });
+        TagRegistry::with_global(|registry| {
+            let mut facets = BTreeMap::new();
+            for (name, bit) in &registry.entries {
+                let count: usize = bits.iter().filter(|(set, _)| *set & bit != 0).map(|(_, n)| n).sum();
+                if count > 0 {
+                    facets.insert(name.to_string(), count);
+                }
+            }
+            facets
+        })
+    }
+
     /// Generate ResEntry from ScoredEntry
     /// # Arguments
     /// * `results` - The scored entries to generate results from
     /// * `range` - The range of results to include
     /// * `tag` - The tag to filter results by
     /// * `tag_exclusive` - Whether to use exclusive tag filtering
+    /// * `exclude_tag` - Results having any of these tags are dropped
     /// # Returns
     /// Vector of ResEntry
-    pub fn generate_results(&self, results: Vec<ScoredEntry>, range: Range<usize>, tag: Tags, tag_exclusive: bool) -> Vec<ResEntry> {
+    pub fn generate_results(&self, results: Vec<ScoredEntry>, range: Range<usize>, tag: Tags, tag_exclusive: bool, exclude_tag: Tags) -> Vec<ResEntry> {
+        self.generate_results_backfilled(results, range, tag, tag_exclusive, exclude_tag, false)
+    }
+
+    /// generate_results で meta を引けなかった (シャードがない / ロックが壊れている / meta がない) 件数分を
+    /// backfill なら range の後ろから補う (タグで除いた分は補わない)
+    pub fn generate_results_backfilled(&self, results: Vec<ScoredEntry>, range: Range<usize>, tag: Tags, tag_exclusive: bool, exclude_tag: Tags, backfill: bool) -> Vec<ResEntry> {
+        self.generate_results_with(results, range, tag, tag_exclusive, exclude_tag, ResultOptions { backfill, ..Default::default() })
+    }
+
+    /// generate_results に backfill / lite / exclude_urls を指定する
+    /// lite なら descriptions (空) と favicon (None) を meta から複製しない
+    /// exclude_urls で除いた分はタグと同じく補わない
+    pub fn generate_results_with(&self, results: Vec<ScoredEntry>, range: Range<usize>, tag: Tags, tag_exclusive: bool, exclude_tag: Tags, options: ResultOptions) -> Vec<ResEntry> {
+        let ResultOptions { backfill, lite, exclude_urls, max_results, collapse_by_host, reads } = options;
+        let default_reads = SearchReads::default();
+        let reads = reads.unwrap_or(&default_reads);
         let mut res_entries = Vec::new();
-        let range_results: &[ScoredEntry] = {
-            let len = results.len();
-            let start = range.start.min(len);
-            let end = range.end.min(len);
-            if start >= end {
-            &[]
-            } else {
-            &results[start..end]
-            }
+        let len = results.len();
+        let range = match max_results {
+            Some(max) => range.start..range.end.min(range.start.saturating_add(max)),
+            None => range,
         };
-        for scored in range_results {
-            let index = match self.indexes.get(scored.index_id) {
-                Some(idx) => idx,
-                None => continue,
-            };
-            let index_read = match index.read() {
-                Ok(r) => r,
-                Err(_poison) => {
-                    warn!("RwLock poisoned for index id {}, skipping", scored.index_id);
+        if collapse_by_host {
+            return self.generate_collapsed(&results, range, tag, tag_exclusive, exclude_tag, options).0;
+        }
+        let mut end = range.end.min(len);
+        let mut i = range.start.min(len);
+        while i < end {
+            let scored = &results[i];
+            i += 1;
+            let index = self.shard(scored.index_id);
+            let index_read = match index.as_ref().map(|index| self.read_for_results(scored.index_id, index, reads)) {
+                Some(Some(r)) => r,
+                Some(None) => {
+                    warn!("Index id {} is not readable, skipping key {}", scored.index_id, scored.key);
+                    if backfill {
+                        end = (end + 1).min(len);
+                    }
                     continue; // Skip poisoned lock
                 }
+                None => {
+                    warn!("Index id {} not found, skipping key {}", scored.index_id, scored.key);
+                    if backfill {
+                        end = (end + 1).min(len);
+                    }
+                    continue;
+                }
             };
             let meta = match index_read.meta_from_id(scored.key) {
                 Some(m) => m,
-                None => continue,
-            };
-            // タグフィルタリング
-            // example: tag_exclusive = true -> 完全一致, false -> 部分一致
-            // タグ指定が空でなければフィルタ
-            if !tag.is_empty() {
-                if tag_exclusive {
-                    if !meta.tags.is_filter_contains(tag) {
-                        continue;
-                    }
-                } else {
-                    if !meta.tags.contains(tag) {
-                        continue;
+                None => {
+                    warn!("Meta not found for index id {} key {}, skipping", scored.index_id, scored.key);
+                    if backfill {
+                        end = (end + 1).min(len);
                     }
+                    continue;
                 }
+            };
+            if !meta.tags.matches_filter(tag, tag_exclusive, exclude_tag) || url_excluded(&meta.url, exclude_urls) {
+                continue;
             }
-            res_entries.push(ResEntry {
-                url: meta.url.clone(),
-                title: meta.title.clone(),
-                favicon: meta.favicon.clone(),
-                tags: meta.tags.tags(),
-                descriptions: meta.description.clone(),
-                score: scored.score,
-                point: meta.points,
-                length: scored.length,
-                id: scored.key,
-                index_id: scored.index_id,
-                time: meta.time,
-            });
+            res_entries.push(res_entry(meta, scored, lite));
         }
         res_entries
     }
 
+    /// collapse_by_host の generate_results_with (まとめた後の代表の件数も返す)
+    /// スコア順に見て各ホストの最初の結果を代表にし、同じホストの後の結果を代表の others に最大 MAX_COLLAPSED_OTHERS 件入れる
+    /// ホストはシャードごとに read lock を一度ずつ取って全件分引き、代表の件数を数える
+    /// range は代表の並びに対して切り出し、結果を作るのは range 内の代表と others だけ
+    /// meta を引けなかった結果は飛ばす (backfill は使わない)
+    pub fn generate_collapsed(&self, results: &[ScoredEntry], range: Range<usize>, tag: Tags, tag_exclusive: bool, exclude_tag: Tags, options: ResultOptions) -> (Vec<ResEntry>, usize) {
+        let default_reads = SearchReads::default();
+        let reads = options.reads.unwrap_or(&default_reads);
+        let hosts = self.result_hosts(results, tag, tag_exclusive, exclude_tag, options.exclude_urls, reads);
+        // 代表ごとの (代表, others) の位置 (range 外の代表は位置を持たない)
+        let mut groups: Vec<Option<(usize, Vec<usize>)>> = Vec::new();
+        let mut host_groups: HashMap<&str, usize> = HashMap::new();
+        for (i, host) in hosts.iter().enumerate() {
+            let Some(host) = host else { continue; };
+            match host_groups.get(host.as_str()) {
+                Some(&group) => {
+                    if let Some(Some((_, others))) = groups.get_mut(group)
+                        && others.len() < MAX_COLLAPSED_OTHERS {
+                        others.push(i);
+                    }
+                }
+                None => {
+                    host_groups.insert(host, groups.len());
+                    groups.push(range.contains(&groups.len()).then(|| (i, Vec::new())));
+                }
+            }
+        }
+        let total = groups.len();
+        let entry = |i: usize| -> Option<ResEntry> {
+            let scored = &results[i];
+            let index = self.shard(scored.index_id)?;
+            let idx = self.read_for_results(scored.index_id, &index, reads)?;
+            idx.meta_from_id(scored.key).map(|meta| res_entry(meta, scored, options.lite))
+        };
+        let res_entries = groups.into_iter()
+            .flatten()
+            .filter_map(|(primary, others)| Some(ResEntry {
+                others: Some(others.into_iter().filter_map(entry).collect()),
+                ..entry(primary)?
+            }))
+            .collect();
+        (res_entries, total)
+    }
+
+    /// 結果ごとの URL のホスト (meta を引けない、タグが合わない、exclude_urls に当たるものは None)
+    /// シャードごとにまとめて read lock を一度ずつ取る
+    fn result_hosts(&self, results: &[ScoredEntry], tag: Tags, tag_exclusive: bool, exclude_tag: Tags, exclude_urls: &[String], reads: &SearchReads) -> Vec<Option<String>> {
+        let mut by_shard: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, scored) in results.iter().enumerate() {
+            by_shard.entry(scored.index_id).or_default().push(i);
+        }
+        let mut hosts = vec![None; results.len()];
+        for (shard_id, positions) in by_shard {
+            let Some(index) = self.shard(shard_id) else { continue; };
+            let Some(idx) = self.read_for_results(shard_id, &index, reads) else {
+                warn!("Index id {} is not readable, skipping", shard_id);
+                continue;
+            };
+            for i in positions {
+                hosts[i] = idx.meta_from_id(results[i].key)
+                    .filter(|meta| meta.tags.matches_filter(tag, tag_exclusive, exclude_tag) && !url_excluded(&meta.url, exclude_urls))
+                    .map(|meta| url_host(&meta.url));
+            }
+        }
+        hosts
+    }
+
     /// add document to index pool
     /// meta.id は上書きされます
     /// # Arguments
@@ -167,55 +706,67 @@ impl IndexPool {
     /// Some(bool) true is new, false is update, None if failed
     /// CALCULATE_BIN_SIZE_INTERVAL ごとにバイナリサイズを再計算し、SAVE_FILE_INTERVAL ごとにディスクに保存します
     /// もっとも負荷の低いシャードに追加されます
-    /// 既存のURLがあれば上書きされます
+    /// 既存のURLがあれば上書きされます (URL か別名のどれかが既存のドキュメントの URL / 別名と一致すれば同じドキュメント)
     pub fn add_document(&self,
+        token_fq: &TokenFrequency,
+        meta: IndexMeta,
+    ) -> Option<bool> {
+        self.add_document_with_text(token_fq, meta, "")
+    }
+
+    /// add_document に加えて、trigram_index 有効時は title / description / text から 3-gram インデックスを作る
+    pub fn add_document_with_text(&self,
         token_fq: &TokenFrequency,
         mut meta: IndexMeta,
+        text: &str,
     ) -> Option<bool> {
-        let url = meta.url.clone();
+        let _mutation = self.mutation_lock.read().unwrap_or_else(|poison| poison.into_inner());
+        let trigram_text = self.trigram_index.then(|| format!("{}\n{}\n{}", meta.title, meta.description, text));
+        let adds_vocab = token_fq.token_set_ref_str().iter().any(|t| !self.corpus.load().token_counts.contains_key(*t));
         let mut is_new = true;
-        let mut shard_id = 0;
+        let shard_id;
         let mut doc_id = 0;
         // 既存で登録されているかチェック
-        // 最小サイズシャード選択用 (初期は最大値)
-        let mut best_size: u64 = u64::MAX;
-        for index in &self.indexes {
-            match index.read() {
-            Ok(idx) => {
-                if is_new {
-                if let Some(m) = idx.meta_from_url(&url) {
-                    shard_id = idx.id;
-                    doc_id = m.id;
-                    is_new = false;
-                    break;
-                }
-                }
-                // 未登録なら最もサイズの小さいシャードへ
-                let size = idx.meta_bin_size.max(idx.vectorizer_bin_size);
-                if size <= best_size {
-                best_size = size;
-                shard_id = idx.id;
+        if let Some((s, d)) = meta.urls().find_map(|u| self.locate(u)) {
+            shard_id = s;
+            doc_id = d;
+            is_new = false;
+        } else {
+            // 未登録なら上限に達していないシャードのうち最もサイズの小さいものへ
+            let sizes: Vec<(usize, u64)> = self.shards().iter()
+                .filter_map(|index| match index.read() {
+                    Ok(idx) => Some((idx.id, idx.bin_size())),
+                    Err(_poison) => {
+                        warn!("RwLock poisoned, skipping");
+                        None
+                    }
+                })
+                .collect();
+            match choose_shard(&sizes, self.max_shard_bytes) {
+                Some(id) => shard_id = id,
+                None => {
+                    error!("All shards exceed the size limit ({} bytes), refusing new document {}", self.max_shard_bytes, meta.url);
+                    return None;
                 }
             }
-            Err(_poison) => {
-                warn!("RwLock poisoned, skipping");
-                continue;
-            }
-            }
         }
-        let do_save;
-        let do_calculate_size ;
+        let actions;
         if is_new {
             // 新規登録
-            if let Ok(mut idx) = self.indexes[shard_id].write() {
+            if let Ok(mut idx) = self.indexes[shard_id].load().write() {
+                let _timer = self.lock_metrics.write_timer();
                 doc_id = idx.generate_next_id();
                 idx.vectorizer.add_doc(doc_id, token_fq);
                 idx.vectorizer.update_idf();
                 meta.id = doc_id;
+                if let Some(text) = &trigram_text {
+                    idx.trigrams.insert(doc_id, text);
+                }
+                if let Ok(mut map) = self.url_map.write() {
+                    insert_url_keys(&mut map, &mut meta, (shard_id, doc_id));
+                }
                 idx.meta.push(meta);
-                do_save = idx.update_count % SAVE_FILE_INTERVAL == 0;
-                do_calculate_size = idx.update_count % CALCULATE_BIN_SIZE_INTERVAL == 0;
-                idx.update_count += 1;
+                actions = idx.record_update();
                 self.counter.fetch_add(1, Ordering::SeqCst);
             } else {
                 error!("RwLock poisoned for index id {}, skipping", shard_id);
@@ -223,223 +774,898 @@ impl IndexPool {
             }
         } else {
             // 既存を削除してから再登録
-            if let Ok(mut idx) = self.indexes[shard_id].write() {
+            if let Ok(mut idx) = self.indexes[shard_id].load().write() {
+                let _timer = self.lock_metrics.write_timer();
                 idx.vectorizer.del_doc(&doc_id);
                 idx.vectorizer.add_doc(doc_id, token_fq);
                 idx.vectorizer.update_idf();
-                idx.meta_from_id_mut(doc_id).map(|m| {
+                if let Some(text) = &trigram_text {
+                    idx.trigrams.insert(doc_id, text);
+                }
+                if let Some(m) = idx.meta_from_id_mut(doc_id) {
+                    // 別名の URL で登録し直したときは元の URL を表示用に残す
+                    if m.url != meta.url && m.aliases.contains(&meta.url) {
+                        let aliases: Vec<Box<str>> = m.aliases.iter().chain(meta.aliases.iter()).cloned().collect();
+                        meta.url = m.url.clone();
+                        meta.set_aliases(&aliases);
+                    }
+                    // 別名が変わることがあるので url_map を入れ替える
+                    if let Ok(mut map) = self.url_map.write() {
+                        remove_url_keys(&mut map, m, (shard_id, doc_id));
+                        insert_url_keys(&mut map, &mut meta, (shard_id, doc_id));
+                    }
                     m.url = meta.url.clone();
                     m.title = meta.title.clone();
                     m.favicon = meta.favicon.clone();
-                    m.tags = meta.tags.clone();
+                    m.tags = meta.tags;
                     m.description = meta.description.clone();
                     m.points = meta.points;
                     m.time = meta.time;
-                });
-                do_save = idx.update_count % SAVE_FILE_INTERVAL == 0;
-                do_calculate_size = idx.update_count % CALCULATE_BIN_SIZE_INTERVAL == 0;
-                idx.update_count += 1;
+                    m.author = meta.author.clone();
+                    m.published = meta.published;
+                    m.modified = meta.modified;
+                    m.desc_tokens = meta.desc_tokens.clone();
+                    m.aliases = meta.aliases.clone();
+                }
+                actions = idx.record_update();
             } else {
                 error!("RwLock poisoned for index id {}, skipping", shard_id);
                 return None;
             }
         }
-
-        if do_save {
-            // Save the index to disk
-            if let Ok(bin_size) = self.save_shard(shard_id, &self.index_dir) {
-                if let Ok(mut idx) = self.indexes[shard_id].write() {
-                    idx.vectorizer_bin_size = bin_size.0;
-                    idx.meta_bin_size = bin_size.1;
-                }
-            }
-        } else if do_calculate_size {
-            // Just calculate the binary size
-            if let Ok(bin_size) = self.calculate_shard_size(shard_id) {
-                if let Ok(mut idx) = self.indexes[shard_id].write() {
-                    idx.vectorizer_bin_size = bin_size.0;
-                    idx.meta_bin_size = bin_size.1;
-                }
-            }
+        // コーパスに入れてから立てる (先に立てると並行する save_shard が更新前のコーパスを書いて下ろしてしまう)
+        if adds_vocab {
+            self.corpus_dirty.store(true, Ordering::SeqCst);
         }
+        self.bump_generation();
+        self.after_update(shard_id, actions);
 
         Some(is_new)
     }
 
-    pub fn del_document(&self, url: &str) -> bool {
-        let mut found = false;
-        let mut shard_id = 0;
-        let mut doc_id = 0;
-        // 既存で登録されているかチェック
-        for index in &self.indexes {
-            match index.read() {
-                Ok(idx) => {
-                    if let Some(meta) = idx.meta_from_url(url) {
-                        // 既に登録されている
-                        shard_id = idx.id;
-                        doc_id = meta.id;
-                        found = true;
-                        break;
-                    }
-                }
-                Err(_poison) => {
-                    warn!("RwLock poisoned, skipping");
-                    continue; // Skip poisoned lock
-                }
-            }
-        }
-        if found {
-            if let Ok(mut idx) = self.indexes[shard_id].write() {
-                idx.vectorizer.del_doc(&doc_id);
-                idx.vectorizer.update_idf();
-                // metaは先所しない、 削除するロジックにしたら多少ファイルサイズ小さくなるかもだけどlock延長のほうが悪いとおもうので
-                // idx.meta.retain(|m| m.id != doc_id);
-                idx.update_count += 1;
-                self.counter.fetch_sub(1, Ordering::SeqCst);
-            } else {
-                error!("RwLock poisoned for index id {}, skipping", shard_id);
-                return false;
+    /// record_update の結果に応じて保存 / バイナリサイズの再計算をする
+    /// write lock を外してから呼ぶ
+    fn after_update(&self, shard_id: usize, actions: UpdateActions) {
+        let bin_size = if actions.save {
+            // Save the index to disk
+            let saved = self.save_shard(shard_id, &self.index_dir).ok();
+            if saved.is_some() {
+                self.auto_saves.fetch_add(1, Ordering::SeqCst);
             }
+            saved
+        } else if actions.calculate_size {
+            // Just calculate the binary size
+            self.calculate_shard_size(shard_id).ok()
+        } else {
+            None
+        };
+        if let Some(bin_size) = bin_size {
+            self.store_bin_size(shard_id, bin_size);
         }
-        found
     }
 
-    /// Load indexes and corpus from the specified directory
-    /// if not found corpus, create new instance
-    pub fn load_or_new(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        match Self::load(path) {
-            Ok(pool) => Ok(pool),
-            Err(e) => {
-                warn!("Failed to load index pool from {}: {}, creating new instance", path, e);
-                Ok(Self::new(path))
+    /// 計算したバイナリサイズ (.index, .meta) をシャードに記録する
+    /// 上限を超えたらログを出す
+    fn store_bin_size(&self, shard_id: usize, bin_size: (u64, u64)) {
+        if let Ok(mut idx) = self.indexes[shard_id].load().write() {
+            let before = idx.bin_size();
+            idx.vectorizer_bin_size = bin_size.0;
+            idx.meta_bin_size = bin_size.1;
+            if before < self.max_shard_bytes && idx.bin_size() >= self.max_shard_bytes {
+                warn!(
+                    "Shard {} reached the size limit ({} bytes >= {} bytes), new documents go to other shards",
+                    shard_id, idx.bin_size(), self.max_shard_bytes,
+                );
             }
         }
     }
 
-    /// Load indexes and corpus from the specified directory
-    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        // .corpus
-        let corpus_path = std::fs::read_dir(path)?
-            .filter_map(|entry| {
-                let entry = match entry {
-                    Ok(e) => e,
+    /// 全シャードのバイナリサイズを今の内容で計算し直して記録する (POST /recalc_sizes)
+    /// CALCULATE_BIN_SIZE_INTERVAL 回の更新を待たずにシャードの振り分けを最新のサイズにする
+    /// ロックが壊れたシャードは飛ばす
+    pub fn recalc_sizes(&self) -> Vec<ShardBinSizes> {
+        (0..self.indexes.len())
+            .filter_map(|shard_id| {
+                let bin_size = match self.calculate_shard_size(shard_id) {
+                    Ok(size) => size,
                     Err(e) => {
-                        log::error!("Failed to read dir entry: {}", e);
+                        warn!("Failed to calculate size of index id {}: {}", shard_id, e);
                         return None;
                     }
                 };
-                let path = entry.path();
-                if path.extension()? == "corpus" {
-                    Some(path)
-                } else {
-                    None
-                }
+                self.store_bin_size(shard_id, bin_size);
+                let documents = self.indexes[shard_id].load().read().ok()?.vectorizer.doc_num();
+                Some(ShardBinSizes {
+                    id: shard_id,
+                    documents,
+                    vectorizer_bin_size: bin_size.0,
+                    meta_bin_size: bin_size.1,
+                    over_limit: bin_size.0.max(bin_size.1) >= self.max_shard_bytes,
+                })
             })
-            .next()
-            .ok_or("No corpus file found")?;
-
-        // N.index (N: usize)
-        let index_paths = std::fs::read_dir(path)?
-            .filter_map(|entry| {
-                let entry = match entry {
-                    Ok(e) => e,
-                    Err(e) => {
-                        log::warn!("Failed to read dir entry: {}", e);
-                        return None;
-                    }
-                };
-                let path = entry.path();
-                if path.extension()? == "index" {
-                    Some(path)
-                } else {
-                    None
+            .collect()
+    }
+
+    /// 全シャード・url_map・コーパスの整合性を調べる (GET /fsck) 何も直さない
+    /// 調べている間は add / del を待たせる (検索は止めない) ロックが壊れたシャードは飛ばす
+    /// - vectorizer のドキュメントに meta があるか / ID の重複 / meta の並び / 語の次元
+    /// - url_map のキーが登録中のドキュメントの URL か別名を指しているか、その逆
+    /// - コーパスの文書頻度と文書数が実データと合っているか
+    pub fn fsck(&self) -> FsckReport {
+        let _mutation = self.mutation_lock.write().unwrap_or_else(|poison| poison.into_inner());
+        let mut report = FsckReport { counter: self.counter.load(Ordering::SeqCst), ..Default::default() };
+        let shards = self.shards();
+        let mut guards = Vec::with_capacity(shards.len());
+        for (shard_id, index) in shards.iter().enumerate() {
+            match index.read() {
+                Ok(idx) => guards.push(idx),
+                Err(_poison) => {
+                    warn!("RwLock poisoned for index id {}, skipping fsck", shard_id);
+                    report.skipped.push(shard_id);
                 }
-            })
-            .collect::<Vec<_>>();
+            }
+        }
+        // シャードごとの登録中のドキュメント ID
+        let mut live: HashMap<usize, HashSet<usize>> = HashMap::new();
+        for idx in &guards {
+            let mut shard = ShardFsck {
+                id: idx.id,
+                documents: idx.vectorizer.doc_num(),
+                meta: idx.meta.len(),
+                meta_unordered: !idx.meta.windows(2).all(|w| w[0].id <= w[1].id),
+                ..Default::default()
+            };
+            let mut doc_ids = HashSet::with_capacity(shard.documents);
+            for doc in &idx.vectorizer.documents {
+                if !doc_ids.insert(doc.key) {
+                    shard.duplicate_doc_ids.push(doc.key);
+                }
+                if idx.meta_from_id(doc.key).is_none() {
+                    shard.docs_without_meta.push(doc.key);
+                }
+                if doc.tf_vec.raw_iter().any(|(dim, _)| idx.vectorizer.token_dim_sample.get_index(dim).is_none()) {
+                    shard.invalid_token_dims.push(doc.key);
+                }
+            }
+            let mut meta_ids = HashSet::with_capacity(shard.meta);
+            for m in &idx.meta {
+                if !meta_ids.insert(m.id) {
+                    shard.duplicate_meta_ids.push(m.id);
+                }
+                if !doc_ids.contains(&m.id) {
+                    shard.deleted_meta += 1;
+                }
+            }
+            report.documents += shard.documents;
+            live.insert(idx.id, doc_ids);
+            report.shards.push(shard);
+        }
 
-        let meta_paths = std::fs::read_dir(path)?
-            .filter_map(|entry| {
-                let entry = match entry {
-                    Ok(e) => e,
-                    Err(e) => {
-                        log::warn!("Failed to read dir entry: {}", e);
-                        return None;
+        let shard_of = |shard_id: usize| guards.iter().find(|idx| idx.id == shard_id);
+        let url_map = self.url_map.read().unwrap_or_else(|poison| poison.into_inner());
+        report.url_map.entries = url_map.len();
+        for (&key, &(shard_id, doc_id)) in url_map.iter() {
+            if report.skipped.contains(&shard_id) {
+                continue;
+            }
+            let valid = live.get(&shard_id).is_some_and(|ids| ids.contains(&doc_id))
+                && shard_of(shard_id)
+                    .and_then(|idx| idx.meta_from_id(doc_id))
+                    .is_some_and(|m| m.urls().any(|u| url_hash(u) == key));
+            if !valid {
+                report.url_map.dangling += 1;
+            }
+        }
+        for idx in &guards {
+            for m in idx.meta.iter().filter(|m| live[&idx.id].contains(&m.id)) {
+                for u in m.urls() {
+                    if url_map.get(&url_hash(u)) != Some(&(idx.id, m.id)) {
+                        report.url_map.missing.push(u.to_string());
                     }
-                };
-                let path = entry.path();
-                if path.extension()? == "meta" {
-                    Some(path)
-                } else {
-                    None
                 }
-            })
-            .collect::<Vec<_>>();
+            }
+        }
+        drop(url_map);
 
-        let corpus_data = match std::fs::read(corpus_path.as_path()) {
-            Ok(data) => data,
-            Err(e) => {
-                log::error!("Failed to read corpus file: {}", e);
-                return Err(Box::new(e));
+        // 飛ばしたシャードがあると実データの文書頻度が足りないので比べない
+        report.corpus.vocab_size = self.corpus.load().vocab_size();
+        report.corpus.doc_num = self.corpus.load().get_doc_num();
+        if report.skipped.is_empty() {
+            let doc_freq = count_doc_freq(guards.iter().map(|idx| &**idx));
+            for entry in self.corpus.load().token_counts.iter() {
+                if doc_freq.get(entry.key().as_ref()).copied().unwrap_or(0) != *entry.value() {
+                    report.corpus.mismatched_counts += 1;
+                }
             }
+            report.corpus.missing_tokens = doc_freq.keys()
+                .filter(|token| !self.corpus.load().token_counts.contains_key(**token))
+                .map(|token| token.to_string())
+                .collect();
+            report.corpus.missing_tokens.sort();
+        }
+        drop(guards);
+
+        report.ok = report.skipped.is_empty()
+            && report.counter == report.documents as u64
+            && report.shards.iter().all(ShardFsck::is_ok)
+            && report.url_map.dangling == 0
+            && report.url_map.missing.is_empty()
+            && report.corpus.mismatched_counts == 0
+            && report.corpus.missing_tokens.is_empty()
+            && report.corpus.doc_num == report.documents as u64;
+        if !report.ok {
+            warn!("fsck found problems in the index");
+        }
+        report
+    }
+
+    /// URL か別名で指定したドキュメントを削除する (URL と全ての別名が引けなくなる)
+    pub fn del_document(&self, url: &str) -> bool {
+        let _mutation = self.mutation_lock.read().unwrap_or_else(|poison| poison.into_inner());
+        // 既存で登録されているかチェック
+        let (shard_id, doc_id) = match self.locate(url) {
+            Some(v) => v,
+            None => return false,
         };
-        let corpus: Arc<Corpus> = match bincode::deserialize(&corpus_data) {
-            Ok(c) => Arc::new(c),
-            Err(e) => {
-                log::error!("Failed to deserialize corpus: {}", e);
-                return Err(Box::new(e));
+        let actions;
+        if let Ok(mut idx) = self.indexes[shard_id].load().write() {
+            let _timer = self.lock_metrics.write_timer();
+            idx.vectorizer.del_doc(&doc_id);
+            idx.vectorizer.update_idf();
+            idx.trigrams.remove(doc_id);
+            if let Some(m) = idx.meta_from_id(doc_id)
+                && let Ok(mut map) = self.url_map.write() {
+                remove_url_keys(&mut map, m, (shard_id, doc_id));
             }
-        };
+            // metaは先所しない、 削除するロジックにしたら多少ファイルサイズ小さくなるかもだけどlock延長のほうが悪いとおもうので
+            // remove_meta_on_delete のときだけ消す (長時間動かして入れ替えが多い場合のメモリ用)
+            // 末尾は次の id の採番に使うので残す (消すと id が再利用される)
+            if self.remove_meta_on_delete && idx.meta.last().is_some_and(|m| m.id != doc_id) {
+                idx.meta.retain(|m| m.id != doc_id);
+            }
+            actions = idx.record_update();
+            self.counter.fetch_sub(1, Ordering::SeqCst);
+        } else {
+            error!("RwLock poisoned for index id {}, skipping", shard_id);
+            return false;
+        }
+        // 文書頻度が 0 になった語はコーパスから消えるので書き直す
+        self.corpus_dirty.store(true, Ordering::SeqCst);
+        self.bump_generation();
+        self.after_update(shard_id, actions);
+        true
+    }
 
-        let mut vectorizer_map: HashMap<usize, TFIDFVectorizer<u16, usize>> = index_paths.iter()
-            .filter_map(|path| {
-                let id = path.file_stem()
-                    .and_then(|s| s.to_str())
-                    .and_then(|s| s.parse::<usize>().ok())?;
+    /// シャードの内容をダンプ (デバッグ用)
+    /// meta は range の範囲のみ返す
+    /// # Returns
+    /// None: シャードが存在しない / lock poisoned
+    pub fn dump_shard(&self, shard_id: usize, range: Range<usize>) -> Option<ShardDump> {
+        let index = self.shard(shard_id)?;
+        let idx = match index.read() {
+            Ok(idx) => idx,
+            Err(_poison) => {
+                warn!("RwLock poisoned for index id {}, skipping", shard_id);
+                return None;
+            }
+        };
+        let entries = idx.meta.iter()
+            .skip(range.start)
+            .take(range.end.saturating_sub(range.start))
+            .map(|m| ShardDumpEntry {
+                id: m.id,
+                url: m.url.clone(),
+                title: m.title.clone(),
+            })
+            .collect();
+        Some(ShardDump {
+            id: idx.id,
+            documents: idx.vectorizer.doc_num(),
+            meta_count: idx.meta.len(),
+            update_count: idx.update_count,
+            vectorizer_bin_size: idx.vectorizer_bin_size,
+            meta_bin_size: idx.meta_bin_size,
+            range,
+            entries,
+        })
+    }
+
+    /// 結果ごとに description 中のクエリの語の位置 (文字単位) を埋める
+    /// インデックス時に保存した desc_tokens を使うので再トークン化しない (保存していないドキュメントは None のまま)
+    pub fn fill_highlights<T: AsRef<str>>(&self, entries: &mut [ResEntry], query_tokens: &[T], reads: &SearchReads) {
+        for entry in entries.iter_mut() {
+            let index = self.shard(entry.index_id);
+            let Some(idx) = index.as_ref().and_then(|i| self.read_for_results(entry.index_id, i, reads)) else { continue; };
+            let Some(spans) = idx.meta_from_id(entry.id).and_then(|m| m.desc_tokens.as_ref()) else { continue; };
+            entry.highlights = Some(spans.iter()
+                .filter(|s| query_tokens.iter().any(|q| q.as_ref() == s.token.as_ref()))
+                .map(|s| s.start as usize..(s.start + s.len) as usize)
+                .collect());
+        }
+    }
+
+    /// 結果ごとにタイトル / description に含まれるクエリの語 (クエリの順、重複なし) を matched_title / matched_desc に入れる
+    /// description は desc_tokens があればそれを使い、タイトルと desc_tokens のない description は
+    /// tokenize ((テキスト, lang) の列) の 1 回の呼び出しでまとめてトークン化する
+    /// tokenize は sudachi を呼ぶので read lock の外で行う 失敗したら None のまま
+    pub fn fill_matched_tokens<T, F>(&self, entries: &mut [ResEntry], query_tokens: &[T], reads: &SearchReads, tokenize: F)
+    where
+        T: AsRef<str>,
+        F: FnOnce(&[(&str, Option<&str>)]) -> Result<Vec<Vec<String>>, SudachiError>,
+    {
+        let matched = |tokens: &[&str]| -> Vec<String> {
+            let mut matched: Vec<String> = Vec::new();
+            for q in query_tokens.iter().map(|q| q.as_ref()) {
+                if tokens.contains(&q) && !matched.iter().any(|m| m == q) {
+                    matched.push(q.to_string());
+                }
+            }
+            matched
+        };
+        let metas: Vec<Option<IndexMeta>> = entries.iter()
+            .map(|entry| self.shard(entry.index_id).as_ref()
+                .and_then(|i| self.read_for_results(entry.index_id, i, reads))
+                .and_then(|idx| idx.meta_from_id(entry.id).cloned()))
+            .collect();
+        let mut texts: Vec<(&str, Option<&str>)> = Vec::new();
+        for meta in metas.iter().flatten() {
+            texts.push((&meta.title, meta.lang.as_deref()));
+            if meta.desc_tokens.is_none() {
+                texts.push((&meta.description, meta.lang.as_deref()));
+            }
+        }
+        let mut tokenized = match tokenize(&texts) {
+            Ok(tokenized) => tokenized.into_iter(),
+            Err(e) => {
+                warn!("Failed to tokenize titles / descriptions for matched tokens: {}", e);
+                return;
+            }
+        };
+        for (entry, meta) in entries.iter_mut().zip(&metas) {
+            let Some(meta) = meta else { continue; };
+            let title = tokenized.next().unwrap_or_default();
+            let desc = match &meta.desc_tokens {
+                Some(spans) => spans.iter().map(|s| s.token.to_string()).collect(),
+                None => tokenized.next().unwrap_or_default(),
+            };
+            entry.matched_title = Some(matched(&title.iter().map(|t| t.as_str()).collect::<Vec<_>>()));
+            entry.matched_desc = Some(matched(&desc.iter().map(|t| t.as_str()).collect::<Vec<_>>()));
+        }
+    }
+
+    /// 結果ごとに description からクエリの語が密集している部分を最大 count 個切り出す
+    /// fill_highlights と同じく desc_tokens を保存していないドキュメントは None のまま
+    pub fn fill_snippets<T: AsRef<str>>(&self, entries: &mut [ResEntry], query_tokens: &[T], count: usize, reads: &SearchReads) {
+        for entry in entries.iter_mut() {
+            let index = self.shard(entry.index_id);
+            let Some(idx) = index.as_ref().and_then(|i| self.read_for_results(entry.index_id, i, reads)) else { continue; };
+            let Some(spans) = idx.meta_from_id(entry.id).and_then(|m| m.desc_tokens.as_ref()) else { continue; };
+            let matches: Vec<Range<usize>> = spans.iter()
+                .filter(|s| query_tokens.iter().any(|q| q.as_ref() == s.token.as_ref()))
+                .map(|s| s.start as usize..(s.start + s.len) as usize)
+                .collect();
+            let chars: Vec<char> = entry.descriptions.chars().collect();
+            entry.snippets = Some(snippet_windows(chars.len(), &matches, count, SNIPPET_WINDOW)
+                .into_iter()
+                .map(|r| chars[r].iter().collect())
+                .collect());
+        }
+    }
+
+    /// コーパスをシャードの実データと突き合わせて整理
+    /// シャードの実際の文書頻度からコーパスを作り直し (どのドキュメントにも含まれない語は消え、文書頻度のずれも直る)、
+    /// 各シャードをそれに付け替えて IDF を再計算する
+    /// (シャード単位の保存と global.corpus の保存がずれた状態で再起動した場合などに残る)
+    /// rebuild_shards で行うので、その間検索は前のデータで続き、add / del は待たされる
+    pub fn prune_corpus(&self) -> Result<PruneStats, Box<dyn std::error::Error>> {
+        let _mutation = self.mutation_lock.write().unwrap_or_else(|poison| poison.into_inner());
+        let shards = self.shards();
+        let (corpus, stats) = {
+            let guards = shards.iter()
+                .map(|index| index.read().map_err(|e| {
+                    log::error!("Failed to acquire read lock for index: {}", e);
+                    Box::new(Error::other("RwLock poisoned"))
+                }))
+                .collect::<Result<Vec<_>, _>>()?;
+            let corpus = corpus_from_shards(guards.iter().map(|idx| &**idx));
+            let current = self.corpus.load();
+            let stats = PruneStats {
+                removed: current.token_counts.iter().filter(|entry| !corpus.token_counts.contains_key(entry.key())).count(),
+                corrected: corpus.token_counts.iter().filter(|entry| current.token_counts.get(entry.key()).is_none_or(|count| *count != *entry.value())).count(),
+                vocab_size: corpus.vocab_size(),
+            };
+            (Arc::new(corpus), stats)
+        };
+        drop(shards);
+
+        self.rebuild_shards(|idx| idx.vectorizer.set_corpus_ref(Arc::clone(&corpus)))?;
+        self.corpus.store(corpus);
+        self.corpus_dirty.store(true, Ordering::SeqCst);
+        self.bump_generation();
+        Ok(stats)
+    }
+
+    /// コピーオンライトでシャードを 1 つずつ作り直す
+    /// シャードの複製に rebuild を適用してから Arc ごと入れ替えるので、検索は入れ替えまで前のシャードを読み、入れ替えも待たない
+    /// 余分に使うメモリは 1 シャード分
+    /// 複製してから入れ替えるまでの更新が消えないように mutation_lock の write を取った状態で呼ぶ
+    fn rebuild_shards<T>(&self, mut rebuild: impl FnMut(&mut Index) -> T) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+        // 途中で止まるとシャードごとに新旧が混ざるので先に確認する
+        if let Some(shard_id) = self.shards().iter().position(|index| index.is_poisoned()) {
+            log::error!("RwLock poisoned for index id {}, not rebuilt", shard_id);
+            return Err(Box::new(Error::other("RwLock poisoned")));
+        }
+        let mut results = Vec::with_capacity(self.indexes.len());
+        for slot in &self.indexes {
+            let mut shard = slot.load().read().unwrap_or_else(|poison| poison.into_inner()).clone();
+            results.push(rebuild(&mut shard));
+            // 古いシャードは読み終えた検索から順に解放される
+            slot.store(Arc::new(RwLock::new(shard)));
+        }
+        Ok(results)
+    }
+
+    /// 全ドキュメントを消してコーパス / url_map / 件数を空にし、path のインデックスファイルを削除する (POST /clear)
+    /// 世代番号は戻さない (キャッシュが古い結果を返さないように進める) ので世代番号のファイルは残す
+    /// 保存中なら待ち、全シャードの write lock を id 順に取ってから行う
+    /// ロックは他の更新と同じく mutation_lock → save_lock の順に取る
+    pub fn clear(&self, path: &str) -> Result<ClearSummary, Box<dyn std::error::Error>> {
+        let _mutation = self.mutation_lock.read().unwrap_or_else(|poison| poison.into_inner());
+        let _save_guard = self.save_lock.lock().unwrap_or_else(|poison| poison.into_inner());
+        let shards = self.shards();
+        let mut guards = Vec::with_capacity(shards.len());
+        for index in &shards {
+            guards.push(index.write().map_err(|e| {
+                log::error!("Failed to acquire write lock for index: {}", e);
+                Box::new(Error::other("RwLock poisoned"))
+            })?);
+        }
+
+        let removed: usize = guards.iter().map(|idx| idx.vectorizer.doc_num()).sum();
+        // 空のコーパスに付け替える (前のコーパスを読んでいる検索はそのまま終わる)
+        let corpus = Arc::new(Corpus::new());
+        for idx in guards.iter_mut() {
+            **idx = Index::new(idx.id, Arc::clone(&corpus));
+        }
+        self.corpus.store(corpus);
+        self.url_map.write().unwrap_or_else(|poison| poison.into_inner()).clear();
+        self.counter.store(0, Ordering::SeqCst);
+        self.corpus_dirty.store(true, Ordering::SeqCst);
+
+        let mut files = Vec::new();
+        for ext in ["index", "meta", "state", "trigram"] {
+            files.extend(self.layout.shard_files(path, ext));
+        }
+        files.extend([std::path::Path::new(path).join(&self.corpus_file), std::path::Path::new(path).join(URL_MAP_FILE)]);
+        let mut deleted_files = 0;
+        for file in files {
+            match std::fs::remove_file(&file) {
+                Ok(()) => deleted_files += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("Failed to delete {:?}: {}", file, e).into()),
+            }
+        }
+        drop(guards);
+        self.bump_generation();
+        Ok(ClearSummary { removed, deleted_files })
+    }
+
+    /// 削除済みドキュメントの meta を消す (DEL_REMOVES_META を後からまとめて適用する)
+    /// シャード内で最新の meta は id の採番に使うので残す
+    /// # Returns
+    /// 消した meta の数
+    pub fn compact_meta(&self) -> usize {
+        let _mutation = self.mutation_lock.write().unwrap_or_else(|poison| poison.into_inner());
+        let compacted = self.rebuild_shards(|idx| {
+            let last_id = idx.meta.last().map(|m| m.id);
+            let before = idx.meta.len();
+            let Index { meta, vectorizer, .. } = idx;
+            meta.retain(|m| Some(m.id) == last_id || vectorizer.contains_doc(&m.id));
+            before - meta.len()
+        }).map(|removed| removed.into_iter().sum());
+        let removed = match compacted {
+            Ok(removed) => removed,
+            Err(e) => {
+                warn!("Failed to compact meta: {}", e);
+                0
+            }
+        };
+        if removed > 0 {
+            self.bump_generation();
+        }
+        removed
+    }
+
+    /// meta から url_map を作り直し、全シャードの IDF を再計算する
+    /// 元の本文は保存していないので再トークン化はしない
+    /// # Returns
+    /// url_map の件数
+    pub fn rebuild(&self) -> usize {
+        // IDF はシャードごとにその場で再計算する (複製はしない)
+        for (shard_id, index) in self.shards().iter().enumerate() {
+            match index.write() {
+                Ok(mut idx) => idx.vectorizer.update_idf(),
+                Err(e) => warn!("Failed to recompute idf for index id {}: {}", shard_id, e),
+            }
+        }
+        let map = build_url_map(&self.shards());
+        let len = map.len();
+        match self.url_map.write() {
+            Ok(mut url_map) => *url_map = map,
+            Err(poison) => *poison.into_inner() = map,
+        }
+        self.bump_generation();
+        len
+    }
+
+    /// シャードのドキュメントの後半 (新しい方) を別のシャードへ移して 2 つに分ける (POST /split/:id)
+    /// シャード数は固定なので、移す先は上限に達していない他のシャードのうち最もサイズの小さいもの
+    /// TF ベクトルは移す先の次元に付け替えてそのまま移すので再トークン化はせず、コーパスも変わらない
+    /// 移したドキュメントは移す先で新しい id を振り、meta / url_map / 3-gram も移してから両方のシャードを保存する
+    /// 移す元の meta は末尾 (id の採番用) だけ残す
+    /// 2 つのシャードの write lock を id 順に取るので、その間この 2 つへの add / del / 検索は待たされる
+    /// 移した後の保存に失敗してもメモリ上の分割は済んでいるので、Ok で返して warnings に載せる (次の保存で書かれる)
+    pub fn split_shard(&self, shard_id: usize) -> Result<SplitSummary, Box<dyn std::error::Error>> {
+        if shard_id >= self.indexes.len() {
+            return Err(Box::new(SplitShardError::NotFound));
+        }
+        // 移す先のサイズは mutation_lock を取ってから見る (ロックを待つ間に古くなったサイズで選ばない)
+        let mutation = self.mutation_lock.read().unwrap_or_else(|poison| poison.into_inner());
+        let shards = self.shards();
+        let sizes: Vec<(usize, u64)> = shards.iter()
+            .filter_map(|index| index.read().ok().map(|idx| (idx.id, idx.bin_size())))
+            .filter(|(id, _)| *id != shard_id)
+            .collect();
+        let target_id = choose_shard(&sizes, self.max_shard_bytes).ok_or(SplitShardError::NoTarget)?;
+
+        let lock = |id: usize| shards[id].write().map_err(|e| {
+            log::error!("Failed to acquire write lock for index: {}", e);
+            Box::new(Error::other("RwLock poisoned"))
+        });
+        let (mut low, mut high) = (lock(shard_id.min(target_id))?, lock(shard_id.max(target_id))?);
+        let (source, target) = if shard_id < target_id { (&mut *low, &mut *high) } else { (&mut *high, &mut *low) };
+        let timer = self.lock_metrics.write_timer();
+        let live = source.vectorizer.doc_num();
+        if live < 2 {
+            return Err(Box::new(SplitShardError::TooFewDocuments));
+        }
+
+        let moving = source.vectorizer.documents.split_off(live / 2);
+        let mut moved_ids = std::collections::HashSet::new();
+        let mut url_map = self.url_map.write().unwrap_or_else(|poison| poison.into_inner());
+        for mut doc in moving {
+            let old_id = doc.key;
+            let Some(mut meta) = source.meta_from_id(old_id).cloned() else {
+                // meta がなければ id を振れないので残す
+                warn!("Meta not found for index id {} doc {}, not moved", shard_id, old_id);
+                source.vectorizer.documents.push(doc);
+                continue;
+            };
+            let new_id = target.generate_next_id();
+            // 次元を移す先の token_dim_sample に付け替え、昇順に並べて詰め直す
+            let mut entries: Vec<(usize, u16)> = doc.tf_vec.raw_iter()
+                .filter_map(|(dim, q)| {
+                    let token = source.vectorizer.token_dim_sample.get_index(dim)?;
+                    Some((target.vectorizer.token_dim_sample.insert_full(token.clone()).0, *q))
+                })
+                .collect();
+            entries.sort_unstable_by_key(|(dim, _)| *dim);
+            let mut tf_vec = ZeroSpVec::new();
+            for (dim, q) in entries {
+                tf_vec.add_dim(dim - tf_vec.len());
+                tf_vec.push(q);
+            }
+            tf_vec.add_dim(target.vectorizer.token_dim_sample.len() - tf_vec.len());
+            doc.tf_vec = tf_vec;
+            doc.key = new_id;
+            target.vectorizer.documents.push(doc);
+
+            if let Some(grams) = source.trigrams.take(old_id) {
+                target.trigrams.insert_grams(new_id, grams);
+            }
+            remove_url_keys(&mut url_map, &meta, (shard_id, old_id));
+            for u in meta.urls() {
+                url_map.insert(url_hash(u), (target_id, new_id));
+            }
+            meta.id = new_id;
+            target.meta.push(meta);
+            moved_ids.insert(old_id);
+        }
+        drop(url_map);
+        let last_id = source.meta.last().map(|m| m.id);
+        source.meta.retain(|m| Some(m.id) == last_id || !moved_ids.contains(&m.id));
+
+        // コーパスは変わらないが次元が変わったので IDF を計算し直す (update_idf は世代番号が同じだと何もしない)
+        let corpus = self.corpus.load_full();
+        source.vectorizer.set_corpus_ref(Arc::clone(&corpus));
+        target.vectorizer.set_corpus_ref(corpus);
+        let mut summary = SplitSummary {
+            source: shard_id,
+            target: target_id,
+            moved: moved_ids.len(),
+            source_documents: source.vectorizer.doc_num(),
+            target_documents: target.vectorizer.doc_num(),
+            warnings: Vec::new(),
+        };
+        drop(timer);
+        drop(low);
+        drop(high);
+        drop(mutation);
+        self.bump_generation();
+
+        for id in [shard_id, target_id] {
+            match self.save_shard(id, &self.index_dir) {
+                Ok((vectorizer_bin_size, meta_bin_size)) => {
+                    if let Ok(mut idx) = self.indexes[id].load().write() {
+                        idx.vectorizer_bin_size = vectorizer_bin_size;
+                        idx.meta_bin_size = meta_bin_size;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to save index id {} after split: {}", id, e);
+                    summary.warnings.push(format!("Shard {} was split but not saved: {}", id, e));
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// ウォームアップ
+    /// 各シャードの語彙からサンプルクエリを作って per_similarity を一度走らせ、meta も一通り読む
+    /// ページキャッシュと rayon のスレッドプールを温めて初回検索のレイテンシを安定させる
+    /// # Returns
+    /// 走査したドキュメント数
+    pub fn warmup(&self) -> usize {
+        let mut query = TokenFrequency::new();
+        let mut touched = 0;
+        for index in &self.shards() {
+            let idx = match index.read() {
+                Ok(idx) => idx,
+                Err(_poison) => {
+                    warn!("RwLock poisoned, skipping");
+                    continue;
+                }
+            };
+            if let Some(token) = idx.vectorizer.token_dim_sample.first() {
+                query.add_token(token);
+            }
+            touched += idx.meta.iter().filter(|m| !m.url.is_empty()).count();
+        }
+        if !query.token_set_ref_str().is_empty() {
+            let scored = self.per_similarity(&query, &SimilarityAlgorithm::BM25(1.2, 0.75));
+            log::debug!("Warmup: {} documents scored", scored.len());
+        }
+        touched
+    }
+
+    /// 結果ごとにクエリの語の一致数と出現回数の合計を埋める
+    /// Hits には語ごとの情報がないので vectorizer の TF ベクトルから引き直す
+    /// TF は最大頻度の語を基準に量子化されているので、回数は token_sum を比で按分して復元する
+    pub fn fill_match_info(&self, entries: &mut [ResEntry], query: &TokenFrequency, reads: &SearchReads) {
+        let query_tokens = query.token_set_ref_str();
+        for entry in entries.iter_mut() {
+            let index = self.shard(entry.index_id);
+            let Some(idx) = index.as_ref().and_then(|i| self.read_for_results(entry.index_id, i, reads)) else { continue; };
+            let Some(tf) = idx.vectorizer.get_tf(&entry.id) else { continue; };
+            let query_dims: Vec<usize> = query_tokens.iter()
+                .filter_map(|t| idx.vectorizer.token_dim_sample.get_index_of(*t))
+                .collect();
+            let mut total_q: f64 = 0.0;
+            let mut matched_q: f64 = 0.0;
+            let mut matched = 0;
+            for (dim, q) in tf.tf_vec.raw_iter() {
+                let q = *q as f64;
+                total_q += q;
+                if query_dims.contains(&dim) {
+                    matched += 1;
+                    matched_q += q;
+                }
+            }
+            entry.matched_terms = Some(matched);
+            entry.term_freq = Some(if total_q > 0.0 {
+                (matched_q / total_q * tf.token_sum as f64).round() as u64
+            } else {
+                0
+            });
+        }
+    }
+
+    /// URL (別名を含む) で指定したドキュメントの TF ベクトル
+    /// None: 登録されていない
+    pub fn doc_vector(&self, url: &str) -> Option<DocVector> {
+        let (shard_id, doc_id) = self.locate(url)?;
+        let index = self.shard(shard_id)?;
+        let idx = index.read().ok()?;
+        let meta = idx.meta_from_id(doc_id)?;
+        Some(DocVector {
+            url: meta.url.clone(),
+            index_id: shard_id,
+            id: doc_id,
+            token_sum: idx.vectorizer.get_tf(&doc_id)?.token_sum,
+            tokens: idx.doc_token_counts(doc_id)?,
+        })
+    }
+
+    /// 共有コーパスでの語の IDF (文書数 / (文書頻度 + 1)、シャードごとの量子化の前の値)
+    /// None: 語彙にない
+    pub fn idf(&self, token: &str) -> Option<f64> {
+        let doc_freq = *self.corpus.load().token_counts.get(token)?;
+        Some(self.corpus.load().get_doc_num() as f64 / (doc_freq as f64 + 1.0))
+    }
+
+    /// 語ごとの IDF (語彙にない語は None)
+    pub fn idf_map<S: AsRef<str>>(&self, tokens: &[S]) -> BTreeMap<Box<str>, Option<f64>> {
+        tokens.iter().map(|t| (t.as_ref().into(), self.idf(t.as_ref()))).collect()
+    }
+
+    /// 設定のうちプールが持つもの (SCORE_FLOOR, DEL_REMOVES_META, 保存の設定, TRIGRAM_INDEX, SEARCH_LOCK_TIMEOUT_MS) を反映する
+    /// サーバと CLI で同じ設定になるよう、読み込んだ直後に呼ぶ
+    pub fn apply_config(&mut self, config: &crate::config::Config) {
+        self.score_floor = config.score_floor;
+        self.remove_meta_on_delete = config.del_removes_meta;
+        self.save_options = config.save_options;
+        self.trigram_index = config.trigram_index;
+        self.search_lock_timeout = config.search_lock_timeout_ms.map(Duration::from_millis);
+    }
+
+    /// Load indexes and corpus from the specified directory
+    /// if not found corpus, create new instance
+    /// コーパスが複数あって選べない場合は新規作成せずエラー (既存データを上書きしないため)
+    /// layout は新規作成時のみ使う (既存のインデックスは保存されている配置のまま)
+    pub fn load_or_new(path: &str, corpus_file: Option<&str>, layout: ShardLayout) -> Result<Self, Box<dyn std::error::Error>> {
+        match Self::load(path, corpus_file) {
+            Ok(pool) => {
+                if pool.layout != layout {
+                    warn!("Index in {} uses {:?} shard layout, keeping it (requested {:?})", path, pool.layout, layout);
+                }
+                Ok(pool)
+            }
+            // 新規作成すると保存時に既存のインデックスを上書きしてしまう
+            Err(e) if e.is::<MultipleCorpusError>() || e.is::<SchemaVersionError>() => Err(e),
+            // CORPUS_FILE を指定していて読めないときも空のプールで上書きしない
+            // (ディレクトリにまだ何もない初回起動だけ新規作成する)
+            Err(e) if corpus_file.is_some_and(|name| std::path::Path::new(path).join(name).exists() || has_index_files(path)) => {
+                Err(Box::new(CorpusFileError { file: corpus_file.unwrap_or_default().to_string(), reason: e.to_string() }))
+            }
+            Err(e) => {
+                warn!("Failed to load index pool from {}: {}, creating new instance", path, e);
+                let mut pool = Self::new(path);
+                pool.layout = layout;
+                if let Some(name) = corpus_file {
+                    pool.corpus_file = name.to_string();
+                }
+                Ok(pool)
+            }
+        }
+    }
+
+    /// Load indexes and corpus from the specified directory
+    /// corpus_file を指定した場合はそのファイルを使う
+    /// 未指定なら .corpus がちょうど一つであることを要求し、複数あれば MultipleCorpusError
+    /// ファイルのスキーマバージョンが SCHEMA_VERSION より新しければ SchemaVersionError (古ければ .meta を移行して読む)
+    pub fn load(path: &str, corpus_file: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        // .corpus
+        let corpus_path = match corpus_file {
+            Some(name) => {
+                let corpus_path = std::path::Path::new(path).join(name);
+                if !corpus_path.is_file() {
+                    return Err(format!("Corpus file not found: {:?}", corpus_path).into());
+                }
+                corpus_path
+            }
+            None => {
+                let mut corpus_paths = std::fs::read_dir(path)?
+                    .filter_map(|entry| {
+                        let entry = match entry {
+                            Ok(e) => e,
+                            Err(e) => {
+                                log::error!("Failed to read dir entry: {}", e);
+                                return None;
+                            }
+                        };
+                        let path = entry.path();
+                        if path.extension()? == "corpus" {
+                            Some(path)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                match corpus_paths.len() {
+                    0 => return Err("No corpus file found".into()),
+                    1 => corpus_paths.remove(0),
+                    _ => {
+                        corpus_paths.sort();
+                        return Err(Box::new(MultipleCorpusError { files: corpus_paths }));
+                    }
+                }
+            }
+        };
+        let corpus_file = corpus_path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(DEFAULT_CORPUS_FILE)
+            .to_string();
+
+        // N.index / N.meta (N: usize) 配置はディレクトリから判定
+        let layout = ShardLayout::detect(path);
+        let index_paths = layout.shard_files(path, "index");
+        let meta_paths = layout.shard_files(path, "meta");
+
+        let corpus_data = match std::fs::read(corpus_path.as_path()) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("Failed to read corpus file: {}", e);
+                return Err(Box::new(e));
+            }
+        };
+        let corpus: Arc<Corpus> = match bincode::deserialize(strip_header(&corpus_path, &corpus_data)?.1) {
+            Ok(c) => Arc::new(c),
+            Err(e) => {
+                log::error!("Failed to deserialize corpus: {}", e);
+                return Err(Box::new(e));
+            }
+        };
+
+        // スキーマバージョンの不一致は読み込みを止める (それ以外の失敗はそのシャードがないものとして扱う)
+        let mut vectorizer_map: HashMap<usize, TFIDFVectorizer<u16, usize>> = index_paths.iter()
+            .map(|path| {
+                let Some(id) = path.file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse::<usize>().ok()) else { return Ok(None); };
                 let data = match std::fs::read(path) {
                     Ok(d) => d,
                     Err(e) => {
                         log::warn!("Failed to read index file {:?}: {}", path, e);
-                        return None;
+                        return Ok(None);
                     }
                 };
-                let index: TFIDFData<u16, usize> = match bincode::deserialize(&data) {
+                let index: TFIDFData<u16, usize> = match bincode::deserialize(strip_header(path, &data)?.1) {
                     Ok(idx) => idx,
                     Err(e) => {
                         log::warn!("Failed to deserialize index file {:?}: {}", path, e);
-                        return None;
+                        return Ok(None);
                     }
                 };
                 let vectorizer = index.into_tf_idf_vectorizer(corpus.clone());
-                Some((id, vectorizer))
+                Ok(Some((id, vectorizer)))
             })
+            .collect::<Result<Vec<_>, SchemaVersionError>>()?
+            .into_iter()
+            .flatten()
             .collect();
 
         let mut meta_map: HashMap<usize, Vec<IndexMeta>> = meta_paths.iter()
-            .filter_map(|path| {
-                let id = path.file_stem()
+            .map(|path| {
+                let Some(id) = path.file_stem()
                     .and_then(|s| s.to_str())
-                    .and_then(|s| s.parse::<usize>().ok())?;
+                    .and_then(|s| s.parse::<usize>().ok()) else { return Ok(None); };
                 let data = match std::fs::read(path) {
                     Ok(d) => d,
                     Err(e) => {
                         log::warn!("Failed to read meta file {:?}: {}", path, e);
-                        return None;
+                        return Ok(None);
                     }
                 };
-                let meta: Vec<IndexMeta> = match bincode::deserialize(&data) {
+                let (version, body) = strip_header(path, &data)?;
+                let meta: Vec<IndexMeta> = match deserialize_meta(version, body) {
                     Ok(m) => m,
                     Err(e) => {
                         log::warn!("Failed to deserialize meta file {:?}: {}", path, e);
-                        return None;
+                        return Ok(None);
                     }
                 };
-                Some((id, meta))
+                Ok(Some((id, meta)))
             })
+            .collect::<Result<Vec<_>, SchemaVersionError>>()?
+            .into_iter()
+            .flatten()
             .collect();
 
         let mut indexes = Vec::with_capacity(DEFAULT_INDEX_SHARD_NUM);
 
         let mut counter: u64 = 0;
+        // meta の ID を直したシャードがあれば保存済みの url_map は信用しない
+        let mut meta_repaired = false;
 
         for i in 0..DEFAULT_INDEX_SHARD_NUM {
             let vectorizer = vectorizer_map.remove(&i).ok_or_else(|| {
@@ -448,43 +1674,142 @@ impl IndexPool {
             })?;
             counter += vectorizer.doc_num() as u64;
             let vectorizer_bin_size = bincode::serialized_size(&vectorizer)?;
-            let meta = meta_map.remove(&i).ok_or_else(|| {
+            let mut meta = meta_map.remove(&i).ok_or_else(|| {
                 log::error!("No meta found for index id {}", i);
                 Box::new(Error::new(std::io::ErrorKind::NotFound, "Meta not found"))
             })?;
+            meta_repaired |= check_meta_ids(&mut meta, i).repaired();
             let meta_bin_size = bincode::serialized_size(&meta)?;
-            indexes.push(Arc::new(RwLock::new(Index::with_vectorizer(i, vectorizer, meta, vectorizer_bin_size, meta_bin_size))));
+            let shard_dir = layout.shard_dir(path, i);
+            let state = ShardState::load(&shard_dir, i);
+            let mut index = Index::with_vectorizer(i, vectorizer, meta, vectorizer_bin_size, meta_bin_size);
+            index.update_count = state.update_count;
+            index.trigrams = TrigramIndex::load(&shard_dir, i);
+            indexes.push(Arc::new(RwLock::new(index)));
         }
 
-        Ok(Self { corpus, indexes, index_dir: path.to_string(), counter: AtomicU64::new(counter) })
+        let url_map = if meta_repaired {
+            log::info!("Rebuilding url map from repaired meta");
+            build_url_map(&indexes)
+        } else {
+            load_url_map(path, &indexes)
+        };
+        // 保存済みの上限から再開する (それ以前の番号は使用済みかもしれない)
+        let generation = load_generation(path, &corpus_file);
+
+        Ok(Self {
+            corpus: ArcSwap::new(corpus),
+            indexes: indexes.into_iter().map(ArcSwap::new).collect(),
+            index_dir: path.to_string(),
+            corpus_file,
+            counter: AtomicU64::new(counter),
+            url_map: RwLock::new(url_map),
+            layout,
+            generation: AtomicU64::new(generation),
+            generation_ceiling: Mutex::new(generation),
+            save_lock: Mutex::new(()),
+            lock_metrics: LockMetrics::new(DEFAULT_INDEX_SHARD_NUM),
+            score_floor: DEFAULT_SCORE_FLOOR,
+            remove_meta_on_delete: false,
+            save_options: SaveOptions::default(),
+            auto_saves: AtomicU64::new(0),
+            trigram_index: false,
+            corpus_dirty: AtomicBool::new(false),
+            max_shard_bytes: MAX_FILE_SIZE as u64,
+            search_lock_timeout: None,
+            mutation_lock: RwLock::new(()),
+        })
+    }
+
+    /// コーパスを保存 (失敗したら次の save_shard でも書き直す)
+    fn save_corpus(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let corpus_path = std::path::Path::new(path).join(&self.corpus_file);
+        write_bincode(&corpus_path, &**self.corpus.load(), self.save_options).inspect_err(|_| {
+            self.corpus_dirty.store(true, Ordering::SeqCst);
+        })
+    }
+
+    /// url_map を保存
+    pub fn save_url_map(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let map = self.url_map.read().map_err(|e| {
+            log::error!("Failed to acquire read lock for url map: {}", e);
+            Box::new(Error::other("RwLock poisoned"))
+        })?;
+        let map_path = std::path::Path::new(path).join(URL_MAP_FILE);
+        write_bincode(&map_path, &*map, self.save_options)
     }
 
     /// Save indexes and corpus to the specified directory
-    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    /// 全シャードを保存
+    /// ロックが poison されたシャードは飛ばして残りを保存する (Ctrl+C 時に 1 シャードのせいで全て失わないため)
+    /// 他の保存中なら終わるまで待つ
+    pub fn save(&self, path: &str) -> Result<SaveSummary, Box<dyn std::error::Error>> {
+        let _guard = self.save_lock.lock().unwrap_or_else(|poison| poison.into_inner());
+        self.save_all(path)
+    }
+
+    /// save と同じだが、他の保存中なら待たずに SaveInProgressError
+    pub fn try_save(&self, path: &str) -> Result<SaveSummary, Box<dyn std::error::Error>> {
+        let _guard = match self.save_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(std::sync::TryLockError::Poisoned(poison)) => poison.into_inner(),
+            Err(std::sync::TryLockError::WouldBlock) => return Err(Box::new(SaveInProgressError)),
+        };
+        self.save_all(path)
+    }
+
+    /// save_lock を取った状態で呼ぶ
+    fn save_all(&self, path: &str) -> Result<SaveSummary, Box<dyn std::error::Error>> {
         std::fs::create_dir_all(path)?;
 
         // Save corpus
-        let corpus_path = std::path::Path::new(path).join("global.corpus");
-        let corpus_data = bincode::serialize(&*self.corpus)?;
-        std::fs::write(corpus_path, corpus_data)?;
+        self.corpus_dirty.store(false, Ordering::SeqCst);
+        self.save_corpus(path)?;
 
         // Save each index and meta
-        for entry in self.indexes.iter() {
-            let index = entry.read().map_err(|e| {
-                log::error!("Failed to acquire read lock for index: {}", e);
-                Box::new(Error::new(std::io::ErrorKind::Other, "RwLock poisoned"))
-            })?;
-            let index_path = std::path::Path::new(path).join(format!("{}.index", index.id));
-            let meta_path = std::path::Path::new(path).join(format!("{}.meta", index.id));
+        let mut summary = SaveSummary::default();
+        for (shard_id, entry) in self.shards().iter().enumerate() {
+            let index = match entry.read() {
+                Ok(index) => index,
+                Err(e) => {
+                    log::error!("Failed to acquire read lock for index id {}, not saved: {}", shard_id, e);
+                    summary.skipped.push(shard_id);
+                    continue;
+                }
+            };
+            let shard_dir = self.layout.shard_dir(path, index.id);
+            std::fs::create_dir_all(&shard_dir)?;
+            let index_path = std::path::Path::new(&shard_dir).join(format!("{}.index", index.id));
+            let meta_path = std::path::Path::new(&shard_dir).join(format!("{}.meta", index.id));
 
-            let index_data = bincode::serialize(&index.vectorizer)?;
-            std::fs::write(index_path, index_data)?;
+            write_bincode(&index_path, &index.vectorizer, self.save_options)?;
+            write_meta(&meta_path, &index.meta, self.save_options)?;
 
-            let meta_data = bincode::serialize(&index.meta)?;
-            std::fs::write(meta_path, meta_data)?;
+            ShardState::from_index(&index).save(&shard_dir, index.id, self.save_options)?;
+            if self.trigram_index {
+                index.trigrams.save(&shard_dir, index.id, self.save_options)?;
+            }
+            summary.saved.push(shard_id);
+            summary.sizes.push(ShardFileSizes {
+                id: shard_id,
+                index_bytes: std::fs::metadata(&index_path)?.len(),
+                meta_bytes: std::fs::metadata(&meta_path)?.len(),
+            });
         }
 
-        Ok(())
+        self.save_url_map(path)?;
+        self.save_generation(path)?;
+
+        Ok(summary)
+    }
+
+    /// 世代番号の上限を保存 (index_dir 以外に保存する場合用)
+    pub fn save_generation(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let ceiling = match self.generation_ceiling.lock() {
+            Ok(c) => *c,
+            Err(poison) => *poison.into_inner(),
+        };
+        save_generation(path, &self.corpus_file, ceiling.max(self.generation.load(Ordering::SeqCst)), self.save_options)
     }
 
     /// 指定したシャードのみ上書き保存
@@ -495,32 +1820,42 @@ impl IndexPool {
     /// Ok((u64, u64)) or Err
     /// u64: vectorizer size, u64: meta size
     pub fn save_shard(&self, shard_id: usize, path: &str) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+        let _guard = self.save_lock.lock().unwrap_or_else(|poison| poison.into_inner());
         std::fs::create_dir_all(path)?;
 
-        // Save corpus
-        let corpus_path = std::path::Path::new(path).join("global.corpus");
-        let corpus_file = std::fs::File::create(&corpus_path)?;
-        let mut corpus_writer = std::io::BufWriter::new(corpus_file);
-        bincode::serialize_into(&mut corpus_writer, &*self.corpus)?;
+        // 語彙が増えていなければコーパスは書き直さない
+        // (既存の語の文書頻度のずれは次の全体保存か prune_corpus で直る)
+        if self.corpus_dirty.swap(false, Ordering::SeqCst) {
+            self.save_corpus(path)?;
+        }
 
         // Save specified index and meta
-        if let Some(entry) = self.indexes.get(shard_id) {
+        if let Some(entry) = self.shard(shard_id) {
             let index = entry.read().map_err(|e| {
                 log::error!("Failed to acquire read lock for index: {}", e);
-                Box::new(Error::new(std::io::ErrorKind::Other, "RwLock poisoned"))
+                Box::new(Error::other("RwLock poisoned"))
             })?;
 
+            let shard_dir = self.layout.shard_dir(path, index.id);
+            std::fs::create_dir_all(&shard_dir)?;
+
             // Save vectorizer
-            let index_path = std::path::Path::new(path).join(format!("{}.index", index.id));
-            let index_file = std::fs::File::create(&index_path)?;
-            let mut index_writer = std::io::BufWriter::new(index_file);
-            bincode::serialize_into(&mut index_writer, &index.vectorizer)?;
+            let index_path = std::path::Path::new(&shard_dir).join(format!("{}.index", index.id));
+            write_bincode(&index_path, &index.vectorizer, self.save_options)?;
 
             // Save metadata
-            let meta_path = std::path::Path::new(path).join(format!("{}.meta", index.id));
-            let meta_file = std::fs::File::create(&meta_path)?;
-            let mut meta_writer = std::io::BufWriter::new(meta_file);
-            bincode::serialize_into(&mut meta_writer, &index.meta)?;
+            let meta_path = std::path::Path::new(&shard_dir).join(format!("{}.meta", index.id));
+            write_meta(&meta_path, &index.meta, self.save_options)?;
+
+            // Save shard state
+            ShardState::from_index(&index).save(&shard_dir, index.id, self.save_options)?;
+            if self.trigram_index {
+                index.trigrams.save(&shard_dir, index.id, self.save_options)?;
+            }
+            drop(index);
+
+            // Save url map
+            self.save_url_map(path)?;
 
             // Get file sizes
             let vectorizer_bin_size = std::fs::metadata(&index_path)?.len();
@@ -528,204 +1863,3598 @@ impl IndexPool {
 
             Ok((vectorizer_bin_size, meta_bin_size))
         } else {
-            return Err(Box::new(Error::new(std::io::ErrorKind::NotFound, "Index shard not found")));
+            Err(Box::new(Error::new(std::io::ErrorKind::NotFound, "Index shard not found")))
         }
     }
 
     pub fn calculate_shard_size(&self, shard_id: usize) -> Result<(u64, u64), Box<dyn std::error::Error>> {
         // Just calculate the binary size of the specified shard
-        if let Some(entry) = self.indexes.get(shard_id) {
+        if let Some(entry) = self.shard(shard_id) {
             let index = entry.read().map_err(|e| {
                 log::error!("Failed to acquire read lock for index: {}", e);
-                Box::new(Error::new(std::io::ErrorKind::Other, "RwLock poisoned"))
+                Box::new(Error::other("RwLock poisoned"))
             })?;
 
             let vectorizer_bin_size = bincode::serialized_size(&index.vectorizer)?;
-            let meta_bin_size = bincode::serialized_size(&index.meta)?;
+            let meta_bin_size = match self.save_options.meta_format {
+                MetaFormat::Bincode => bincode::serialized_size(&index.meta)?,
+                MetaFormat::Json => serde_json::to_vec_pretty(&index.meta)?.len() as u64,
+            };
 
             Ok((vectorizer_bin_size, meta_bin_size))
         } else {
-            return Err(Box::new(Error::new(std::io::ErrorKind::NotFound, "Index shard not found")));
+            Err(Box::new(Error::new(std::io::ErrorKind::NotFound, "Index shard not found")))
         }
     }
 }
 
-
-pub struct Index {
-    pub id: usize,
-    /// TF-IDF Vectorizer
-    /// u16: token ID
-    /// usize: document ID (= index in meta)
-    pub vectorizer: TFIDFVectorizer<u16, usize>,
-    /// Metadata for each document
-    /// The index in this vector corresponds to the document ID in the vectorizer
-    pub meta: Vec<IndexMeta>,
-    pub update_count: usize,
-    pub vectorizer_bin_size: u64,
-    pub meta_bin_size: u64,
+/// SCORE_TOP_K 有効時にシャードごとの上位 K 件をまとめる方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScoreMerge {
+    /// 全シャードの上位 K 件を結合して並べ直す (最大 シャード数 × K 件)
+    #[default]
+    Flat,
+    /// スコア順の各シャードの上位 K 件を k-way マージし、全体の上位 K 件だけ残す
+    KWay,
 }
 
-impl Index {
-    pub fn new(id: usize, corpus: Arc<Corpus>) -> Self {
-        Self {
-            id,
-            vectorizer: TFIDFVectorizer::<u16, usize>::new(corpus),
-            meta: Vec::new(),
-            update_count: 0,
-            vectorizer_bin_size: 0,
-            meta_bin_size: 0,
+impl ScoreMerge {
+    /// "flat" / "kway" (大文字小文字を区別しない)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "flat" => Some(Self::Flat),
+            "kway" | "k-way" => Some(Self::KWay),
+            _ => None,
         }
     }
+}
 
-    pub fn with_vectorizer(id: usize, vectorizer: TFIDFVectorizer<u16, usize>, meta: Vec<IndexMeta>, vectorizer_bin_size: u64, meta_bin_size: u64) -> Self {
-        Self {
-            id,
-            vectorizer,
-            meta,
-            update_count: 0,
-            vectorizer_bin_size,
-            meta_bin_size,
-        }
+/// IndexMeta::date で使う日付
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateField {
+    Published,
+    Modified,
+}
+
+/// generate_results_with のオプション
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResultOptions<'a> {
+    /// meta を引けなかった結果の分を range の後ろから補う
+    pub backfill: bool,
+    /// descriptions / favicon を複製しない (lite=true)
+    pub lite: bool,
+    /// URL にいずれかを含む結果を除外する (小文字にしたもの)
+    pub exclude_urls: &'a [String],
+    /// 生成する結果の上限 (range の幅をこれに切り詰める、None なら range のまま)
+    pub max_results: Option<usize>,
+    /// URL のホストごとにまとめ、同じホストの残りを others に入れる (range はまとめた後の結果に対して)
+    pub collapse_by_host: bool,
+    /// 検索のリクエストの読み方 (None なら SearchReads::default、ロックを待つ)
+    pub reads: Option<&'a SearchReads>,
+}
+
+/// IndexPool::for_each_shard_keys に渡すシャードの read lock の取り方 (try_read_for_search / read_for_results)
+type ShardRead = for<'a> fn(&IndexPool, usize, &'a RwLock<Index>, &SearchReads) -> Option<std::sync::RwLockReadGuard<'a, Index>>;
+
+/// 検索 1 回ぶんのシャードの read lock の取り方 (IndexPool::search_reads で作る)
+/// SEARCH_LOCK_TIMEOUT_MS の期限はリクエストに一つで、スコア計算の後の読み取りも同じ期限で諦める
+/// Default は期限なし (スコア計算は待たずに飛ばし、その後は待つ)
+#[derive(Debug, Default)]
+pub struct SearchReads {
+    deadline: Option<Instant>,
+    /// 書き込み中 (またはロックが壊れていて) 読めなかったシャード
+    skipped: Mutex<Vec<usize>>,
+}
+
+impl SearchReads {
+    fn skip(&self, shard_id: usize) {
+        self.skipped.lock().unwrap_or_else(|poison| poison.into_inner()).push(shard_id);
     }
 
-    pub fn meta_from_url(&self, url: &str) -> Option<&IndexMeta> {
-        self.meta.iter().find(|m| m.url.as_ref() == url)
+    /// ここまでに読めなかったシャード (読んだ順、重複しうる)
+    pub fn skipped(&self) -> Vec<usize> {
+        self.skipped.lock().unwrap_or_else(|poison| poison.into_inner()).clone()
     }
+}
 
-    /// idからメタを取得
-    /// indexで取得してでなければiter rev で探索
-    pub fn meta_from_id(&self, id: usize) -> Option<&IndexMeta> {
-        if id > self.meta.len() {
-            return None;
-        }
+/// collapse_by_host で代表の others に入れる件数の上限
+pub const MAX_COLLAPSED_OTHERS: usize = 3;
+
+/// meta とスコアから結果の 1 件を作る
+/// lite なら descriptions (空) と favicon (None) を meta から複製しない
+fn res_entry(meta: &IndexMeta, scored: &ScoredEntry, lite: bool) -> ResEntry {
+    ResEntry {
+        url: meta.url.clone(),
+        title: meta.title.clone(),
+        favicon: if lite { None } else { meta.favicon.clone() },
+        tags: meta.tags.tags(),
+        descriptions: if lite { Box::default() } else { meta.description.clone() },
+        score: scored.score,
+        point: meta.points,
+        length: scored.length,
+        id: scored.key,
+        index_id: scored.index_id,
+        time: meta.time,
+        author: meta.author.clone(),
+        published: meta.published,
+        modified: meta.modified,
+        matched_terms: None,
+        term_freq: None,
+        highlights: None,
+        snippets: None,
+        raw_score: None,
+        matched_title: None,
+        matched_desc: None,
+        others: None,
+    }
+}
 
+/// URL のホスト (小文字、ユーザ情報とポートを除く) collapse_by_host のまとめ方
+/// スキームがなければ先頭からホストとみなす
+pub fn url_host(url: &str) -> String {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = match host.strip_prefix('[') {
+        // IPv6 ([::1]:8080)
+        Some(v6) => v6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    host.to_ascii_lowercase()
+}
 
-        // `id` 以降の要素は存在しないため、`id` の位置から逆方向に探索
-        let skip_count = self.meta.len().saturating_sub(id + 1);
-        self.meta.iter().rev().skip(skip_count).find(|m| m.id == id)
+/// url (大文字小文字を区別しない) に patterns (小文字) のいずれかが含まれるか
+pub fn url_excluded(url: &str, patterns: &[String]) -> bool {
+    if patterns.is_empty() {
+        return false;
     }
+    let url = url.to_lowercase();
+    patterns.iter().any(|p| url.contains(p.as_str()))
+}
 
-    pub fn meta_from_id_mut(&mut self, id: usize) -> Option<&mut IndexMeta> {
-        if id > self.meta.len() {
-            return None;
+/// 新規ドキュメントを入れるシャード
+/// sizes: (シャード ID, サイズ) のうち limit 未満で最小のもの (同じサイズなら後ろ)、全て limit 以上なら None
+pub fn choose_shard(sizes: &[(usize, u64)], limit: u64) -> Option<usize> {
+    sizes.iter().rev()
+        .filter(|(_, size)| *size < limit)
+        .min_by_key(|(_, size)| *size)
+        .map(|(id, _)| *id)
+}
+
+/// スコア順 (降順) のリストを k-way マージして上位 k 件を返す
+/// 各リストの先頭だけをヒープに入れ、k 件取り出したら残りは見ない
+pub fn merge_top_k(lists: Vec<Vec<ScoredEntry>>, k: usize) -> Vec<ScoredEntry> {
+    let mut iters: Vec<std::vec::IntoIter<ScoredEntry>> = lists.into_iter().map(|l| l.into_iter()).collect();
+    let mut heap: BinaryHeap<(HeapEntry, Reverse<usize>)> = BinaryHeap::with_capacity(iters.len());
+    for (i, iter) in iters.iter_mut().enumerate() {
+        if let Some(e) = iter.next() {
+            heap.push((HeapEntry(e), Reverse(i)));
+        }
+    }
+    let mut merged = Vec::with_capacity(k.min(heap.len() * k));
+    while merged.len() < k {
+        let Some((HeapEntry(e), Reverse(i))) = heap.pop() else { break; };
+        merged.push(e);
+        if let Some(next) = iters[i].next() {
+            heap.push((HeapEntry(next), Reverse(i)));
         }
+    }
+    merged
+}
 
-        // `id` 以降の要素は存在しないため、`id` の位置から逆方向に探索
-        let skip_count = self.meta.len().saturating_sub(id + 1);
-        self.meta.iter_mut().rev().skip(skip_count).find(|m| m.id == id)
+/// top-K ヒープ用の ScoredEntry
+/// スコアで比較 (NaN は最小)、同点は key / index_id の小さい方を上位とする
+struct HeapEntry(ScoredEntry);
+
+impl HeapEntry {
+    fn cmp_key(&self) -> (f64, Reverse<usize>, Reverse<usize>) {
+        let score = if self.0.score.is_nan() { f64::NEG_INFINITY } else { self.0.score };
+        (score, Reverse(self.0.index_id), Reverse(self.0.key))
     }
+}
 
-    pub fn generate_next_id(&self) -> usize {
-        self.meta.last().and_then(|m| Some(m.id + 1)).unwrap_or(0)
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
     }
 }
 
-/// Index の基本情報
-/// URL, title, description, favicon, time, points, tags
-/// Hash と Equal は URL のみで判定
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct IndexMeta {
-    pub id: usize,
-    /// URL
-    /// only URL is used for Hash and Equal
-    pub url: Box<str>,
-    /// Title
-    pub title: Box<str>,
-    /// Description
-    pub description: Box<str>,
-    /// Favicon URL
-    pub favicon: Option<Box<str>>,
-    /// Upload Time
-    pub time: DateTime<Utc>,
-    /// Score
-    pub points: f64,
-    /// Tags
-    /// General Tag:
-    /// - Wiki: wikipedia, ニコニコ大百科, etc
-    /// - News: yahoo!, GIGAZINE, ITmedia, etc
-    /// - SNS: twitter, facebook, youtube, instagram, etc
-    /// - Blog: hatena, zenn, etc
-    /// - Forum: 5ch, reddit, stackoverflow, etc
-    /// - Shopping: amazon, rakuten, ebay, etc
-    /// - Academic: arxiv, ciNii, etc
-    /// - Tools: translate, map, etc
-    pub tags: Tags,
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct Tags(u64);
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        let (a, a_index, a_key) = self.cmp_key();
+        let (b, b_index, b_key) = other.cmp_key();
+        a.total_cmp(&b).then(a_index.cmp(&b_index)).then(a_key.cmp(&b_key))
+    }
+}
 
-impl Tags {
-    pub const WIKI: u64 = 1 << 0;
-    pub const NEWS: u64 = 1 << 1;
-    pub const SNS: u64 = 1 << 2;
-    pub const BLOG: u64 = 1 << 3;
-    pub const FORUM: u64 = 1 << 4;
-    pub const SHOPPING: u64 = 1 << 5;
-    pub const ACADEMIC: u64 = 1 << 6;
-    pub const TOOLS: u64 = 1 << 7;
+/// prune_corpus の結果
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PruneStats {
+    /// 削除した語の数
+    pub removed: usize,
+    /// 文書頻度を修正した語の数
+    pub corrected: usize,
+    /// 整理後の語彙数
+    pub vocab_size: usize,
+}
 
-    /// eg: Tags::new(Tags::NEWS | Tags::BLOG)
-    pub fn new(set: u64) -> Self {
-        Self(set)
+/// clear の結果
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClearSummary {
+    /// 消したドキュメント数
+    pub removed: usize,
+    /// 削除したファイル数
+    pub deleted_files: usize,
+}
+
+/// split_shard の結果
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SplitSummary {
+    /// 分けたシャード
+    pub source: usize,
+    /// ドキュメントを移した先のシャード
+    pub target: usize,
+    /// 移したドキュメント数
+    pub moved: usize,
+    /// 分けた後の各シャードのドキュメント数
+    pub source_documents: usize,
+    pub target_documents: usize,
+    /// 保存に失敗したシャード (分割はメモリ上で済んでいる)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// split_shard できない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitShardError {
+    /// シャードが存在しない
+    NotFound,
+    /// ドキュメントが 2 件未満
+    TooFewDocuments,
+    /// 上限に達していない移す先のシャードがない
+    NoTarget,
+}
+
+impl std::fmt::Display for SplitShardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Shard not found"),
+            Self::TooFewDocuments => write!(f, "Shard has fewer than 2 documents"),
+            Self::NoTarget => write!(f, "No other shard is below the size limit"),
+        }
     }
+}
 
-    /// すべて満たしてるか
-    /// tagはselfに含まれている必要がある
-    /// eg: is_filter_contains(Tags::NEWS | Tags::BLOG) -> NEWSとBLOGの両方を含む場合のみtrue
-    pub fn is_filter_contains<T: Into<u64> + Copy>(&self, tag: T) -> bool {
-        (self.0 & tag.into()) == tag.into()
+impl std::error::Error for SplitShardError {}
+
+/// ディレクトリに .corpus が複数あり、どれを読むか決められない
+#[derive(Debug)]
+pub struct MultipleCorpusError {
+    pub files: Vec<std::path::PathBuf>,
+}
+
+impl std::fmt::Display for MultipleCorpusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Multiple corpus files found ({:?}); set CORPUS_FILE to choose one", self.files)
     }
+}
 
-    pub fn contains<T: Into<u64>>(&self, tag: T) -> bool {
-        (self.0 & tag.into()) != 0
+impl std::error::Error for MultipleCorpusError {}
+
+/// CORPUS_FILE で指定したコーパスが読めない (ないか壊れている)
+#[derive(Debug)]
+pub struct CorpusFileError {
+    pub file: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for CorpusFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to load CORPUS_FILE {}: {}", self.file, self.reason)
     }
+}
 
-    pub fn is_empty(&self) -> bool {
-        self.0 == 0
+impl std::error::Error for CorpusFileError {}
+
+/// ディレクトリに既存のインデックス (.corpus やシャードのファイル) があるか
+fn has_index_files(path: &str) -> bool {
+    let Ok(entries) = std::fs::read_dir(path) else { return false; };
+    entries.flatten().any(|entry| {
+        let p = entry.path();
+        p.is_dir() || matches!(p.extension().and_then(|e| e.to_str()), Some("corpus" | "index" | "meta"))
+    })
+}
+
+/// 他の保存 (POST /save、自動保存、終了時の保存) が実行中
+#[derive(Debug)]
+pub struct SaveInProgressError;
+
+impl std::fmt::Display for SaveInProgressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A save is already in progress")
+    }
+}
+
+impl std::error::Error for SaveInProgressError {}
+
+/// 保存ファイルの先頭のマジック (この後に u32 LE のスキーマバージョンが続く)
+pub const FILE_MAGIC: &[u8; 4] = b"WKIX";
+/// 保存ファイルのスキーマバージョン
+/// IndexMeta や vectorizer などの形式を変えたら上げて、旧バージョンの読み込み (移行) を用意する
+/// 1: ヘッダ導入時 (IndexMetaV2)
+/// 2: IndexMeta.aliases (IndexMetaV3)
+/// 3: IndexMeta.lang (IndexMetaV4)
+/// 4: IndexMeta.modified
+pub const SCHEMA_VERSION: u32 = 4;
+/// ヘッダのないファイル (ヘッダ導入前) のバージョン
+pub const HEADERLESS_SCHEMA_VERSION: u32 = 0;
+
+/// 保存ファイルのスキーマバージョンがこのバイナリと違う
+#[derive(Debug)]
+pub struct SchemaVersionError {
+    pub path: std::path::PathBuf,
+    pub found: u32,
+    pub supported: u32,
+}
+
+impl std::fmt::Display for SchemaVersionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: index schema v{}, binary supports v{}; run migration", self.path, self.found, self.supported)
     }
+}
 
-    pub fn tags(&self) -> Vec<Box<str>> {
-        let mut result = Vec::new();
-        if self.contains(Self::WIKI) { result.push("WIKI".into()); }
-        if self.contains(Self::NEWS) { result.push("NEWS".into()); }
-        if self.contains(Self::SNS) { result.push("SNS".into()); }
-        if self.contains(Self::BLOG) { result.push("BLOG".into()); }
-        if self.contains(Self::FORUM) { result.push("FORUM".into()); }
-        if self.contains(Self::SHOPPING) { result.push("SHOPPING".into()); }
-        if self.contains(Self::ACADEMIC) { result.push("ACADEMIC".into()); }
-        if self.contains(Self::TOOLS) { result.push("TOOLS".into()); }
-        result
+impl std::error::Error for SchemaVersionError {}
+
+/// ヘッダ (FILE_MAGIC + バージョン) を外してバージョンと本体を返す
+/// ヘッダがなければヘッダ導入前のファイルとして HEADERLESS_SCHEMA_VERSION とそのままのデータを返す
+/// 1..=SCHEMA_VERSION 以外のバージョンは SchemaVersionError
+pub fn strip_header<'a>(path: &std::path::Path, data: &'a [u8]) -> Result<(u32, &'a [u8]), SchemaVersionError> {
+    let Some(rest) = data.strip_prefix(FILE_MAGIC.as_slice()) else { return Ok((HEADERLESS_SCHEMA_VERSION, data)); };
+    let (version, body) = match rest.split_first_chunk::<4>() {
+        Some((version, body)) => (u32::from_le_bytes(*version), body),
+        // マジックの後にバージョンがない = ヘッダではない
+        None => return Ok((HEADERLESS_SCHEMA_VERSION, data)),
+    };
+    if !(1..=SCHEMA_VERSION).contains(&version) {
+        return Err(SchemaVersionError { path: path.to_path_buf(), found: version, supported: SCHEMA_VERSION });
     }
+    Ok((version, body))
+}
 
-    pub fn from_strs<T>(tags: &[T]) -> Self
-    where
-        T: AsRef<str>,
+/// ヘッダ付きの bincode ファイルを読む (補助ファイル用、失敗はメッセージにして返す)
+/// 補助ファイルの形式はバージョン間で変わっていないのでバージョンは見ない
+pub(crate) fn read_bincode<T: serde::de::DeserializeOwned>(path: &std::path::Path, data: &[u8]) -> Result<T, String> {
+    let (_, body) = strip_header(path, data).map_err(|e| e.to_string())?;
+    bincode::deserialize(body).map_err(|e| e.to_string())
+}
+
+/// 世代番号のファイル (コーパスの隣)
+fn generation_path(path: &str, corpus_file: &str) -> std::path::PathBuf {
+    std::path::Path::new(path).join(format!("{}.generation", corpus_file))
+}
+
+fn save_generation(path: &str, corpus_file: &str, generation: u64, options: SaveOptions) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(path)?;
+    write_bincode(&generation_path(path, corpus_file), &generation, options)
+}
+
+/// ファイル保存の設定
+#[derive(Debug, Clone, Copy)]
+pub struct SaveOptions {
+    /// 書き込み後に fsync する (電源断でも保存済みのデータが残る代わりに保存が遅くなる)
+    pub durable: bool,
+    /// BufWriter の容量
+    pub buffer_capacity: usize,
+    /// .meta の形式
+    pub meta_format: MetaFormat,
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        Self { durable: false, buffer_capacity: DEFAULT_SAVE_BUFFER_BYTES, meta_format: MetaFormat::Bincode }
+    }
+}
+
+/// .meta の保存形式
+/// 読み込み時は中身で判別するので、切り替えても既存のファイルはそのまま読める
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaFormat {
+    #[default]
+    Bincode,
+    /// 整形した JSON (運用者が中身を確認・手で修正できる)
+    Json,
+}
+
+impl MetaFormat {
+    /// "bincode" / "json" (大文字小文字を区別しない)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "bincode" => Some(Self::Bincode),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// fsync できる書き込み先
+pub trait SyncWrite: Write {
+    fn sync_all(&self) -> std::io::Result<()>;
+}
+
+impl SyncWrite for std::fs::File {
+    fn sync_all(&self) -> std::io::Result<()> {
+        std::fs::File::sync_all(self)
+    }
+}
+
+/// ヘッダを付けて bincode で書き出して flush し、durable なら sync_all する
+fn write_bincode_to<W: SyncWrite, T: Serialize + ?Sized>(inner: W, value: &T, options: SaveOptions) -> Result<W, Box<dyn std::error::Error>> {
+    write_with(inner, options, |w| {
+        w.write_all(FILE_MAGIC)?;
+        w.write_all(&SCHEMA_VERSION.to_le_bytes())?;
+        Ok(bincode::serialize_into(w, value)?)
+    })
+}
+
+/// write で書き出して flush し、durable なら sync_all する
+/// BufWriter の drop に任せると flush のエラーが捨てられるので明示的に取り出す
+fn write_with<W, F>(inner: W, options: SaveOptions, write: F) -> Result<W, Box<dyn std::error::Error>>
+where
+    W: SyncWrite,
+    F: FnOnce(&mut std::io::BufWriter<W>) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let mut writer = std::io::BufWriter::with_capacity(options.buffer_capacity, inner);
+    write(&mut writer)?;
+    let inner = writer.into_inner().map_err(|e| e.into_error())?;
+    if options.durable {
+        inner.sync_all()?;
+    }
+    Ok(inner)
+}
+
+/// path に bincode で保存
+pub(crate) fn write_bincode<T: Serialize + ?Sized>(path: &std::path::Path, value: &T, options: SaveOptions) -> Result<(), Box<dyn std::error::Error>> {
+    replace_file(path, options, |file| write_bincode_to(file, value, options))
+}
+
+/// {path}.tmp に書いてから rename で置き換える
+/// 書き込み中に落ちても元のファイルは壊れない
+/// durable なら rename も残るように親ディレクトリを sync する
+fn replace_file<F>(path: &std::path::Path, options: SaveOptions, write: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(std::fs::File) -> Result<std::fs::File, Box<dyn std::error::Error>>,
+{
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = std::path::PathBuf::from(tmp_path);
+    let written = std::fs::File::create(&tmp_path)
+        .map_err(|e| -> Box<dyn std::error::Error> { Box::new(e) })
+        .and_then(write);
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    std::fs::rename(&tmp_path, path)?;
+    if options.durable {
+        sync_parent_dir(path)?;
+    }
+    Ok(())
+}
+
+/// ディレクトリエントリの変更 (rename) をディスクに書き出す
+/// Windows ではディレクトリを開けないので何もしない
+fn sync_parent_dir(path: &std::path::Path) -> std::io::Result<()> {
+    #[cfg(unix)]
     {
-        let mut set = 0;
-        for tag in tags {
-            let s = tag.as_ref();
-            if s.eq_ignore_ascii_case("wiki")      { set |= Self::WIKI; }
-            else if s.eq_ignore_ascii_case("news") { set |= Self::NEWS; }
-            else if s.eq_ignore_ascii_case("sns")  { set |= Self::SNS; }
-            else if s.eq_ignore_ascii_case("blog") { set |= Self::BLOG; }
-            else if s.eq_ignore_ascii_case("forum"){ set |= Self::FORUM; }
-            else if s.eq_ignore_ascii_case("shopping"){ set |= Self::SHOPPING; }
-            else if s.eq_ignore_ascii_case("academic"){ set |= Self::ACADEMIC; }
-            else if s.eq_ignore_ascii_case("tools"){ set |= Self::TOOLS; }
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => std::path::Path::new("."),
+        };
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// .meta を options.meta_format で保存 (JSON にはヘッダを付けない)
+fn write_meta(path: &std::path::Path, meta: &[IndexMeta], options: SaveOptions) -> Result<(), Box<dyn std::error::Error>> {
+    match options.meta_format {
+        MetaFormat::Bincode => write_bincode(path, meta, options),
+        MetaFormat::Json => {
+            replace_file(path, options, |file| write_with(file, options, |w| Ok(serde_json::to_writer_pretty(w, meta)?)))
         }
-        Self(set)
     }
 }
 
-impl PartialEq for IndexMeta {
-    fn eq(&self, other: &Self) -> bool {
-        self.url == other.url
+/// 保存済みの世代番号 ファイルがない / 壊れている場合は 0
+fn load_generation(path: &str, corpus_file: &str) -> u64 {
+    let generation_path = generation_path(path, corpus_file);
+    match std::fs::read(&generation_path) {
+        Ok(data) => read_bincode(&generation_path, &data).unwrap_or_else(|e| {
+            log::warn!("Failed to deserialize generation {:?}: {}", generation_path, e);
+            0
+        }),
+        Err(_) => 0,
+    }
+}
+
+/// url_map のキー
+/// 永続化するので実行ごとに変わらない FNV-1a を使う
+pub fn url_hash(url: &str) -> u64 {
+    url.bytes().fold(0xcbf29ce484222325, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// meta の URL と別名を url_map から消す
+/// 別のドキュメントに付け替わっているキーは残す
+fn remove_url_keys(map: &mut HashMap<u64, (usize, usize)>, meta: &IndexMeta, entry: (usize, usize)) {
+    for u in meta.urls() {
+        let key = url_hash(u);
+        if map.get(&key) == Some(&entry) {
+            map.remove(&key);
+        }
+    }
+}
+
+/// meta の URL と別名を url_map に entry として登録する
+/// 別のドキュメントが使っている別名は奪わずに警告して meta から外す
+fn insert_url_keys(map: &mut HashMap<u64, (usize, usize)>, meta: &mut IndexMeta, entry: (usize, usize)) {
+    map.insert(url_hash(&meta.url), entry);
+    meta.aliases.retain(|alias| {
+        let key = url_hash(alias);
+        match map.get(&key) {
+            Some(&owner) if owner != entry => {
+                warn!("Alias {} of {} is already used by document {:?}, ignoring it", alias, meta.url, owner);
+                false
+            }
+            _ => {
+                map.insert(key, entry);
+                true
+            }
+        }
+    });
+}
+
+/// check_meta_ids の結果
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetaIdCheck {
+    /// ID の昇順に並べ直した
+    pub reordered: bool,
+    /// 同じ ID で除いた meta の数
+    pub duplicates: usize,
+    /// 0..=最大 ID のうち meta がない ID の数 (DEL_REMOVES_META での削除の跡なら正常)
+    pub gaps: usize,
+}
+
+impl MetaIdCheck {
+    /// meta を変更したか
+    pub fn repaired(&self) -> bool {
+        self.reordered || self.duplicates > 0
+    }
+}
+
+/// 読み込んだ meta の ID を確認して meta_from_id の前提 (昇順・重複なし) にそろえる
+/// 壊れた .meta でも panic や別のドキュメントへの解決をしないように
+/// 重複は後ろ (後から書かれた方) を残す
+pub fn check_meta_ids(meta: &mut Vec<IndexMeta>, shard_id: usize) -> MetaIdCheck {
+    let mut check = MetaIdCheck {
+        reordered: !meta.windows(2).all(|w| w[0].id <= w[1].id),
+        ..Default::default()
+    };
+    if check.reordered {
+        // 安定ソートなので同じ ID の中ではファイルの順のまま
+        meta.sort_by_key(|m| m.id);
+    }
+    let mut deduped: Vec<IndexMeta> = Vec::with_capacity(meta.len());
+    for m in meta.drain(..) {
+        match deduped.last_mut() {
+            Some(last) if last.id == m.id => {
+                check.duplicates += 1;
+                *last = m;
+            }
+            _ => deduped.push(m),
+        }
+    }
+    *meta = deduped;
+    check.gaps = meta.last().map(|m| m.id + 1 - meta.len()).unwrap_or(0);
+    if check.repaired() {
+        log::warn!("Meta of index id {} has broken ids (reordered: {}, duplicates dropped: {})", shard_id, check.reordered, check.duplicates);
+    }
+    if check.gaps > 0 {
+        log::debug!("Meta of index id {} has {} missing ids", shard_id, check.gaps);
+    }
+    check
+}
+
+/// シャードの実データから語ごとの文書頻度を数える (コーパスのあるべき値)
+fn count_doc_freq<'a>(shards: impl Iterator<Item = &'a Index>) -> HashMap<&'a str, u64> {
+    let mut doc_freq: HashMap<&str, u64> = HashMap::new();
+    for idx in shards {
+        for doc in &idx.vectorizer.documents {
+            for (dim, _) in doc.tf_vec.raw_iter() {
+                if let Some(token) = idx.vectorizer.token_dim_sample.get_index(dim) {
+                    *doc_freq.entry(token.as_ref()).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    doc_freq
+}
+
+/// シャードの実データから作り直したコーパス (ドキュメントごとに add_set する)
+fn corpus_from_shards<'a>(shards: impl Iterator<Item = &'a Index>) -> Corpus {
+    let corpus = Corpus::new();
+    for idx in shards {
+        for doc in &idx.vectorizer.documents {
+            let tokens: Vec<&str> = doc.tf_vec.raw_iter()
+                .filter_map(|(dim, _)| idx.vectorizer.token_dim_sample.get_index(dim).map(|t| t.as_ref()))
+                .collect();
+            corpus.add_set(&tokens);
+        }
+    }
+    corpus
+}
+
+/// url_map を作り直す
+/// del_document は meta を残すので vectorizer に存在するものだけ採用 (後の meta 優先)
+/// 別名も URL と同じドキュメントを指すキーとして入れる
+pub fn build_url_map(indexes: &[Arc<RwLock<Index>>]) -> HashMap<u64, (usize, usize)> {
+    let mut map = HashMap::new();
+    for index in indexes {
+        let idx = match index.read() {
+            Ok(idx) => idx,
+            Err(_poison) => {
+                warn!("RwLock poisoned, skipping");
+                continue;
+            }
+        };
+        for m in idx.meta.iter().filter(|m| idx.vectorizer.contains_doc(&m.id)) {
+            for u in m.urls() {
+                map.insert(url_hash(u), (idx.id, m.id));
+            }
+        }
+    }
+    map
+}
+
+/// 保存済みの url_map が meta と一致するか
+/// 全てのキーが登録中のドキュメントの URL / 別名を指していて、件数が URL + 別名の数と同じなら一致
+/// (キーが重複しないので、全ての URL / 別名がちょうど一度ずつ入っている)
+/// ロックが壊れたシャードがあれば一致しないとみなす
+fn url_map_matches(map: &HashMap<u64, (usize, usize)>, indexes: &[Arc<RwLock<Index>>]) -> bool {
+    let Ok(guards) = indexes.iter().map(|index| index.read()).collect::<Result<Vec<_>, _>>() else { return false; };
+    let live: Vec<HashSet<usize>> = guards.iter()
+        .map(|idx| idx.vectorizer.documents.iter().map(|doc| doc.key).collect())
+        .collect();
+    let expected: usize = guards.iter().zip(&live)
+        .map(|(idx, live)| idx.meta.iter().filter(|m| live.contains(&m.id)).map(|m| 1 + m.aliases.len()).sum::<usize>())
+        .sum();
+    if map.len() != expected {
+        return false;
+    }
+    map.iter().all(|(&key, &(shard_id, doc_id))| {
+        let Some(idx) = guards.get(shard_id) else { return false; };
+        live[shard_id].contains(&doc_id)
+            && idx.meta_from_id(doc_id).is_some_and(|m| m.urls().any(|u| url_hash(u) == key))
+    })
+}
+
+/// 保存済み url_map を読み込む
+/// ファイルがない / 壊れている / meta と一致しない場合は meta から再構築
+fn load_url_map(path: &str, indexes: &[Arc<RwLock<Index>>]) -> HashMap<u64, (usize, usize)> {
+    let map_path = std::path::Path::new(path).join(URL_MAP_FILE);
+    let loaded = std::fs::read(&map_path).ok()
+        .and_then(|data| match read_bincode::<HashMap<u64, (usize, usize)>>(&map_path, &data) {
+            Ok(map) => Some(map),
+            Err(e) => {
+                log::warn!("Failed to deserialize url map {:?}: {}", map_path, e);
+                None
+            }
+        });
+    match loaded {
+        Some(map) if url_map_matches(&map, indexes) => map,
+        Some(_) => {
+            log::warn!("Url map {:?} does not match meta, rebuilding", map_path);
+            build_url_map(indexes)
+        }
+        None => {
+            log::info!("Rebuilding url map from meta");
+            build_url_map(indexes)
+        }
+    }
+}
+
+/// スコアの (最小, 最大) (非有限値は除く、なければ (0, 0))
+pub fn score_bounds(scores: impl Iterator<Item = f64>) -> (f64, f64) {
+    let (min, max) = scores
+        .filter(|s| s.is_finite())
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), s| (min.min(s), max.max(s)));
+    if min.is_finite() { (min, max) } else { (0.0, 0.0) }
+}
+
+/// score を (最小, 最大) で [0, 1] に min-max 正規化
+/// 非有限値は 0、全て同じスコア (1 件だけの場合も) なら 1
+pub fn min_max_normalize(score: f64, (min, max): (f64, f64)) -> f64 {
+    if !score.is_finite() {
+        0.0
+    } else if max > min {
+        ((score - min) / (max - min)).clamp(0.0, 1.0)
+    } else {
+        1.0
+    }
+}
+
+/// スコアを min-max 正規化 ([0,1])
+fn normalize_scores(entries: &mut [ScoredEntry]) {
+    let bounds = score_bounds(entries.iter().map(|e| e.score));
+    for e in entries.iter_mut() {
+        e.score = min_max_normalize(e.score, bounds);
+    }
+}
+
+
+pub struct Index {
+    pub id: usize,
+    /// TF-IDF Vectorizer
+    /// u16: token ID
+    /// usize: document ID (= index in meta)
+    pub vectorizer: TFIDFVectorizer<u16, usize>,
+    /// Metadata for each document
+    /// The index in this vector corresponds to the document ID in the vectorizer
+    pub meta: Vec<IndexMeta>,
+    pub update_count: usize,
+    pub vectorizer_bin_size: u64,
+    pub meta_bin_size: u64,
+    /// 文字 3-gram インデックス (IndexPool::trigram_index 有効時のみ作る)
+    pub trigrams: TrigramIndex,
+}
+
+/// rebuild_shards の複製用
+/// TFIDFVectorizer は DefaultTFIDFEngine が Clone でないので derive できず、pub なフィールドから作る
+impl Clone for Index {
+    fn clone(&self) -> Self {
+        let mut vectorizer = TFIDFVectorizer::new(Arc::clone(&self.vectorizer.corpus_ref));
+        vectorizer.documents = self.vectorizer.documents.clone();
+        vectorizer.token_dim_sample = self.vectorizer.token_dim_sample.clone();
+        vectorizer.idf = self.vectorizer.idf.clone();
+        Self {
+            id: self.id,
+            vectorizer,
+            meta: self.meta.clone(),
+            update_count: self.update_count,
+            vectorizer_bin_size: self.vectorizer_bin_size,
+            meta_bin_size: self.meta_bin_size,
+            trigrams: self.trigrams.clone(),
+        }
+    }
+}
+
+impl Index {
+    pub fn new(id: usize, corpus: Arc<Corpus>) -> Self {
+        Self {
+            id,
+            vectorizer: TFIDFVectorizer::<u16, usize>::new(corpus),
+            meta: Vec::new(),
+            update_count: 0,
+            vectorizer_bin_size: 0,
+            meta_bin_size: 0,
+            trigrams: TrigramIndex::default(),
+        }
+    }
+
+    pub fn with_vectorizer(id: usize, vectorizer: TFIDFVectorizer<u16, usize>, meta: Vec<IndexMeta>, vectorizer_bin_size: u64, meta_bin_size: u64) -> Self {
+        Self {
+            id,
+            vectorizer,
+            meta,
+            update_count: 0,
+            vectorizer_bin_size,
+            meta_bin_size,
+            trigrams: TrigramIndex::default(),
+        }
+    }
+
+    /// URL か別名でメタを取得
+    pub fn meta_from_url(&self, url: &str) -> Option<&IndexMeta> {
+        self.meta.iter().find(|m| m.matches_url(url))
+    }
+
+    /// ドキュメントのトークンごとの出現回数
+    /// TF ベクトルの次元をトークン文字列に戻し、fill_match_info と同じく token_sum を比で按分して回数を復元する
+    pub fn doc_token_counts(&self, doc_id: usize) -> Option<BTreeMap<Box<str>, u64>> {
+        let tf = self.vectorizer.get_tf(&doc_id)?;
+        let total_q: f64 = tf.tf_vec.raw_iter().map(|(_, q)| *q as f64).sum();
+        let counts = tf.tf_vec.raw_iter()
+            .filter_map(|(dim, q)| {
+                let token = self.vectorizer.token_dim_sample.get_index(dim)?;
+                let count = if total_q > 0.0 { (*q as f64 / total_q * tf.token_sum as f64).round() as u64 } else { 0 };
+                Some((token.as_ref().into(), count))
+            })
+            .collect();
+        Some(counts)
+    }
+
+    /// idからメタを取得
+    /// indexで取得してでなければ二分探索 (meta は ID の昇順・重複なし、読み込み時に check_meta_ids でそろえる)
+    pub fn meta_from_id(&self, id: usize) -> Option<&IndexMeta> {
+        self.meta_position(id).map(|i| &self.meta[i])
+    }
+
+    pub fn meta_from_id_mut(&mut self, id: usize) -> Option<&mut IndexMeta> {
+        self.meta_position(id).map(|i| &mut self.meta[i])
+    }
+
+    /// 削除の跡がなければ位置 = ID
+    /// 削除で meta を消していると位置が ID より前にずれるので二分探索する
+    fn meta_position(&self, id: usize) -> Option<usize> {
+        if self.meta.get(id).is_some_and(|m| m.id == id) {
+            return Some(id);
+        }
+        self.meta.binary_search_by_key(&id, |m| m.id).ok()
+    }
+
+    /// 更新回数を数え、この更新で保存 / バイナリサイズの再計算をするか返す
+    /// 増やしてから判定するので SAVE_FILE_INTERVAL 回目、2 倍回目… の更新で保存する
+    /// write lock の中で呼ぶので同時に更新しても同じ回数を二度数えない
+    pub fn record_update(&mut self) -> UpdateActions {
+        self.update_count += 1;
+        UpdateActions {
+            save: self.update_count.is_multiple_of(SAVE_FILE_INTERVAL),
+            calculate_size: self.update_count.is_multiple_of(CALCULATE_BIN_SIZE_INTERVAL),
+        }
+    }
+
+    pub fn generate_next_id(&self) -> usize {
+        self.meta.last().map(|m| m.id + 1).unwrap_or(0)
+    }
+
+    /// 最後に計算した .index / .meta のサイズの大きい方
+    pub fn bin_size(&self) -> u64 {
+        self.meta_bin_size.max(self.vectorizer_bin_size)
     }
 }
 
-impl Into<u64> for Tags {
-    fn into(self) -> u64 {
-        self.0
+/// スニペットの範囲 (文字単位) を選ぶ
+/// マッチごとにそれを中心とした長さ window の窓を作り、含むマッチの数が多い順に重ならないものを最大 count 個
+/// 位置順で返す マッチがなければ先頭の窓
+pub fn snippet_windows(text_len: usize, matches: &[Range<usize>], count: usize, window: usize) -> Vec<Range<usize>> {
+    let window = window.min(text_len);
+    if count == 0 || window == 0 {
+        return Vec::new();
+    }
+    if matches.is_empty() {
+        return std::iter::once(0..window).collect();
     }
-}
\ No newline at end of file
+    let mut candidates: Vec<(usize, Range<usize>)> = matches.iter()
+        .map(|m| {
+            let center = (m.start + m.end) / 2;
+            let start = center.saturating_sub(window / 2).min(text_len - window);
+            let range = start..start + window;
+            let density = matches.iter().filter(|o| o.start >= range.start && o.end <= range.end).count();
+            (density, range)
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.start.cmp(&b.1.start)));
+    let mut picked: Vec<Range<usize>> = Vec::with_capacity(count);
+    for (_, range) in candidates {
+        if picked.len() >= count {
+            break;
+        }
+        if picked.iter().all(|p| range.end <= p.start || p.end <= range.start) {
+            picked.push(range);
+        }
+    }
+    picked.sort_by_key(|r| r.start);
+    picked
+}
+
+/// IndexPool::save の結果
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SaveSummary {
+    /// 保存したシャード
+    pub saved: Vec<usize>,
+    /// ロックが poison されていて保存しなかったシャード
+    pub skipped: Vec<usize>,
+    /// 保存したシャードのファイルサイズ
+    pub sizes: Vec<ShardFileSizes>,
+}
+
+/// 保存後のシャードのファイルサイズ (バイト)
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ShardFileSizes {
+    pub id: usize,
+    pub index_bytes: u64,
+    pub meta_bytes: u64,
+}
+
+/// recalc_sizes で計算し直したシャードのバイナリサイズ (バイト)
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ShardBinSizes {
+    pub id: usize,
+    pub documents: usize,
+    pub vectorizer_bin_size: u64,
+    pub meta_bin_size: u64,
+    /// max_shard_bytes 以上 (新規ドキュメントを入れない)
+    pub over_limit: bool,
+}
+
+/// fsck の結果
+/// ok 以外は見つかった問題 (deleted_meta は削除の跡で問題ではない)
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct FsckReport {
+    /// 問題が見つからなかった
+    pub ok: bool,
+    /// 全シャードの登録中のドキュメント数
+    pub documents: usize,
+    /// 件数のカウンタ (documents と一致するはず)
+    pub counter: u64,
+    pub shards: Vec<ShardFsck>,
+    /// ロックが壊れていて調べられなかったシャード
+    pub skipped: Vec<usize>,
+    pub url_map: UrlMapFsck,
+    pub corpus: CorpusFsck,
+}
+
+/// fsck のシャードごとの結果
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct ShardFsck {
+    pub id: usize,
+    pub documents: usize,
+    pub meta: usize,
+    /// meta がない (検索結果に出せない) ドキュメント ID
+    pub docs_without_meta: Vec<usize>,
+    /// vectorizer に複数あるドキュメント ID
+    pub duplicate_doc_ids: Vec<usize>,
+    /// token_dim_sample にない次元を持つドキュメント ID
+    pub invalid_token_dims: Vec<usize>,
+    /// meta が ID の昇順でない
+    pub meta_unordered: bool,
+    /// meta に複数ある ID
+    pub duplicate_meta_ids: Vec<usize>,
+    /// vectorizer にない meta の数 (削除の跡)
+    pub deleted_meta: usize,
+}
+
+impl ShardFsck {
+    pub fn is_ok(&self) -> bool {
+        self.docs_without_meta.is_empty()
+            && self.duplicate_doc_ids.is_empty()
+            && self.invalid_token_dims.is_empty()
+            && !self.meta_unordered
+            && self.duplicate_meta_ids.is_empty()
+    }
+}
+
+/// fsck の url_map の結果
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct UrlMapFsck {
+    pub entries: usize,
+    /// 登録中のドキュメントの URL / 別名を指していないキーの数
+    pub dangling: usize,
+    /// url_map で引けない (別のドキュメントを指している) 登録中のドキュメントの URL / 別名
+    pub missing: Vec<String>,
+}
+
+/// fsck のコーパスの結果
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct CorpusFsck {
+    pub vocab_size: usize,
+    /// コーパスの文書数 (documents と一致するはず)
+    pub doc_num: u64,
+    /// 文書頻度が実データと合わない語の数 (POST /debug/prune_corpus で直る)
+    pub mismatched_counts: usize,
+    /// ドキュメントにあるがコーパスにない語
+    pub missing_tokens: Vec<String>,
+}
+
+/// LockMetrics の読み取り結果 (/status 用)
+#[derive(Debug, Clone, Serialize)]
+pub struct LockMetricsSnapshot {
+    pub search_skips: u64,
+    pub search_skips_per_shard: Vec<u64>,
+    pub write_count: u64,
+    pub write_hold_total_ms: f64,
+    pub write_hold_max_ms: f64,
+}
+
+/// シャードのロック競合の計測
+/// 検索時に書き込み中で飛ばしたシャードの回数と、add / del が write lock を保持した時間
+pub struct LockMetrics {
+    /// シャードごとの検索スキップ回数
+    search_skips: Vec<AtomicU64>,
+    write_count: AtomicU64,
+    write_nanos: AtomicU64,
+    write_max_nanos: AtomicU64,
+}
+
+impl LockMetrics {
+    pub fn new(shards: usize) -> Self {
+        Self {
+            search_skips: (0..shards).map(|_| AtomicU64::new(0)).collect(),
+            write_count: AtomicU64::new(0),
+            write_nanos: AtomicU64::new(0),
+            write_max_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn record_search_skip(&self, shard_id: usize) {
+        if let Some(counter) = self.search_skips.get(shard_id) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// drop までの時間を write lock の保持時間として記録する
+    /// lock を取った直後に作る
+    pub fn write_timer(&self) -> WriteTimer<'_> {
+        WriteTimer { metrics: self, started: std::time::Instant::now() }
+    }
+
+    fn record_write(&self, held: std::time::Duration) {
+        let nanos = held.as_nanos().min(u64::MAX as u128) as u64;
+        self.write_count.fetch_add(1, Ordering::Relaxed);
+        self.write_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.write_max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LockMetricsSnapshot {
+        let per_shard: Vec<u64> = self.search_skips.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        LockMetricsSnapshot {
+            search_skips: per_shard.iter().sum(),
+            search_skips_per_shard: per_shard,
+            write_count: self.write_count.load(Ordering::Relaxed),
+            write_hold_total_ms: self.write_nanos.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+            write_hold_max_ms: self.write_max_nanos.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+pub struct WriteTimer<'a> {
+    metrics: &'a LockMetrics,
+    started: std::time::Instant,
+}
+
+impl Drop for WriteTimer<'_> {
+    fn drop(&mut self) {
+        self.metrics.record_write(self.started.elapsed());
+    }
+}
+
+/// シャードファイル (N.index / N.meta / N.state) の配置
+/// コーパス / url.map / 世代番号は常にインデックスディレクトリ直下
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShardLayout {
+    /// インデックスディレクトリ直下 (従来)
+    #[default]
+    Flat,
+    /// シャードごとのサブディレクトリ (shard_N/)
+    Nested,
+}
+
+impl ShardLayout {
+    /// shard_0/ があれば Nested
+    pub fn detect(path: &str) -> Self {
+        if std::path::Path::new(path).join(Self::shard_dir_name(0)).is_dir() {
+            ShardLayout::Nested
+        } else {
+            ShardLayout::Flat
+        }
+    }
+
+    fn shard_dir_name(shard_id: usize) -> String {
+        format!("shard_{}", shard_id)
+    }
+
+    /// シャードのファイルを置くディレクトリ
+    pub fn shard_dir(self, path: &str, shard_id: usize) -> String {
+        match self {
+            ShardLayout::Flat => path.to_string(),
+            ShardLayout::Nested => std::path::Path::new(path).join(Self::shard_dir_name(shard_id)).to_string_lossy().into_owned(),
+        }
+    }
+
+    /// 存在するシャードファイル ({id}.{ext}) のパス (id 順)
+    /// シャード数を決め打ちにせずディレクトリを走査する (今のシャード数の範囲外のファイルも拾う)
+    fn shard_files(self, path: &str, ext: &str) -> Vec<std::path::PathBuf> {
+        let shard_id = |name: &std::ffi::OsStr, prefix: &str, suffix: &str| -> Option<usize> {
+            name.to_str()?.strip_prefix(prefix)?.strip_suffix(suffix)?.parse().ok()
+        };
+        let Ok(entries) = std::fs::read_dir(path) else { return Vec::new(); };
+        let suffix = format!(".{}", ext);
+        let mut files: Vec<(usize, std::path::PathBuf)> = entries.flatten()
+            .filter_map(|entry| match self {
+                ShardLayout::Flat => shard_id(&entry.file_name(), "", &suffix).map(|id| (id, entry.path())),
+                ShardLayout::Nested => shard_id(&entry.file_name(), "shard_", "")
+                    .map(|id| (id, entry.path().join(format!("{}{}", id, suffix)))),
+            })
+            .filter(|(_, p)| p.is_file())
+            .collect();
+        files.sort();
+        files.into_iter().map(|(_, p)| p).collect()
+    }
+}
+
+/// 更新後にすること (Index::record_update)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateActions {
+    pub save: bool,
+    pub calculate_size: bool,
+}
+
+/// シャードの補助状態
+/// .index / .meta とは別に {id}.state として保存する
+/// 存在しない場合 (旧フォーマット) はデフォルト値で復元
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShardState {
+    /// 更新回数
+    /// SAVE_FILE_INTERVAL / CALCULATE_BIN_SIZE_INTERVAL の周期を再起動後も維持するため
+    pub update_count: usize,
+}
+
+impl ShardState {
+    pub fn from_index(index: &Index) -> Self {
+        Self {
+            update_count: index.update_count,
+        }
+    }
+
+    pub fn save(&self, path: &str, shard_id: usize, options: SaveOptions) -> Result<(), Box<dyn std::error::Error>> {
+        let state_path = std::path::Path::new(path).join(format!("{}.state", shard_id));
+        write_bincode(&state_path, self, options)
+    }
+
+    /// 読み込めなければデフォルト値
+    pub fn load(path: &str, shard_id: usize) -> Self {
+        let state_path = std::path::Path::new(path).join(format!("{}.state", shard_id));
+        let data = match std::fs::read(&state_path) {
+            Ok(d) => d,
+            Err(_) => {
+                log::debug!("No state file for index id {}, using default", shard_id);
+                return Self::default();
+            }
+        };
+        match read_bincode(&state_path, &data) {
+            Ok(state) => state,
+            Err(e) => {
+                log::warn!("Failed to deserialize state file {:?}: {}", state_path, e);
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Index の基本情報
+/// URL, title, description, favicon, time, points, tags, author, published, desc_tokens, aliases, lang, modified
+/// Hash と Equal は URL のみで判定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexMeta {
+    pub id: usize,
+    /// URL
+    /// only URL is used for Hash and Equal
+    pub url: Box<str>,
+    /// Title
+    pub title: Box<str>,
+    /// Description
+    pub description: Box<str>,
+    /// Favicon URL
+    pub favicon: Option<Box<str>>,
+    /// Upload Time
+    pub time: DateTime<Utc>,
+    /// Score
+    pub points: f64,
+    /// Tags
+    /// General Tag:
+    /// - Wiki: wikipedia, ニコニコ大百科, etc
+    /// - News: yahoo!, GIGAZINE, ITmedia, etc
+    /// - SNS: twitter, facebook, youtube, instagram, etc
+    /// - Blog: hatena, zenn, etc
+    /// - Forum: 5ch, reddit, stackoverflow, etc
+    /// - Shopping: amazon, rakuten, ebay, etc
+    /// - Academic: arxiv, ciNii, etc
+    /// - Tools: translate, map, etc
+    pub tags: Tags,
+    /// Author (スクレイパの先頭の author)
+    #[serde(default)]
+    pub author: Option<Box<str>>,
+    /// Published Time (スクレイパの先頭の published)
+    #[serde(default)]
+    pub published: Option<DateTime<Utc>>,
+    /// description のトークンと位置 (STORE_DESC_TOKENS 有効時のみ)
+    /// 検索時のハイライトに使う
+    #[serde(default)]
+    pub desc_tokens: Option<Vec<TokenSpan>>,
+    /// 同じページを指す別の URL
+    /// meta_from_url / del_document で URL と同じように引ける 表示には url を使う
+    #[serde(default)]
+    pub aliases: Vec<Box<str>>,
+    /// インデックス時に指定された言語 (未指定なら None = 日本語として sudachi でトークン化)
+    #[serde(default)]
+    pub lang: Option<Box<str>>,
+    /// Modified Time (スクレイパの先頭の modified)
+    #[serde(default)]
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl IndexMeta {
+    /// /add で登録する meta (time は現在時刻、id は add_document で振り直す)
+    /// author / published / modified / desc_tokens / aliases は後から埋める
+    pub fn new(url: Box<str>, title: Box<str>, description: Box<str>, favicon: Option<Box<str>>, tags: Tags, lang: Option<Box<str>>) -> Self {
+        Self {
+            id: 0,
+            url,
+            title,
+            description,
+            favicon,
+            time: Utc::now(),
+            points: 0.0,
+            tags,
+            author: None,
+            published: None,
+            desc_tokens: None,
+            aliases: Vec::new(),
+            lang,
+            modified: None,
+        }
+    }
+
+    /// sort / 日付での絞り込みに使う日時
+    /// published は published、modified は modified → published の順で、なければインデックスした時刻
+    pub fn date(&self, field: DateField) -> DateTime<Utc> {
+        match field {
+            DateField::Published => self.published.unwrap_or(self.time),
+            DateField::Modified => self.modified.or(self.published).unwrap_or(self.time),
+        }
+    }
+
+    /// URL と別名
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.url.as_ref()).chain(self.aliases.iter().map(|a| a.as_ref()))
+    }
+
+    /// URL か別名のどれかが一致する
+    pub fn matches_url(&self, url: &str) -> bool {
+        self.urls().any(|u| u == url)
+    }
+
+    /// 別名を設定する
+    /// 空白を除き、空のもの / URL と同じもの / 重複は捨てる
+    pub fn set_aliases<S: AsRef<str>>(&mut self, aliases: &[S]) {
+        let mut result: Vec<Box<str>> = Vec::new();
+        for alias in aliases.iter().map(|a| a.as_ref().trim()) {
+            if !alias.is_empty() && alias != self.url.as_ref() && !result.iter().any(|a| a.as_ref() == alias) {
+                result.push(alias.into());
+            }
+        }
+        self.aliases = result;
+    }
+}
+
+/// author / published 追加前の IndexMeta
+/// bincode はフィールドを自己記述しないので旧 .meta の読み込みに使う
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexMetaV0 {
+    pub id: usize,
+    pub url: Box<str>,
+    pub title: Box<str>,
+    pub description: Box<str>,
+    pub favicon: Option<Box<str>>,
+    pub time: DateTime<Utc>,
+    pub points: f64,
+    pub tags: Tags,
+}
+
+/// desc_tokens 追加前の IndexMeta
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexMetaV1 {
+    pub id: usize,
+    pub url: Box<str>,
+    pub title: Box<str>,
+    pub description: Box<str>,
+    pub favicon: Option<Box<str>>,
+    pub time: DateTime<Utc>,
+    pub points: f64,
+    pub tags: Tags,
+    pub author: Option<Box<str>>,
+    pub published: Option<DateTime<Utc>>,
+}
+
+/// aliases 追加前の IndexMeta
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexMetaV2 {
+    pub id: usize,
+    pub url: Box<str>,
+    pub title: Box<str>,
+    pub description: Box<str>,
+    pub favicon: Option<Box<str>>,
+    pub time: DateTime<Utc>,
+    pub points: f64,
+    pub tags: Tags,
+    pub author: Option<Box<str>>,
+    pub published: Option<DateTime<Utc>>,
+    pub desc_tokens: Option<Vec<TokenSpan>>,
+}
+
+impl From<IndexMetaV0> for IndexMeta {
+    fn from(m: IndexMetaV0) -> Self {
+        Self {
+            id: m.id,
+            url: m.url,
+            title: m.title,
+            description: m.description,
+            favicon: m.favicon,
+            time: m.time,
+            points: m.points,
+            tags: m.tags,
+            author: None,
+            published: None,
+            desc_tokens: None,
+            aliases: Vec::new(),
+            lang: None,
+            modified: None,
+        }
+    }
+}
+
+impl From<IndexMetaV1> for IndexMeta {
+    fn from(m: IndexMetaV1) -> Self {
+        Self {
+            id: m.id,
+            url: m.url,
+            title: m.title,
+            description: m.description,
+            favicon: m.favicon,
+            time: m.time,
+            points: m.points,
+            tags: m.tags,
+            author: m.author,
+            published: m.published,
+            desc_tokens: None,
+            aliases: Vec::new(),
+            lang: None,
+            modified: None,
+        }
+    }
+}
+
+impl From<IndexMetaV2> for IndexMeta {
+    fn from(m: IndexMetaV2) -> Self {
+        Self {
+            id: m.id,
+            url: m.url,
+            title: m.title,
+            description: m.description,
+            favicon: m.favicon,
+            time: m.time,
+            points: m.points,
+            tags: m.tags,
+            author: m.author,
+            published: m.published,
+            desc_tokens: m.desc_tokens,
+            aliases: Vec::new(),
+            lang: None,
+            modified: None,
+        }
+    }
+}
+
+/// lang 追加前の IndexMeta
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexMetaV3 {
+    pub id: usize,
+    pub url: Box<str>,
+    pub title: Box<str>,
+    pub description: Box<str>,
+    pub favicon: Option<Box<str>>,
+    pub time: DateTime<Utc>,
+    pub points: f64,
+    pub tags: Tags,
+    pub author: Option<Box<str>>,
+    pub published: Option<DateTime<Utc>>,
+    pub desc_tokens: Option<Vec<TokenSpan>>,
+    pub aliases: Vec<Box<str>>,
+}
+
+impl From<IndexMetaV3> for IndexMeta {
+    fn from(m: IndexMetaV3) -> Self {
+        Self {
+            id: m.id,
+            url: m.url,
+            title: m.title,
+            description: m.description,
+            favicon: m.favicon,
+            time: m.time,
+            points: m.points,
+            tags: m.tags,
+            author: m.author,
+            published: m.published,
+            desc_tokens: m.desc_tokens,
+            aliases: m.aliases,
+            lang: None,
+            modified: None,
+        }
+    }
+}
+
+/// modified 追加前の IndexMeta
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexMetaV4 {
+    pub id: usize,
+    pub url: Box<str>,
+    pub title: Box<str>,
+    pub description: Box<str>,
+    pub favicon: Option<Box<str>>,
+    pub time: DateTime<Utc>,
+    pub points: f64,
+    pub tags: Tags,
+    pub author: Option<Box<str>>,
+    pub published: Option<DateTime<Utc>>,
+    pub desc_tokens: Option<Vec<TokenSpan>>,
+    pub aliases: Vec<Box<str>>,
+    pub lang: Option<Box<str>>,
+}
+
+impl From<IndexMetaV4> for IndexMeta {
+    fn from(m: IndexMetaV4) -> Self {
+        Self {
+            id: m.id,
+            url: m.url,
+            title: m.title,
+            description: m.description,
+            favicon: m.favicon,
+            time: m.time,
+            points: m.points,
+            tags: m.tags,
+            author: m.author,
+            published: m.published,
+            desc_tokens: m.desc_tokens,
+            aliases: m.aliases,
+            lang: m.lang,
+            modified: None,
+        }
+    }
+}
+
+/// .meta をデシリアライズ
+/// version はヘッダのスキーマバージョンで、その版の IndexMeta として読んで現行の形式に移行する
+/// ヘッダのないファイルは '[' で始まれば JSON (MetaFormat::Json) として読み、読めなければ bincode として読む
+/// (bincode の先頭は件数なので '[' になることがある)
+/// ヘッダ導入前の bincode はバージョンが分からないので、その時点までの形式 (v2, v1, v0) を新しい順に試す
+pub fn deserialize_meta(version: u32, data: &[u8]) -> Result<Vec<IndexMeta>, bincode::Error> {
+    use bincode::Options;
+    let options = || bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes();
+    fn migrate<M: Into<IndexMeta>>(format: &str, meta: Vec<M>) -> Vec<IndexMeta> {
+        log::info!("Loaded meta format {} ({} entries)", format, meta.len());
+        meta.into_iter().map(Into::into).collect()
+    }
+    match version {
+        SCHEMA_VERSION => return options().deserialize::<Vec<IndexMeta>>(data),
+        3 => return options().deserialize::<Vec<IndexMetaV4>>(data).map(|m| migrate("v4", m)),
+        2 => return options().deserialize::<Vec<IndexMetaV3>>(data).map(|m| migrate("v3", m)),
+        1 => return options().deserialize::<Vec<IndexMetaV2>>(data).map(|m| migrate("v2", m)),
+        _ => {}
+    }
+    // ヘッダなし
+    let json_error = match data.trim_ascii_start().first() {
+        Some(b'[') => match serde_json::from_slice::<Vec<IndexMeta>>(data) {
+            Ok(meta) => return Ok(meta),
+            Err(e) => Some(e),
+        },
+        _ => None,
+    };
+    let e = match options().deserialize::<Vec<IndexMetaV2>>(data) {
+        Ok(v2) => return Ok(migrate("v2", v2)),
+        Err(e) => e,
+    };
+    if let Ok(v1) = options().deserialize::<Vec<IndexMetaV1>>(data) {
+        return Ok(migrate("v1", v1));
+    }
+    if let Ok(v0) = options().deserialize::<Vec<IndexMetaV0>>(data) {
+        return Ok(migrate("v0", v0));
+    }
+    match json_error {
+        Some(json_error) => Err(Box::new(bincode::ErrorKind::Custom(format!("invalid JSON meta: {}", json_error)))),
+        None => Err(e),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Tags(u64);
+
+impl Tags {
+    pub const WIKI: u64 = 1 << 0;
+    pub const NEWS: u64 = 1 << 1;
+    pub const SNS: u64 = 1 << 2;
+    pub const BLOG: u64 = 1 << 3;
+    pub const FORUM: u64 = 1 << 4;
+    pub const SHOPPING: u64 = 1 << 5;
+    pub const ACADEMIC: u64 = 1 << 6;
+    pub const TOOLS: u64 = 1 << 7;
+
+    /// eg: Tags::new(Tags::NEWS | Tags::BLOG)
+    pub fn new(set: u64) -> Self {
+        Self(set)
+    }
+
+    /// すべて満たしてるか
+    /// tagはselfに含まれている必要がある
+    /// eg: is_filter_contains(Tags::NEWS | Tags::BLOG) -> NEWSとBLOGの両方を含む場合のみtrue
+    pub fn is_filter_contains<T: Into<u64> + Copy>(&self, tag: T) -> bool {
+        (self.0 & tag.into()) == tag.into()
+    }
+
+    pub fn contains<T: Into<u64>>(&self, tag: T) -> bool {
+        (self.0 & tag.into()) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// 検索のタグフィルタを通るか
+    /// tag が空でなければ tag_exclusive = true で全て含む、false でいずれかを含む
+    /// exclude_tag のいずれかを含めば除外
+    pub fn matches_filter(&self, tag: Tags, tag_exclusive: bool, exclude_tag: Tags) -> bool {
+        if !tag.is_empty() {
+            let matched = if tag_exclusive { self.is_filter_contains(tag) } else { self.contains(tag) };
+            if !matched {
+                return false;
+            }
+        }
+        exclude_tag.is_empty() || !self.contains(exclude_tag)
+    }
+
+    /// タグ名 (大文字) の一覧
+    /// 名前は TagRegistry から引く
+    pub fn tags(&self) -> Vec<Box<str>> {
+        TagRegistry::with_global(|registry| registry.names(self.0))
+    }
+
+    /// タグ名からビット集合を作る (大文字小文字無視、未知の名前は無視)
+    /// 名前は TagRegistry から引く
+    pub fn from_strs<T>(tags: &[T]) -> Self
+    where
+        T: AsRef<str>,
+    {
+        Self(TagRegistry::with_global(|registry| registry.bits(tags)))
+    }
+
+    /// タグ名からビット集合を作り、未知の名前も返す
+    pub fn parse_strs<T>(tags: &[T]) -> (Self, Vec<String>)
+    where
+        T: AsRef<str>,
+    {
+        TagRegistry::with_global(|registry| {
+            let unknown = tags.iter()
+                .map(|t| t.as_ref())
+                .filter(|t| registry.bit(t).is_none())
+                .map(|t| t.to_string())
+                .collect();
+            (Self(registry.bits(tags)), unknown)
+        })
+    }
+
+    /// リクエストのタグ名を検証してビット集合にする
+    /// 未知の名前があれば strict なら Err (400 用のメッセージ)、そうでなければ warnings に追加して無視
+    pub fn from_strs_checked<T>(tags: &[T], strict: bool, warnings: &mut Vec<String>) -> Result<Self, String>
+    where
+        T: AsRef<str>,
+    {
+        let (set, unknown) = Self::parse_strs(tags);
+        if unknown.is_empty() {
+            return Ok(set);
+        }
+        if strict {
+            let valid = TagRegistry::with_global(|registry| registry.entries.iter().map(|(name, _)| name.to_string()).collect::<Vec<_>>());
+            return Err(format!("Unknown tag: {} (valid tags: {})", unknown.join(", "), valid.join(", ")));
+        }
+        warnings.extend(unknown.iter().map(|t| format!("unknown tag: {}", t)));
+        Ok(set)
+    }
+}
+
+/// タグ名とビットの対応
+/// 起動時に TAGS_FILE から読み込んで install する。なければ組み込みの 8 種
+/// IndexMeta はビットのまま保存するので、既存タグのビットを変えない限り定義を増やしても互換
+#[derive(Debug, Clone)]
+pub struct TagRegistry {
+    /// (名前, ビット) ビット順
+    entries: Vec<(Box<str>, u64)>,
+    /// (別名 (normalize_tag_name 済み), タグ名)
+    aliases: Vec<(Box<str>, Box<str>)>,
+}
+
+/// 組み込みのタグの別名 (別名, タグ名)
+/// 対象のタグが定義されていないものは使わない
+pub const DEFAULT_TAG_ALIASES: &[(&str, &str)] = &[
+    ("wikipedia", "wiki"),
+    ("ウィキ", "wiki"),
+    ("ウィキペディア", "wiki"),
+    ("百科事典", "wiki"),
+    ("ニュース", "news"),
+    ("social", "sns"),
+    ("ソーシャル", "sns"),
+    ("ブログ", "blog"),
+    ("掲示板", "forum"),
+    ("フォーラム", "forum"),
+    ("shop", "shopping"),
+    ("通販", "shopping"),
+    ("ショッピング", "shopping"),
+    ("買い物", "shopping"),
+    ("paper", "academic"),
+    ("論文", "academic"),
+    ("学術", "academic"),
+    ("tool", "tools"),
+    ("ツール", "tools"),
+];
+
+/// タグ名の比較用の正規化
+/// 前後の空白を除き、全角英数記号を半角に、英字を小文字にする
+pub fn normalize_tag_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| match c {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            '\u{3000}' => ' ',
+            _ => c,
+        })
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+/// 定義できるタグの最大数 (u64 のビット数)
+pub const MAX_TAGS: usize = 64;
+
+static TAG_REGISTRY: std::sync::LazyLock<RwLock<TagRegistry>> =
+    std::sync::LazyLock::new(|| RwLock::new(TagRegistry::default()));
+
+impl Default for TagRegistry {
+    fn default() -> Self {
+        let builtin = [
+            ("wiki", Tags::WIKI),
+            ("news", Tags::NEWS),
+            ("sns", Tags::SNS),
+            ("blog", Tags::BLOG),
+            ("forum", Tags::FORUM),
+            ("shopping", Tags::SHOPPING),
+            ("academic", Tags::ACADEMIC),
+            ("tools", Tags::TOOLS),
+        ];
+        let entries = builtin.iter().map(|(name, bit)| ((*name).into(), *bit)).collect();
+        Self::with_default_aliases(entries)
+    }
+}
+
+impl TagRegistry {
+    /// 組み込みの別名のうち対象のタグがあるものを付ける
+    fn with_default_aliases(entries: Vec<(Box<str>, u64)>) -> Self {
+        let mut registry = Self { entries, aliases: Vec::new() };
+        for (alias, name) in DEFAULT_TAG_ALIASES {
+            if registry.exact_bit(name).is_some() {
+                registry.aliases.push((normalize_tag_name(alias).into(), (*name).into()));
+            }
+        }
+        registry
+    }
+
+    /// 名前 -> ビット番号 (0..64) の JSON オブジェクトから構築
+    /// 例: {"wiki": 0, "news": 1, "recipe": 8}
+    /// 別名も定義するなら {"tags": {...}, "aliases": {"レシピ": "recipe"}} (組み込みの別名に追加)
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let value: serde_json::Value = serde_json::from_str(json).map_err(|e| format!("Invalid tag config: {}", e))?;
+        let (tags, aliases) = match value.get("tags") {
+            Some(tags) if tags.is_object() => (tags.clone(), value.get("aliases").cloned()),
+            _ => (value, None),
+        };
+        let map: HashMap<String, u32> = serde_json::from_value(tags).map_err(|e| format!("Invalid tag config: {}", e))?;
+        let aliases: HashMap<String, String> = match aliases {
+            Some(aliases) => serde_json::from_value(aliases).map_err(|e| format!("Invalid tag aliases: {}", e))?,
+            None => HashMap::new(),
+        };
+        if map.len() > MAX_TAGS {
+            return Err(format!("Too many tags: {} (max {})", map.len(), MAX_TAGS));
+        }
+        let mut entries: Vec<(Box<str>, u64)> = Vec::with_capacity(map.len());
+        for (name, bit) in map {
+            let name = name.trim();
+            if name.is_empty() {
+                return Err("Empty tag name".to_string());
+            }
+            if bit as usize >= MAX_TAGS {
+                return Err(format!("Tag bit out of range: {} = {}", name, bit));
+            }
+            if let Some((other, _)) = entries.iter().find(|(n, b)| *b == 1 << bit || n.eq_ignore_ascii_case(name)) {
+                return Err(format!("Duplicate tag: {} and {}", other, name));
+            }
+            entries.push((name.into(), 1 << bit));
+        }
+        entries.sort_by_key(|(_, bit)| *bit);
+        let mut registry = Self::with_default_aliases(entries);
+        for (alias, name) in aliases {
+            let Some((target, _)) = registry.entries.iter().find(|(n, _)| n.eq_ignore_ascii_case(name.trim())) else {
+                return Err(format!("Unknown tag for alias: {} -> {}", alias, name));
+            };
+            let normalized = normalize_tag_name(&alias);
+            if normalized.is_empty() {
+                return Err("Empty tag alias".to_string());
+            }
+            let target = target.clone();
+            registry.aliases.retain(|(a, _)| **a != *normalized);
+            registry.aliases.push((normalized.into(), target));
+        }
+        Ok(registry)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("Failed to read tag config {}: {}", path, e))?;
+        Self::from_json(&json)
+    }
+
+    /// 以後の Tags::from_strs / tags() で使う定義を差し替える
+    pub fn install(self) {
+        match TAG_REGISTRY.write() {
+            Ok(mut registry) => *registry = self,
+            Err(e) => error!("Failed to install tag registry: {}", e),
+        }
+    }
+
+    fn with_global<R>(f: impl FnOnce(&TagRegistry) -> R) -> R {
+        match TAG_REGISTRY.read() {
+            Ok(registry) => f(&registry),
+            Err(poison) => f(&poison.into_inner()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn bits<T: AsRef<str>>(&self, tags: &[T]) -> u64 {
+        tags.iter()
+            .filter_map(|t| self.bit(t.as_ref()))
+            .fold(0, |set, bit| set | bit)
+    }
+
+    /// 名前または別名のビット
+    /// 大文字小文字・全角半角は区別しない
+    pub fn bit(&self, tag: &str) -> Option<u64> {
+        let tag = normalize_tag_name(tag);
+        self.exact_bit(&tag).or_else(|| {
+            self.aliases.iter()
+                .find(|(alias, _)| **alias == *tag)
+                .and_then(|(_, name)| self.exact_bit(name))
+        })
+    }
+
+    fn exact_bit(&self, tag: &str) -> Option<u64> {
+        self.entries.iter().find(|(name, _)| name.eq_ignore_ascii_case(tag)).map(|(_, bit)| *bit)
+    }
+
+    pub fn names(&self, set: u64) -> Vec<Box<str>> {
+        self.entries.iter()
+            .filter(|(_, bit)| set & bit != 0)
+            .map(|(name, _)| name.to_ascii_uppercase().into_boxed_str())
+            .collect()
+    }
+}
+
+impl PartialEq for IndexMeta {
+    fn eq(&self, other: &Self) -> bool {
+        self.url == other.url
+    }
+}
+
+impl From<Tags> for u64 {
+    fn from(tags: Tags) -> u64 {
+        tags.0
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("wk-search-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.to_string_lossy().into_owned()
+    }
+
+    /// テスト用のメタ (他モジュールのテストからも使う)
+    pub(crate) fn test_meta(url: &str) -> IndexMeta {
+        IndexMeta::new(url.into(), "title".into(), "description".into(), None, Tags::new(0), None)
+    }
+
+    /// (url, tokens) を add_document で登録する
+    /// 保存前はシャードのサイズがすべて 0 なので、同じシャードに入り BM25 の平均文書長もそろう
+    pub(crate) fn add_test_docs(pool: &IndexPool, docs: &[(&str, &[&str])]) {
+        for (url, tokens) in docs {
+            pool.add_document(&TokenFrequency::from(*tokens), test_meta(url)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_update_count_survives_save_load() {
+        let dir = test_dir("update-count");
+        let pool = IndexPool::new(&dir);
+        for i in 0..5 {
+            let token_fq = TokenFrequency::from(&["rust", "search"][..]);
+            pool.add_document(&token_fq, test_meta(&format!("https://example.com/{}", i)));
+        }
+        let counts: Vec<usize> = pool.shards().iter().map(|i| i.read().unwrap().update_count).collect();
+        assert_eq!(counts.iter().sum::<usize>(), 5);
+        pool.save(&dir).unwrap();
+
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        let loaded_counts: Vec<usize> = loaded.shards().iter().map(|i| i.read().unwrap().update_count).collect();
+        assert_eq!(counts, loaded_counts);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_duplicate_meta_ids() {
+        let dir = test_dir("duplicate-meta-ids");
+        let pool = IndexPool::new(&dir);
+        for i in 0..3 {
+            pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        let (shard_id, doc_id) = pool.locate("https://example.com/0").unwrap();
+        // 同じ ID の meta を末尾に足して、重複かつ順不同の .meta にする
+        {
+            let shard = pool.shard(shard_id).unwrap();
+            let mut idx = shard.write().unwrap();
+            let mut dup = idx.meta_from_id(doc_id).unwrap().clone();
+            dup.url = "https://example.com/dup".into();
+            idx.meta.insert(0, test_meta("https://example.com/stale"));
+            idx.meta[0].id = doc_id;
+            idx.meta.push(IndexMeta { id: doc_id + 100, ..test_meta("https://example.com/gap") });
+            idx.meta.push(dup);
+        }
+        pool.save(&dir).unwrap();
+
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        let shard = loaded.shard(shard_id).unwrap();
+        let idx = shard.read().unwrap();
+        assert!(idx.meta.windows(2).all(|w| w[0].id < w[1].id));
+        // 後から書かれた方に解決する
+        assert_eq!(&*idx.meta_from_id(doc_id).unwrap().url, "https://example.com/dup");
+        assert_eq!(&*idx.meta_from_id(doc_id + 100).unwrap().url, "https://example.com/gap");
+        drop(idx);
+        assert_eq!(loaded.locate("https://example.com/dup"), Some((shard_id, doc_id)));
+        assert_eq!(loaded.locate("https://example.com/0"), None);
+
+        let mut meta: Vec<IndexMeta> = [2, 0, 2, 5].iter().map(|&id| IndexMeta { id, ..test_meta("https://example.com/") }).collect();
+        assert_eq!(check_meta_ids(&mut meta, 0), MetaIdCheck { reordered: true, duplicates: 1, gaps: 3 });
+        assert_eq!(meta.iter().map(|m| m.id).collect::<Vec<_>>(), vec![0, 2, 5]);
+        assert!(!check_meta_ids(&mut meta, 0).repaired());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_nested_layout_round_trip() {
+        let dir = test_dir("nested-layout");
+        let mut pool = IndexPool::load_or_new(&dir, None, ShardLayout::Nested).unwrap();
+        assert_eq!(pool.layout, ShardLayout::Nested);
+        for i in 0..5 {
+            pool.add_document(&TokenFrequency::from(&["rust", "search"][..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        pool.save(&dir).unwrap();
+        let root = std::path::Path::new(&dir);
+        assert!(root.join("shard_0").join("0.index").is_file());
+        assert!(root.join("shard_15").join("15.meta").is_file());
+        assert!(!root.join("0.index").exists());
+        assert!(root.join(DEFAULT_CORPUS_FILE).is_file());
+
+        // 配置は自動判定 (指定より保存済みの配置を優先)
+        let loaded = IndexPool::load_or_new(&dir, None, ShardLayout::Flat).unwrap();
+        assert_eq!(loaded.layout, ShardLayout::Nested);
+        assert_eq!(loaded.counter.load(Ordering::SeqCst), 5);
+        assert!(loaded.locate("https://example.com/3").is_some());
+
+        // 従来の配置も読める
+        let flat_dir = test_dir("flat-layout");
+        pool.layout = ShardLayout::Flat;
+        pool.save(&flat_dir).unwrap();
+        let flat = IndexPool::load(&flat_dir, None).unwrap();
+        assert_eq!(flat.layout, ShardLayout::Flat);
+        assert_eq!(flat.counter.load(Ordering::SeqCst), 5);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&flat_dir);
+    }
+
+    #[test]
+    fn test_deserialize_legacy_meta() {
+        let legacy = vec![IndexMetaV0 {
+            id: 0,
+            url: "https://example.com/".into(),
+            title: "title".into(),
+            description: "description".into(),
+            favicon: None,
+            time: Utc::now(),
+            points: 0.0,
+            tags: Tags::new(Tags::WIKI),
+        }];
+        let meta = deserialize_meta(HEADERLESS_SCHEMA_VERSION, &bincode::serialize(&legacy).unwrap()).unwrap();
+        assert_eq!(meta[0].url.as_ref(), "https://example.com/");
+        assert!(meta[0].author.is_none() && meta[0].published.is_none());
+
+        let v1 = vec![IndexMetaV1 {
+            id: 0,
+            url: "https://example.com/".into(),
+            title: "title".into(),
+            description: "description".into(),
+            favicon: None,
+            time: Utc::now(),
+            points: 0.0,
+            tags: Tags::new(0),
+            author: Some("Alice".into()),
+            published: None,
+        }];
+        let meta = deserialize_meta(HEADERLESS_SCHEMA_VERSION, &bincode::serialize(&v1).unwrap()).unwrap();
+        assert_eq!(meta[0].author.as_deref(), Some("Alice"));
+        assert!(meta[0].desc_tokens.is_none());
+
+        let mut current = test_meta("https://example.com/a");
+        current.author = Some("Alice".into());
+        current.published = Some(Utc::now());
+        current.desc_tokens = Some(vec![TokenSpan { token: "a".into(), start: 0, len: 1 }]);
+        current.lang = Some("en".into());
+        current.modified = Some(Utc::now());
+        let meta = deserialize_meta(SCHEMA_VERSION, &bincode::serialize(&vec![current.clone()]).unwrap()).unwrap();
+        assert_eq!(meta[0].author, current.author);
+        assert_eq!(meta[0].published, current.published);
+        assert_eq!(meta[0].desc_tokens, current.desc_tokens);
+        assert_eq!(meta[0].lang, current.lang);
+        assert_eq!(meta[0].modified, current.modified);
+
+        let v4 = vec![IndexMetaV4 {
+            id: 0,
+            url: "https://example.com/".into(),
+            title: "title".into(),
+            description: "description".into(),
+            favicon: None,
+            time: Utc::now(),
+            points: 0.0,
+            tags: Tags::new(0),
+            author: None,
+            published: None,
+            desc_tokens: None,
+            aliases: Vec::new(),
+            lang: Some("en".into()),
+        }];
+        let meta = deserialize_meta(3, &bincode::serialize(&v4).unwrap()).unwrap();
+        assert_eq!(meta[0].lang.as_deref(), Some("en"));
+        assert!(meta[0].modified.is_none());
+        // バージョンと形式が合わなければ他の形式を試さずにエラー
+        assert!(deserialize_meta(SCHEMA_VERSION, &bincode::serialize(&v4).unwrap()).is_err());
+        assert!(deserialize_meta(HEADERLESS_SCHEMA_VERSION, &bincode::serialize(&v4).unwrap()).is_err());
+
+        let v3 = vec![IndexMetaV3 {
+            id: 0,
+            url: "https://example.com/".into(),
+            title: "title".into(),
+            description: "description".into(),
+            favicon: None,
+            time: Utc::now(),
+            points: 0.0,
+            tags: Tags::new(0),
+            author: None,
+            published: None,
+            desc_tokens: None,
+            aliases: vec!["https://example.com/alias".into()],
+        }];
+        let meta = deserialize_meta(2, &bincode::serialize(&v3).unwrap()).unwrap();
+        assert_eq!(meta[0].aliases, v3[0].aliases);
+        assert!(meta[0].lang.is_none());
+
+        let v2 = vec![IndexMetaV2 {
+            id: 0,
+            url: "https://example.com/".into(),
+            title: "title".into(),
+            description: "description".into(),
+            favicon: None,
+            time: Utc::now(),
+            points: 0.0,
+            tags: Tags::new(0),
+            author: None,
+            published: None,
+            desc_tokens: Some(vec![TokenSpan { token: "a".into(), start: 0, len: 1 }]),
+        }];
+        let meta = deserialize_meta(1, &bincode::serialize(&v2).unwrap()).unwrap();
+        assert_eq!(meta[0].desc_tokens, v2[0].desc_tokens);
+        assert!(meta[0].aliases.is_empty());
+    }
+
+    #[test]
+    fn test_json_meta_round_trip() {
+        let dir = test_dir("json-meta");
+        let mut pool = IndexPool::new(&dir);
+        pool.save_options.meta_format = MetaFormat::Json;
+        let mut meta = test_meta("https://example.com/json");
+        meta.author = Some("Alice".into());
+        meta.published = Some(Utc::now());
+        meta.desc_tokens = Some(vec![TokenSpan { token: "東京".into(), start: 0, len: 2 }]);
+        pool.add_document(&TokenFrequency::from(&["東京"][..]), meta.clone());
+        pool.save(&dir).unwrap();
+
+        let (shard_id, doc_id) = pool.locate("https://example.com/json").unwrap();
+        let meta_path = std::path::Path::new(&dir).join(format!("{}.meta", shard_id));
+        let text = std::fs::read_to_string(&meta_path).unwrap();
+        assert!(text.starts_with('['));
+        assert!(text.contains("\"author\": \"Alice\""));
+
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        let shard = loaded.shard(shard_id).unwrap();
+        let idx = shard.read().unwrap();
+        let m = idx.meta_from_id(doc_id).unwrap();
+        assert_eq!(m.url, meta.url);
+        assert_eq!(m.author, meta.author);
+        assert_eq!(m.published, meta.published);
+        assert_eq!(m.desc_tokens, meta.desc_tokens);
+        drop(idx);
+
+        // 件数の下位バイトが '[' (91) のヘッダなしの bincode も bincode として読める
+        let many: Vec<IndexMetaV0> = (0..91).map(|i| IndexMetaV0 {
+            id: i,
+            url: format!("https://example.com/{}", i).into(),
+            title: "title".into(),
+            description: "description".into(),
+            favicon: None,
+            time: Utc::now(),
+            points: 0.0,
+            tags: Tags::new(0),
+        }).collect();
+        let data = bincode::serialize(&many).unwrap();
+        assert_eq!(data[0], b'[');
+        assert_eq!(deserialize_meta(HEADERLESS_SCHEMA_VERSION, &data).unwrap().len(), 91);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_schema_version_header() {
+        let dir = test_dir("schema-version");
+        let pool = IndexPool::new(&dir);
+        pool.add_document(&TokenFrequency::from(&["東京"][..]), test_meta("https://example.com/schema"));
+        pool.save(&dir).unwrap();
+        let corpus_path = std::path::Path::new(&dir).join(DEFAULT_CORPUS_FILE);
+        let data = std::fs::read(&corpus_path).unwrap();
+        assert_eq!(&data[..4], FILE_MAGIC);
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), SCHEMA_VERSION);
+
+        // ヘッダのないファイル (ヘッダ導入前) も読める
+        std::fs::write(&corpus_path, &data[8..]).unwrap();
+        assert_eq!(IndexPool::load(&dir, None).unwrap().counter.load(Ordering::SeqCst), 1);
+
+        // 違うバージョンは分かるエラーにする
+        let mut future = FILE_MAGIC.to_vec();
+        future.extend_from_slice(&(SCHEMA_VERSION + 1).to_le_bytes());
+        future.extend_from_slice(&data[8..]);
+        std::fs::write(&corpus_path, &future).unwrap();
+        let err = IndexPool::load(&dir, None).err().unwrap();
+        assert!(err.is::<SchemaVersionError>());
+        assert!(err.to_string().contains(&format!("index schema v{}, binary supports v{}; run migration", SCHEMA_VERSION + 1, SCHEMA_VERSION)));
+        // 新規作成にフォールバックしない
+        assert!(IndexPool::load_or_new(&dir, None, ShardLayout::Flat).is_err());
+
+        // シャードのファイルでも同じ
+        std::fs::write(&corpus_path, &data).unwrap();
+        let meta_path = std::path::Path::new(&dir).join("0.meta");
+        let mut meta = std::fs::read(&meta_path).unwrap();
+        meta[4..8].copy_from_slice(&(SCHEMA_VERSION + 1).to_le_bytes());
+        std::fs::write(&meta_path, &meta).unwrap();
+        assert!(IndexPool::load(&dir, None).err().unwrap().is::<SchemaVersionError>());
+
+        // 古いバージョンの .meta はそのバージョンの形式として読んで移行する
+        let v4 = vec![IndexMetaV4 {
+            id: 0,
+            url: "https://example.com/schema".into(),
+            title: "title".into(),
+            description: "description".into(),
+            favicon: None,
+            time: Utc::now(),
+            points: 0.0,
+            tags: Tags::new(0),
+            author: None,
+            published: None,
+            desc_tokens: None,
+            aliases: Vec::new(),
+            lang: Some("en".into()),
+        }];
+        let mut old = FILE_MAGIC.to_vec();
+        old.extend_from_slice(&3u32.to_le_bytes());
+        old.extend_from_slice(&bincode::serialize(&v4).unwrap());
+        meta[4..8].copy_from_slice(&SCHEMA_VERSION.to_le_bytes());
+        std::fs::write(&meta_path, &meta).unwrap();
+        let (shard_id, doc_id) = pool.locate("https://example.com/schema").unwrap();
+        std::fs::write(std::path::Path::new(&dir).join(format!("{}.meta", shard_id)), &old).unwrap();
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(loaded.locate("https://example.com/schema"), Some((shard_id, doc_id)));
+        let shard = loaded.shard(shard_id).unwrap();
+        let m = shard.read().unwrap().meta_from_id(doc_id).cloned().unwrap();
+        assert_eq!(m.lang.as_deref(), Some("en"));
+        assert!(m.modified.is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_multiple_corpus_files_error() {
+        let dir = test_dir("multi-corpus");
+        let pool = IndexPool::new(&dir);
+        pool.save(&dir).unwrap();
+        std::fs::copy(
+            std::path::Path::new(&dir).join(DEFAULT_CORPUS_FILE),
+            std::path::Path::new(&dir).join("other.corpus"),
+        ).unwrap();
+
+        let err = IndexPool::load(&dir, None).err().unwrap();
+        let multi = err.downcast_ref::<MultipleCorpusError>().unwrap();
+        assert_eq!(multi.files.len(), 2);
+        assert!(err.to_string().contains("other.corpus"));
+        // 新規作成にフォールバックしない
+        assert!(IndexPool::load_or_new(&dir, None, ShardLayout::Flat).is_err());
+        // 名前を指定すれば読める
+        let loaded = IndexPool::load(&dir, Some("other.corpus")).unwrap();
+        assert_eq!(loaded.corpus_file, "other.corpus");
+        assert!(IndexPool::load(&dir, Some("missing.corpus")).is_err());
+        // 既存のインデックスがあるのに指定のコーパスがなければ新規作成しない
+        let err = IndexPool::load_or_new(&dir, Some("missing.corpus"), ShardLayout::Flat).err().unwrap();
+        assert!(err.is::<CorpusFileError>());
+        // 壊れていても同じ
+        std::fs::write(std::path::Path::new(&dir).join("other.corpus"), b"broken").unwrap();
+        assert!(IndexPool::load_or_new(&dir, Some("other.corpus"), ShardLayout::Flat).err().unwrap().is::<CorpusFileError>());
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // 空のディレクトリなら初回起動として作る
+        let fresh = test_dir("multi-corpus-fresh");
+        let pool = IndexPool::load_or_new(&fresh, Some("global.corpus"), ShardLayout::Flat).unwrap();
+        assert_eq!(pool.corpus_file, "global.corpus");
+        let _ = std::fs::remove_dir_all(&fresh);
+    }
+
+    #[test]
+    fn test_fill_highlights() {
+        let dir = test_dir("highlights");
+        let mut pool = IndexPool::new(&dir);
+        let mut meta = test_meta("https://example.com/1");
+        meta.description = "東京の天気は晴れ".into();
+        meta.desc_tokens = Some(vec![
+            TokenSpan { token: "東京".into(), start: 0, len: 2 },
+            TokenSpan { token: "の".into(), start: 2, len: 1 },
+            TokenSpan { token: "天気".into(), start: 3, len: 2 },
+            TokenSpan { token: "は".into(), start: 5, len: 1 },
+            TokenSpan { token: "晴れる".into(), start: 6, len: 2 },
+        ]);
+        pool.add_document(&TokenFrequency::from(&["東京", "天気", "晴れる"][..]), meta);
+        pool.add_document(&TokenFrequency::from(&["東京"][..]), test_meta("https://example.com/2"));
+        // 一致しないドキュメントも結果に残す
+        pool.score_floor = f64::NEG_INFINITY;
+        let query = ["天気", "晴れる"];
+        let scored = pool.per_similarity(&TokenFrequency::from(&query[..]), &SimilarityAlgorithm::CosineSimilarity);
+        let mut results = pool.generate_results(pool.sort_by_score(scored), 0..10, Tags::new(0), false, Tags::new(0));
+        pool.fill_highlights(&mut results, &query, &SearchReads::default());
+        let first = results.iter().find(|r| r.url.as_ref() == "https://example.com/1").unwrap();
+        let highlights = first.highlights.clone().unwrap();
+        let chars: Vec<char> = first.descriptions.chars().collect();
+        let words: Vec<String> = highlights.into_iter().map(|r| chars[r].iter().collect()).collect();
+        // 正規化形 (晴れる) で一致しても表層形 (晴れ) の位置を返す
+        assert_eq!(words, vec!["天気", "晴れ"]);
+        let second = results.iter().find(|r| r.url.as_ref() == "https://example.com/2").unwrap();
+        assert!(second.highlights.is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_save_skips_poisoned_shard() {
+        let dir = test_dir("poisoned-save");
+        let pool = IndexPool::new(&dir);
+        let push = |shard_id: usize, url: &str| {
+            let shard = pool.shard(shard_id).unwrap();
+            let mut idx = shard.write().unwrap();
+            let doc_id = idx.meta.len();
+            idx.vectorizer.add_doc(doc_id, &TokenFrequency::from(&["rust"][..]));
+            idx.meta.push(IndexMeta { id: doc_id, ..test_meta(url) });
+        };
+        push(0, "https://example.com/0");
+        push(1, "https://example.com/1");
+        pool.save(&dir).unwrap();
+        // 前回の保存以降の変更
+        push(0, "https://example.com/2");
+        let poisoned = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let shard = pool.shard(1).unwrap();
+            let _guard = shard.write().unwrap();
+            panic!("poison shard 1");
+        }));
+        assert!(poisoned.is_err() && pool.indexes[1].load().is_poisoned());
+
+        let summary = pool.save(&dir).unwrap();
+        assert_eq!(summary.skipped, vec![1]);
+        assert_eq!(summary.saved, (0..pool.indexes.len()).filter(|&i| i != 1).collect::<Vec<_>>());
+        // poison されたシャードは前回保存した内容のまま
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(loaded.indexes[0].load().read().unwrap().meta.len(), 2);
+        assert_eq!(loaded.indexes[1].load().read().unwrap().meta.len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_snippets_from_distant_sections() {
+        // 密集している方を優先
+        let matches = [0..2, 5..7, 100..102];
+        assert_eq!(snippet_windows(200, &matches, 1, 20).first(), Some(&(0..20)));
+        assert_eq!(snippet_windows(200, &matches, 3, 20), vec![0..20, 91..111]);
+        // マッチがなければ先頭
+        let head = snippet_windows(10, &[], 2, 20);
+        assert_eq!((head.len(), head.first()), (1, Some(&(0..10))));
+
+        let dir = test_dir("snippets");
+        let mut pool = IndexPool::new(&dir);
+        pool.score_floor = f64::NEG_INFINITY;
+        let filler = "あ".repeat(100);
+        let mut meta = test_meta("https://example.com/1");
+        meta.description = format!("東京の話{}大阪から東京へ{}", filler, filler).into();
+        meta.desc_tokens = Some(vec![
+            TokenSpan { token: "東京".into(), start: 0, len: 2 },
+            TokenSpan { token: "大阪".into(), start: 104, len: 2 },
+            TokenSpan { token: "東京".into(), start: 108, len: 2 },
+        ]);
+        pool.add_document(&TokenFrequency::from(&["東京", "大阪"][..]), meta);
+        let query = ["東京"];
+        let scored = pool.per_similarity(&TokenFrequency::from(&query[..]), &SimilarityAlgorithm::CosineSimilarity);
+        let mut results = pool.generate_results(pool.sort_by_score(scored), 0..10, Tags::new(0), false, Tags::new(0));
+        pool.fill_snippets(&mut results, &query, 2, &SearchReads::default());
+        let snippets = results[0].snippets.clone().unwrap();
+        assert_eq!(snippets.len(), 2);
+        assert!(snippets[0].starts_with("東京の話"));
+        assert!(snippets[1].contains("大阪から東京へ"));
+        assert!(snippets.iter().all(|s| s.chars().count() == SNIPPET_WINDOW));
+        pool.fill_snippets(&mut results, &query, 1, &SearchReads::default());
+        assert_eq!(results[0].snippets.as_ref().unwrap().len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_collapse_by_host() {
+        assert_eq!(url_host("https://User@Example.COM:8080/a?b#c"), "example.com");
+        assert_eq!(url_host("http://[::1]:3000/"), "::1");
+        assert_eq!(url_host("example.com/path"), "example.com");
+
+        let dir = test_dir("collapse");
+        let pool = IndexPool::new(&dir);
+        // a.example の 5 ページ、b.example の 2 ページ、c.example の 1 ページ (語の数でスコアの順を決める)
+        let docs: [(&str, &[&str]); 8] = [
+            ("https://a.example/1", &["rust", "rust", "rust", "rust"]),
+            ("https://A.example/2", &["rust", "rust", "rust"]),
+            ("https://b.example/1", &["rust", "rust", "rust", "web"]),
+            ("https://a.example/3", &["rust", "rust", "web"]),
+            ("https://b.example/2", &["rust", "web"]),
+            ("https://a.example/4", &["rust", "web", "web"]),
+            ("https://c.example/1", &["rust", "web", "web", "web"]),
+            ("https://a.example/5", &["rust", "web", "web", "web", "web"]),
+        ];
+        add_test_docs(&pool, &docs);
+        let sorted = || pool.sort_by_score(pool.per_similarity(&TokenFrequency::from(&["rust"][..]), &SimilarityAlgorithm::CosineSimilarity));
+        let urls = |entries: &[ResEntry]| entries.iter().map(|e| e.url.to_string()).collect::<Vec<_>>();
+        let plain = pool.generate_results(sorted(), 0..10, Tags::new(0), false, Tags::new(0));
+        assert_eq!(plain.len(), 8);
+
+        let options = ResultOptions { collapse_by_host: true, ..Default::default() };
+        let collapsed = pool.generate_results_with(sorted(), 0..10, Tags::new(0), false, Tags::new(0), options);
+        // ホストごとに最上位が代表、順番は代表のスコア順
+        let hosts: Vec<String> = collapsed.iter().map(|e| url_host(&e.url)).collect();
+        assert_eq!(hosts, vec!["a.example", "b.example", "c.example"]);
+        for primary in &collapsed {
+            let host = url_host(&primary.url);
+            let expected: Vec<String> = urls(&plain).into_iter().filter(|u| url_host(u) == host).collect();
+            assert_eq!(primary.url.as_ref(), expected[0]);
+            // others は同じホストの続きをスコア順に上限まで
+            let others = primary.others.as_ref().unwrap();
+            let take = (expected.len() - 1).min(MAX_COLLAPSED_OTHERS);
+            assert_eq!(urls(others), expected[1..1 + take].to_vec());
+            assert!(others.iter().all(|o| o.others.is_none()));
+        }
+        // range はまとめた後の代表に対して
+        let second = pool.generate_results_with(sorted(), 1..2, Tags::new(0), false, Tags::new(0), options);
+        assert_eq!(urls(&second), vec![collapsed[1].url.to_string()]);
+        assert_eq!(second[0].others.as_ref().unwrap().len(), 1);
+        // total はまとめた後の代表の件数 (range に関係なく、除外した URL のホストは数えない)
+        let (page, total) = pool.generate_collapsed(&sorted(), 2..3, Tags::new(0), false, Tags::new(0), options);
+        assert_eq!((urls(&page), total), (vec!["https://c.example/1".to_string()], 3));
+        let exclude = vec!["c.example".to_string()];
+        let (page, total) = pool.generate_collapsed(&sorted(), 2..3, Tags::new(0), false, Tags::new(0), ResultOptions { exclude_urls: &exclude, ..options });
+        assert!(page.is_empty() && total == 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fresh_meta() {
+        let dir = test_dir("fresh-meta");
+        let pool = IndexPool::new(&dir);
+        let now = Utc::now();
+        for (url, minutes) in [("https://example.com/recent", 10), ("https://example.com/stale", 120)] {
+            let mut meta = test_meta(url);
+            meta.time = now - chrono::Duration::minutes(minutes);
+            meta.set_aliases(&[format!("{}/alias", url)]);
+            pool.add_document(&TokenFrequency::from(&["rust"][..]), meta);
+        }
+        let hour = Duration::from_secs(3600);
+        // 最近登録したものはスキップ (別名でも)、古いものと未登録は更新する
+        let recent = pool.fresh_meta("https://example.com/recent", hour, now).unwrap();
+        assert_eq!(recent.url.as_ref(), "https://example.com/recent");
+        assert!(pool.fresh_meta("https://example.com/recent/alias", hour, now).is_some());
+        assert!(pool.fresh_meta("https://example.com/stale", hour, now).is_none());
+        assert!(pool.fresh_meta("https://example.com/missing", hour, now).is_none());
+        assert!(pool.fresh_meta("https://example.com/recent", Duration::ZERO, now).is_none());
+        // 時計が戻っても (time が未来) 新しいものとして扱う
+        assert!(pool.fresh_meta("https://example.com/stale", hour, now - chrono::Duration::hours(3)).is_some());
+
+        // canonical で保存したページは /add と同じくリクエストの URL を別名にしておけば引ける (canonical 自身は別名にしない)
+        let requested = "https://example.com/canonical?utm_source=feed";
+        let mut meta = test_meta("https://example.com/canonical");
+        meta.set_aliases(&["https://example.com/canonical", requested]);
+        assert_eq!(meta.aliases, vec![Box::from(requested)]);
+        pool.add_document(&TokenFrequency::from(&["rust"][..]), meta);
+        assert_eq!(pool.fresh_meta(requested, hour, Utc::now()).unwrap().url.as_ref(), "https://example.com/canonical");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_indexed_status() {
+        let dir = test_dir("indexed-status");
+        let pool = IndexPool::new(&dir);
+        for i in 0..3 {
+            let mut meta = test_meta(&format!("https://example.com/{}", i));
+            if i == 1 {
+                meta.set_aliases(&["https://example.com/alias"]);
+            }
+            pool.add_document(&TokenFrequency::from(&["rust"][..]), meta);
+        }
+        assert!(pool.del_document("https://example.com/2"));
+        let urls = ["https://example.com/1", "https://example.com/missing", "https://example.com/0", "https://example.com/2", "https://example.com/alias"];
+        let status = pool.indexed_status(&urls);
+        assert_eq!(status.iter().map(|s| s.url.as_str()).collect::<Vec<_>>(), urls);
+        assert_eq!(status.iter().map(|s| s.indexed).collect::<Vec<_>>(), vec![true, false, true, false, true]);
+        for s in &status {
+            assert_eq!(s.time.is_some() && s.id.is_some() && s.index_id.is_some(), s.indexed);
+        }
+        // locate と同じ場所、別名は元のドキュメント
+        let located = pool.locate("https://example.com/1").unwrap();
+        assert_eq!((status[0].index_id.unwrap(), status[0].id.unwrap()), located);
+        assert_eq!((status[4].index_id, status[4].id), (status[0].index_id, status[0].id));
+        assert_eq!(status[0].time, pool.indexes[located.0].load().read().unwrap().meta_from_id(located.1).map(|m| m.time));
+        let json = serde_json::to_value(&status[1]).unwrap();
+        assert_eq!(json, serde_json::json!({ "url": "https://example.com/missing", "indexed": false }));
+        assert!(pool.indexed_status::<&str>(&[]).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fsck_reports_orphans() {
+        let dir = test_dir("fsck");
+        let pool = IndexPool::new(&dir);
+        for i in 0..4 {
+            pool.add_document(&TokenFrequency::from(&["rust", "search"][..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        // 削除の跡の meta は問題ではない
+        assert!(pool.del_document("https://example.com/0"));
+        let clean = pool.fsck();
+        assert!(clean.ok, "{:?}", clean);
+        assert_eq!((clean.documents, clean.counter, clean.corpus.doc_num), (3, 3, 3));
+        assert_eq!(clean.shards.iter().map(|s| s.deleted_meta).sum::<usize>(), 1);
+
+        // meta のないドキュメント、url_map の抜けと余分なキー、コーパスのずれを作る
+        let (shard_id, doc_id) = pool.locate("https://example.com/1").unwrap();
+        {
+            let shard = pool.shard(shard_id).unwrap();
+            let mut idx = shard.write().unwrap();
+            idx.vectorizer.add_doc(999, &TokenFrequency::from(&["orphan"][..]));
+        }
+        {
+            let mut map = pool.url_map.write().unwrap();
+            map.remove(&url_hash("https://example.com/2"));
+            map.insert(url_hash("https://example.com/gone"), (shard_id, doc_id));
+        }
+        pool.corpus.load().token_counts.insert("rust".into(), 10);
+        pool.corpus.load().token_counts.remove("search");
+
+        let report = pool.fsck();
+        assert!(!report.ok);
+        let shard = &report.shards[shard_id];
+        assert_eq!(shard.docs_without_meta, vec![999]);
+        assert!(!shard.is_ok());
+        assert_eq!(report.documents, 4);
+        assert_eq!(report.counter, 3);
+        assert_eq!(report.url_map.dangling, 1);
+        assert_eq!(report.url_map.missing, vec!["https://example.com/2"]);
+        assert_eq!(report.corpus.mismatched_counts, 1);
+        assert_eq!(report.corpus.missing_tokens, vec!["search"]);
+        // 何も直さない
+        assert_eq!(pool.fsck(), report);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_min_max_normalize() {
+        let bounds = score_bounds([2.0, f64::NAN, 4.0, 3.0].into_iter());
+        assert_eq!(bounds, (2.0, 4.0));
+        assert_eq!(min_max_normalize(3.0, bounds), 0.5);
+        assert_eq!(min_max_normalize(f64::NAN, bounds), 0.0);
+        // 1 件だけ / 全て同じなら 1
+        let mut single = vec![ScoredEntry { index_id: 0, key: 0, length: 1, score: 0.3 }];
+        normalize_scores(&mut single);
+        assert_eq!(single[0].score, 1.0);
+    }
+
+    #[test]
+    fn test_fill_matched_tokens() {
+        let dir = test_dir("matched-tokens");
+        let mut pool = IndexPool::new(&dir);
+        pool.score_floor = f64::NEG_INFINITY;
+        let mut stored = test_meta("https://example.com/1");
+        stored.title = "東京 天気".into();
+        stored.description = "東京の天気は晴れ".into();
+        stored.desc_tokens = Some(vec![
+            TokenSpan { token: "東京".into(), start: 0, len: 2 },
+            TokenSpan { token: "天気".into(), start: 3, len: 2 },
+            TokenSpan { token: "晴れる".into(), start: 6, len: 2 },
+        ]);
+        pool.add_document(&TokenFrequency::from(&["東京", "天気", "晴れる"][..]), stored);
+        let mut plain = test_meta("https://example.com/2");
+        plain.title = "大阪".into();
+        plain.description = "晴れる 大阪".into();
+        pool.add_document(&TokenFrequency::from(&["大阪", "晴れる"][..]), plain);
+        // 空白区切りの仮のトークナイザ (呼び出し回数と渡されたテキストを記録)
+        let calls = std::cell::RefCell::new(Vec::new());
+        let tokenize = |texts: &[(&str, Option<&str>)]| -> Result<Vec<Vec<String>>, SudachiError> {
+            calls.borrow_mut().push(texts.iter().map(|(t, _)| t.to_string()).collect::<Vec<_>>());
+            Ok(texts.iter().map(|(t, _)| t.split_whitespace().map(str::to_string).collect()).collect())
+        };
+        let query = ["晴れる", "東京", "晴れる"];
+        let scored = pool.per_similarity(&TokenFrequency::from(&query[..]), &SimilarityAlgorithm::CosineSimilarity);
+        let mut results = pool.generate_results(pool.sort_by_score(scored), 0..10, Tags::new(0), false, Tags::new(0));
+        pool.fill_matched_tokens(&mut results, &query, &SearchReads::default(), tokenize);
+        let find = |url: &str| results.iter().find(|r| r.url.as_ref() == url).unwrap();
+        // desc_tokens があればそれを使う (正規化形で一致)、クエリの順で重複なし
+        let first = find("https://example.com/1");
+        assert_eq!(first.matched_title.as_deref(), Some(&["東京".to_string()][..]));
+        assert_eq!(first.matched_desc.as_deref(), Some(&["晴れる".to_string(), "東京".to_string()][..]));
+        // desc_tokens がなければ description をトークン化する
+        let second = find("https://example.com/2");
+        assert_eq!(second.matched_title.as_deref(), Some(&[][..]));
+        assert_eq!(second.matched_desc.as_deref(), Some(&["晴れる".to_string()][..]));
+        // まとめて 1 回、desc_tokens のある description は渡さない
+        let calls = calls.into_inner();
+        assert_eq!(calls.len(), 1);
+        assert!(!calls[0].iter().any(|t| t == "東京の天気は晴れ"));
+        assert_eq!(calls[0].len(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// sync_all の回数を数える書き込み先
+    #[derive(Default)]
+    struct CountingFile {
+        data: Vec<u8>,
+        syncs: std::cell::Cell<usize>,
+    }
+
+    impl Write for CountingFile {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.data.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SyncWrite for CountingFile {
+        fn sync_all(&self) -> std::io::Result<()> {
+            self.syncs.set(self.syncs.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_durable_save_syncs() {
+        let value = vec![1u64, 2, 3];
+        let options = SaveOptions { durable: false, buffer_capacity: 4, ..Default::default() };
+        let file = write_bincode_to(CountingFile::default(), &value, options).unwrap();
+        assert_eq!(file.syncs.get(), 0);
+        // バッファより大きくても全て書き出される
+        assert_eq!(read_bincode::<Vec<u64>>(std::path::Path::new("test"), &file.data).unwrap(), value);
+
+        let file = write_bincode_to(CountingFile::default(), &value, SaveOptions { durable: true, ..options }).unwrap();
+        assert_eq!(file.syncs.get(), 1);
+        assert_eq!(read_bincode::<Vec<u64>>(std::path::Path::new("test"), &file.data).unwrap(), value);
+
+        // 実ファイルでも保存して読み戻せる
+        let dir = test_dir("durable-save");
+        let mut pool = IndexPool::new(&dir);
+        pool.save_options = SaveOptions { durable: true, buffer_capacity: 64 * 1024, ..Default::default() };
+        assert!(pool.save(&dir).unwrap().skipped.is_empty());
+        assert!(IndexPool::load(&dir, None).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_del_document_meta_modes() {
+        for remove_meta in [false, true] {
+            let dir = test_dir(&format!("del-meta-{}", remove_meta));
+            let mut pool = IndexPool::new(&dir);
+            pool.remove_meta_on_delete = remove_meta;
+            let urls: Vec<String> = (0..3).map(|i| format!("https://example.com/{}", i)).collect();
+            {
+                let shard = pool.shard(0).unwrap();
+                let mut idx = shard.write().unwrap();
+                for url in &urls {
+                    let doc_id = idx.generate_next_id();
+                    idx.vectorizer.add_doc(doc_id, &TokenFrequency::from(&["rust"][..]));
+                    let mut meta = test_meta(url);
+                    meta.id = doc_id;
+                    idx.meta.push(meta);
+                }
+            }
+            {
+                let mut map = pool.url_map.write().unwrap();
+                for (doc_id, url) in urls.iter().enumerate() {
+                    map.insert(url_hash(url), (0, doc_id));
+                }
+            }
+            assert!(pool.del_document(&urls[0]));
+            // 末尾は採番用に残る
+            assert!(pool.del_document(&urls[2]));
+            let shard = pool.shard(0).unwrap();
+            let idx = shard.read().unwrap();
+            let ids: Vec<usize> = idx.meta.iter().map(|m| m.id).collect();
+            if remove_meta {
+                assert_eq!(ids, vec![1, 2]);
+            } else {
+                assert_eq!(ids, vec![0, 1, 2]);
+            }
+            // 残ったドキュメントは引き続き引ける
+            assert_eq!(idx.meta_from_id(1).unwrap().url.as_ref(), urls[1]);
+            assert_eq!(idx.generate_next_id(), 3);
+            drop(idx);
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn test_save_every_interval() {
+        let dir = test_dir("save-interval");
+        let pool = IndexPool::new(&dir);
+        let url = "https://example.com/interval";
+        // 新規登録 1 回 + 更新で 3 周期分
+        let total = SAVE_FILE_INTERVAL * 3;
+        for n in 1..=total {
+            let tokens: &[&str] = if n.is_multiple_of(2) { &["東京"] } else { &["天気"] };
+            let before = pool.auto_saves.load(Ordering::SeqCst);
+            assert_eq!(pool.add_document(&TokenFrequency::from(tokens), test_meta(url)), Some(n == 1));
+            let saved = pool.auto_saves.load(Ordering::SeqCst) - before;
+            // SAVE_FILE_INTERVAL 回目ごとにちょうど 1 回
+            assert_eq!(saved, u64::from(n.is_multiple_of(SAVE_FILE_INTERVAL)), "update {}", n);
+        }
+        assert_eq!(pool.auto_saves.load(Ordering::SeqCst), 3);
+        let (shard_id, _) = pool.locate(url).unwrap();
+        assert_eq!(pool.indexes[shard_id].load().read().unwrap().update_count, total);
+        // 削除も 1 回の更新として数える
+        for _ in 0..SAVE_FILE_INTERVAL - 1 {
+            pool.add_document(&TokenFrequency::from(&["東京"][..]), test_meta(url));
+        }
+        assert!(pool.del_document(url));
+        assert_eq!(pool.auto_saves.load(Ordering::SeqCst), 4);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_generation_increases() {
+        let dir = test_dir("generation");
+        let pool = IndexPool::new(&dir);
+        let mut last = pool.generation.load(Ordering::SeqCst);
+        let mut check = |pool: &IndexPool| {
+            let current = pool.generation.load(Ordering::SeqCst);
+            assert!(current > last, "{} > {}", current, last);
+            last = current;
+        };
+        pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta("https://example.com/1"));
+        check(&pool);
+        pool.add_document(&TokenFrequency::from(&["search"][..]), test_meta("https://example.com/1"));
+        check(&pool);
+        assert!(pool.del_document("https://example.com/1"));
+        check(&pool);
+        pool.prune_corpus().unwrap();
+        check(&pool);
+        // 失敗した削除では進まない
+        let before = pool.generation.load(Ordering::SeqCst);
+        assert!(!pool.del_document("https://example.com/missing"));
+        assert_eq!(pool.generation.load(Ordering::SeqCst), before);
+
+        // 世代番号を保存せずに再起動しても戻らない
+        pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta("https://example.com/2"));
+        let before_restart = pool.generation.load(Ordering::SeqCst);
+        for i in 0..DEFAULT_INDEX_SHARD_NUM {
+            pool.save_shard(i, &dir).unwrap();
+        }
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        assert!(loaded.generation.load(Ordering::SeqCst) >= before_restart);
+        assert!(loaded.bump_generation() > before_restart);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_prune_corpus() {
+        let dir = test_dir("prune-corpus");
+        let pool = IndexPool::new(&dir);
+        pool.add_document(&TokenFrequency::from(&["common", "rare"][..]), test_meta("https://example.com/1"));
+        pool.add_document(&TokenFrequency::from(&["common"][..]), test_meta("https://example.com/2"));
+        pool.save(&dir).unwrap();
+        let corpus_path = std::path::Path::new(&dir).join(DEFAULT_CORPUS_FILE);
+        let before_delete = std::fs::read(&corpus_path).unwrap();
+        assert!(pool.del_document("https://example.com/1"));
+        pool.save(&dir).unwrap();
+        // シャードは保存できたがコーパスの書き込みが残らなかった (書き込み中に落ちたなど) 状態
+        std::fs::write(&corpus_path, &before_delete).unwrap();
+        let pool = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(pool.corpus.load().get_token_count("rare"), 1);
+        assert_eq!(pool.corpus.load().get_token_count("common"), 2);
+        assert_eq!(pool.corpus.load().get_doc_num(), 2);
+
+        let stats = pool.prune_corpus().unwrap();
+        assert_eq!(stats.removed, 1);
+        assert_eq!(stats.corrected, 1);
+        assert_eq!(pool.corpus.load().get_token_count("rare"), 0);
+        assert_eq!(pool.corpus.load().get_token_count("common"), 1);
+        assert_eq!(pool.corpus.load().get_doc_num(), 1);
+        // IDF も再計算されている
+        for index in &pool.shards() {
+            let idx = index.read().unwrap();
+            assert_eq!(idx.vectorizer.idf.latest_entropy, pool.corpus.load().get_gen_num());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_prune_corpus_swaps_shards() {
+        let dir = test_dir("prune-swap");
+        let pool = IndexPool::new(&dir);
+        pool.add_document(&TokenFrequency::from(&["common"][..]), test_meta("https://example.com/1"));
+        let old_corpus = pool.corpus.load_full();
+        old_corpus.token_counts.insert("stale".into(), 3);
+
+        // 検索中のシャードの read lock を持ったままでも作り直しは終わる
+        let (shard_id, _) = pool.locate("https://example.com/1").unwrap();
+        let old_shard = pool.shard(shard_id).unwrap();
+        let reading = old_shard.read().unwrap();
+        pool.prune_corpus().unwrap();
+        // 読んでいた側は前のシャードとコーパスのまま (入れ替え前に書き換えない)
+        assert!(Arc::ptr_eq(&reading.vectorizer.corpus_ref, &old_corpus));
+        assert_eq!(old_corpus.get_token_count("stale"), 3);
+        drop(reading);
+
+        // 新しいシャードは作り直したコーパスを使う
+        let new_corpus = pool.corpus.load_full();
+        assert!(!Arc::ptr_eq(&old_corpus, &new_corpus));
+        assert_eq!(new_corpus.get_token_count("stale"), 0);
+        assert!(!Arc::ptr_eq(&old_shard, &pool.shard(shard_id).unwrap()));
+        for index in &pool.shards() {
+            assert!(Arc::ptr_eq(&index.read().unwrap().vectorizer.corpus_ref, &new_corpus));
+        }
+        // 以降の追加は新しいコーパスに入る
+        pool.add_document(&TokenFrequency::from(&["fresh"][..]), test_meta("https://example.com/2"));
+        assert_eq!(pool.corpus.load().get_token_count("fresh"), 1);
+        assert_eq!(old_corpus.get_token_count("fresh"), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_recalc_sizes() {
+        let dir = test_dir("recalc-sizes");
+        let pool = IndexPool::new(&dir);
+        // 間隔ごとの計算を待たないと 0 のまま
+        pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta("https://example.com/0"));
+        assert!(pool.shards().iter().all(|i| i.read().unwrap().bin_size() == 0));
+        let before = pool.recalc_sizes();
+        assert_eq!(before.len(), pool.indexes.len());
+        assert_eq!(before.iter().map(|s| s.documents).sum::<usize>(), 1);
+
+        for i in 1..20 {
+            pool.add_document(&TokenFrequency::from(&["rust", "search", "engine"][..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        let after = pool.recalc_sizes();
+        assert_eq!(after.iter().map(|s| s.documents).sum::<usize>(), 20);
+        let total = |sizes: &[ShardBinSizes]| sizes.iter().map(|s| s.vectorizer_bin_size + s.meta_bin_size).sum::<u64>();
+        assert!(total(&after) > total(&before));
+        // 計算した値がシャードに記録されている
+        for size in &after {
+            let shard = pool.shard(size.id).unwrap();
+            let idx = shard.read().unwrap();
+            assert_eq!((idx.vectorizer_bin_size, idx.meta_bin_size), (size.vectorizer_bin_size, size.meta_bin_size));
+            assert!(!size.over_limit);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_search_during_rebuild() {
+        let dir = test_dir("search-during-rebuild");
+        let pool = IndexPool::new(&dir);
+        for i in 0..200 {
+            pool.add_document(&TokenFrequency::from(&["rust", "search"][..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        let query = TokenFrequency::from(&["rust"][..]);
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let searcher = s.spawn(|| {
+                let mut searches = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let reads = SearchReads::default();
+                    let scored = pool.per_similarity_with_skips(&query, &SimilarityAlgorithm::BM25(1.2, 0.75), &reads);
+                    // 作り直しの間も入れ替えの瞬間も、欠けずに全件返る
+                    assert!(reads.skipped().is_empty(), "skipped {:?}", reads.skipped());
+                    assert_eq!(scored.len(), 200);
+                    searches += 1;
+                }
+                searches
+            });
+            for _ in 0..10 {
+                pool.prune_corpus().unwrap();
+                pool.rebuild();
+                pool.compact_meta();
+            }
+            stop.store(true, Ordering::SeqCst);
+            assert!(searcher.join().unwrap() > 0);
+        });
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 200..220 {
+                    pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta(&format!("https://example.com/{}", i)));
+                }
+            });
+            for _ in 0..10 {
+                pool.prune_corpus().unwrap();
+            }
+        });
+        // 作り直しと並行した追加も消えない
+        assert_eq!(pool.per_similarity(&query, &SimilarityAlgorithm::BM25(1.2, 0.75)).len(), 220);
+        assert_eq!(pool.counter.load(Ordering::SeqCst), 220);
+        assert_eq!(pool.corpus.load().get_token_count("rust"), 220);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_per_similarity_top_k() {
+        let dir = test_dir("top-k");
+        let pool = IndexPool::new(&dir);
+        for i in 0..100 {
+            // rust の出現回数を変えてスコアに差をつける
+            let mut tokens = vec!["rust"; i % 7 + 1];
+            tokens.extend(std::iter::repeat_n("other", 10));
+            pool.add_document(&TokenFrequency::from(&tokens[..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        let query = TokenFrequency::from(&["rust"][..]);
+        let algo = SimilarityAlgorithm::CosineSimilarity;
+        let full = pool.sort_by_score(pool.per_similarity(&query, &algo));
+        let k = 5;
+        let top = pool.per_similarity_top_k(&query, &algo, k);
+        // シャードごとに k 件まで
+        let mut per_shard: HashMap<usize, usize> = HashMap::new();
+        for e in &top {
+            *per_shard.entry(e.index_id).or_insert(0) += 1;
+        }
+        assert!(per_shard.values().all(|&n| n <= k));
+        assert!(top.len() < full.len());
+        // 全体の上位 k 件のスコアは一致する
+        let top = pool.sort_by_score(top);
+        let full_scores: Vec<f64> = full.iter().take(k).map(|e| e.score).collect();
+        let top_scores: Vec<f64> = top.iter().take(k).map(|e| e.score).collect();
+        assert_eq!(full_scores, top_scores);
+        assert!(pool.per_similarity_top_k(&query, &algo, 0).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_save_shard_skips_unchanged_corpus() {
+        let dir = test_dir("corpus-skip");
+        let pool = IndexPool::new(&dir);
+        pool.add_document(&TokenFrequency::from(&["東京", "天気"][..]), test_meta("https://example.com/1"));
+        pool.save_shard(0, &dir).unwrap();
+        let corpus_path = std::path::Path::new(&dir).join(DEFAULT_CORPUS_FILE);
+        assert!(corpus_path.exists());
+
+        // 既知の語だけなら書き直さない
+        std::fs::remove_file(&corpus_path).unwrap();
+        pool.add_document(&TokenFrequency::from(&["天気"][..]), test_meta("https://example.com/2"));
+        pool.save_shard(0, &dir).unwrap();
+        assert!(!corpus_path.exists());
+
+        // 新しい語が増えたら書き直す
+        pool.add_document(&TokenFrequency::from(&["大阪"][..]), test_meta("https://example.com/3"));
+        pool.save_shard(0, &dir).unwrap();
+        assert!(corpus_path.exists());
+        let saved: Corpus = read_bincode(&corpus_path, &std::fs::read(&corpus_path).unwrap()).unwrap();
+        assert!(saved.token_counts.contains_key("大阪"));
+
+        // 削除で語が消えたときも書き直す
+        std::fs::remove_file(&corpus_path).unwrap();
+        assert!(pool.del_document("https://example.com/3"));
+        pool.save_shard(0, &dir).unwrap();
+        let saved: Corpus = read_bincode(&corpus_path, &std::fs::read(&corpus_path).unwrap()).unwrap();
+        assert!(!saved.token_counts.contains_key("大阪"));
+        // 一時ファイルは残らない
+        let leftovers: Vec<_> = std::fs::read_dir(&dir).unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "tmp"))
+            .collect();
+        assert!(leftovers.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_idf() {
+        let dir = test_dir("idf");
+        let pool = IndexPool::new(&dir);
+        pool.add_document(&TokenFrequency::from(&["東京", "天気"][..]), test_meta("https://example.com/1"));
+        pool.add_document(&TokenFrequency::from(&["大阪", "天気"][..]), test_meta("https://example.com/2"));
+        pool.add_document(&TokenFrequency::from(&["大阪", "観光"][..]), test_meta("https://example.com/3"));
+        let idf = pool.idf_map(&["東京", "天気", "京都"]);
+        assert_eq!(idf["東京"], Some(3.0 / 2.0));
+        assert_eq!(idf["天気"], Some(1.0));
+        // 語彙にない語
+        assert_eq!(idf["京都"], None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_generate_results_backfill() {
+        let dir = test_dir("backfill");
+        let pool = IndexPool::new(&dir);
+        for i in 0..4 {
+            pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        let query = TokenFrequency::from(&["rust"][..]);
+        let scored = || pool.sort_by_score(pool.per_similarity(&query, &SimilarityAlgorithm::CosineSimilarity));
+        // 先頭の結果の meta だけを消す (ベクトルは残る)
+        let first = scored().remove(0);
+        pool.indexes[first.index_id].load().write().unwrap().meta.retain(|m| m.id != first.key);
+
+        let results = pool.generate_results(scored(), 0..2, Tags::new(0), false, Tags::new(0));
+        assert_eq!(results.len(), 1);
+        let results = pool.generate_results_backfilled(scored(), 0..2, Tags::new(0), false, Tags::new(0), true);
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| (r.index_id, r.id) != (first.index_id, first.key)));
+        // 補える結果がなければ足りないまま
+        assert_eq!(pool.generate_results_backfilled(scored(), 0..10, Tags::new(0), false, Tags::new(0), true).len(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// lite の有無での結果生成の比較 (cargo test --release -- --ignored bench_lite_results --nocapture)
+    #[ignore]
+    #[test]
+    fn bench_lite_results() {
+        let dir = test_dir("bench-lite");
+        let pool = IndexPool::new(&dir);
+        for i in 0..1000 {
+            let mut meta = test_meta(&format!("https://example.com/{}", i));
+            meta.description = "説明文".repeat(34).chars().take(100).collect::<String>().into();
+            meta.favicon = Some(format!("https://example.com/{}/favicon.ico", i).into());
+            pool.add_document(&TokenFrequency::from(&["rust"][..]), meta);
+        }
+        let scored = || pool.sort_by_score(pool.per_similarity(&TokenFrequency::from(&["rust"][..]), &SimilarityAlgorithm::CosineSimilarity));
+        for lite in [false, true] {
+            let options = ResultOptions { lite, ..Default::default() };
+            let mut elapsed = std::time::Duration::ZERO;
+            let mut bytes = 0;
+            for _ in 0..50 {
+                let scored = scored();
+                let start = std::time::Instant::now();
+                let results = pool.generate_results_with(scored, 0..1000, Tags::new(0), false, Tags::new(0), options);
+                elapsed += start.elapsed();
+                bytes = results.iter().map(|r| r.descriptions.len() + r.favicon.as_ref().map_or(0, |f| f.len())).sum::<usize>();
+                std::hint::black_box(results);
+            }
+            println!("lite={}: {:?} per 1000 results, descriptions + favicon {} bytes", lite, elapsed / 50, bytes);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_oversized_shards_avoided() {
+        assert_eq!(choose_shard(&[(0, 10), (1, 5), (2, 5)], 100), Some(2));
+        assert_eq!(choose_shard(&[(0, 10), (1, 200)], 100), Some(0));
+        assert_eq!(choose_shard(&[(0, 100), (1, 200)], 100), None);
+
+        let dir = test_dir("max-shard");
+        let mut pool = IndexPool::new(&dir);
+        pool.max_shard_bytes = 1000;
+        // シャード 3 以外を上限超えに
+        for (i, index) in pool.shards().iter().enumerate() {
+            if i != 3 {
+                index.write().unwrap().vectorizer_bin_size = 5000;
+            }
+        }
+        for i in 0..3 {
+            assert_eq!(pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta(&format!("https://example.com/{}", i))), Some(true));
+            assert_eq!(pool.locate(&format!("https://example.com/{}", i)).unwrap().0, 3);
+        }
+        // 全て上限超えなら新規は入れない (既存の更新はできる)
+        pool.indexes[3].load().write().unwrap().meta_bin_size = 1000;
+        assert_eq!(pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta("https://example.com/new")), None);
+        assert!(pool.locate("https://example.com/new").is_none());
+        assert_eq!(pool.add_document(&TokenFrequency::from(&["go"][..]), test_meta("https://example.com/0")), Some(false));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_clear() {
+        let dir = test_dir("clear");
+        let mut pool = IndexPool::new(&dir);
+        pool.trigram_index = true;
+        for i in 0..5 {
+            pool.add_document_with_text(&TokenFrequency::from(&["tokyo", "weather"][..]), test_meta(&format!("https://example.com/{}", i)), "body");
+        }
+        pool.save(&dir).unwrap();
+        let generation = pool.generation.load(Ordering::SeqCst);
+
+        let summary = pool.clear(&dir).unwrap();
+        assert_eq!(summary.removed, 5);
+        // /status の documents
+        assert_eq!(pool.counter.load(Ordering::SeqCst), 0);
+        assert_eq!(pool.corpus.load().vocab_size(), 0);
+        assert_eq!(pool.corpus.load().get_doc_num(), 0);
+        assert!(pool.generation.load(Ordering::SeqCst) > generation);
+        // 全シャードが新しい空のコーパスを使う
+        let corpus = pool.corpus.load_full();
+        assert!(pool.shards().iter().all(|index| Arc::ptr_eq(&index.read().unwrap().vectorizer.corpus_ref, &corpus)));
+        assert!(pool.locate("https://example.com/0").is_none());
+        assert!(pool.per_similarity(&TokenFrequency::from(&["tokyo"][..]), &SimilarityAlgorithm::CosineSimilarity).is_empty());
+        let path = std::path::Path::new(&dir);
+        assert!(!path.join("0.index").exists() && !path.join(URL_MAP_FILE).exists() && !path.join(DEFAULT_CORPUS_FILE).exists());
+        // シャード数の範囲外の id のファイルも消す
+        let stray = path.join(format!("{}.index", DEFAULT_INDEX_SHARD_NUM + 4));
+        std::fs::write(&stray, b"old").unwrap();
+        assert_eq!(pool.layout.shard_files(&dir, "index"), vec![stray.clone()]);
+        assert_eq!(pool.clear(&dir).unwrap().deleted_files, 1);
+        assert!(!stray.exists());
+
+        // 消した後も登録でき、読み直しても消した分は戻らない
+        pool.add_document(&TokenFrequency::from(&["osaka"][..]), test_meta("https://example.com/new"));
+        pool.save(&dir).unwrap();
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(loaded.counter.load(Ordering::SeqCst), 1);
+        assert_eq!(loaded.corpus.load().vocab_size(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_split_shard() {
+        let dir = test_dir("split-shard");
+        let mut pool = IndexPool::new(&dir);
+        pool.trigram_index = true;
+        let words = ["rust", "go", "python", "tokyo", "osaka", "weather"];
+        for i in 0..10 {
+            let mut tokens = vec![words[i % words.len()]; i % 3 + 1];
+            tokens.push(words[(i + 1) % words.len()]);
+            tokens.push("common");
+            pool.add_document_with_text(&TokenFrequency::from(&tokens[..]), test_meta(&format!("https://example.com/{}", i)), &format!("body {}", i));
+        }
+        let (shard_id, _) = pool.locate("https://example.com/0").unwrap();
+        let search = |pool: &IndexPool, q: &[&str]| -> Vec<(Box<str>, f64)> {
+            let scored = pool.sort_by_score(pool.per_similarity(&TokenFrequency::from(q), &SimilarityAlgorithm::CosineSimilarity));
+            pool.generate_results(scored, 0..100, Tags::new(0), false, Tags::new(0)).into_iter().map(|e| (e.url, e.score)).collect()
+        };
+        let queries: [&[&str]; 3] = [&["rust"], &["tokyo", "weather"], &["common"]];
+        let before: Vec<_> = queries.iter().map(|q| search(&pool, q)).collect();
+        let corpus_before = pool.corpus.load().vocab_size();
+        pool.save(&dir).unwrap();
+
+        let summary = pool.split_shard(shard_id).unwrap();
+        assert_eq!(summary.source, shard_id);
+        assert_ne!(summary.target, shard_id);
+        assert_eq!(summary.moved, 5);
+        assert_eq!((summary.source_documents, summary.target_documents), (5, 5));
+        assert_eq!(pool.counter.load(Ordering::SeqCst), 10);
+        assert_eq!(pool.corpus.load().vocab_size(), corpus_before);
+        // 全てのドキュメントが引け、検索結果 (URL とスコア) も変わらない
+        for i in 0..10 {
+            assert!(pool.locate(&format!("https://example.com/{}", i)).is_some());
+        }
+        assert_eq!(pool.locate("https://example.com/9").unwrap().0, summary.target);
+        for (q, before) in queries.iter().zip(&before) {
+            let after = search(&pool, q);
+            let mut urls: Vec<_> = after.iter().map(|(u, _)| u.clone()).collect();
+            let mut before_urls: Vec<_> = before.iter().map(|(u, _)| u.clone()).collect();
+            urls.sort();
+            before_urls.sort();
+            assert_eq!(urls, before_urls);
+            for (url, score) in before {
+                let moved = after.iter().find(|(u, _)| u == url).unwrap().1;
+                assert!((moved - score).abs() < 1e-3, "{}: {} != {}", url, moved, score);
+            }
+        }
+        assert_eq!(pool.indexes[summary.target].load().read().unwrap().trigrams.len(), 5);
+
+        // split_shard が書き直した 2 つのシャードを読み直しても同じ
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(search(&loaded, &["rust"]), search(&pool, &["rust"]));
+        assert!(matches!(pool.split_shard(99).unwrap_err().downcast_ref::<SplitShardError>(), Some(SplitShardError::NotFound)));
+        let empty = (0..pool.indexes.len()).find(|id| pool.indexes[*id].load().read().unwrap().vectorizer.doc_num() == 0).unwrap();
+        assert!(matches!(pool.split_shard(empty).unwrap_err().downcast_ref::<SplitShardError>(), Some(SplitShardError::TooFewDocuments)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_split_shard_save_failure() {
+        let dir = test_dir("split-shard-save-failure");
+        let mut pool = IndexPool::new(&dir);
+        for i in 0..4 {
+            pool.add_document(&TokenFrequency::from(&["rust", "common"][..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        let (shard_id, _) = pool.locate("https://example.com/0").unwrap();
+        // ファイルをインデックスディレクトリにして保存を失敗させる
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = std::path::Path::new(&dir).join("not-a-dir");
+        std::fs::write(&file, b"").unwrap();
+        pool.index_dir = file.to_string_lossy().into_owned();
+
+        // 分割はメモリ上で済んでいるので Ok で、保存の失敗は warnings に載る
+        let summary = pool.split_shard(shard_id).unwrap();
+        assert_eq!(summary.moved, 2);
+        assert_eq!(summary.warnings.len(), 2, "{:?}", summary.warnings);
+        assert_eq!(pool.locate("https://example.com/3").unwrap().0, summary.target);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_merged_top_k() {
+        let dir = test_dir("merged-top-k");
+        let pool = IndexPool::new(&dir);
+        for i in 0..100 {
+            let mut tokens = vec!["rust"; i % 7 + 1];
+            tokens.extend(std::iter::repeat_n("other", 10));
+            pool.add_document(&TokenFrequency::from(&tokens[..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        let query = TokenFrequency::from(&["rust"][..]);
+        let algo = SimilarityAlgorithm::CosineSimilarity;
+        let k = 5;
+        let flat = pool.sort_by_score(pool.per_similarity_top_k(&query, &algo, k));
+        let (merged, matched) = pool.per_similarity_merged_top_k_with_skips(&query, &algo, k, &SearchReads::default());
+        // 全体の上位 k 件だけ、スコア順 (件数は上位に入らなかった分も数える)
+        assert_eq!(merged.len(), k);
+        assert_eq!(matched, 100);
+        assert_eq!(pool.per_similarity_top_k_with_skips(&query, &algo, k, &SearchReads::default()).1, 100);
+        let flat_scores: Vec<f64> = flat.iter().take(k).map(|e| e.score).collect();
+        let merged_scores: Vec<f64> = merged.iter().map(|e| e.score).collect();
+        assert_eq!(merged_scores, flat_scores);
+        assert!(pool.per_similarity_merged_top_k_with_skips(&query, &algo, 0, &SearchReads::default()).0.is_empty());
+
+        let entry = |score: f64, key: usize| ScoredEntry { score, key, length: 1, index_id: 0 };
+        let lists = || vec![vec![entry(0.9, 0), entry(0.5, 1)], vec![], vec![entry(0.7, 2)]];
+        let keys: Vec<usize> = merge_top_k(lists(), 10).iter().map(|e| e.key).collect();
+        assert_eq!(keys, vec![0, 2, 1]);
+        assert_eq!(merge_top_k(lists(), 2).len(), 2);
+        assert_eq!(ScoreMerge::parse("KWay"), Some(ScoreMerge::KWay));
+        assert_eq!(ScoreMerge::parse("heap"), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// SCORE_TOP_K の有無でスコア計算後に保持する件数とメモリの比較 (cargo test --release -- --ignored bench_score_top_k --nocapture)
+    /// シャードごとの Hits は vectorizer が作るのでどちらも同じで、違うのは結合した結果の Vec
+    #[ignore]
+    #[test]
+    fn bench_score_top_k() {
+        let dir = test_dir("bench-top-k");
+        let pool = IndexPool::new(&dir);
+        for i in 0..100_000 {
+            let mut tokens = vec!["rust"; i % 13 + 1];
+            tokens.extend(std::iter::repeat_n("other", i % 17 + 5));
+            pool.add_document(&TokenFrequency::from(&tokens[..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        let query = TokenFrequency::from(&["rust"][..]);
+        let algo = SimilarityAlgorithm::BM25(1.2, 0.75);
+        let entry_bytes = std::mem::size_of::<ScoredEntry>();
+        let start = std::time::Instant::now();
+        let mut all_bytes = 0;
+        let mut all_len = 0;
+        for _ in 0..10 {
+            let all = pool.sort_by_score(pool.per_similarity(&query, &algo));
+            all_len = all.len();
+            all_bytes = all.capacity() * entry_bytes;
+            std::hint::black_box(all);
+        }
+        println!("all: {:?} ({} entries, {} bytes)", start.elapsed() / 10, all_len, all_bytes);
+        for k in [10, 100, 1000] {
+            let start = std::time::Instant::now();
+            let mut top_bytes = 0;
+            let mut top_len = 0;
+            for _ in 0..10 {
+                let top = pool.sort_by_score(pool.per_similarity_top_k(&query, &algo, k));
+                top_len = top.len();
+                top_bytes = top.capacity() * entry_bytes;
+                std::hint::black_box(top);
+            }
+            println!("k={}: {:?} ({} entries, {} bytes)", k, start.elapsed() / 10, top_len, top_bytes);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// flat と k-way マージの比較 (cargo test --release -- --ignored bench_score_merge --nocapture)
+    #[ignore]
+    #[test]
+    fn bench_score_merge() {
+        let dir = test_dir("bench-merge");
+        let pool = IndexPool::new(&dir);
+        for i in 0..20000 {
+            let mut tokens = vec!["rust"; i % 13 + 1];
+            tokens.extend(std::iter::repeat_n("other", i % 17 + 5));
+            pool.add_document(&TokenFrequency::from(&tokens[..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        let query = TokenFrequency::from(&["rust"][..]);
+        let algo = SimilarityAlgorithm::BM25(1.2, 0.75);
+        let entry_bytes = std::mem::size_of::<ScoredEntry>();
+        for k in [10, 100, 1000] {
+            let start = std::time::Instant::now();
+            let mut peak_flat = 0;
+            for _ in 0..20 {
+                let flat = pool.per_similarity_top_k(&query, &algo, k);
+                peak_flat = peak_flat.max(flat.len());
+                std::hint::black_box(pool.sort_by_score(flat));
+            }
+            let flat_time = start.elapsed() / 20;
+            let start = std::time::Instant::now();
+            let mut peak_merged = 0;
+            for _ in 0..20 {
+                let (merged, _) = pool.per_similarity_merged_top_k_with_skips(&query, &algo, k, &SearchReads::default());
+                peak_merged = peak_merged.max(merged.len());
+                std::hint::black_box(merged);
+            }
+            let merged_time = start.elapsed() / 20;
+            println!(
+                "k={}: flat {:?} ({} entries, {} bytes), kway {:?} ({} entries, {} bytes)",
+                k, flat_time, peak_flat, peak_flat * entry_bytes, merged_time, peak_merged, peak_merged * entry_bytes,
+            );
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_custom_tag_registry() {
+        let registry = TagRegistry::from_json(r#"{"wiki": 0, "recipe": 8, "Video": 63}"#).unwrap();
+        assert_eq!(registry.len(), 3);
+        let bits = registry.bits(&["RECIPE", "video", "news"]);
+        assert_eq!(bits, (1 << 8) | (1 << 63));
+        assert_eq!(registry.names(bits), vec!["RECIPE".into(), "VIDEO".into()] as Vec<Box<str>>);
+        // 組み込みのビットと互換
+        assert_eq!(registry.bits(&["wiki"]), Tags::WIKI);
+
+        assert!(TagRegistry::from_json(r#"{"a": 64}"#).is_err());
+        assert!(TagRegistry::from_json(r#"{"a": 1, "b": 1}"#).is_err());
+        let too_many: HashMap<String, u32> = (0..65).map(|i| (format!("t{}", i), i % 64)).collect();
+        assert!(TagRegistry::from_json(&serde_json::to_string(&too_many).unwrap()).unwrap_err().contains("Too many"));
+
+        // デフォルトは従来の 8 種
+        let default = TagRegistry::default();
+        assert_eq!(default.bits(&["News", "tools"]), Tags::NEWS | Tags::TOOLS);
+        assert_eq!(default.names(Tags::WIKI | Tags::SNS), vec!["WIKI".into(), "SNS".into()] as Vec<Box<str>>);
+    }
+
+    #[test]
+    fn test_tag_aliases() {
+        let default = TagRegistry::default();
+        assert_eq!(default.bit("ブログ"), Some(Tags::BLOG));
+        assert_eq!(default.bit("ニュース"), Some(Tags::NEWS));
+        assert_eq!(default.bit("Social"), Some(Tags::SNS));
+        assert_eq!(default.bits(&["掲示板", "論文", "通販"]), Tags::FORUM | Tags::ACADEMIC | Tags::SHOPPING);
+        // 全角・前後の空白
+        assert_eq!(default.bit("ＢＬＯＧ"), Some(Tags::BLOG));
+        assert_eq!(default.bit("　ｗｉｋｉ "), Some(Tags::WIKI));
+        assert_eq!(default.bit("ブロ"), None);
+
+        // 別名も定義する形式
+        let registry = TagRegistry::from_json(r#"{"tags": {"blog": 3, "recipe": 8}, "aliases": {"レシピ": "recipe", "料理": "Recipe"}}"#).unwrap();
+        assert_eq!(registry.bits(&["レシピ", "料理"]), 1 << 8);
+        // 組み込みの別名は対象のタグがあるものだけ
+        assert_eq!(registry.bit("ブログ"), Some(Tags::BLOG));
+        assert_eq!(registry.bit("ニュース"), None);
+        assert!(TagRegistry::from_json(r#"{"tags": {"blog": 3}, "aliases": {"動画": "video"}}"#).unwrap_err().contains("Unknown tag"));
+    }
+
+    #[test]
+    fn test_tags_from_strs_checked() {
+        let mut warnings = Vec::new();
+        // 既知のタグのみ
+        let valid = Tags::from_strs_checked(&["wiki", "NEWS"], false, &mut warnings).unwrap();
+        assert!(valid.is_filter_contains(Tags::WIKI | Tags::NEWS));
+        assert!(warnings.is_empty());
+        // 未知のタグ
+        let (set, unknown) = Tags::parse_strs(&["nws"]);
+        assert!(set.is_empty());
+        assert_eq!(unknown, vec!["nws"]);
+        let err = Tags::from_strs_checked(&["nws"], true, &mut warnings).unwrap_err();
+        assert!(err.starts_with("Unknown tag: nws (valid tags: wiki, news,"));
+        // 混在: 非 strict なら既知のものだけ使い警告を残す
+        let mixed = Tags::from_strs_checked(&["blog", "nws"], false, &mut warnings).unwrap();
+        assert!(mixed.contains(Tags::BLOG) && !mixed.contains(Tags::NEWS));
+        assert_eq!(warnings, vec!["unknown tag: nws"]);
+    }
+
+    #[test]
+    fn test_tag_facets() {
+        let dir = test_dir("tag-facets");
+        let pool = IndexPool::new(&dir);
+        let docs = [
+            ("https://example.com/1", Tags::NEWS, vec!["rust"]),
+            ("https://example.com/2", Tags::NEWS | Tags::BLOG, vec!["rust", "search"]),
+            ("https://example.com/3", Tags::BLOG | Tags::SNS, vec!["rust"]),
+            ("https://example.com/4", Tags::WIKI, vec!["python"]),
+        ];
+        for (url, tags, tokens) in docs {
+            let mut meta = test_meta(url);
+            meta.tags = Tags::new(tags);
+            pool.add_document(&TokenFrequency::from(&tokens[..]), meta);
+        }
+        let query = TokenFrequency::from(&["rust"][..]);
+        let scored = pool.per_similarity(&query, &SimilarityAlgorithm::CosineSimilarity);
+
+        // スコア 0 (python のみ) は数えない、range に関係なく全件
+        let all = pool.tag_facets(&scored, Tags::new(0), false, Tags::new(0), &SearchReads::default());
+        assert_eq!(all, BTreeMap::from([("news".to_string(), 2), ("blog".to_string(), 2), ("sns".to_string(), 1)]));
+        // タグフィルタは結果と同じ
+        let filtered = pool.tag_facets(&scored, Tags::new(Tags::BLOG), false, Tags::new(Tags::SNS), &SearchReads::default());
+        assert_eq!(filtered, BTreeMap::from([("news".to_string(), 1), ("blog".to_string(), 1)]));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_lock_metrics() {
+        let dir = test_dir("lock-metrics");
+        let pool = IndexPool::new(&dir);
+        for i in 0..4 {
+            pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        pool.del_document("https://example.com/0");
+        let before = pool.lock_metrics.snapshot();
+        assert_eq!(before.write_count, 5);
+        assert_eq!(before.search_skips, 0);
+
+        // 書き込み中のシャードは検索で飛ばされる
+        let query = TokenFrequency::from(&["rust"][..]);
+        let (held, release) = (std::sync::Barrier::new(2), std::sync::Barrier::new(2));
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let shard = pool.shard(2).unwrap();
+                let _guard = shard.write().unwrap();
+                held.wait();
+                release.wait();
+            });
+            held.wait();
+            pool.per_similarity(&query, &SimilarityAlgorithm::CosineSimilarity);
+            pool.per_similarity_top_k(&query, &SimilarityAlgorithm::CosineSimilarity, 10);
+            release.wait();
+        });
+        let after = pool.lock_metrics.snapshot();
+        assert_eq!(after.search_skips, 2);
+        assert_eq!(after.search_skips_per_shard[2], 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_score_floor_drops_zero_overlap() {
+        let dir = test_dir("score-floor");
+        let mut pool = IndexPool::new(&dir);
+        pool.add_document(&TokenFrequency::from(&["rust", "search"][..]), test_meta("https://example.com/1"));
+        pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta("https://example.com/2"));
+        pool.add_document(&TokenFrequency::from(&["python"][..]), test_meta("https://example.com/3"));
+        let query = TokenFrequency::from(&["rust"][..]);
+        let algo = SimilarityAlgorithm::CosineSimilarity;
+        let urls = |pool: &IndexPool, scored: Vec<ScoredEntry>| -> Vec<Box<str>> {
+            pool.generate_results(pool.sort_by_score(scored), 0..10, Tags::new(0), false, Tags::new(0))
+                .into_iter().map(|r| r.url).collect()
+        };
+        let hits = urls(&pool, pool.per_similarity(&query, &algo));
+        assert_eq!(hits.len(), 2);
+        assert!(!hits.contains(&"https://example.com/3".into()));
+        assert_eq!(pool.per_similarity_top_k(&query, &algo, 10).len(), 2);
+
+        // 閾値を上げるとさらに絞られる
+        let top_score = pool.per_similarity(&query, &algo).iter().map(|e| e.score).fold(0.0, f64::max);
+        pool.score_floor = top_score * 0.99;
+        assert_eq!(urls(&pool, pool.per_similarity(&query, &algo)).len(), 1);
+        // 負の値なら全件
+        pool.score_floor = f64::NEG_INFINITY;
+        assert_eq!(pool.per_similarity(&query, &algo).len(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_warmup() {
+        let dir = test_dir("warmup");
+        let pool = IndexPool::new(&dir);
+        assert_eq!(pool.warmup(), 0);
+        for i in 0..3 {
+            pool.add_document(&TokenFrequency::from(&["rust", "search"][..]), test_meta(&format!("https://example.com/{}", i)));
+        }
+        assert_eq!(pool.warmup(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_dump_shard() {
+        let dir = test_dir("dump-shard");
+        let pool = IndexPool::new(&dir);
+        // 全て同じシャードに入るよう直接追加
+        {
+            let shard = pool.shard(3).unwrap();
+            let mut idx = shard.write().unwrap();
+            for i in 0..5 {
+                let mut meta = test_meta(&format!("https://example.com/{}", i));
+                meta.id = i;
+                idx.vectorizer.add_doc(i, &TokenFrequency::from(&["token"][..]));
+                idx.meta.push(meta);
+            }
+        }
+        let dump = pool.dump_shard(3, 1..3).unwrap();
+        assert_eq!(dump.id, 3);
+        assert_eq!(dump.documents, 5);
+        assert_eq!(dump.meta_count, 5);
+        assert_eq!(dump.entries.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(dump.entries[0].url.as_ref(), "https://example.com/1");
+        assert!(pool.dump_shard(DEFAULT_INDEX_SHARD_NUM, 0..10).is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_url_lookup_targets_single_shard() {
+        let dir = test_dir("url-map");
+        let pool = Arc::new(IndexPool::new(&dir));
+        let token_fq = TokenFrequency::from(&["rust", "search"][..]);
+        for i in 0..200 {
+            pool.add_document(&token_fq, test_meta(&format!("https://example.com/{}", i)));
+        }
+        let url = "https://example.com/123";
+        let (shard_id, doc_id) = pool.locate(url).unwrap();
+        assert_eq!(pool.indexes[shard_id].load().read().unwrap().meta_from_id(doc_id).unwrap().url.as_ref(), url);
+
+        // 対象以外のシャードを全て write lock しても削除が完了する (= 走査していない)
+        let shards = pool.shards();
+        let guards: Vec<_> = shards.iter()
+            .filter(|i| i.read().unwrap().id != shard_id)
+            .map(|i| i.write().unwrap())
+            .collect();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let pool_clone = Arc::clone(&pool);
+        std::thread::spawn(move || {
+            tx.send(pool_clone.del_document(url)).unwrap();
+        });
+        let deleted = rx.recv_timeout(std::time::Duration::from_secs(5));
+        drop(guards);
+        assert_eq!(deleted, Ok(true));
+        assert!(pool.locate(url).is_none());
+        assert!(!pool.del_document(url));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let mut pool = IndexPool::new(&test_dir("apply-config"));
+        let config = crate::config::Config {
+            score_floor: f64::NEG_INFINITY,
+            del_removes_meta: true,
+            save_options: SaveOptions { durable: true, meta_format: MetaFormat::Json, ..SaveOptions::default() },
+            trigram_index: true,
+            search_lock_timeout_ms: Some(250),
+            ..Default::default()
+        };
+        pool.apply_config(&config);
+        assert_eq!(pool.score_floor, f64::NEG_INFINITY);
+        assert!(pool.remove_meta_on_delete && pool.trigram_index);
+        assert!(pool.save_options.durable);
+        assert_eq!(pool.save_options.meta_format, MetaFormat::Json);
+        assert_eq!(pool.search_lock_timeout, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn test_url_map_persist_and_rebuild() {
+        let dir = test_dir("url-map-persist");
+        let pool = IndexPool::new(&dir);
+        let token_fq = TokenFrequency::from(&["rust"][..]);
+        for i in 0..20 {
+            pool.add_document(&token_fq, test_meta(&format!("https://example.com/{}", i)));
+        }
+        pool.del_document("https://example.com/3");
+        pool.save(&dir).unwrap();
+        let expected = pool.url_map.read().unwrap().clone();
+        assert_eq!(expected.len(), 19);
+
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(*loaded.url_map.read().unwrap(), expected);
+
+        // ファイルがなくても meta から再構築 (削除済みは含まない)
+        std::fs::remove_file(std::path::Path::new(&dir).join(URL_MAP_FILE)).unwrap();
+        let rebuilt = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(*rebuilt.url_map.read().unwrap(), expected);
+        assert!(rebuilt.locate("https://example.com/3").is_none());
+        assert!(rebuilt.locate("https://example.com/4").is_some());
+
+        // 件数が同じでも指す先が違えば再構築
+        let mut stale = expected.clone();
+        let (a, b) = (url_hash("https://example.com/4"), url_hash("https://example.com/5"));
+        let (entry_a, entry_b) = (stale[&a], stale[&b]);
+        stale.insert(a, entry_b);
+        stale.insert(b, entry_a);
+        let map_path = std::path::Path::new(&dir).join(URL_MAP_FILE);
+        write_bincode(&map_path, &stale, SaveOptions::default()).unwrap();
+        let rebuilt = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(*rebuilt.url_map.read().unwrap(), expected);
+        // 削除済みの URL のキーが残っていても同じ
+        let mut stale = expected.clone();
+        stale.remove(&a);
+        stale.insert(url_hash("https://example.com/3"), entry_a);
+        write_bincode(&map_path, &stale, SaveOptions::default()).unwrap();
+        let rebuilt = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(*rebuilt.url_map.read().unwrap(), expected);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_doc_vector() {
+        let dir = test_dir("doc-vector");
+        let pool = IndexPool::new(&dir);
+        let tokens = ["東京", "天気", "天気", "晴れ", "天気"];
+        pool.add_document(&TokenFrequency::from(&tokens[..]), test_meta("https://example.com/a"));
+        pool.add_document(&TokenFrequency::from(&["大阪"][..]), test_meta("https://example.com/b"));
+
+        let vector = pool.doc_vector("https://example.com/a").unwrap();
+        assert_eq!(vector.url.as_ref(), "https://example.com/a");
+        assert_eq!(vector.token_sum, 5);
+        let expected: BTreeMap<Box<str>, u64> = [("東京", 1), ("天気", 3), ("晴れ", 1)].into_iter().map(|(t, n)| (t.into(), n)).collect();
+        assert_eq!(vector.tokens, expected);
+        assert!(pool.doc_vector("https://example.com/missing").is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_try_save() {
+        let dir = test_dir("try-save");
+        let pool = IndexPool::new(&dir);
+        pool.add_document(&TokenFrequency::from(&["rust"][..]), test_meta("https://example.com/a"));
+        let summary = pool.try_save(&dir).unwrap();
+        assert_eq!(summary.sizes.len(), DEFAULT_INDEX_SHARD_NUM);
+        for size in &summary.sizes {
+            let shard = std::path::Path::new(&dir);
+            assert_eq!(std::fs::metadata(shard.join(format!("{}.index", size.id))).unwrap().len(), size.index_bytes);
+            assert_eq!(std::fs::metadata(shard.join(format!("{}.meta", size.id))).unwrap().len(), size.meta_bytes);
+        }
+        assert!(IndexPool::load(&dir, None).unwrap().locate("https://example.com/a").is_some());
+
+        // 保存中は待たずにエラー
+        let guard = pool.save_lock.lock().unwrap();
+        assert!(pool.try_save(&dir).unwrap_err().is::<SaveInProgressError>());
+        drop(guard);
+        assert!(pool.try_save(&dir).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_url_aliases() {
+        let dir = test_dir("url-aliases");
+        let pool = IndexPool::new(&dir);
+        let token_fq = TokenFrequency::from(&["rust"][..]);
+        let mut meta = test_meta("https://example.com/a");
+        meta.set_aliases(&["https://www.example.com/a", " ", "https://example.com/a", "https://www.example.com/a"]);
+        assert_eq!(meta.aliases.len(), 1);
+        assert_eq!(pool.add_document(&token_fq, meta), Some(true));
+
+        // 別名でも同じドキュメントが引ける
+        let located = pool.locate("https://www.example.com/a").unwrap();
+        assert_eq!(pool.locate("https://example.com/a"), Some(located));
+        let shard = pool.shard(located.0).unwrap();
+        let idx = shard.read().unwrap();
+        assert_eq!(idx.meta_from_url("https://www.example.com/a").unwrap().url.as_ref(), "https://example.com/a");
+        drop(idx);
+
+        // 別名で登録し直しても増えず、表示用の URL はそのまま
+        assert_eq!(pool.add_document(&token_fq, test_meta("https://www.example.com/a")), Some(false));
+        assert_eq!(pool.counter.load(Ordering::SeqCst), 1);
+        let shard = pool.shard(located.0).unwrap();
+        let idx = shard.read().unwrap();
+        assert_eq!(idx.meta_from_id(located.1).unwrap().url.as_ref(), "https://example.com/a");
+        drop(idx);
+
+        // 他のドキュメントの URL は別名として奪わない
+        assert_eq!(pool.add_document(&token_fq, test_meta("https://example.com/b")), Some(true));
+        let other = pool.locate("https://example.com/b").unwrap();
+        let mut meta = test_meta("https://example.com/b");
+        meta.set_aliases(&["https://example.com/a", "https://www.example.com/a", "https://example.com/b2"]);
+        assert_eq!(pool.add_document(&token_fq, meta), Some(false));
+        assert_eq!(pool.locate("https://example.com/a"), Some(located));
+        assert_eq!(pool.locate("https://www.example.com/a"), Some(located));
+        assert_eq!(pool.locate("https://example.com/b2"), Some(other));
+        let shard = pool.shard(other.0).unwrap();
+        assert_eq!(shard.read().unwrap().meta_from_id(other.1).unwrap().aliases, vec![Box::from("https://example.com/b2")]);
+        assert!(pool.del_document("https://example.com/b"));
+
+        // 読み込み直しても別名が残る
+        let mut meta = test_meta("https://example.com/a");
+        meta.set_aliases(&["https://m.example.com/a"]);
+        pool.add_document(&token_fq, meta);
+        pool.save(&dir).unwrap();
+        let loaded = IndexPool::load(&dir, None).unwrap();
+        assert_eq!(loaded.locate("https://m.example.com/a"), Some(located));
+        assert!(loaded.locate("https://www.example.com/a").is_none());
+
+        // 別名で削除すると URL も引けなくなる
+        assert!(loaded.del_document("https://m.example.com/a"));
+        assert!(loaded.locate("https://example.com/a").is_none());
+        assert!(loaded.url_map.read().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fill_match_info() {
+        let dir = test_dir("match-info");
+        let mut pool = IndexPool::new(&dir);
+        // 一致しないドキュメントも結果に残す
+        pool.score_floor = f64::NEG_INFINITY;
+        pool.add_document(&TokenFrequency::from(&["a", "b", "b", "c"][..]), test_meta("https://example.com/1"));
+        pool.add_document(&TokenFrequency::from(&["c", "e"][..]), test_meta("https://example.com/2"));
+        let query = TokenFrequency::from(&["a", "b", "d"][..]);
+        let scored = pool.per_similarity(&query, &SimilarityAlgorithm::CosineSimilarity);
+        let sorted = pool.sort_by_score(scored);
+        let mut results = pool.generate_results(sorted, 0..10, Tags::new(0), false, Tags::new(0));
+        assert!(results.iter().all(|r| r.matched_terms.is_none()));
+        pool.fill_match_info(&mut results, &query, &SearchReads::default());
+        let first = results.iter().find(|r| r.url.as_ref() == "https://example.com/1").unwrap();
+        assert_eq!(first.matched_terms, Some(2));
+        assert_eq!(first.term_freq, Some(3));
+        let second = results.iter().find(|r| r.url.as_ref() == "https://example.com/2").unwrap();
+        assert_eq!(second.matched_terms, Some(0));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn ranking(pool: &IndexPool, scored: Vec<ScoredEntry>) -> Vec<usize> {
+        pool.sort_by_score(scored).iter().map(|e| e.key).collect()
+    }
+
+    #[test]
+    fn test_mixed_similarity() {
+        let dir = test_dir("mixed");
+        let mut pool = IndexPool::new(&dir);
+        // 正規化の範囲を変えないようスコア 0 も残す
+        pool.score_floor = f64::NEG_INFINITY;
+        let docs: Vec<Vec<&str>> = vec![
+            vec!["a", "a", "a", "a", "x", "y", "z", "w", "v", "u", "t", "s"],
+            vec!["a", "b"],
+            vec!["b", "b", "b", "c", "c", "c", "c", "c", "c"],
+            vec!["a", "b", "c", "d", "e", "f", "g", "h"],
+            vec!["q", "r"],
+        ];
+        {
+            let shard = pool.shard(0).unwrap();
+            let mut idx = shard.write().unwrap();
+            for (i, d) in docs.iter().enumerate() {
+                idx.vectorizer.add_doc(i, &TokenFrequency::from(&d[..]));
+            }
+            idx.vectorizer.update_idf();
+        }
+        let query = TokenFrequency::from(&["a", "b"][..]);
+        let bm25 = ranking(&pool, pool.per_similarity(&query, &SimilarityAlgorithm::BM25(1.2, 0.75)));
+        let cosine = ranking(&pool, pool.per_similarity(&query, &SimilarityAlgorithm::CosineSimilarity));
+        let mix = [(SimilarityAlgorithm::BM25(1.2, 0.75), 0.875), (SimilarityAlgorithm::CosineSimilarity, 0.125)];
+        let mixed = ranking(&pool, pool.mixed_similarity(&query, &mix));
+        assert_ne!(mixed, bm25);
+        assert_ne!(mixed, cosine);
+        // 再現性
+        for _ in 0..5 {
+            assert_eq!(ranking(&pool, pool.mixed_similarity(&query, &mix)), mixed);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
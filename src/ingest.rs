@@ -0,0 +1,426 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use reqwest::Url;
+use tf_idf_vectorizer::TokenFrequency;
+
+use crate::{collect::ScrapeResults, index::{IndexMeta, Tags}};
+
+/// スクレイパ結果によるメタデータ補完の設定
+/// 各項目は個別に有効化する
+#[derive(Debug, Clone, Default)]
+pub struct EnrichOptions {
+    /// スクレイパの tags を Tags に取り込む
+    pub scraper_tags: bool,
+    /// 先頭の author を保存
+    pub author: bool,
+    /// 先頭の published / modified を保存
+    pub published: bool,
+    /// 見出しのトークンを本文の何倍で数えるか (0 で無効)
+    pub heading_weight: u64,
+    /// タイトルのトークンを本文の何倍で数えるか (0 で無効)
+    pub title_weight: u64,
+}
+
+/// TITLE_WEIGHT のデフォルト
+/// 上げると既存のドキュメントとスコアがそろわなくなるので、変えるなら再インデックスする
+pub const DEFAULT_TITLE_WEIGHT: u64 = 1;
+
+/// /add で保存する description の取得元
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptionSource {
+    /// リクエストの descriptions
+    Request,
+    /// スクレイパの descriptions の 2 件目以降 (meta description)
+    ScraperMeta,
+    /// 本文 (スクレイパの descriptions の先頭) の先頭部分
+    BodyHead,
+}
+
+/// DESC_SOURCES 未設定時の優先順
+pub const DEFAULT_DESCRIPTION_SOURCES: &[DescriptionSource] = &[
+    DescriptionSource::Request,
+    DescriptionSource::ScraperMeta,
+    DescriptionSource::BodyHead,
+];
+
+impl DescriptionSource {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "request" => Some(DescriptionSource::Request),
+            "scraper_meta" | "meta" => Some(DescriptionSource::ScraperMeta),
+            "body_head" | "body" => Some(DescriptionSource::BodyHead),
+            _ => None,
+        }
+    }
+
+    /// カンマ区切りの優先順をパース (未知の名前は無視、有効なものがなければ None)
+    pub fn parse_list(s: &str) -> Option<Vec<Self>> {
+        let sources: Vec<Self> = s.split(',').filter_map(Self::parse).collect();
+        if sources.is_empty() { None } else { Some(sources) }
+    }
+}
+
+/// sources の順に最初に空でないものを max_len 文字に切り詰めて返す (どれもなければ空)
+pub fn choose_description(request: Option<&str>, results: &ScrapeResults, sources: &[DescriptionSource], max_len: usize) -> String {
+    sources.iter()
+        .find_map(|source| match source {
+            DescriptionSource::Request => request,
+            DescriptionSource::ScraperMeta => results.descriptions.iter().skip(1).map(|d| d.as_str()).find(|d| !d.trim().is_empty()),
+            DescriptionSource::BodyHead => results.descriptions.first().map(|d| d.as_str()),
+        }
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty()))
+        .map(|d| d.chars().take(max_len).collect())
+        .unwrap_or_default()
+}
+
+/// スクレイパが success:false を返したときの分類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrapeFailure {
+    /// 対象ページが存在しない (404 / 410)
+    DeadLink,
+    /// 対象ページ側の一時的な失敗 (403, 5xx, タイムアウトなど)
+    Soft,
+    /// スクレイパ自体の失敗
+    Upstream,
+}
+
+impl ScrapeFailure {
+    /// スクレイパ呼び出しの HTTP ステータス、ペイロードの status、error の文言から分類
+    pub fn classify(http_status: u16, payload_status: Option<u16>, error: &str) -> Self {
+        if http_status >= 500 {
+            return ScrapeFailure::Upstream;
+        }
+        let status = payload_status.or((400..500).contains(&http_status).then_some(http_status));
+        match status {
+            Some(404 | 410) => return ScrapeFailure::DeadLink,
+            Some(400..=599) => return ScrapeFailure::Soft,
+            _ => {}
+        }
+        // status がなければ文言で判断
+        let error = error.to_ascii_lowercase();
+        if ["404", "410", "not found", "gone"].iter().any(|k| error.contains(k)) {
+            ScrapeFailure::DeadLink
+        } else {
+            ScrapeFailure::Soft
+        }
+    }
+
+    /// /add のレスポンスのステータス
+    pub fn http_status(self) -> u16 {
+        match self {
+            ScrapeFailure::DeadLink => 404,
+            ScrapeFailure::Soft | ScrapeFailure::Upstream => 502,
+        }
+    }
+}
+
+/// スクレイパの tags から既知のカテゴリのみ Tags に変換
+/// 別名 (ブログ、wikipedia など) は TagRegistry で解決する
+pub fn tags_from_scraper(tags: &[String]) -> Tags {
+    Tags::from_strs(tags)
+}
+
+/// タイムゾーンのない日時の形式 (UTC とみなす)
+const NAIVE_DATETIME_FORMATS: &[&str] = &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S", "%Y/%m/%d %H:%M:%S", "%Y/%m/%d %H:%M"];
+/// 日付のみの形式 (UTC 0時)
+const NAIVE_DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%Y/%m/%d", "%Y.%m.%d", "%Y年%m月%d日"];
+
+/// 日付文字列をパース (published / modified / 検索の日付の指定)
+/// RFC 3339 / RFC 2822 / タイムゾーンなしの日時 (UTC とみなす) / 日付のみ (UTC 0時) に対応
+pub fn parse_date(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(d) = DateTime::parse_from_rfc3339(s).or_else(|_| DateTime::parse_from_rfc2822(s)) {
+        return Some(d.with_timezone(&Utc));
+    }
+    NAIVE_DATETIME_FORMATS.iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .or_else(|| NAIVE_DATE_FORMATS.iter()
+            .find_map(|f| NaiveDate::parse_from_str(s, f).ok())
+            .and_then(|d| d.and_hms_opt(0, 0, 0)))
+        .map(|d| d.and_utc())
+}
+
+/// 有効な補完をメタに適用
+/// リクエストで指定されたタグは残し、スクレイパのタグを追加する
+pub fn enrich_meta(meta: &mut IndexMeta, results: &ScrapeResults, options: &EnrichOptions) {
+    if options.scraper_tags {
+        meta.tags = Tags::new(u64::from(meta.tags) | u64::from(tags_from_scraper(&results.tags)));
+    }
+    if options.author {
+        meta.author = results.author.iter()
+            .map(|a| a.trim())
+            .find(|a| !a.is_empty())
+            .map(|a| a.into());
+    }
+    if options.published {
+        meta.published = results.published.iter().find_map(|p| parse_date(p));
+        meta.modified = results.modified.iter().find_map(|p| parse_date(p));
+    }
+}
+
+/// ドキュメントの異なり語数を max_unique までに抑える
+/// 出現回数の多い語を残し、同数なら先に出現した語を残す (残した語の出現はすべて残す)
+/// # Returns
+/// 捨てた異なり語数
+pub fn cap_unique_tokens(tokens: &mut Vec<String>, max_unique: usize) -> usize {
+    let mut counts: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (pos, token) in tokens.iter().enumerate() {
+        counts.entry(token.as_str()).or_insert((0, pos)).0 += 1;
+    }
+    if counts.len() <= max_unique {
+        return 0;
+    }
+    let mut ranked: Vec<(&str, (usize, usize))> = counts.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.0.cmp(&a.1.0).then(a.1.1.cmp(&b.1.1)));
+    let dropped = ranked.len() - max_unique;
+    let keep: HashSet<String> = ranked.into_iter().take(max_unique).map(|(t, _)| t.to_string()).collect();
+    tokens.retain(|t| keep.contains(t));
+    dropped
+}
+
+/// タイトル / 見出しを加えた後の TokenFrequency の異なり語数を max_unique までに抑える
+/// cap_unique_tokens は本文にしか効かないので、加えた語で上限を超えないように最後にもう一度かける
+/// 重み付きの出現回数の多い語を残し、同数なら order (本文・タイトル・見出しの順に並べたトークン) で先に出現した語を残す
+/// # Returns
+/// 捨てた異なり語数
+pub fn cap_unique_token_freq<T: AsRef<str>>(token_fq: &mut TokenFrequency, order: &[T], max_unique: usize) -> usize {
+    if token_fq.token_num() <= max_unique {
+        return 0;
+    }
+    let mut first: HashMap<&str, usize> = HashMap::new();
+    for (pos, token) in order.iter().enumerate() {
+        first.entry(token.as_ref()).or_insert(pos);
+    }
+    let position = |token: &str| first.get(token).copied().unwrap_or(usize::MAX);
+    let mut ranked = token_fq.token_count_vector_ref_str();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(position(a.0).cmp(&position(b.0))).then(a.0.cmp(b.0)));
+    let dropped = ranked.len() - max_unique;
+    let keep: HashSet<String> = ranked.into_iter().take(max_unique).map(|(t, _)| t.to_string()).collect();
+    token_fq.remove_tokens_by(|token, _| !keep.contains(token));
+    dropped
+}
+
+/// 見出し / タイトルのトークンを weight 回分として加算
+pub fn add_weighted_tokens<T: AsRef<str>>(token_fq: &mut TokenFrequency, tokens: &[T], weight: u64) {
+    for token in tokens {
+        let token = token.as_ref();
+        let count = token_fq.token_count(token);
+        token_fq.set_token_count(token, count + weight);
+    }
+}
+
+/// 本文のトークンにタイトルのトークンを title_weight 回分として加えた TokenFrequency
+/// /add の通常の経路とタイトルだけでインデックスする経路で共通
+pub fn title_weighted_tokens<T: AsRef<str>>(body: &[T], title: &[T], title_weight: u64) -> TokenFrequency {
+    let mut token_fq = TokenFrequency::from(body);
+    add_weighted_tokens(&mut token_fq, title, title_weight);
+    token_fq
+}
+
+/// インデックスに保存する URL を決定
+/// スクレイパの canonical (先頭) が有効ならそれを優先し、なければスクレイパが返した URL
+/// canonical が相対 URL の場合はスクレイパの URL を基準に解決する
+/// 同じページを別 URL で登録しても canonical が同じなら一つのドキュメントにまとまる
+/// 別サイトのドキュメントを canonical で上書きされないように、canonical はスクレイパの URL と同じホストか
+/// 一方が他方のサブドメイン (m.example.com と example.com など) のときだけ使う
+/// どちらも Url で正規化した形で返す (同じページが末尾の / の有無などで別のキーにならないように)
+pub fn resolve_url(scraped_url: &str, canonical: &[String]) -> String {
+    let Ok(base) = Url::parse(scraped_url) else { return scraped_url.to_string(); };
+    let canonical = canonical.first()
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+        .and_then(|c| base.join(c).ok())
+        .filter(|u| matches!(u.scheme(), "http" | "https") && same_site(&base, u));
+    canonical.unwrap_or(base).to_string()
+}
+
+/// 同じホストか、一方が他方のサブドメインか (canonical を信用してよいか)
+/// 親の方は 2 ラベル以上 (com などの TLD だけでは同じサイトとみなさない)
+fn same_site(a: &Url, b: &Url) -> bool {
+    let (Some(a_host), Some(b_host)) = (a.host_str(), b.host_str()) else { return false; };
+    if a_host.eq_ignore_ascii_case(b_host) {
+        return true;
+    }
+    let (Some(a), Some(b)) = (a.domain(), b.domain()) else { return false; };
+    let (a, b) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+    let (child, parent) = if a.len() > b.len() { (a, b) } else { (b, a) };
+    parent.contains('.') && child.ends_with(&format!(".{}", parent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{tests::test_meta, IndexPool};
+
+    fn test_results() -> ScrapeResults {
+        serde_json::from_value(serde_json::json!({
+            "author": ["", "Alice"],
+            "base": [], "canonical": [], "content_html": [], "descriptions": ["body"],
+            "favicon": [], "headings": ["見出し"], "lang": [], "links": [], "modified": ["not a date", "2024/06/01"],
+            "next": [], "prev": [], "published": ["2024-05-01T12:00:00+09:00"],
+            "rss": [], "site_name": [], "tags": ["News", "ツール", "unknown"], "title": [],
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_url() {
+        let canonical = vec!["https://example.com/page".to_string()];
+        assert_eq!(resolve_url("https://example.com/page?utm=1", &canonical), "https://example.com/page");
+        // 相対 canonical
+        let relative = vec!["/page".to_string()];
+        assert_eq!(resolve_url("https://example.com/a/b", &relative), "https://example.com/page");
+        // 無効な canonical は無視
+        let invalid = vec!["javascript:void(0)".to_string()];
+        assert_eq!(resolve_url("https://example.com/a", &invalid), "https://example.com/a");
+        assert_eq!(resolve_url("https://example.com/a", &[]), "https://example.com/a");
+        // 別サイトの canonical は使わない (他サイトのドキュメントを上書きさせない)
+        let other = vec!["https://victim.example/".to_string()];
+        assert_eq!(resolve_url("https://evil.example/page", &other), "https://evil.example/page");
+        let tld = vec!["https://example/".to_string()];
+        assert_eq!(resolve_url("https://evil.example/", &tld), "https://evil.example/");
+        // サブドメインとの間なら使う
+        let parent = vec!["https://example.com/page".to_string()];
+        assert_eq!(resolve_url("https://m.example.com/page", &parent), "https://example.com/page");
+        let www = vec!["https://www.example.com/page".to_string()];
+        assert_eq!(resolve_url("https://example.com/page", &www), "https://www.example.com/page");
+        // canonical を使わないときも同じ形に正規化する
+        assert_eq!(resolve_url("https://Example.com", &[]), "https://example.com/");
+        assert_eq!(resolve_url("https://example.com", &other), resolve_url("https://example.com/", &[]));
+        assert_eq!(resolve_url("not a url", &parent), "not a url");
+    }
+
+    #[test]
+    fn test_same_canonical_collapses_to_one_document() {
+        let dir = std::env::temp_dir().join(format!("wk-search-test-canonical-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let pool = IndexPool::new(&dir.to_string_lossy());
+        let canonical = vec!["https://example.com/article".to_string()];
+        for requested in ["https://example.com/article?ref=top", "https://m.example.com/article"] {
+            let url = resolve_url(requested, &canonical);
+            pool.add_document(&TokenFrequency::from(&["token"][..]), test_meta(&url));
+        }
+        assert_eq!(pool.counter.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_enrich_author_and_published() {
+        let results = test_results();
+        let mut meta = IndexMeta { tags: Tags::new(Tags::BLOG), ..test_meta("https://example.com/") };
+        let all = EnrichOptions { scraper_tags: true, author: true, published: true, heading_weight: 0, title_weight: 0 };
+        enrich_meta(&mut meta, &results, &all);
+        assert_eq!(meta.author.as_deref(), Some("Alice"));
+        assert_eq!(meta.published, Some(parse_date("2024-05-01T03:00:00Z").unwrap()));
+        // 読めない値は飛ばす
+        assert_eq!(meta.modified, parse_date("2024-06-01"));
+        assert!(meta.tags.contains(Tags::BLOG) && meta.tags.contains(Tags::NEWS) && meta.tags.contains(Tags::TOOLS));
+
+        // 無効なら触らない
+        let mut untouched = IndexMeta { tags: Tags::new(Tags::BLOG), ..test_meta("https://example.com/") };
+        enrich_meta(&mut untouched, &results, &EnrichOptions { author: true, ..Default::default() });
+        assert_eq!(untouched.author.as_deref(), Some("Alice"));
+        assert!(untouched.published.is_none() && untouched.modified.is_none());
+        assert_eq!(u64::from(untouched.tags), Tags::BLOG);
+    }
+
+    #[test]
+    fn test_classify_scrape_failure() {
+        // ペイロードの status
+        assert_eq!(ScrapeFailure::classify(200, Some(404), "fetch failed"), ScrapeFailure::DeadLink);
+        assert_eq!(ScrapeFailure::classify(200, Some(410), ""), ScrapeFailure::DeadLink);
+        assert_eq!(ScrapeFailure::classify(200, Some(503), "fetch failed"), ScrapeFailure::Soft);
+        // スクレイパの HTTP ステータス
+        assert_eq!(ScrapeFailure::classify(404, None, "fetch failed"), ScrapeFailure::DeadLink);
+        assert_eq!(ScrapeFailure::classify(500, Some(404), "internal error"), ScrapeFailure::Upstream);
+        assert_eq!(ScrapeFailure::classify(502, None, ""), ScrapeFailure::Upstream);
+        // 文言のみ
+        assert_eq!(ScrapeFailure::classify(200, None, "HTTP 404 Not Found"), ScrapeFailure::DeadLink);
+        assert_eq!(ScrapeFailure::classify(200, None, "timeout"), ScrapeFailure::Soft);
+
+        assert_eq!(ScrapeFailure::DeadLink.http_status(), 404);
+        assert_eq!(ScrapeFailure::Soft.http_status(), 502);
+        assert_eq!(ScrapeFailure::Upstream.http_status(), 502);
+    }
+
+    #[test]
+    fn test_choose_description() {
+        let mut results = test_results();
+        let long_body = "本文".repeat(100);
+        results.descriptions = vec![long_body.clone(), "".to_string(), " ページの説明 ".to_string()];
+        let sources = DEFAULT_DESCRIPTION_SOURCES;
+        // meta description を本文の先頭より優先
+        assert_eq!(choose_description(None, &results, sources, 100), "ページの説明");
+        assert_eq!(choose_description(Some("指定"), &results, sources, 100), "指定");
+        // meta がなければ本文の先頭を MAX_DESC_LENGTH まで
+        results.descriptions.truncate(1);
+        assert_eq!(choose_description(None, &results, sources, 100).chars().count(), 100);
+        let body_first = DescriptionSource::parse_list("body_head, bogus, request").unwrap();
+        assert_eq!(body_first, vec![DescriptionSource::BodyHead, DescriptionSource::Request]);
+        assert!(choose_description(Some("指定"), &results, &body_first, 10).starts_with("本文"));
+        assert!(DescriptionSource::parse_list("bogus").is_none());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let expected = parse_date("2024-05-01T12:30:00Z").unwrap();
+        assert_eq!(parse_date("Wed, 01 May 2024 12:30:00 +0000"), Some(expected));
+        assert_eq!(parse_date("2024-05-01T21:30:00+09:00"), Some(expected));
+        // タイムゾーンなしは UTC とみなす
+        assert_eq!(parse_date("2024-05-01T12:30:00"), Some(expected));
+        assert_eq!(parse_date("2024-05-01 12:30:00"), Some(expected));
+        assert_eq!(parse_date("2024/05/01 12:30"), Some(expected));
+        // 日付のみは 0 時
+        let day = parse_date("2024-05-01T00:00:00Z");
+        for s in ["2024-05-01", "2024/05/01", "2024.05.01", "2024年5月1日", " 2024-05-01 "] {
+            assert_eq!(parse_date(s), day, "{}", s);
+        }
+        assert!(parse_date("yesterday").is_none());
+        assert!(parse_date("2024-13-01").is_none());
+    }
+
+    #[test]
+    fn test_cap_unique_tokens() {
+        // 異なり語 10000 + 頻出語
+        let mut tokens: Vec<String> = (0..10000).map(|i| format!("t{}", i)).collect();
+        tokens.extend(["t5000", "t5000", "t9999"].iter().map(|t| t.to_string()));
+        let total = tokens.len();
+        assert_eq!(cap_unique_tokens(&mut tokens, 10000), 0);
+        assert_eq!(tokens.len(), total);
+
+        assert_eq!(cap_unique_tokens(&mut tokens, 3), 9997);
+        assert_eq!(tokens, vec!["t0", "t5000", "t9999", "t5000", "t5000", "t9999"]);
+        let token_fq = TokenFrequency::from(&tokens[..]);
+        assert_eq!(token_fq.token_count("t5000"), 3);
+    }
+
+    #[test]
+    fn test_cap_with_title_tokens() {
+        // 本文だけで上限ちょうど、タイトルの語を加えると超える
+        let mut body: Vec<String> = (0..100).map(|i| format!("t{}", i)).collect();
+        body.push("t0".to_string());
+        assert_eq!(cap_unique_tokens(&mut body, 100), 0);
+        let title = vec!["タイトル".to_string(), "ページ".to_string()];
+        let mut token_fq = title_weighted_tokens(&body, &title, 3);
+        assert_eq!(token_fq.token_num(), 102);
+
+        let order: Vec<&String> = body.iter().chain(&title).collect();
+        assert_eq!(cap_unique_token_freq(&mut token_fq, &order, 100), 2);
+        assert_eq!(token_fq.token_num(), 100);
+        // 重みの大きいタイトルの語と本文の頻出語が残り、同数なら本文で後に出た語から捨てる
+        assert_eq!(token_fq.token_count("タイトル"), 3);
+        assert_eq!(token_fq.token_count("t0"), 2);
+        assert!(token_fq.contains_token("t97"));
+        assert!(!token_fq.contains_token("t98") && !token_fq.contains_token("t99"));
+        assert_eq!(token_fq.token_sum(), 101 - 2 + 6);
+    }
+
+    #[test]
+    fn test_add_weighted_tokens() {
+        let mut token_fq = TokenFrequency::from(&["本文", "見出し"][..]);
+        add_weighted_tokens(&mut token_fq, &["見出し"], 3);
+        assert_eq!(token_fq.token_count("見出し"), 4);
+        assert_eq!(token_fq.token_count("本文"), 1);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use serde::Serialize;
+
+use crate::collect::{IndexReq, IndexRes};
+
+/// 同時に保持するジョブの上限
+pub const MAX_JOBS: usize = 64;
+/// 完了したジョブを保持する時間
+pub const JOB_TTL: Duration = Duration::from_secs(3600);
+/// /bulk_add と /indexed の 1 リクエストあたりの URL 数の上限
+pub const MAX_BULK_ITEMS: usize = 1000;
+
+/// 1 URL ぶんの結果
+#[derive(Debug, Clone, Serialize)]
+pub struct JobItem {
+    pub url: String,
+    pub status: u16,
+    pub result: IndexRes,
+}
+
+/// GET /jobs/:id のレスポンス
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub finished: bool,
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+    /// これまでに終わった URL の結果 (処理順)
+    pub results: Vec<JobItem>,
+}
+
+struct Job {
+    created: Instant,
+    /// 完了した時刻 (実行中は None)
+    finished: Option<Instant>,
+    total: usize,
+    results: Vec<JobItem>,
+}
+
+/// バックグラウンドの /bulk_add の進捗 (メモリ上のみ、再起動で消える)
+/// 完了から ttl を過ぎたものは次にジョブを作るときに消す
+pub struct JobStore {
+    capacity: usize,
+    ttl: Duration,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        Self::new(MAX_JOBS, JOB_TTL)
+    }
+}
+
+impl JobStore {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self { capacity, ttl, jobs: Mutex::new(HashMap::new()) }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Job>> {
+        match self.jobs.lock() {
+            Ok(jobs) => jobs,
+            Err(poison) => poison.into_inner(),
+        }
+    }
+
+    /// ジョブを登録して ID を返す
+    /// 容量を超える場合は最も古い完了済みのジョブを捨てる (全て実行中なら None)
+    pub fn create(&self, total: usize) -> Option<String> {
+        let mut jobs = self.lock();
+        let now = Instant::now();
+        jobs.retain(|_, job| job.finished.is_none_or(|t| now.duration_since(t) < self.ttl));
+        if jobs.len() >= self.capacity {
+            let oldest = jobs.iter()
+                .filter(|(_, job)| job.finished.is_some())
+                .min_by_key(|(_, job)| job.created)
+                .map(|(id, _)| id.clone())?;
+            jobs.remove(&oldest);
+        }
+        let id = uuid::Uuid::new_v4().to_string();
+        jobs.insert(id.clone(), Job { created: now, finished: None, total, results: Vec::with_capacity(total) });
+        Some(id)
+    }
+
+    pub fn record(&self, id: &str, item: JobItem) {
+        if let Some(job) = self.lock().get_mut(id) {
+            job.results.push(item);
+        }
+    }
+
+    pub fn finish(&self, id: &str) {
+        if let Some(job) = self.lock().get_mut(id) {
+            job.finished = Some(Instant::now());
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        let jobs = self.lock();
+        let job = jobs.get(id)?;
+        if job.finished.is_some_and(|t| t.elapsed() >= self.ttl) {
+            return None;
+        }
+        Some(JobStatus {
+            id: id.to_string(),
+            finished: job.finished.is_some(),
+            total: job.total,
+            done: job.results.len(),
+            failed: job.results.iter().filter(|item| matches!(item.result, IndexRes::Failed { .. })).count(),
+            results: job.results.clone(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// items を 1 件ずつ add に渡すバックグラウンドタスクを起動して ID を返す
+    /// スクレイパへの負荷を抑えるため並列にはしない
+    pub fn spawn_bulk<F, Fut>(self: &Arc<Self>, items: Vec<IndexReq>, add: F) -> Option<String>
+    where
+        F: Fn(IndexReq) -> Fut + Send + 'static,
+        Fut: Future<Output = (u16, IndexRes)> + Send,
+    {
+        let id = self.create(items.len())?;
+        let store = self.clone();
+        let job_id = id.clone();
+        tokio::spawn(async move {
+            for item in items {
+                let url = item.url.clone();
+                let (status, result) = add(item).await;
+                store.record(&job_id, JobItem { url, status, result });
+            }
+            store.finish(&job_id);
+            log::info!("Bulk job {} finished", job_id);
+        });
+        Some(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(url: &str) -> IndexReq {
+        serde_json::from_value(serde_json::json!({ "url": url, "tags": [] })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bulk_job_progress() {
+        let store = Arc::new(JobStore::default());
+        let items = vec![req("https://example.com/1"), req("https://example.com/bad"), req("https://example.com/3")];
+        let id = store.spawn_bulk(items, |item| async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            if item.url.ends_with("bad") {
+                (502, IndexRes::Failed { error: "scraper error".to_string(), detail: None })
+            } else {
+                (200, IndexRes::Success {
+                    url: item.url.clone().into(),
+                    requested_url: item.url.into(),
+                    title: "title".into(),
+                    favicon: None,
+                    tags: Vec::new(),
+                    descriptions: "body".into(),
+                    warnings: Vec::new(),
+                    skipped: false,
+                })
+            }
+        }).unwrap();
+
+        let status = loop {
+            let status = store.get(&id).unwrap();
+            assert!(status.done <= status.total);
+            if status.finished {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        };
+        assert_eq!((status.total, status.done, status.failed), (3, 3, 1));
+        assert_eq!(status.results[1].url, "https://example.com/bad");
+        assert_eq!(status.results[1].status, 502);
+        assert!(store.get("missing").is_none());
+
+        // 容量いっぱいで全て実行中なら作れない、完了済みがあればそれを捨てる
+        let small = JobStore::new(1, JOB_TTL);
+        let first = small.create(1).unwrap();
+        assert!(small.create(1).is_none());
+        small.finish(&first);
+        assert!(small.create(1).is_some());
+        assert!(small.get(&first).is_none());
+
+        // TTL を過ぎた完了済みのジョブは見えない
+        let expired = JobStore::new(4, Duration::ZERO);
+        let id = expired.create(0).unwrap();
+        expired.finish(&id);
+        assert!(expired.get(&id).is_none());
+    }
+}
@@ -1,4 +1,14 @@
+pub mod config;
 pub mod context;
 pub mod index;
 pub mod tokenize;
-pub mod collect;
\ No newline at end of file
+pub mod collect;
+pub mod http_client;
+pub mod search;
+pub mod ingest;
+pub mod version;
+pub mod startup;
+pub mod cli;
+pub mod trigram;
+pub mod openapi;
+pub mod jobs;
@@ -1,34 +1,71 @@
-mod tokenize;
-mod context;
-mod collect;
-mod http_client;
-mod index;
-
-
-use kurosabi::Kurosabi;
-use log::{debug, info, warn, LevelFilter};
+use kurosabi::{kurosabi::Context, Kurosabi};
+use log::{info, warn, LevelFilter};
 use tokio::signal;
 use std::{io::Write, sync::atomic::{AtomicBool, Ordering}};
-use percent_encoding::percent_decode_str;
-use tf_idf_vectorizer::{SimilarityAlgorithm, TokenFrequency};
 
-use crate::{collect::{IndexReq, IndexRes, ScraperResult, SearchRes}, context::SearchContext, http_client::fetch_scraper_api, index::{IndexMeta, Tags}, tokenize::{sudachi_tokenize_large, SudachiMode}};
+use clap::Parser;
+use wk_371tti_net_search_engine::{cli::{Cli, Command, DEFAULT_INDEX_DIR}, config::Config, collect::{BulkAddReq, ClearReq, IndexReq, IndexedReq, IndexRes, ScraperResult, SearchRes}, context::{check_admin_auth, check_scraper_enabled, not_found, read_json_body, resolve_scraper_url, SearchContext}, http_client::fetch_scraper_api, jobs::{JobItem, MAX_BULK_ITEMS}, openapi, index::{IndexMeta, SaveInProgressError, SplitShardError, Tags}, ingest::{add_weighted_tokens, cap_unique_token_freq, cap_unique_tokens, choose_description, enrich_meta, resolve_url, title_weighted_tokens, ScrapeFailure}, search::{decode_query, idf_response, parse_bool, parse_range_param, split_list, search, trending_window, SearchParams, SearchReq, ERROR_INVALID_REQUEST}, startup::startup_check, tokenize::{normalize_lang, probe_sudachi, sudachi_tokenize_detailed, token_spans, tokenize_lang, tokenize_lang_batch, uses_sudachi, SudachiMode, TokenizeOptions}, version::BuildInfo};
 
-pub const INDEX_DIR: &str = "./index_data";
+pub const INDEX_DIR: &str = DEFAULT_INDEX_DIR;
 pub const SCRAPER_API_URL: &str = "http://localhost:88/url/";
 pub const MAX_DESC_LENGTH: usize = 100; // 説明文の最大長
 pub const MAX_TITLE_LENGTH: usize = 100; // タイトルの最大長
-pub const MAX_SEARCH_RESULTS: usize = 1000; // 検索結果の最大数
-pub const DEFAULT_SEARCH_RESULTS: usize = 20; // 検索結果のデフォルト数
+/// add_document が失敗したとき (全シャードが上限に達しているかロックが壊れている) の /add のエラー
+pub const INDEX_FULL_ERROR: &str = "Failed to add document: all shards are full or unavailable";
 
 static CTRL_C_SAVED: AtomicBool = AtomicBool::new(false);
 
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
     init_logging();
     info!("Logger initialized");
-    let context = SearchContext::new(INDEX_DIR);
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = config.install_globals() {
+        log::error!("Invalid config: {}", e);
+        std::process::exit(1);
+    }
+    // serve 以外はサーバを起動せずにインデックスを操作して終わる
+    if let Some(command) = cli.command.filter(|c| !matches!(c, Command::Serve)) {
+        if let Err(e) = wk_371tti_net_search_engine::cli::run(&command, &config, &mut std::io::stdout().lock()) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    // sudachi がなければ /add も /search も動かないので起動しない
+    match probe_sudachi() {
+        Ok(()) => info!("Tokenizer ready ({})", config.sudachi.bin),
+        Err(e) if e.is_unavailable() => {
+            log::error!("Tokenizer unavailable, is `{}` installed and on PATH? ({})", config.sudachi.bin, e);
+            std::process::exit(1);
+        }
+        Err(e) => warn!("Tokenizer probe failed: {}", e),
+    }
+    let context = SearchContext::new(INDEX_DIR, config);
+
+    if context.config.warmup {
+        let started = std::time::Instant::now();
+        let documents = context.index_pool.warmup();
+        info!("Warmup finished: {} documents in {:?}", documents, started.elapsed());
+    }
+
+    let report = startup_check(&context, SCRAPER_API_URL).await;
+    report.log();
+    if !report.is_ok() {
+        if context.config.strict_startup {
+            log::error!("Startup check failed, exiting (STRICT_STARTUP=1)");
+            std::process::exit(1);
+        }
+        warn!("Startup check found problems, continuing anyway");
+    }
 
     let context_clone = context.clone();
 
@@ -43,9 +80,11 @@ async fn main() {
             .is_ok()
         {
             log::info!("Ctrl+C detected. Flushing index to disk...");
-            context_clone.index_pool.save(INDEX_DIR).unwrap_or_else(|e| {
-                log::error!("Index save failed: {}", e);
-            });
+            match context_clone.index_pool.save(&context_clone.index_pool.index_dir) {
+                Ok(summary) if summary.skipped.is_empty() => {}
+                Ok(summary) => log::error!("Index saved partially, skipped shards: {:?}", summary.skipped),
+                Err(e) => log::error!("Index save failed: {}", e),
+            }
             log::info!("Shutdown complete.");
         } else {
             log::warn!("Ctrl+C received again; already saving / shutting down.");
@@ -57,10 +96,40 @@ async fn main() {
     let mut kurosabi = Kurosabi::with_context(context);
 
     kurosabi.get("/status", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
         let count = c.c.index_pool.counter.load(Ordering::SeqCst);
         let result = serde_json::json!({
             "status": "ok",
             "documents": count,
+            "generation": c.c.index_pool.generation.load(Ordering::SeqCst),
+            "auto_saves": c.c.index_pool.auto_saves.load(Ordering::SeqCst),
+            "locks": c.c.index_pool.lock_metrics.snapshot(),
+            "version": BuildInfo::current(),
+        });
+        c.res.json_value(&result);
+        c.res.set_status(200);
+        c
+    });
+
+    kurosabi.get("/openapi.json", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        c.res.json_value(&openapi::document());
+        c.res.set_status(200);
+        c
+    });
+
+    kurosabi.get("/version", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        let result = serde_json::json!({
+            "build": BuildInfo::current(),
+            "shards": c.c.index_pool.indexes.len(),
+            "index_dir": c.c.index_pool.index_dir,
         });
         c.res.json_value(&result);
         c.res.set_status(200);
@@ -68,105 +137,118 @@ async fn main() {
     });
 
     kurosabi.post("/add", |mut c| async move {
-        let index_req = match c.req.body_de_struct::<IndexReq>().await {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        if let Err((status, error)) = check_scraper_enabled(&c.c.config) {
+            let result = IndexRes::Failed { error, detail: None };
+            c.res.json_value(&serde_json::to_value(&result).unwrap());
+            c.res.set_status(status);
+            return c;
+        }
+        let index_req = match read_json_body::<IndexReq>(&mut c).await {
             Ok(v) => v,
-            Err(_) => {
-                warn!("Missing or invalid request body");
-                let result = IndexRes::Failed { error: "Invalid request body".to_string() };
+            Err(e) => {
+                warn!("[{}] Rejected request body: {} ({})", c.c.request_id, e.error, e.detail.as_deref().unwrap_or("-"));
+                let result = IndexRes::Failed { error: e.error, detail: e.detail };
                 c.res.json_value(&serde_json::to_value(&result).unwrap());
-                c.res.set_status(400);
+                c.res.set_status(e.status);
                 return c;
             },
         };
 
-        let scraper_result = match fetch_scraper_api(&format!("{}{}", SCRAPER_API_URL, index_req.url)).await {
-            Ok(res) => res,
+        let (status, result) = add_request(&c.c, index_req).await;
+        c.res.json_value(&serde_json::to_value(&result).unwrap());
+        c.res.set_status(status);
+        c
+    });
+
+    kurosabi.post("/bulk_add", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        if let Err((status, error)) = check_scraper_enabled(&c.c.config) {
+            let result = IndexRes::Failed { error, detail: None };
+            c.res.json_value(&serde_json::to_value(&result).unwrap());
+            c.res.set_status(status);
+            return c;
+        }
+        let bulk_req = match read_json_body::<BulkAddReq>(&mut c).await {
+            Ok(v) => v,
             Err(e) => {
-                warn!("Failed to fetch scraper API: {}", e);
-                let result = IndexRes::Failed { error: format!("Failed to fetch scraper API: {}", e) };
+                warn!("[{}] Rejected request body: {} ({})", c.c.request_id, e.error, e.detail.as_deref().unwrap_or("-"));
+                let result = IndexRes::Failed { error: e.error, detail: e.detail };
                 c.res.json_value(&serde_json::to_value(&result).unwrap());
-                c.res.set_status(500);
+                c.res.set_status(e.status);
                 return c;
-            }
+            },
         };
+        if bulk_req.items.is_empty() || bulk_req.items.len() > MAX_BULK_ITEMS {
+            let result = IndexRes::Failed { error: format!("items must contain 1 to {} documents", MAX_BULK_ITEMS), detail: None };
+            c.res.json_value(&serde_json::to_value(&result).unwrap());
+            c.res.set_status(400);
+            return c;
+        }
+        let total = bulk_req.items.len();
+        info!("[{}] Bulk add request: {} documents (async: {})", c.c.request_id, total, bulk_req.background);
 
-        match scraper_result {
-            ScraperResult::Success { results, status: _, url, success: _ } => {
-                let body = match results.descriptions.first() {
-                    Some(d) => d,
-                    None => {
-                        warn!("No body text found");
-                        let result = IndexRes::Failed { error: "No body text found".to_string() };
-                        c.res.json_value(&serde_json::to_value(&result).unwrap());
-                        c.res.set_status(404);
-                        return c;
-                    }
-                };
-
-                let title = match index_req.title.or_else(|| results.title.first().cloned()) {
-                    Some(t) => t,
-                    None => "No Title".to_string(),
-                }.chars().take(MAX_TITLE_LENGTH).collect();
-
-                let description = match index_req.descriptions.clone() {
-                    Some(d) => d.chars().take(MAX_DESC_LENGTH).collect(),
-                    None => body.chars().take(MAX_DESC_LENGTH).collect(), // 先頭500文字を説明に
-                };
-                
-                let favicon: Option<Box<str>> = index_req.favicon.or_else(|| results.favicon.first().cloned()).map(|s| s.into_boxed_str());
-
-                let url = url.into_boxed_str();
-
-                let tags = Tags::from_strs(&index_req.tags);
-
-                let meta = IndexMeta { 
-                    id: 0, 
-                    url, 
-                    title, 
-                    description, 
-                    favicon, 
-                    time: chrono::Utc::now(), 
-                    points: 0.0, 
-                    tags 
-                };
-
-                let tokens = match sudachi_tokenize_large(body, SudachiMode::A, 2000) {
-                    Ok(t) => t,
-                    Err(e) => {
-                        warn!("sudachi_tokenize_large error: {}", e);
-                        let result = IndexRes::Failed { error: format!("Tokenization error: {}", e) };
-                        c.res.json_value(&serde_json::to_value(&result).unwrap());
-                        c.res.set_status(500);
-                        return c;
-                    }
-                };
+        if bulk_req.background {
+            let ctx = c.c.clone();
+            let job = c.c.jobs.spawn_bulk(bulk_req.items, move |index_req| {
+                let ctx = ctx.clone();
+                async move { add_request(&ctx, index_req).await }
+            });
+            match job {
+                Some(job_id) => {
+                    info!("[{}] Started bulk job {}", c.c.request_id, job_id);
+                    c.res.json_value(&serde_json::json!({ "success": true, "job_id": job_id, "total": total }));
+                    c.res.set_status(202);
+                }
+                None => {
+                    let result = IndexRes::Failed { error: "Too many running jobs".to_string(), detail: None };
+                    c.res.json_value(&serde_json::to_value(&result).unwrap());
+                    c.res.set_status(429);
+                }
+            }
+            return c;
+        }
 
-                let token_fq = TokenFrequency::from(&tokens[..]);
+        let mut results = Vec::with_capacity(total);
+        for index_req in bulk_req.items {
+            let url = index_req.url.clone();
+            let (status, result) = add_request(&c.c, index_req).await;
+            results.push(JobItem { url, status, result });
+        }
+        let failed = results.iter().filter(|item| matches!(item.result, IndexRes::Failed { .. })).count();
+        c.res.json_value(&serde_json::json!({ "success": true, "total": total, "failed": failed, "results": results }));
+        c.res.set_status(200);
+        c
+    });
 
-                c.c.index_pool.add_document(&token_fq, meta.clone());
-                info!("Added URL: {}", meta.url);
-                let result = IndexRes::Success { 
-                    url: meta.url, 
-                    title: meta.title, 
-                    favicon: meta.favicon, 
-                    tags: meta.tags.tags(), 
-                    descriptions: meta.description, 
-                };
-                c.res.json_value(&serde_json::to_value(&result).unwrap());
+    kurosabi.get("/jobs/:id", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        let status = c.req.path.get_field("id").and_then(|id| c.c.jobs.get(&id));
+        match status {
+            Some(status) => {
+                let mut result = serde_json::to_value(&status).unwrap();
+                result["success"] = true.into();
+                c.res.json_value(&result);
                 c.res.set_status(200);
-                return c;
             }
-            ScraperResult::Failed { error , success } => {
-                warn!("Scraper API returned error: {}", error);
-                let result = IndexRes::Failed { error: format!("Scraper API error: {}", error) };
-                c.res.json_value(&serde_json::to_value(&result).unwrap());
-                c.res.set_status(500);
-                return c;
+            None => {
+                c.res.json_value(&serde_json::json!({ "success": false, "error": "Job not found" }));
+                c.res.set_status(404);
             }
         }
+        c
     });
 
     kurosabi.get("/del/*", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
         // パスパラメータからurlを取得
         let full_path = &c.req.path.path;
         let del_part = if let Some(idx) = full_path.find("/del/") {
@@ -195,101 +277,448 @@ async fn main() {
         c
     });
 
-    kurosabi.get("/search", |mut c| async move {
-        // query（URLエンコードされている可能性があるためデコード）
-        let query_str = match c.req.path.get_query("query") {
-            Some(q) => {
-                let decoded = percent_decode_str(&q)
-                    .decode_utf8()
-                    .map(|cow| cow.into_owned())
-                    .unwrap_or(q);
-                let trimmed = decoded.trim().to_string();
-                if trimmed.is_empty() {
-                    let result = SearchRes::Failed { error: "Missing query".to_string() };
-                    c.res.json_value(&serde_json::to_value(&result).unwrap());
-                    c.res.set_status(400);
-                    return c;
-                }
-                trimmed
+    kurosabi.get("/doc/vector", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        let Some(url) = decode_query(&mut c.req.path, "url").filter(|u| !u.is_empty()) else {
+            let result = serde_json::json!({
+                "success": false,
+                "error": "Missing url parameter",
+            });
+            c.res.json_value(&result);
+            c.res.set_status(400);
+            return c;
+        };
+        match c.c.index_pool.doc_vector(&url) {
+            Some(vector) => {
+                c.res.json_value(&serde_json::to_value(&vector).unwrap());
+                c.res.set_status(200);
             }
             None => {
-                let result = SearchRes::Failed { error: "Missing query".to_string() };
+                let result = serde_json::json!({
+                    "success": false,
+                    "error": "Document not found",
+                });
+                c.res.json_value(&result);
+                c.res.set_status(404);
+            }
+        }
+        c
+    });
+
+    kurosabi.post("/indexed", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        let indexed_req = match read_json_body::<IndexedReq>(&mut c).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("[{}] Rejected request body: {} ({})", c.c.request_id, e.error, e.detail.as_deref().unwrap_or("-"));
+                c.res.json_value(&serde_json::json!({ "success": false, "error": e.error, "detail": e.detail }));
+                c.res.set_status(e.status);
+                return c;
+            },
+        };
+        if indexed_req.urls.is_empty() || indexed_req.urls.len() > MAX_BULK_ITEMS {
+            c.res.json_value(&serde_json::json!({ "success": false, "error": format!("urls must contain 1 to {} URLs", MAX_BULK_ITEMS) }));
+            c.res.set_status(400);
+            return c;
+        }
+        let results = c.c.index_pool.indexed_status(&indexed_req.urls);
+        let indexed = results.iter().filter(|r| r.indexed).count();
+        c.res.json_value(&serde_json::json!({ "success": true, "total": results.len(), "indexed": indexed, "results": results }));
+        c.res.set_status(200);
+        c
+    });
+
+    kurosabi.get("/idf", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        let terms = decode_query(&mut c.req.path, "tokens").map(|v| split_list(&v)).unwrap_or_default();
+        // tokenize=true なら各語を sudachi で分割してから引く
+        // 全ての語を 1 回の sudachi に流し、プロセスの起動と待ちは blocking スレッドで行う
+        let tokenize = decode_query(&mut c.req.path, "tokenize").is_some_and(|v| parse_bool(&v));
+        let pool = c.c.index_pool.clone();
+        let options = c.c.config.tokenize.clone();
+        let (status, res) = tokio::task::spawn_blocking(move || {
+            idf_response(&pool, &terms, tokenize, |texts| tokenize_lang_batch(texts, &options))
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        c.res.json_value(&res);
+        c.res.set_status(status);
+        c
+    });
+
+    kurosabi.get("/search", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        let search_req = match SearchReq::from_path(&mut c.req.path) {
+            Ok(req) => req,
+            Err(error) => {
+                let result = SearchRes::failed(ERROR_INVALID_REQUEST, error, None);
                 c.res.json_value(&serde_json::to_value(&result).unwrap());
                 c.res.set_status(400);
                 return c;
             }
         };
-        // range パラメータ正規化
-        let (range_start, range_end) = parse_range_param(c.req.path.get_query("range"));
-        let range = range_start..range_end;
-        // algo (URLエンコードの可能性があるためデコードしてから簡易パース)
-        let algo_str_raw = c
-            .req
-            .path
-            .get_query("algo")
-            .unwrap_or_else(|| "BM25(1.2,0.75)".to_string());
-        let algo_str = percent_decode_str(&algo_str_raw)
-            .decode_utf8()
-            .map(|cow| cow.into_owned())
-            .unwrap_or(algo_str_raw);
-        let algo = parse_algo(&algo_str);
-        // tag (URLエンコードの可能性があるためデコードしてからパース)
-        // tag=tag1,tag2,...
-        let tag_str = c.req.path.get_query("tag").unwrap_or_default();
-        let tag_decoded = percent_decode_str(&tag_str)
-            .decode_utf8()
-            .map(|cow| cow.into_owned())
-            .unwrap_or(tag_str.to_string());
-        let tags = Tags::from_strs(&tag_decoded.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).collect::<Vec<_>>());
-        let tag_exclusive = c.req.path.get_query("tag_exclusive")
-            .map(|v| {
-            let v = v.trim().to_ascii_lowercase();
-            v == "true" || v == "1"
-            })
-            .unwrap_or(false);
+        respond_search(c, search_req, None).await
+    });
 
-        debug!("tag_exclusive={}", tag_exclusive);
+    kurosabi.get("/trending", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        let window = match trending_window(&mut c.req.path) {
+            Ok(w) => w,
+            Err(error) => {
+                let result = SearchRes::failed(ERROR_INVALID_REQUEST, error, None);
+                c.res.json_value(&serde_json::to_value(&result).unwrap());
+                c.res.set_status(400);
+                return c;
+            }
+        };
+        let search_req = match SearchReq::from_path(&mut c.req.path) {
+            Ok(req) => req,
+            Err(error) => {
+                let result = SearchRes::failed(ERROR_INVALID_REQUEST, error, None);
+                c.res.json_value(&serde_json::to_value(&result).unwrap());
+                c.res.set_status(400);
+                return c;
+            }
+        };
+        respond_search(c, search_req, Some(window)).await
+    });
 
-        // tokenize (Sudachi 正規化)
-        let tokens = match sudachi_tokenize_large(&query_str, SudachiMode::A, 2000) {
-            Ok(t) => t,
+    kurosabi.post("/search", |mut c| async move {
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        let search_req = match read_json_body::<SearchReq>(&mut c).await {
+            Ok(v) => v,
             Err(e) => {
-                warn!("sudachi_tokenize_large error: {}", e);
-                let result = SearchRes::Failed { error: format!("Tokenization error: {}", e) };
+                warn!("[{}] Rejected request body: {} ({})", c.c.request_id, e.error, e.detail.as_deref().unwrap_or("-"));
+                let error = match e.detail {
+                    Some(detail) => format!("{}: {}", e.error, detail),
+                    None => e.error,
+                };
+                let result = SearchRes::failed(ERROR_INVALID_REQUEST, error, None);
                 c.res.json_value(&serde_json::to_value(&result).unwrap());
-                c.res.set_status(500);
+                c.res.set_status(e.status);
                 return c;
-            }
+            },
         };
-        if tokens.is_empty() {
-            let result = SearchRes::Success { query: query_str, tokenize_query: tokens, algorithm: algo_str.clone(), range, results: Vec::new() };
-            c.res.json_value(&serde_json::to_value(&result).unwrap());
-            c.res.set_status(200);
+        respond_search(c, search_req, None).await
+    });
+
+    kurosabi.get("/debug/shard/:id", |mut c| async move {
+        if reject_unauthorized(&mut c) {
             return c;
         }
+        if !c.c.config.debug_endpoints {
+            let result = serde_json::json!({
+                "success": false,
+                "error": "Debug endpoints are disabled",
+            });
+            c.res.json_value(&result);
+            c.res.set_status(403);
+            return c;
+        }
+        let shard_id = c.req.path.get_field("id").and_then(|id| id.parse::<usize>().ok());
+        let range = parse_range_param(c.req.path.get_query("range")).range;
+        match shard_id.and_then(|id| c.c.index_pool.dump_shard(id, range)) {
+            Some(dump) => {
+                c.res.json_value(&serde_json::to_value(&dump).unwrap());
+                c.res.set_status(200);
+            }
+            None => {
+                let result = serde_json::json!({
+                    "success": false,
+                    "error": "Shard not found",
+                });
+                c.res.json_value(&result);
+                c.res.set_status(404);
+            }
+        }
+        c
+    });
 
-        let tf = TokenFrequency::from(&tokens[..]);
+    kurosabi.post("/debug/prune_corpus", |mut c| async move {
+        // 全シャードのコーパスを書き換えて保存するので管理系と同じトークンで守る (/clear と同じく DEBUG_ENDPOINTS より先に見る)
+        if let Err((status, error)) = check_admin_auth(c.c.config.reload_token.as_deref(), c.req.header.get("Authorization")) {
+            warn!("[{}] Rejected prune_corpus: {}", c.c.request_id, error);
+            c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+            c.res.set_status(status);
+            return c;
+        }
+        if !c.c.config.debug_endpoints {
+            let result = serde_json::json!({
+                "success": false,
+                "error": "Debug endpoints are disabled",
+            });
+            c.res.json_value(&result);
+            c.res.set_status(403);
+            return c;
+        }
+        // mutation_lock の write を持ったまま全シャードを作り直して保存するので blocking スレッドで実行する
+        let pool = c.c.index_pool.clone();
+        let pruned = tokio::task::spawn_blocking(move || {
+            let stats = pool.prune_corpus().map_err(|e| e.to_string())?;
+            let saved = pool.save(&pool.index_dir).map_err(|e| e.to_string());
+            Ok::<_, String>((stats, saved))
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        match pruned {
+            Ok((stats, saved)) => {
+                info!("[{}] Pruned corpus: {:?}", c.c.request_id, stats);
+                // メモリ上の整理は済んでいるので統計は返すが、保存できなければ /save と同じく 500 にする
+                let mut result = serde_json::to_value(&stats).unwrap();
+                let status = match saved {
+                    Ok(summary) if summary.skipped.is_empty() => 200,
+                    Ok(summary) => {
+                        log::error!("[{}] Index saved partially, skipped shards: {:?}", c.c.request_id, summary.skipped);
+                        result["success"] = false.into();
+                        result["error"] = "Index saved partially".into();
+                        result["skipped"] = serde_json::to_value(&summary.skipped).unwrap();
+                        500
+                    }
+                    Err(e) => {
+                        log::error!("[{}] Index save failed: {}", c.c.request_id, e);
+                        result["success"] = false.into();
+                        result["error"] = format!("Index save failed: {}", e).into();
+                        500
+                    }
+                };
+                c.res.json_value(&result);
+                c.res.set_status(status);
+            }
+            Err(e) => {
+                let result = serde_json::json!({
+                    "success": false,
+                    "error": e,
+                });
+                c.res.json_value(&result);
+                c.res.set_status(500);
+            }
+        }
+        c
+    });
 
-        // IndexPool を使ってスコア計算
-        let scored = c.c.index_pool.per_similarity(&tf, &algo);
-        println!("Scored {} documents", scored.len());
-        let sorted = c.c.index_pool.sort_by_score(scored);
-        let results = c.c.index_pool.generate_results(sorted, range.clone(), tags, tag_exclusive);
-        let result = SearchRes::Success { 
-            query: query_str, 
-            tokenize_query: tokens, 
-            algorithm: algo_str, 
-            range: range, 
-            results: results 
-        };
-        c.res.json_value(&serde_json::to_value(&result).unwrap());
+    kurosabi.post("/save", |mut c| async move {
+        if let Err((status, error)) = check_admin_auth(c.c.config.reload_token.as_deref(), c.req.header.get("Authorization")) {
+            warn!("[{}] Rejected save: {}", c.c.request_id, error);
+            c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+            c.res.set_status(status);
+            return c;
+        }
+        // 終了時の保存が始まっていれば任せる
+        if CTRL_C_SAVED.load(Ordering::SeqCst) {
+            c.res.json_value(&serde_json::json!({ "success": false, "error": "Shutting down, save in progress" }));
+            c.res.set_status(409);
+            return c;
+        }
+        // 全シャードをファイルに書き出すので blocking スレッドで実行する
+        let started = std::time::Instant::now();
+        let pool = c.c.index_pool.clone();
+        let saved = tokio::task::spawn_blocking(move || {
+            pool.try_save(&pool.index_dir).map_err(|e| (if e.is::<SaveInProgressError>() { 409 } else { 500 }, e.to_string()))
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        match saved {
+            Ok(summary) => {
+                let duration_ms = started.elapsed().as_millis() as u64;
+                info!("[{}] Index saved on request in {} ms", c.c.request_id, duration_ms);
+                if !summary.skipped.is_empty() {
+                    log::error!("[{}] Index saved partially, skipped shards: {:?}", c.c.request_id, summary.skipped);
+                }
+                c.res.json_value(&serde_json::json!({
+                    "success": summary.skipped.is_empty(),
+                    "duration_ms": duration_ms,
+                    "shards": summary.sizes,
+                    "skipped": summary.skipped,
+                }));
+                c.res.set_status(if summary.skipped.is_empty() { 200 } else { 500 });
+            }
+            Err((status, error)) => {
+                warn!("[{}] Save failed: {}", c.c.request_id, error);
+                c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+                c.res.set_status(status);
+            }
+        }
+        c
+    });
+
+    kurosabi.post("/recalc_sizes", |mut c| async move {
+        if let Err((status, error)) = check_admin_auth(c.c.config.reload_token.as_deref(), c.req.header.get("Authorization")) {
+            warn!("[{}] Rejected recalc_sizes: {}", c.c.request_id, error);
+            c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+            c.res.set_status(status);
+            return c;
+        }
+        // 全シャードを直列化し直すので blocking スレッドで実行する
+        let started = std::time::Instant::now();
+        let pool = c.c.index_pool.clone();
+        let shards = tokio::task::spawn_blocking(move || pool.recalc_sizes())
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        let duration_ms = started.elapsed().as_millis() as u64;
+        info!("[{}] Recalculated shard sizes in {} ms", c.c.request_id, duration_ms);
+        c.res.json_value(&serde_json::json!({
+            "success": true,
+            "duration_ms": duration_ms,
+            "max_shard_bytes": c.c.index_pool.max_shard_bytes,
+            "shards": shards,
+        }));
         c.res.set_status(200);
         c
     });
 
+    kurosabi.get("/fsck", |mut c| async move {
+        if let Err((status, error)) = check_admin_auth(c.c.config.reload_token.as_deref(), c.req.header.get("Authorization")) {
+            warn!("[{}] Rejected fsck: {}", c.c.request_id, error);
+            c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+            c.res.set_status(status);
+            return c;
+        }
+        // 全ドキュメントを mutation_lock の write を持ったまま調べるので blocking スレッドで実行する
+        let started = std::time::Instant::now();
+        let pool = c.c.index_pool.clone();
+        let report = tokio::task::spawn_blocking(move || pool.fsck())
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        let duration_ms = started.elapsed().as_millis() as u64;
+        info!("[{}] fsck finished in {} ms (ok: {})", c.c.request_id, duration_ms, report.ok);
+        let mut result = serde_json::to_value(&report).unwrap();
+        result["success"] = true.into();
+        result["duration_ms"] = duration_ms.into();
+        c.res.json_value(&result);
+        c.res.set_status(200);
+        c
+    });
+
+    kurosabi.post("/split/:id", |mut c| async move {
+        if let Err((status, error)) = check_admin_auth(c.c.config.reload_token.as_deref(), c.req.header.get("Authorization")) {
+            warn!("[{}] Rejected split: {}", c.c.request_id, error);
+            c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+            c.res.set_status(status);
+            return c;
+        }
+        let Some(shard_id) = c.req.path.get_field("id").and_then(|id| id.parse::<usize>().ok()) else {
+            c.res.json_value(&serde_json::json!({ "success": false, "error": "Shard not found" }));
+            c.res.set_status(404);
+            return c;
+        };
+        // シャードの write lock を持ったまま分割して保存するので blocking スレッドで実行する
+        let pool = c.c.index_pool.clone();
+        let split = tokio::task::spawn_blocking(move || {
+            pool.split_shard(shard_id).map_err(|e| {
+                let status = match e.downcast_ref::<SplitShardError>() {
+                    Some(SplitShardError::NotFound) => 404,
+                    Some(SplitShardError::TooFewDocuments) => 409,
+                    Some(SplitShardError::NoTarget) => 507,
+                    None => 500,
+                };
+                (status, e.to_string())
+            })
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        match split {
+            Ok(summary) => {
+                info!("[{}] Split shard {}: moved {} documents to shard {}", c.c.request_id, shard_id, summary.moved, summary.target);
+                let mut result = serde_json::to_value(&summary).unwrap();
+                result["success"] = true.into();
+                c.res.json_value(&result);
+                c.res.set_status(200);
+            }
+            Err((status, error)) => {
+                warn!("[{}] Split failed: {}", c.c.request_id, error);
+                c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+                c.res.set_status(status);
+            }
+        }
+        c
+    });
+
+    kurosabi.post("/clear", |mut c| async move {
+        // 認証を先に見る (トークンなしで DEBUG_ENDPOINTS の設定を探れないように)
+        if let Err((status, error)) = check_admin_auth(c.c.config.reload_token.as_deref(), c.req.header.get("Authorization")) {
+            warn!("[{}] Rejected clear: {}", c.c.request_id, error);
+            c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+            c.res.set_status(status);
+            return c;
+        }
+        if !c.c.config.debug_endpoints {
+            c.res.json_value(&serde_json::json!({ "success": false, "error": "Debug endpoints are disabled" }));
+            c.res.set_status(403);
+            return c;
+        }
+        let confirmed = match read_json_body::<ClearReq>(&mut c).await {
+            Ok(req) => req.confirm == "yes",
+            Err(_) => false,
+        };
+        if !confirmed {
+            c.res.json_value(&serde_json::json!({ "success": false, "error": "Clearing the index requires {\"confirm\": \"yes\"}" }));
+            c.res.set_status(400);
+            return c;
+        }
+        // 全シャードの write lock を取ってファイルを消すので blocking スレッドで実行する
+        let pool = c.c.index_pool.clone();
+        let cleared = tokio::task::spawn_blocking(move || pool.clear(&pool.index_dir).map_err(|e| e.to_string()))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        match cleared {
+            Ok(summary) => {
+                warn!("[{}] Index cleared: removed {} documents, deleted {} files", c.c.request_id, summary.removed, summary.deleted_files);
+                let mut result = serde_json::to_value(&summary).unwrap();
+                result["success"] = true.into();
+                c.res.json_value(&result);
+                c.res.set_status(200);
+            }
+            Err(e) => {
+                log::error!("[{}] Clear failed: {}", c.c.request_id, e);
+                c.res.json_value(&serde_json::json!({ "success": false, "error": e }));
+                c.res.set_status(500);
+            }
+        }
+        c
+    });
+
+    kurosabi.post("/reload", |mut c| async move {
+        if let Err((status, error)) = check_admin_auth(c.c.config.reload_token.as_deref(), c.req.header.get("Authorization")) {
+            warn!("[{}] Rejected reload: {}", c.c.request_id, error);
+            c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+            c.res.set_status(status);
+            return c;
+        }
+        match Config::load().and_then(|config| c.c.reload(config)) {
+            Ok(restart_required) => {
+                info!("[{}] Config reloaded", c.c.request_id);
+                if !restart_required.is_empty() {
+                    warn!("[{}] Changed settings need a restart: {:?}", c.c.request_id, restart_required);
+                }
+                c.res.json_value(&serde_json::json!({ "success": true, "restart_required": restart_required }));
+                c.res.set_status(200);
+            }
+            Err(e) => {
+                log::error!("[{}] Reload failed: {}", c.c.request_id, e);
+                c.res.json_value(&serde_json::json!({ "success": false, "error": e }));
+                c.res.set_status(400);
+            }
+        }
+        c
+    });
+
     kurosabi.not_found_handler(|mut c| async move {
-        c.res.text("Not Found");
-        c.res.set_status(404);
+        if reject_unauthorized(&mut c) {
+            return c;
+        }
+        not_found(&mut c.res);
         c
     });
 
@@ -321,116 +750,289 @@ fn init_logging() {
         .try_init();
 }
 
-// 検索アルゴリズムの簡易パーサ
-fn parse_algo(s: &str) -> SimilarityAlgorithm {
-    let lower = s.trim().to_ascii_lowercase();
-    // 補助: 引数の括弧内から数値を抽出
-    fn nums(src: &str) -> Vec<f64> {
-        if let (Some(l), Some(r)) = (src.find('('), src.rfind(')')) {
-            let inner = &src[l + 1..r];
-            inner
-                .split(',')
-                .filter_map(|p| p.trim().parse::<f64>().ok())
-                .collect()
-        } else {
-            Vec::new()
+/// before_handle で API キーの確認に失敗していればエラーを書き込む
+/// # Returns
+/// 拒否した場合 true (ハンドラはそのまま返す)
+fn reject_unauthorized(c: &mut Context<SearchContext>) -> bool {
+    let Some((status, error)) = c.c.auth_error.take() else { return false; };
+    warn!("[{}] Rejected {}: {}", c.c.request_id, c.req.path.get_raw_path(), error);
+    c.res.json_value(&serde_json::json!({ "success": false, "error": error }));
+    c.res.set_status(status);
+    true
+}
+
+/// /add の本体 (ボディを読んだ後)
+/// /bulk_add の各ドキュメントでも使う
+/// # Returns
+/// (HTTP ステータス, レスポンス)
+async fn add_request(ctx: &SearchContext, index_req: IndexReq) -> (u16, IndexRes) {
+    info!("[{}] Add request: {}", ctx.request_id, index_req.url);
+
+    let mut warnings = Vec::new();
+    let tags = match Tags::from_strs_checked(&index_req.tags, index_req.strict_tags, &mut warnings) {
+        Ok(tags) => tags,
+        Err(error) => {
+            let result = IndexRes::Failed { error, detail: None };
+            return (400, result);
         }
-    }
+    };
 
-    if lower.starts_with("dot") {
-        SimilarityAlgorithm::Dot
-    } else if lower.starts_with("cosine") || lower.starts_with("cosinesimilarity") {
-        SimilarityAlgorithm::CosineSimilarity
-    } else if lower.starts_with("bm25plus") {
-        let v = nums(&lower);
-        let k1 = v.get(0).copied().unwrap_or(1.2);
-        let b = v.get(1).copied().unwrap_or(0.75);
-        let delta = v.get(2).copied().unwrap_or(0.5);
-        SimilarityAlgorithm::BM25plus(k1, b, delta)
-    } else if lower.starts_with("bm25l") {
-        let v = nums(&lower);
-        let k1 = v.get(0).copied().unwrap_or(1.2);
-        let b = v.get(1).copied().unwrap_or(0.75);
-        SimilarityAlgorithm::BM25L(k1, b)
-    } else if lower.starts_with("bm25cosinenormalizedlinearcombination") {
-        let v = nums(&lower);
-        let k1 = v.get(0).copied().unwrap_or(1.2);
-        let b = v.get(1).copied().unwrap_or(0.75);
-        let alpha = v.get(2).copied().unwrap_or(0.5);
-        SimilarityAlgorithm::BM25CosineNormalizedLinearCombination(k1, b, alpha)
-    } else if lower.starts_with("bm25cosinefilter") {
-        let v = nums(&lower);
-        let k1 = v.get(0).copied().unwrap_or(1.2);
-        let b = v.get(1).copied().unwrap_or(0.75);
-        SimilarityAlgorithm::BM25CosineFilter(k1, b)
-    } else if lower.starts_with("bm25prfcosinesimilarity") {
-        let v = nums(&lower);
-        let k1 = v.get(0).copied().unwrap_or(1.2);
-        let b = v.get(1).copied().unwrap_or(0.75);
-        let top_n = v.get(2).copied().unwrap_or(10.0) as usize;
-        let alpha = v.get(3).copied().unwrap_or(0.5);
-        SimilarityAlgorithm::BM25PrfCosineSimilarity(k1, b, top_n, alpha)
-    } else if lower.starts_with("bm25") {
-        let v = nums(&lower);
-        let k1 = v.get(0).copied().unwrap_or(1.2);
-        let b = v.get(1).copied().unwrap_or(0.75);
-        SimilarityAlgorithm::BM25(k1, b)
-    } else {
-        // 既定
-        SimilarityAlgorithm::BM25(1.2, 0.75)
+    // max_age 以内に登録済みならスクレイプもインデックスの更新もしない
+    if let Some(max_age) = index_req.max_age
+        && let Some(meta) = ctx.index_pool.fresh_meta(&index_req.url, std::time::Duration::from_secs(max_age), chrono::Utc::now()) {
+        info!("[{}] Skipped fresh URL: {} (indexed at {})", ctx.request_id, meta.url, meta.time);
+        let result = IndexRes::Success {
+            url: meta.url,
+            requested_url: index_req.url.into_boxed_str(),
+            title: meta.title,
+            favicon: meta.favicon,
+            tags: meta.tags.tags(),
+            descriptions: meta.description,
+            warnings,
+            skipped: true,
+        };
+        return (200, result);
     }
-}
 
-// range クエリ文字列を正規化して (start, end) (endは排他的) を返す
-// 受け入れる形式:
-//   "a..b"  -> a..b
-//   "..b"   -> 0..b
-//   "a.."   -> a..a+DEFAULT_SEARCH_RESULTS
-//   "v"     -> v..v+DEFAULT_SEARCH_RESULTS
-//   空/None  -> 0..DEFAULT_SEARCH_RESULTS
-// 正規化:
-//   1) 解析失敗はデフォルト
-//   2) end < start の場合 swap (例: 20..10 -> 10..20)
-//   3) 幅 > MAX_SEARCH_RESULTS の場合 end = start + MAX_SEARCH_RESULTS
-//   4) 加算は saturating_add でオーバーフロー防止
-fn parse_range_param(raw: Option<String>) -> (usize, usize) {
-    let default_end = DEFAULT_SEARCH_RESULTS.min(MAX_SEARCH_RESULTS);
-    let Some(s) = raw else { return (0, default_end); };
-    if s.is_empty() { return (0, default_end); }
-
-    let (mut start, mut end) = if let Some((l, r)) = s.split_once("..") {
-        // a..b / a.. / ..b
-        let start = if l.is_empty() { 0 } else { l.parse::<usize>().unwrap_or(0) };
-        if r.is_empty() {
-            // a..  -> a..a+DEFAULT
-            let tentative = start.saturating_add(DEFAULT_SEARCH_RESULTS);
-            (start, tentative)
-        } else {
-            // a..b / ..b
-            let end = r.parse::<usize>().unwrap_or(start);
-            let start = if l.is_empty() { 0 } else { start }; // ..b の場合 start=0
-            (start, end)
+    let scraper_base = match resolve_scraper_url(index_req.scraper_url.as_deref(), SCRAPER_API_URL, &ctx.config.scraper_url_allowlist) {
+        Ok(base) => base,
+        Err((status, error)) => {
+            warn!("[{}] Rejected scraper_url: {}", ctx.request_id, error);
+            let result = IndexRes::Failed { error, detail: None };
+            return (status, result);
         }
-    } else {
-        // 単値 v
-        let v = s.parse::<usize>().unwrap_or(0);
-        let end = v.saturating_add(DEFAULT_SEARCH_RESULTS);
-        (v, end)
     };
+    let scraper_url = format!("{}{}", scraper_base, index_req.url);
+    // キャッシュは既定のスクレイパの結果のみ (別のスクレイパの結果と混ぜない)
+    // エラーは Send でないので、後のインデックス処理の await をまたがないように文字列にしておく
+    let fetched = match &ctx.scrape_cache {
+        Some(cache) if index_req.scraper_url.is_none() => cache.get_or_fetch(&index_req.url, || fetch_scraper_api(&scraper_url)).await,
+        _ => fetch_scraper_api(&scraper_url).await,
+    }.map_err(|e| e.to_string());
+    let (scraper_status, scraper_result) = match fetched {
+        Ok(res) => res,
+        Err(e) => {
+            warn!("[{}] Failed to fetch scraper API: {}", ctx.request_id, e);
+            let result = IndexRes::Failed { error: format!("Failed to fetch scraper API: {}", e), detail: None };
+            return (502, result);
+        }
+    };
+
+    match scraper_result {
+        ScraperResult::Success { results, status: _, url, success: _ } => {
+            let body = match results.descriptions.first() {
+                Some(d) => d,
+                None => {
+                    warn!("[{}] No body text found", ctx.request_id);
+                    let result = IndexRes::Failed { error: "No body text found".to_string(), detail: None };
+                    return (404, result);
+                }
+            };
 
-    // swap if reversed
-    if end < start { std::mem::swap(&mut start, &mut end); }
+            let title = match index_req.title.or_else(|| results.title.first().cloned()) {
+                Some(t) => t,
+                None => "No Title".to_string(),
+            }.chars().take(MAX_TITLE_LENGTH).collect();
 
-    // 幅制限
-    let max_end = start.saturating_add(MAX_SEARCH_RESULTS);
-    if end > max_end { end = max_end; }
+            let description = choose_description(
+                index_req.descriptions.as_deref(),
+                &results,
+                &ctx.config.description_sources,
+                MAX_DESC_LENGTH,
+            ).into_boxed_str();
 
-    (start, end)
+
+            let favicon: Option<Box<str>> = index_req.favicon.or_else(|| results.favicon.first().cloned()).map(|s| s.into_boxed_str());
+
+            // canonical があればそちらで保存 (重複登録防止)
+            let url = resolve_url(&url, &results.canonical).into_boxed_str();
+
+            let mut meta = IndexMeta::new(url, title, description, favicon, tags, index_req.lang.as_deref().and_then(normalize_lang));
+            // リクエストの URL が canonical と違えば別名にする (同じ URL の再送を max_age や /indexed で引けるように)
+            meta.set_aliases(&[index_req.aliases.as_slice(), std::slice::from_ref(&index_req.url)].concat());
+            enrich_meta(&mut meta, &results, &ctx.config.enrich);
+
+            // sudachi の起動と write lock で止まるので blocking スレッドで実行する (/bulk_add では件数分続くので)
+            let task_ctx = ctx.clone();
+            let body = body.clone();
+            let headings = results.headings.clone();
+            let pos = index_req.pos.clone();
+            let indexed = tokio::task::spawn_blocking(move || index_scraped(&task_ctx, meta, &body, &headings, pos))
+                .await
+                .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+            let meta = match indexed {
+                Ok(meta) => meta,
+                Err((status, error)) => return (status, IndexRes::Failed { error, detail: None }),
+            };
+            info!("[{}] Added URL: {}", ctx.request_id, meta.url);
+            let result = IndexRes::Success { 
+                url: meta.url, 
+                requested_url: index_req.url.into_boxed_str(), 
+                title: meta.title, 
+                favicon: meta.favicon, 
+                tags: meta.tags.tags(), 
+                descriptions: meta.description, 
+                warnings,
+                skipped: false,
+            };
+            (200, result)
+        }
+        ScraperResult::Failed { error, status, success: _ } => {
+            let failure = ScrapeFailure::classify(scraper_status, status, &error);
+            warn!("[{}] Scraper API returned error ({:?}): {}", ctx.request_id, failure, error);
+            if failure == ScrapeFailure::Soft && ctx.config.index_soft_failures {
+                // 本文が取れないのでリクエストのタイトル (なければ URL) だけでインデックス
+                let title: Box<str> = index_req.title.clone().unwrap_or_else(|| index_req.url.clone()).chars().take(MAX_TITLE_LENGTH).collect();
+                let description: Box<str> = index_req.descriptions.clone().unwrap_or_default().chars().take(MAX_DESC_LENGTH).collect();
+                let lang = index_req.lang.as_deref().and_then(normalize_lang);
+                let mut meta = IndexMeta::new(index_req.url.as_str().into(), title, description, index_req.favicon.map(|s| s.into_boxed_str()), tags, lang);
+                meta.set_aliases(&index_req.aliases);
+                // 通常の経路と同じく sudachi の起動と write lock は blocking スレッドで
+                let task_ctx = ctx.clone();
+                let indexed = tokio::task::spawn_blocking(move || index_title_only(&task_ctx, meta))
+                    .await
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+                let meta = match indexed {
+                    Ok(meta) => meta,
+                    Err((status, error)) => return (status, IndexRes::Failed { error, detail: None }),
+                };
+                info!("[{}] Added URL with title only: {}", ctx.request_id, meta.url);
+                warnings.push(format!("Scraper failed ({}), indexed title only", error));
+                let result = IndexRes::Success {
+                    url: meta.url,
+                    requested_url: index_req.url.into_boxed_str(),
+                    title: meta.title,
+                    favicon: meta.favicon,
+                    tags: meta.tags.tags(),
+                    descriptions: meta.description,
+                    warnings,
+                    skipped: false,
+                };
+                return (200, result);
+            }
+            let result = IndexRes::Failed { error: format!("Scraper API error: {}", error), detail: None };
+            (failure.http_status(), result)
+        }
+    }
 }
 
+/// GET / POST /search 共通の処理
+/// window 指定時は /trending (期間内のドキュメントを新しさ込みで並べる)
+/// 検索はシャードの read lock を待ったり sudachi を起動したりするので blocking スレッドで実行する
+async fn respond_search(mut c: Context<SearchContext>, search_req: SearchReq, window: Option<chrono::Duration>) -> Context<SearchContext> {
+    let query = search_req.query.clone();
+    let params = match SearchParams::new(search_req, &c.c.config) {
+        Ok(p) => SearchParams { window, ..p },
+        Err(e) => {
+            warn!("[{}] Invalid search request: {}", c.c.request_id, e);
+            let result = SearchRes::failed(ERROR_INVALID_REQUEST, e, Some(query));
+            c.res.json_value(&serde_json::to_value(&result).unwrap());
+            c.res.set_status(400);
+            return c;
+        }
+    };
+    let (pool, config) = (c.c.index_pool.clone(), c.c.config.clone());
+    let (params, (status, result)) = tokio::task::spawn_blocking(move || {
+        let res = search(&pool, &config, &params);
+        (params, res)
+    })
+    .await
+    .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+    match &result {
+        SearchRes::Success { results, .. } => info!("[{}] Search query={:?} hits={}", c.c.request_id, params.query, results.len()),
+        SearchRes::Failed { error, .. } => warn!("[{}] Search query={:?} failed: {}", c.c.request_id, params.query, error),
+    }
+    c.res.json_value(&result.to_value_with_fields(params.fields.as_deref()));
+    c.res.set_status(status);
+    c
+}
+
+/// /add の後半 (スクレイプした本文・タイトル・見出しをトークン化してインデックスに入れる)
+/// sudachi の起動とシャードの write lock で止まるので spawn_blocking で呼ぶ
+/// pos はリクエストの品詞指定 (あれば POS_FILTER より優先)
+/// # Returns
+/// Err((HTTP ステータス, エラーメッセージ))
+fn index_scraped(ctx: &SearchContext, mut meta: IndexMeta, body: &str, headings: &[String], pos: Option<Vec<String>>) -> Result<IndexMeta, (u16, String)> {
+    let lang = meta.lang.clone();
+    // ハイライト用に description のトークン位置を保存 (sudachi でトークン化する言語のみ)
+    if ctx.config.store_desc_tokens && uses_sudachi(lang.as_deref()) {
+        match sudachi_tokenize_detailed(&meta.description, SudachiMode::A) {
+            Ok(t) => meta.desc_tokens = Some(token_spans(&meta.description, &t)),
+            Err(e) => warn!("[{}] description tokenize error: {}", ctx.request_id, e),
+        }
+    }
+
+    // リクエストで品詞指定があればそちらを優先
+    let tokenize_options = match pos {
+        Some(pos) => TokenizeOptions { pos_filter: Some(pos), ..ctx.config.tokenize.clone() },
+        None => ctx.config.tokenize.clone(),
+    };
+    let mut tokens = tokenize_lang(body, lang.as_deref(), &tokenize_options).map_err(|e| {
+        warn!("[{}] tokenize error: {}", ctx.request_id, e);
+        e.http_error()
+    })?;
 
+    if let Some(max) = ctx.config.max_doc_tokens {
+        let dropped = cap_unique_tokens(&mut tokens, max);
+        if dropped > 0 {
+            info!("[{}] Dropped {} rare tokens over MAX_DOC_TOKENS={} for {}", ctx.request_id, dropped, max, meta.url);
+        }
+    }
 
+    // タイトルだけにある語でもヒットするように title_weight 回分で数える
+    let title_weight = ctx.config.enrich.title_weight;
+    let title_tokens = if title_weight > 0 && !meta.title.is_empty() {
+        tokenize_lang(&meta.title, lang.as_deref(), &tokenize_options).unwrap_or_else(|e| {
+            warn!("[{}] title tokenize error: {}", ctx.request_id, e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let mut token_fq = title_weighted_tokens(&tokens, &title_tokens, title_weight);
 
+    // 見出しは本文より重く数える
+    let heading_weight = ctx.config.enrich.heading_weight;
+    let heading_tokens = if heading_weight > 0 && !headings.is_empty() {
+        tokenize_lang(&headings.join("\n"), lang.as_deref(), &tokenize_options).unwrap_or_else(|e| {
+            warn!("[{}] heading tokenize error: {}", ctx.request_id, e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    add_weighted_tokens(&mut token_fq, &heading_tokens, heading_weight);
 
+    // タイトル / 見出しの語を加えても MAX_DOC_TOKENS を超えないように
+    if let Some(max) = ctx.config.max_doc_tokens {
+        let order: Vec<&String> = tokens.iter().chain(&title_tokens).chain(&heading_tokens).collect();
+        let dropped = cap_unique_token_freq(&mut token_fq, &order, max);
+        if dropped > 0 {
+            info!("[{}] Dropped {} rare title / heading tokens over MAX_DOC_TOKENS={} for {}", ctx.request_id, dropped, max, meta.url);
+        }
+    }
 
+    if ctx.index_pool.add_document_with_text(&token_fq, meta.clone(), body).is_none() {
+        return Err((507, INDEX_FULL_ERROR.to_string()));
+    }
+    Ok(meta)
+}
 
+/// スクレイプに失敗したページをタイトル (と指定があれば description) だけでインデックスに入れる (INDEX_SOFT_FAILURES)
+/// index_scraped と同じく spawn_blocking で呼ぶ
+/// # Returns
+/// Err((HTTP ステータス, エラーメッセージ))
+fn index_title_only(ctx: &SearchContext, meta: IndexMeta) -> Result<IndexMeta, (u16, String)> {
+    let lang = meta.lang.as_deref();
+    // 通常の経路と同じくタイトルは title_weight 回分で数える (本文がないので 0 でも 1 回は数える)
+    let title_tokens = tokenize_lang(&meta.title, lang, &ctx.config.tokenize).map_err(|e| e.http_error())?;
+    let desc_tokens = match meta.description.is_empty() {
+        true => Vec::new(),
+        false => tokenize_lang(&meta.description, lang, &ctx.config.tokenize).map_err(|e| e.http_error())?,
+    };
+    let token_fq = title_weighted_tokens(&desc_tokens, &title_tokens, ctx.config.enrich.title_weight.max(1));
+    if ctx.index_pool.add_document(&token_fq, meta.clone()).is_none() {
+        return Err((507, INDEX_FULL_ERROR.to_string()));
+    }
+    Ok(meta)
+}
@@ -0,0 +1,401 @@
+use serde_json::{json, Value};
+
+use crate::{collect::RES_ENTRY_FIELDS, version::VERSION};
+
+/// GET /search のクエリパラメータ (名前, 型, 説明)
+/// POST /search の SearchReq と同じ意味 (tag / exclude_tag / exclude_url はカンマ区切り)
+const SEARCH_QUERY_PARAMS: &[(&str, &str, &str)] = &[
+    ("query", "string", "検索クエリ (必須)。`-語` でその語を含むドキュメントを除外"),
+    ("range", "string", "返却範囲 a..b (b は排他的)"),
+    ("algo", "string", "スコアのアルゴリズム (BM25(1.2,0.75) / Cosine / mix(...) など)"),
+    ("tag", "string", "カンマ区切りのタグ (OR)"),
+    ("tag_exclusive", "boolean", "タグを AND 条件にする"),
+    ("exclude_tag", "string", "いずれかを含む結果を除外するタグ (カンマ区切り)"),
+    ("exclude_url", "string", "URL にいずれかを含む結果を除外する文字列 (カンマ区切り、大文字小文字を区別しない)"),
+    ("fields", "string", "結果に含めるフィールド (カンマ区切り)"),
+    ("match_info", "boolean", "一致した語数と出現回数を含める"),
+    ("ids", "string", "index_id:doc_id のリスト (カンマ区切り)"),
+    ("highlight", "boolean", "descriptions 中のクエリの語の位置を含める"),
+    ("strict_tags", "boolean", "未知のタグ名を 400 にする"),
+    ("facets", "boolean", "タグごとのヒット件数を含める"),
+    ("snippet_count", "integer", "結果に含めるスニペットの数"),
+    ("min_doc_length", "integer", "これより短い (トークン数) ドキュメントを除外"),
+    ("debug_shards", "boolean", "シャードごとの候補数を含める"),
+    ("substring", "boolean", "トークンでヒットしたドキュメントを、クエリの語を部分文字列として含むものに絞る"),
+    ("backfill", "boolean", "meta を引けなかった結果の分を range の後ろから補う"),
+    ("lite", "boolean", "descriptions / favicon を返さない"),
+    ("sort", "string", "並び順 score (デフォルト) / published / modified"),
+    ("published_after", "string", "published (なければインデックスした時刻) がこれ以降のドキュメントのみ"),
+    ("published_before", "string", "published (なければインデックスした時刻) がこれより前のドキュメントのみ"),
+    ("show_query_tf", "boolean", "スコア計算に使った語ごとの出現回数 (query_tf) を含める"),
+    ("collapse_by", "string", "host で URL のホストごとにまとめ、同じホストの残りを others に入れる (range と total はまとめた後の結果に対して)"),
+    ("matched_tokens", "boolean", "結果ごとにタイトル / descriptions に含まれるクエリの語 (matched_title / matched_desc) を含める"),
+    ("normalize_score", "boolean", "score をクエリ内で 0〜1 に min-max 正規化する (元の値は raw_score)"),
+    ("lang", "string", "クエリの言語 (省略時と ja は sudachi、それ以外は /add の lang と同じ単語分割)"),
+];
+
+/// GET /openapi.json の OpenAPI 3 ドキュメント
+/// 手で書いているので、ResEntry などのフィールドを変えたらここも直す (テストで突き合わせる)
+pub fn document() -> Value {
+    let search_params: Vec<Value> = SEARCH_QUERY_PARAMS.iter()
+        .map(|(name, ty, description)| json!({
+            "name": name,
+            "in": "query",
+            "required": *name == "query",
+            "description": description,
+            "schema": { "type": ty },
+        }))
+        .collect();
+    let search_responses = json!({
+        "200": { "description": "検索結果", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/SearchRes" } } } },
+        "400": { "description": "不正なリクエスト (code に理由)", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/SearchRes" } } } },
+        "503": { "description": "トークナイザが使えない / SEARCH_LOCK_TIMEOUT_MS 以内に読めないシャードがある (code: SHARD_BUSY)", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/SearchRes" } } } },
+    });
+    let search_req = json!({
+        "type": "object",
+        // query か tokens のどちらかが必要 (tokens があれば query は省略可)
+        "anyOf": [{ "required": ["query"] }, { "required": ["tokens"] }],
+        "additionalProperties": false,
+        "properties": {
+            "query": { "type": "string" },
+            "range": { "type": "string", "example": "0..20" },
+            "algo": { "type": "string" },
+            "tags": { "type": "array", "items": { "type": "string" } },
+            "tag_exclusive": { "type": "boolean" },
+            "exclude_tags": { "type": "array", "items": { "type": "string" } },
+            "exclude_urls": { "type": "array", "items": { "type": "string" }, "maxItems": 32 },
+            "fields": { "type": "array", "items": { "type": "string", "enum": RES_ENTRY_FIELDS } },
+            "match_info": { "type": "boolean" },
+            "ids": { "type": "array", "items": { "type": "string" } },
+            "highlight": { "type": "boolean" },
+            "strict_tags": { "type": "boolean" },
+            "facets": { "type": "boolean" },
+            "tokens": { "type": "array", "items": { "type": "string" }, "description": "トークン化済みのクエリ (指定時は query を省略可)" },
+            "snippet_count": { "type": "integer" },
+            "min_doc_length": { "type": "integer" },
+            "debug_shards": { "type": "boolean" },
+            "substring": { "type": "boolean" },
+            "backfill": { "type": "boolean" },
+            "lite": { "type": "boolean" },
+            "sort": { "type": "string", "enum": ["score", "published", "modified"] },
+            "published_after": { "type": "string", "example": "2024-01-01" },
+            "published_before": { "type": "string", "example": "2024-06-01T00:00:00+09:00" },
+            "show_query_tf": { "type": "boolean" },
+            "normalize_score": { "type": "boolean" },
+            "matched_tokens": { "type": "boolean" },
+            "collapse_by": { "type": "string", "enum": ["host"] },
+            "lang": { "type": "string", "example": "en" },
+        },
+    });
+    let res_entry = json!({
+        "type": "object",
+        "required": ["url", "title", "tags", "descriptions", "score", "point", "length", "id", "index_id", "time"],
+        "properties": {
+            "url": { "type": "string" },
+            "title": { "type": "string" },
+            "favicon": { "type": "string", "nullable": true },
+            "tags": { "type": "array", "items": { "type": "string" } },
+            "descriptions": { "type": "string" },
+            "score": { "type": "number" },
+            "point": { "type": "number" },
+            "length": { "type": "integer" },
+            "id": { "type": "integer" },
+            "index_id": { "type": "integer" },
+            "time": { "type": "string", "format": "date-time" },
+            "author": { "type": "string", "nullable": true },
+            "published": { "type": "string", "format": "date-time", "nullable": true },
+            "modified": { "type": "string", "format": "date-time", "nullable": true },
+            "matched_terms": { "type": "integer" },
+            "term_freq": { "type": "integer" },
+            "highlights": { "type": "array", "items": { "$ref": "#/components/schemas/Range" } },
+            "snippets": { "type": "array", "items": { "type": "string" } },
+            "raw_score": { "type": "number" },
+            "matched_title": { "type": "array", "items": { "type": "string" } },
+            "matched_desc": { "type": "array", "items": { "type": "string" } },
+            "others": { "type": "array", "items": { "$ref": "#/components/schemas/ResEntry" } },
+        },
+    });
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "wk-371tti-net search engine",
+            "version": VERSION,
+        },
+        "paths": {
+            "/search": {
+                "get": {
+                    "summary": "検索",
+                    "parameters": search_params,
+                    "responses": search_responses,
+                },
+                "post": {
+                    "summary": "検索 (JSON ボディ)",
+                    "requestBody": { "required": true, "content": { "application/json": { "schema": { "$ref": "#/components/schemas/SearchReq" } } } },
+                    "responses": search_responses,
+                },
+            },
+            "/add": {
+                "post": {
+                    "summary": "ドキュメント追加",
+                    "security": [{ "ApiKey": [] }],
+                    "requestBody": { "required": true, "content": { "application/json": { "schema": { "$ref": "#/components/schemas/IndexReq" } } } },
+                    "responses": {
+                        "200": { "description": "登録した", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/IndexRes" } } } },
+                        "400": { "description": "不正なリクエスト", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/IndexRes" } } } },
+                        "401": { "description": "API キーがない / 一致しない (API_KEYS を設定したとき)" },
+                        "404": { "description": "対象ページが存在しない / 本文がない", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/IndexRes" } } } },
+                        "413": { "description": "リクエストボディが MAX_BODY_BYTES を超えている" },
+                        "500": { "description": "トークン化のエラー" },
+                        "501": { "description": "スクレイパが無効 (SCRAPER_DISABLED)" },
+                        "502": { "description": "スクレイパのエラー" },
+                        "503": { "description": "トークナイザ (sudachi) を起動できない" },
+                        "507": { "description": "全シャードがサイズの上限を超えている" },
+                    },
+                },
+            },
+            "/status": {
+                "get": {
+                    "summary": "ステータス",
+                    "responses": {
+                        "200": { "description": "ステータス", "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Status" } } } },
+                    },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "この OpenAPI ドキュメント",
+                    "responses": { "200": { "description": "OpenAPI 3 ドキュメント" } },
+                },
+            },
+        },
+        "components": {
+            "securitySchemes": {
+                "ApiKey": { "type": "apiKey", "in": "header", "name": "X-API-Key" },
+            },
+            "schemas": {
+                "SearchReq": search_req,
+                "SearchRes": {
+                    "oneOf": [
+                        { "$ref": "#/components/schemas/SearchSuccess" },
+                        { "$ref": "#/components/schemas/Failure" },
+                    ],
+                },
+                "SearchSuccess": {
+                    "type": "object",
+                    "required": ["success", "query", "tokenize_query", "algorithm", "range", "total", "out_of_range", "results"],
+                    "properties": {
+                        "success": { "type": "string", "enum": ["true"] },
+                        "query": { "type": "string" },
+                        "tokenize_query": { "type": "array", "items": { "type": "string" } },
+                        "query_tf": { "type": "object", "additionalProperties": { "type": "integer" } },
+                        "algorithm": { "type": "string" },
+                        "range": { "$ref": "#/components/schemas/Range" },
+                        "hint": { "type": "string" },
+                        "suggestion": { "type": "string" },
+                        "warnings": { "type": "array", "items": { "type": "string" } },
+                        "facets": { "type": "object", "additionalProperties": { "type": "integer" } },
+                        "total": { "type": "integer" },
+                        "out_of_range": { "type": "boolean" },
+                        "debug_shards": { "type": "array", "items": { "$ref": "#/components/schemas/ShardCandidates" } },
+                        "results": { "type": "array", "items": { "$ref": "#/components/schemas/ResEntry" } },
+                    },
+                },
+                "Failure": {
+                    "type": "object",
+                    "required": ["success", "error"],
+                    "properties": {
+                        "success": { "type": "string", "enum": ["false"] },
+                        "error": { "type": "string" },
+                        "code": { "type": "string" },
+                        "query": { "type": "string" },
+                        "detail": { "type": "string" },
+                    },
+                },
+                "Range": {
+                    "type": "object",
+                    "properties": { "start": { "type": "integer" }, "end": { "type": "integer" } },
+                },
+                "ShardCandidates": {
+                    "type": "object",
+                    "properties": {
+                        "index_id": { "type": "integer" },
+                        "candidates": { "type": "integer" },
+                        "skipped": { "type": "boolean" },
+                    },
+                },
+                "ResEntry": res_entry,
+                "IndexReq": {
+                    "type": "object",
+                    "required": ["url", "tags"],
+                    "properties": {
+                        "url": { "type": "string" },
+                        "title": { "type": "string", "nullable": true },
+                        "favicon": { "type": "string", "nullable": true },
+                        "tags": { "type": "array", "items": { "type": "string" } },
+                        "descriptions": { "type": "string", "nullable": true },
+                        "pos": { "type": "array", "items": { "type": "string" }, "nullable": true },
+                        "strict_tags": { "type": "boolean" },
+                        "aliases": { "type": "array", "items": { "type": "string" } },
+                        "lang": { "type": "string", "nullable": true },
+                        "scraper_url": { "type": "string", "nullable": true, "description": "スクレイパ API の URL (SCRAPER_URL_ALLOWLIST のホストのみ)" },
+                        "max_age": { "type": "integer", "nullable": true, "description": "登録済みでこの秒数以内にインデックスしていればスクレイプせず既存の内容を返す (skipped: true)" },
+                    },
+                },
+                "IndexRes": {
+                    "oneOf": [
+                        { "$ref": "#/components/schemas/IndexSuccess" },
+                        { "$ref": "#/components/schemas/Failure" },
+                    ],
+                },
+                "IndexSuccess": {
+                    "type": "object",
+                    "required": ["success", "url", "requested_url", "title", "tags", "descriptions"],
+                    "properties": {
+                        "success": { "type": "string", "enum": ["true"] },
+                        "url": { "type": "string" },
+                        "requested_url": { "type": "string" },
+                        "title": { "type": "string" },
+                        "favicon": { "type": "string", "nullable": true },
+                        "tags": { "type": "array", "items": { "type": "string" } },
+                        "descriptions": { "type": "string" },
+                        "warnings": { "type": "array", "items": { "type": "string" } },
+                        "skipped": { "type": "boolean" },
+                    },
+                },
+                "Status": {
+                    "type": "object",
+                    "properties": {
+                        "status": { "type": "string" },
+                        "documents": { "type": "integer" },
+                        "generation": { "type": "integer" },
+                        "auto_saves": { "type": "integer" },
+                        "locks": { "type": "object" },
+                        "version": { "type": "object" },
+                    },
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collect::{IndexReq, IndexRes, ResEntry, SearchRes};
+
+    /// スキーマの properties の名前
+    fn properties(doc: &Value, schema: &str) -> Vec<String> {
+        let mut names: Vec<String> = doc["components"]["schemas"][schema]["properties"].as_object()
+            .unwrap_or_else(|| panic!("schema {} has no properties", schema))
+            .keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// JSON オブジェクトのキー (全フィールドを埋めた値をシリアライズしたもの)
+    fn keys(value: Value) -> Vec<String> {
+        let mut names: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_openapi_document() {
+        let doc = document();
+        // そのまま JSON として読み直せる
+        let text = serde_json::to_string(&doc).unwrap();
+        let doc: Value = serde_json::from_str(&text).unwrap();
+        assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+        let paths = doc["paths"].as_object().unwrap();
+        for path in ["/search", "/add", "/status", "/openapi.json"] {
+            assert!(paths.contains_key(path), "{}", path);
+        }
+        assert!(paths["/search"]["get"].is_object() && paths["/search"]["post"].is_object());
+
+        // serde の構造体とフィールドをそろえる
+        let mut fields: Vec<String> = RES_ENTRY_FIELDS.iter().map(|f| f.to_string()).collect();
+        fields.sort();
+        assert_eq!(properties(&doc, "ResEntry"), fields);
+        let entry = ResEntry {
+            url: "u".into(), title: "t".into(), favicon: Some("f".into()), tags: vec![], descriptions: "d".into(),
+            score: 1.0, point: 0.0, length: 1, id: 0, index_id: 0, time: chrono::Utc::now(),
+            author: Some("a".into()), published: Some(chrono::Utc::now()), modified: Some(chrono::Utc::now()), matched_terms: Some(1), term_freq: Some(1),
+            highlights: Some(vec![]), snippets: Some(vec![]), raw_score: Some(1.0),
+            matched_title: Some(vec![]), matched_desc: Some(vec![]), others: Some(vec![]),
+        };
+        assert_eq!(keys(serde_json::to_value(&entry).unwrap()), fields);
+        let res = SearchRes::Success {
+            query: "q".into(), tokenize_query: vec![], query_tf: Some(Default::default()), algorithm: "a".into(), range: 0..1, hint: Some("h".into()),
+            suggestion: Some("s".into()), warnings: vec!["w".into()], facets: Some(Default::default()), total: 1,
+            out_of_range: false, debug_shards: Some(vec![]), results: vec![entry],
+        };
+        assert_eq!(keys(serde_json::to_value(&res).unwrap()), properties(&doc, "SearchSuccess"));
+        assert_eq!(serde_json::to_value(&res).unwrap()["success"], "true");
+        let res = IndexRes::Success {
+            url: "u".into(), requested_url: "u".into(), title: "t".into(), favicon: None, tags: vec![],
+            descriptions: "d".into(), warnings: vec!["w".into()], skipped: true,
+        };
+        assert_eq!(keys(serde_json::to_value(&res).unwrap()), properties(&doc, "IndexSuccess"));
+        // スキーマの全プロパティを埋めたボディを IndexReq として読める
+        let body = json!({
+            "url": "u", "title": "t", "favicon": "f", "tags": ["wiki"], "descriptions": "d",
+            "pos": ["名詞"], "strict_tags": true, "aliases": ["a"], "lang": "en", "scraper_url": "http://s/",
+            "max_age": 3600,
+        });
+        assert_eq!(keys(body.clone()), properties(&doc, "IndexReq"));
+        let req: IndexReq = serde_json::from_value(body).unwrap();
+        assert!(req.strict_tags && req.aliases.len() == 1 && req.lang.as_deref() == Some("en") && req.scraper_url.is_some() && req.max_age == Some(3600));
+        // SearchReq は query か tokens のどちらかがあればよい (tokens だけのボディも受け付ける)
+        let search_req = &doc["components"]["schemas"]["SearchReq"];
+        assert!(search_req.get("required").is_none());
+        let satisfies = |body: &Value| search_req["anyOf"].as_array().unwrap().iter()
+            .any(|branch| branch["required"].as_array().unwrap().iter().all(|k| body.get(k.as_str().unwrap()).is_some()));
+        let tokens_only = json!({ "tokens": ["東京", "天気"] });
+        assert!(satisfies(&tokens_only) && satisfies(&json!({ "query": "東京" })));
+        assert!(!satisfies(&json!({ "range": "0..20" })));
+        let req: crate::search::SearchReq = serde_json::from_value(tokens_only).unwrap();
+        assert!(crate::search::SearchParams::new(req, &crate::config::Config::default()).is_ok());
+        let params: Vec<&str> = paths["/search"]["get"]["parameters"].as_array().unwrap().iter().map(|p| p["name"].as_str().unwrap()).collect();
+        assert!(params.contains(&"query") && params.contains(&"lite"));
+    }
+
+    /// 関数の本体 (start から最初の end まで) に書かれた 3 桁のステータス
+    fn literal_statuses(source: &str, start: &str, end: &str) -> std::collections::BTreeSet<u16> {
+        let body = &source[source.find(start).unwrap_or_else(|| panic!("{} not found", start))..];
+        let body = &body[..body.find(end).unwrap()];
+        ["return (", "set_status(", "Err(("].iter()
+            .flat_map(|prefix| body.match_indices(prefix).map(move |(i, _)| &body[i + prefix.len()..]))
+            .filter(|rest| rest.get(3..).is_some_and(|after| after.starts_with([',', ')'])))
+            .filter_map(|rest| rest[..3].parse().ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_add_responses_match_handler() {
+        use crate::{config::Config, context::{check_api_key, check_content_length, check_scraper_enabled, parse_json_body, resolve_scraper_url, RouteAccess}, ingest::ScrapeFailure, tokenize::SudachiError};
+        // main.rs の /add のハンドラと add_request (blocking スレッドで呼ぶインデックス処理を含む) が直接返すステータス
+        let main = include_str!("main.rs");
+        let mut handler = literal_statuses(main, "kurosabi.post(\"/add\"", "\n    });");
+        for start in ["async fn add_request(", "fn index_scraped(", "fn index_title_only("] {
+            handler.extend(literal_statuses(main, start, "\n}\n"));
+        }
+        // 呼び出している確認・変換の関数が返すステータス
+        let config = Config { api_keys: vec!["key".into()], scraper_disabled: true, ..Config::default() };
+        handler.extend([
+            check_api_key(&config, RouteAccess::Write, None, None).unwrap_err().0,
+            check_scraper_enabled(&config).unwrap_err().0,
+            check_content_length(Some("2"), 1).unwrap_err().0,
+            check_content_length(Some("x"), 1).unwrap_err().0,
+            parse_json_body::<IndexReq>(b"{").unwrap_err().status,
+            resolve_scraper_url(Some("http://other.example"), "http://localhost", &[]).unwrap_err().0,
+            SudachiError::Spawn(std::io::Error::other("x")).http_error().0,
+            SudachiError::Exit(1, String::new()).http_error().0,
+        ]);
+        handler.extend([ScrapeFailure::DeadLink, ScrapeFailure::Soft, ScrapeFailure::Upstream].map(ScrapeFailure::http_status));
+
+        let doc = document();
+        let documented: std::collections::BTreeSet<u16> = doc["paths"]["/add"]["post"]["responses"].as_object().unwrap()
+            .keys().map(|code| code.parse().unwrap()).collect();
+        assert_eq!(documented, handler);
+    }
+}
@@ -0,0 +1,1851 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use kurosabi::request::path::Path;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use tf_idf_vectorizer::{Corpus, SimilarityAlgorithm, TokenFrequency};
+
+use crate::{collect::{parse_fields, shard_candidates, ResEntry, ScoredEntry, SearchRes, LITE_OMITTED_FIELDS, RES_ENTRY_FIELDS}, config::Config, index::{min_max_normalize, score_bounds, DateField, IndexPool, ResultOptions, ScoreMerge, SearchReads, Tags, MAX_SNIPPET_COUNT}, ingest::parse_date, jobs::MAX_BULK_ITEMS, tokenize::{normalize_lang, tokenize_lang, tokenize_lang_batch, SudachiError}, trigram::query_trigrams};
+
+pub const MAX_SEARCH_RESULTS: usize = 1000; // 検索結果の最大数
+pub const DEFAULT_SEARCH_RESULTS: usize = 20; // 検索結果のデフォルト数
+pub const DEFAULT_ALGORITHM: &str = "BM25(1.2,0.75)"; // algo 未指定時 (DEFAULT_ALGORITHM で変更可)
+/// リクエストの内容が不正なときの code
+pub const ERROR_INVALID_REQUEST: &str = "invalid_request";
+/// クエリが MAX_QUERY_CHARS を超えたときのエラーコード
+pub const ERROR_QUERY_TOO_LONG: &str = "QUERY_TOO_LONG";
+/// トークン化してクエリの語が残らなかったときの warnings
+/// (トークナイザのエラーではなく空のクエリとして 0 件を返したことを示す)
+pub const NO_SEARCHABLE_TOKENS: &str = "no searchable tokens in query";
+/// SEARCH_LOCK_TIMEOUT_MS 以内にシャードを読めなかったときのエラーコード
+pub const ERROR_SHARD_BUSY: &str = "SHARD_BUSY";
+/// exclude_url の数の上限 (結果 1 件ごとに全て照合するので)
+pub const MAX_EXCLUDE_URLS: usize = 32;
+/// /trending の window 未指定時
+pub const DEFAULT_TRENDING_WINDOW: &str = "24h";
+/// /trending のスコアに占める新しさの割合
+pub const TRENDING_RECENCY_WEIGHT: f64 = 0.5;
+
+/// 検索リクエスト
+/// GET はクエリ文字列から、POST は JSON ボディからこの形にする
+/// どちらも SearchParams に正規化してから検索する
+/// POST で知らないフィールド (filters など) は黙って無視せず 400 にする
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchReq {
+    pub query: String,
+    /// "a..b" 形式 (parse_range_param 参照)
+    pub range: Option<String>,
+    pub algo: Option<String>,
+    /// OR 条件 (tag_exclusive = true で AND)
+    pub tags: Vec<String>,
+    pub tag_exclusive: bool,
+    /// いずれかを含む結果を除外
+    pub exclude_tags: Vec<String>,
+    /// 結果に含めるフィールド (空なら全て)
+    pub fields: Vec<String>,
+    /// 結果に一致した語数・語の出現回数を含める
+    pub match_info: bool,
+    /// "index_id:doc_id" のリスト (指定時はこれらのドキュメント内のみ検索)
+    pub ids: Vec<String>,
+    /// 結果に descriptions 中のクエリの語の位置を含める
+    pub highlight: bool,
+    /// 未知のタグ名を 400 にする (false なら無視して warnings に載せる)
+    pub strict_tags: bool,
+    /// タグごとのヒット件数を含める
+    pub facets: bool,
+    /// トークン化済みのクエリ (POST のみ、指定時は sudachi を使わずこれで検索する)
+    pub tokens: Option<Vec<String>>,
+    /// 結果に含めるスニペットの数 (未指定なら snippets を含めない)
+    pub snippet_count: Option<usize>,
+    /// これより短い (トークン数) ドキュメントを除外
+    pub min_doc_length: Option<u64>,
+    /// シャードごとの候補数と飛ばしたシャードを含める
+    pub debug_shards: bool,
+    /// クエリの語を部分文字列として含むドキュメントに絞る (TRIGRAM_INDEX が必要)
+    pub substring: bool,
+    /// meta を引けなかった結果の分を range の後ろから補う
+    pub backfill: bool,
+    /// descriptions / favicon を返さない (meta から複製もしない)
+    pub lite: bool,
+    /// URL にいずれかを含む結果を除外 (部分文字列、大文字小文字を区別しない)
+    pub exclude_urls: Vec<String>,
+    /// 並び順 "score" (デフォルト) / "published" / "modified" (日付は新しい順)
+    pub sort: Option<String>,
+    /// published (なければインデックスした時刻) がこれ以降のドキュメントのみ
+    pub published_after: Option<String>,
+    /// published (なければインデックスした時刻) がこれより前のドキュメントのみ
+    pub published_before: Option<String>,
+    /// スコア計算に使った語ごとの出現回数 (重複をまとめたもの) を含める
+    pub show_query_tf: bool,
+    /// score をこのクエリの結果の中で [0, 1] に正規化する (元の値は raw_score)
+    pub normalize_score: bool,
+    /// 結果にタイトル / descriptions に含まれるクエリの語を含める
+    pub matched_tokens: bool,
+    /// "host" で URL のホストごとにまとめる (同じホストの残りは others)
+    pub collapse_by: Option<String>,
+    /// クエリの言語 (ja 以外はインデックス時と同じく sudachi を使わずに単語分割する)
+    pub lang: Option<String>,
+}
+
+impl SearchReq {
+    /// GET のクエリ文字列から構築
+    /// 値は URL デコードしてから格納する
+    /// query, range, algo, tag, tag_exclusive, exclude_tag, fields, match_info, ids, highlight, strict_tags, facets, snippet_count, min_doc_length, debug_shards, substring, backfill, lite, exclude_url, sort, published_after, published_before, show_query_tf, normalize_score, matched_tokens, collapse_by, lang
+    /// 数値のパラメータが数値でなければ Err (POST で型が合わないときと同じく 400 にする)
+    pub fn from_path(path: &mut Path) -> Result<Self, String> {
+        Ok(Self {
+            query: decode_query(path, "query").unwrap_or_default(),
+            range: path.get_query("range"),
+            algo: decode_query(path, "algo"),
+            tags: split_list(&decode_query(path, "tag").unwrap_or_default()),
+            tag_exclusive: decode_query(path, "tag_exclusive").map(|v| parse_bool(&v)).unwrap_or(false),
+            exclude_tags: split_list(&decode_query(path, "exclude_tag").unwrap_or_default()),
+            fields: split_list(&decode_query(path, "fields").unwrap_or_default()),
+            match_info: decode_query(path, "match_info").map(|v| parse_bool(&v)).unwrap_or(false),
+            ids: split_list(&decode_query(path, "ids").unwrap_or_default()),
+            highlight: decode_query(path, "highlight").map(|v| parse_bool(&v)).unwrap_or(false),
+            strict_tags: decode_query(path, "strict_tags").map(|v| parse_bool(&v)).unwrap_or(false),
+            facets: decode_query(path, "facets").map(|v| parse_bool(&v)).unwrap_or(false),
+            tokens: None,
+            snippet_count: parse_number_query(path, "snippet_count")?,
+            min_doc_length: parse_number_query(path, "min_doc_length")?,
+            debug_shards: decode_query(path, "debug_shards").map(|v| parse_bool(&v)).unwrap_or(false),
+            substring: decode_query(path, "substring").map(|v| parse_bool(&v)).unwrap_or(false),
+            backfill: decode_query(path, "backfill").map(|v| parse_bool(&v)).unwrap_or(false),
+            lite: decode_query(path, "lite").map(|v| parse_bool(&v)).unwrap_or(false),
+            exclude_urls: split_list(&decode_query(path, "exclude_url").unwrap_or_default()),
+            sort: decode_query(path, "sort"),
+            published_after: decode_query(path, "published_after"),
+            published_before: decode_query(path, "published_before"),
+            show_query_tf: decode_query(path, "show_query_tf").map(|v| parse_bool(&v)).unwrap_or(false),
+            normalize_score: decode_query(path, "normalize_score").map(|v| parse_bool(&v)).unwrap_or(false),
+            matched_tokens: decode_query(path, "matched_tokens").map(|v| parse_bool(&v)).unwrap_or(false),
+            collapse_by: decode_query(path, "collapse_by"),
+            lang: decode_query(path, "lang"),
+        })
+    }
+}
+
+/// 数値のクエリパラメータ (未指定なら None、数値でなければ Err)
+fn parse_number_query<T: std::str::FromStr>(path: &mut Path, key: &str) -> Result<Option<T>, String> {
+    decode_query(path, key)
+        .map(|v| v.trim().parse().map_err(|_| format!("Invalid {}: {}", key, v)))
+        .transpose()
+}
+
+/// 正規化済みの検索パラメータ
+pub struct SearchParams {
+    pub query: String,
+    /// query から除外語を除いたもの (スコア計算に使う)
+    pub terms: String,
+    /// query 中の "-語" (これを含むドキュメントを除外)
+    pub exclude_terms: Vec<String>,
+    pub range: Range<usize>,
+    /// range を補正した場合の説明
+    pub range_hint: Option<String>,
+    pub algo_str: String,
+    pub scoring: Scoring,
+    pub tags: Tags,
+    pub tag_exclusive: bool,
+    pub exclude_tags: Tags,
+    pub fields: Option<Vec<String>>,
+    pub match_info: bool,
+    /// (index_id, doc_id) の集合 (None なら全ドキュメント)
+    pub ids: Option<HashSet<(usize, usize)>>,
+    pub highlight: bool,
+    pub facets: bool,
+    /// トークン化済みのクエリ (指定時は terms / exclude_terms をトークン化しない)
+    pub tokens: Option<Vec<String>>,
+    /// スニペットの数 (1..=MAX_SNIPPET_COUNT に丸める)
+    pub snippet_count: Option<usize>,
+    /// 指定時はこの期間内にインデックスしたドキュメントのみ、新しさを混ぜたスコアで並べる (/trending)
+    pub window: Option<chrono::Duration>,
+    /// これより短い (トークン数) ドキュメントを除外 (0 は None)
+    pub min_doc_length: Option<u64>,
+    pub debug_shards: bool,
+    pub substring: bool,
+    pub backfill: bool,
+    pub lite: bool,
+    /// 小文字にした exclude_url (空文字は除く)
+    pub exclude_urls: Vec<String>,
+    /// 日付順に並べる (None ならスコア順)
+    pub sort: Option<DateField>,
+    pub published_after: Option<DateTime<Utc>>,
+    pub published_before: Option<DateTime<Utc>>,
+    pub show_query_tf: bool,
+    pub normalize_score: bool,
+    pub matched_tokens: bool,
+    /// ホストごとにまとめる (collapse_by=host)
+    pub collapse_by_host: bool,
+    /// 小文字にしたクエリの言語 (None なら sudachi)
+    pub lang: Option<Box<str>>,
+    /// 無視した入力 (未知のタグなど)
+    pub warnings: Vec<String>,
+}
+
+impl SearchParams {
+    /// Err はそのまま 400 のエラーメッセージとして返す
+    pub fn new(req: SearchReq, config: &Config) -> Result<Self, String> {
+        let tokens = match req.tokens {
+            Some(tokens) => {
+                let tokens: Vec<String> = tokens.iter().map(|t| t.trim()).filter(|t| !t.is_empty()).map(|t| t.to_string()).collect();
+                if tokens.is_empty() {
+                    return Err("Empty tokens".to_string());
+                }
+                Some(tokens)
+            }
+            None => None,
+        };
+        let mut query = req.query.trim().to_string();
+        // tokens 指定時は query を省略できる (除外語だけ書いても良い)
+        if let Some(tokens) = &tokens && split_exclude_terms(&query).0.is_empty() {
+            query = format!("{} {}", tokens.join(" "), query).trim().to_string();
+        }
+        if query.is_empty() {
+            return Err("Missing query".to_string());
+        }
+        let (terms, exclude_terms) = split_exclude_terms(&query);
+        if terms.is_empty() {
+            return Err("Query has only excluded terms".to_string());
+        }
+        // range パラメータ正規化
+        let range = parse_range_param_with(req.range, config.default_search_results, config.max_results_per_request);
+        let algo_str = req.algo.unwrap_or_else(|| config.default_algorithm.clone());
+        let scoring = parse_scoring(&algo_str);
+        let mut fields = if req.fields.is_empty() {
+            None
+        } else {
+            Some(parse_fields(&req.fields.join(",")).map_err(|unknown| format!("Unknown field: {}", unknown))?)
+        };
+        // lite は descriptions / favicon を除いた投影にする
+        if req.lite {
+            let mut lite_fields = fields.unwrap_or_else(|| RES_ENTRY_FIELDS.iter().map(|f| f.to_string()).collect());
+            lite_fields.retain(|f| !LITE_OMITTED_FIELDS.contains(&f.as_str()));
+            fields = Some(lite_fields);
+        }
+        let ids = if req.ids.is_empty() {
+            None
+        } else {
+            Some(parse_ids(&req.ids).map_err(|invalid| format!("Invalid id: {}", invalid))?)
+        };
+        let exclude_urls: Vec<String> = req.exclude_urls.iter()
+            .map(|p| p.trim().to_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        if exclude_urls.len() > MAX_EXCLUDE_URLS {
+            return Err(format!("Too many exclude_url patterns (max {})", MAX_EXCLUDE_URLS));
+        }
+        let sort = parse_sort(req.sort.as_deref())?;
+        let collapse_by_host = parse_collapse_by(req.collapse_by.as_deref())?;
+        let published_after = parse_date_param("published_after", req.published_after.as_deref())?;
+        let published_before = parse_date_param("published_before", req.published_before.as_deref())?;
+        let mut warnings = Vec::new();
+        // descriptions を引かないのでハイライト / スニペットは作れない
+        let (highlight, snippet_count) = if req.lite && (req.highlight || req.snippet_count.is_some()) {
+            warnings.push("lite ignores highlight and snippet_count".to_string());
+            (false, None)
+        } else {
+            (req.highlight, req.snippet_count)
+        };
+        // まとめた後の並びは meta を引けない結果を飛ばして作るので、後ろから補わない
+        if collapse_by_host && req.backfill {
+            warnings.push("collapse_by ignores backfill".to_string());
+        }
+        let tags = Tags::from_strs_checked(&req.tags, req.strict_tags, &mut warnings)?;
+        let exclude_tags = Tags::from_strs_checked(&req.exclude_tags, req.strict_tags, &mut warnings)?;
+        Ok(Self {
+            query,
+            terms,
+            exclude_terms,
+            range: range.range,
+            range_hint: range.warning,
+            algo_str,
+            scoring,
+            tags,
+            tag_exclusive: req.tag_exclusive,
+            exclude_tags,
+            fields,
+            match_info: req.match_info,
+            ids,
+            highlight,
+            facets: req.facets,
+            tokens,
+            snippet_count: snippet_count.map(|n| n.clamp(1, MAX_SNIPPET_COUNT)),
+            window: None,
+            min_doc_length: req.min_doc_length.filter(|&n| n > 0),
+            debug_shards: req.debug_shards,
+            substring: req.substring,
+            backfill: req.backfill,
+            lite: req.lite,
+            exclude_urls,
+            sort,
+            published_after,
+            published_before,
+            show_query_tf: req.show_query_tf,
+            normalize_score: req.normalize_score,
+            matched_tokens: req.matched_tokens,
+            collapse_by_host,
+            lang: req.lang.as_deref().and_then(normalize_lang),
+            warnings,
+        })
+    }
+}
+
+/// 検索を実行
+/// シャードのロック待ちや sudachi の起動で止まるので async のハンドラからは spawn_blocking で呼ぶ
+/// # Returns
+/// (HTTP ステータス, レスポンス)
+pub fn search(pool: &IndexPool, config: &Config, params: &SearchParams) -> (u16, SearchRes) {
+    match score_query(pool, config, params) {
+        Ok(scored) => build_response(pool, config, params, scored),
+        Err(res) => *res,
+    }
+}
+
+/// score_query の結果 (build_response に渡す)
+struct QueryScores {
+    tokens: Vec<String>,
+    exclude: Vec<Vec<String>>,
+    tf: TokenFrequency,
+    scored: Vec<ScoredEntry>,
+    /// top-K 時は上位 K 件に入らなかった分も含めたヒット件数
+    matched: Option<usize>,
+    /// このリクエストのシャードの読み方 (SEARCH_LOCK_TIMEOUT_MS の期限はスコア計算の前に決まる)
+    reads: SearchReads,
+}
+
+/// search の前半 (クエリのトークン化とスコア計算)
+/// Err はそのまま返すレスポンス
+fn score_query(pool: &IndexPool, config: &Config, params: &SearchParams) -> Result<QueryScores, Box<(u16, SearchRes)>> {
+    debug!("tag_exclusive={}", params.tag_exclusive);
+    // 長さに比例して sudachi を分割実行するので、トークン化の前に弾く
+    let query_chars = params.query.chars().count();
+    if query_chars > config.max_query_chars {
+        let error = format!("Query too long ({} chars, max {})", query_chars, config.max_query_chars);
+        return Err(Box::new((400, SearchRes::failed(ERROR_QUERY_TOO_LONG, error, None))));
+    }
+    if params.substring && !pool.trigram_index {
+        let error = "substring search requires TRIGRAM_INDEX".to_string();
+        return Err(Box::new((400, SearchRes::failed(ERROR_INVALID_REQUEST, error, Some(params.query.clone())))));
+    }
+
+    let (tokens, exclude) = match query_tokens(params, config) {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("tokenize error: {}", e);
+            return Err(Box::new(tokenize_failed(params, &e)));
+        }
+    };
+    if tokens.is_empty() {
+        return Err(Box::new((200, empty_tokens_result(params))));
+    }
+
+    let tf = TokenFrequency::from(&tokens[..]);
+
+    // IndexPool を使ってスコア計算
+    // ids / window / min_doc_length / substring / 日付 / タグ / 除外語指定時は対象が上位に入らないと消えるので top-K を使わない
+    // collapse_by もまとめた後の total を数えるのに全件要るので使わない
+    let by_date = params.sort.is_some() || params.published_after.is_some() || params.published_before.is_some();
+    let by_tags = !params.tags.is_empty() || !params.exclude_tags.is_empty();
+    let top_k = config.score_top_k.filter(|_| params.ids.is_none() && params.window.is_none() && params.min_doc_length.is_none() && !params.substring && !by_date && !by_tags && exclude.is_empty() && !params.collapse_by_host);
+    let reads = pool.search_reads();
+    let mut matched = None;
+    let scored = match (&params.scoring, top_k) {
+        (Scoring::Single(algo), Some(k)) => {
+            let (scored, hits) = match config.score_merge {
+                ScoreMerge::Flat => pool.per_similarity_top_k_with_skips(&tf, algo, k, &reads),
+                ScoreMerge::KWay => pool.per_similarity_merged_top_k_with_skips(&tf, algo, k, &reads),
+            };
+            matched = Some(hits);
+            scored
+        }
+        (Scoring::Single(algo), None) => pool.per_similarity_with_skips(&tf, algo, &reads),
+        (Scoring::Mix(algos), _) => pool.mixed_similarity_with_skips(&tf, algos, &reads),
+    };
+    // 待っても読めないシャードがあれば欠けた結果を返さない (待たない設定なら従来どおり飛ばす)
+    if let Some(timeout) = pool.search_lock_timeout && !reads.skipped().is_empty() {
+        return Err(Box::new(shard_busy(params, &reads.skipped(), timeout)));
+    }
+    Ok(QueryScores { tokens, exclude, tf, scored, matched, reads })
+}
+
+/// search の後半 (絞り込み・並べ替え・結果の作成)
+/// シャードは score_query と同じ期限で読み、SEARCH_LOCK_TIMEOUT_MS の設定時に期限までに読めなければ 503
+fn build_response(pool: &IndexPool, config: &Config, params: &SearchParams, scores: QueryScores) -> (u16, SearchRes) {
+    let QueryScores { tokens, exclude, tf, mut scored, matched, reads } = scores;
+    let mut warnings = params.warnings.clone();
+    // 3 文字以上の語がなければトークンの結果のまま
+    if params.substring && let Some(grams) = query_trigrams(&params.terms) {
+        let before = reads.skipped().len();
+        let unindexed = pool.apply_substring(&mut scored, &grams, &reads);
+        if unindexed > 0 {
+            warnings.push(format!("substring: {} documents indexed before TRIGRAM_INDEX was enabled were left out; re-add them to include them", unindexed));
+        }
+        // スコア計算の後に書き込みが始まったシャード (待つ設定ならスコア計算と同じく 503)
+        let busy = reads.skipped().split_off(before);
+        if !busy.is_empty() {
+            if let Some(timeout) = pool.search_lock_timeout {
+                return shard_busy(params, &busy, timeout);
+            }
+            warnings.push(format!("substring: shards {:?} were being written and left out", busy));
+        }
+    }
+    let skipped = reads.skipped();
+    if let Some(ids) = &params.ids {
+        retain_ids(&mut scored, ids);
+    }
+    pool.retain_without_terms(&mut scored, &exclude, &reads);
+    if let Some(min) = params.min_doc_length {
+        retain_min_length(&mut scored, min);
+    }
+    pool.retain_dates(&mut scored, DateField::Published, params.published_after, params.published_before, &reads);
+    // total を絞り込んだ後の件数にするため range で切り出す前に除く
+    pool.retain_tags(&mut scored, params.tags, params.tag_exclusive, params.exclude_tags, &reads);
+    if let Some(window) = params.window {
+        pool.apply_freshness(&mut scored, chrono::Utc::now(), window, TRENDING_RECENCY_WEIGHT, &reads);
+    }
+    debug!("Scored {} documents", scored.len());
+    let mut sorted = pool.sort_by_score(scored);
+    if let Some(field) = params.sort {
+        sorted = pool.sort_by_date(sorted, field, &reads);
+    }
+    // range で切り出す前の全件で数える
+    let facets = params.facets.then(|| Box::new(pool.tag_facets(&sorted, params.tags, params.tag_exclusive, params.exclude_tags, &reads)));
+    let debug_shards = params.debug_shards.then(|| shard_candidates(pool.indexes.len(), &sorted, &skipped));
+    // ページをまたいでも同じ基準になるよう range で切り出す前の全件で求める
+    let score_bounds = params.normalize_score.then(|| score_bounds(sorted.iter().map(|e| e.score)));
+    let options = ResultOptions { backfill: params.backfill, lite: params.lite, exclude_urls: &params.exclude_urls, max_results: Some(config.max_results_per_request), collapse_by_host: params.collapse_by_host, reads: Some(&reads) };
+    // まとめた場合の total は代表 (ホスト) の件数
+    let (mut results, total) = if params.collapse_by_host {
+        pool.generate_collapsed(&sorted, params.range.clone(), params.tags, params.tag_exclusive, params.exclude_tags, options)
+    } else {
+        let total = matched.unwrap_or(sorted.len());
+        (pool.generate_results_with(sorted, params.range.clone(), params.tags, params.tag_exclusive, params.exclude_tags, options), total)
+    };
+    let out_of_range = is_out_of_range(&params.range, total);
+    if params.match_info {
+        pool.fill_match_info(&mut results, &tf, &reads);
+    }
+    if params.highlight {
+        pool.fill_highlights(&mut results, &tokens, &reads);
+    }
+    if let Some(count) = params.snippet_count {
+        pool.fill_snippets(&mut results, &tokens, count, &reads);
+    }
+    if params.matched_tokens {
+        pool.fill_matched_tokens(&mut results, &tokens, &reads, |texts| tokenize_lang_batch(texts, &config.tokenize));
+    }
+    // スコア計算の後に書き込みが始まって期限までに読めなかったシャードがあれば、欠けた結果を返さない
+    // (待つ設定ではここまでに読めなかったシャードはないので、あればスコア計算の後の分)
+    if let Some(timeout) = pool.search_lock_timeout && !reads.skipped().is_empty() {
+        return shard_busy(params, &reads.skipped(), timeout);
+    }
+    // ヒットなしのときだけ求める (範囲外のページで results が空でもヒットはある)
+    let suggestion = if total == 0 {
+        suggest_query(&pool.corpus.load(), &tokens)
+    } else {
+        None
+    };
+    if let Some(bounds) = score_bounds {
+        normalize_result_scores(&mut results, bounds);
+    }
+    let query_tf = params.show_query_tf.then(|| Box::new(tf.token_count_vector().into_iter().collect()));
+    (200, SearchRes::Success {
+        query: params.query.clone(),
+        tokenize_query: tokens,
+        query_tf,
+        algorithm: params.algo_str.clone(),
+        range: params.range.clone(),
+        hint: params.range_hint.clone(),
+        suggestion,
+        warnings,
+        facets,
+        total,
+        out_of_range,
+        debug_shards,
+        results,
+    })
+}
+
+/// score を (最小, 最大) で [0, 1] に min-max 正規化して、元の値を raw_score に残す
+/// クエリごとの相対値なので別のクエリとは比べられない
+fn normalize_result_scores(results: &mut [ResEntry], bounds: (f64, f64)) {
+    for r in results.iter_mut() {
+        if let Some(others) = &mut r.others {
+            normalize_result_scores(others, bounds);
+        }
+        r.raw_score = Some(r.score);
+        r.score = min_max_normalize(r.score, bounds);
+    }
+}
+
+/// トークン化に失敗したときのレスポンス
+fn tokenize_failed(params: &SearchParams, e: &SudachiError) -> (u16, SearchRes) {
+    let (status, error) = e.http_error();
+    (status, SearchRes::failed(e.code(), error, Some(params.query.clone())))
+}
+
+/// SEARCH_LOCK_TIMEOUT_MS 以内に読めなかったシャードがあったときのレスポンス
+fn shard_busy(params: &SearchParams, skipped: &[usize], timeout: std::time::Duration) -> (u16, SearchRes) {
+    let mut shards = skipped.to_vec();
+    shards.sort_unstable();
+    shards.dedup();
+    warn!("Shards {:?} busy for {}ms, search failed", shards, timeout.as_millis());
+    let error = format!("Shards {:?} busy (lock not acquired within {}ms)", shards, timeout.as_millis());
+    (503, SearchRes::failed(ERROR_SHARD_BUSY, error, Some(params.query.clone())))
+}
+
+/// クエリの語が残らなかったときのレスポンス (0 件、warnings に NO_SEARCHABLE_TOKENS)
+fn empty_tokens_result(params: &SearchParams) -> SearchRes {
+    let mut warnings = params.warnings.clone();
+    warnings.push(NO_SEARCHABLE_TOKENS.to_string());
+    SearchRes::Success {
+        query: params.query.clone(),
+        tokenize_query: Vec::new(),
+        query_tf: params.show_query_tf.then(Default::default),
+        algorithm: params.algo_str.clone(),
+        range: params.range.clone(),
+        hint: params.range_hint.clone(),
+        suggestion: None,
+        warnings,
+        facets: params.facets.then(Default::default),
+        total: 0,
+        out_of_range: false,
+        debug_shards: params.debug_shards.then(Vec::new),
+        results: Vec::new(),
+    }
+}
+
+/// GET /idf の応答 (terms は tokens パラメータの語)
+/// tokenize なら全ての語を tokenizer にまとめて渡し、分割したトークンで引く
+/// # Returns
+/// (HTTP ステータス, レスポンスの JSON)
+pub fn idf_response<F>(pool: &IndexPool, terms: &[String], tokenize: bool, tokenizer: F) -> (u16, serde_json::Value)
+where
+    F: FnOnce(&[(&str, Option<&str>)]) -> Result<Vec<Vec<String>>, SudachiError>,
+{
+    if terms.is_empty() {
+        return (400, serde_json::json!({ "success": false, "error": "Missing tokens parameter" }));
+    }
+    if terms.len() > MAX_BULK_ITEMS {
+        return (400, serde_json::json!({ "success": false, "error": format!("tokens must contain 1 to {} terms", MAX_BULK_ITEMS) }));
+    }
+    if !tokenize {
+        return (200, serde_json::to_value(pool.idf_map(terms)).unwrap());
+    }
+    let texts: Vec<(&str, Option<&str>)> = terms.iter().map(|term| (term.as_str(), None)).collect();
+    match tokenizer(&texts) {
+        Ok(tokens) => {
+            let tokens: Vec<String> = tokens.into_iter().flatten().collect();
+            (200, serde_json::to_value(pool.idf_map(&tokens)).unwrap())
+        }
+        Err(e) => {
+            warn!("tokenize error: {}", e);
+            let (status, error) = e.http_error();
+            (status, serde_json::json!({ "success": false, "error": error }))
+        }
+    }
+}
+
+/// range が結果の末尾を越えているか
+/// 先頭ページ (start = 0) は結果が 0 件でも越えたとはみなさない
+pub fn is_out_of_range(range: &Range<usize>, total: usize) -> bool {
+    range.start > 0 && range.start >= total
+}
+
+/// クエリと除外語のトークン
+/// tokens 指定時はそのまま使い、除外語も 1 語 1 トークンとみなす
+/// それ以外は lang に合わせてインデックスと同じ設定でトークン化する
+fn query_tokens(params: &SearchParams, config: &Config) -> Result<(Vec<String>, Vec<Vec<String>>), SudachiError> {
+    if let Some(tokens) = &params.tokens {
+        let exclude = params.exclude_terms.iter().map(|t| vec![t.clone()]).collect();
+        return Ok((tokens.clone(), exclude));
+    }
+    let lang = params.lang.as_deref();
+    let tokens = tokenize_lang(&params.terms, lang, &config.tokenize)?;
+    let mut exclude = Vec::with_capacity(params.exclude_terms.len());
+    for term in &params.exclude_terms {
+        let t = tokenize_lang(term, lang, &config.tokenize)?;
+        if !t.is_empty() {
+            exclude.push(t);
+        }
+    }
+    Ok((tokens, exclude))
+}
+
+/// クエリの語のうちコーパスにない語を、編集距離が最も近い語に置き換えたクエリを返す
+/// 距離が同じなら文書頻度の高い語を選ぶ
+/// 置き換えられる語がなければ None
+pub fn suggest_query<T: AsRef<str>>(corpus: &Corpus, tokens: &[T]) -> Option<String> {
+    let mut changed = false;
+    let suggested: Vec<String> = tokens.iter()
+        .map(|t| {
+            let token = t.as_ref();
+            if corpus.get_token_count(token) > 0 {
+                return token.to_string();
+            }
+            match nearest_term(corpus, token) {
+                Some(term) => {
+                    changed = true;
+                    term
+                }
+                None => token.to_string(),
+            }
+        })
+        .collect();
+    if changed { Some(suggested.join(" ")) } else { None }
+}
+
+/// コーパスの語彙から編集距離が最も近い語
+/// 許容する距離は語の長さの半分まで (最低 1)
+fn nearest_term(corpus: &Corpus, token: &str) -> Option<String> {
+    let len = token.chars().count();
+    let max_distance = (len / 2).max(1);
+    let mut best: Option<(usize, u64, String)> = None;
+    for entry in corpus.token_counts.iter() {
+        let term = entry.key();
+        // 長さの差だけで距離の下限が超えるものは飛ばす
+        if term.chars().count().abs_diff(len) > max_distance {
+            continue;
+        }
+        let distance = edit_distance(token, term);
+        if distance > max_distance {
+            continue;
+        }
+        let count = *entry.value();
+        let better = match &best {
+            None => true,
+            Some((d, c, t)) => (distance, std::cmp::Reverse(count), term.as_ref()) < (*d, std::cmp::Reverse(*c), t.as_str()),
+        };
+        if better {
+            best = Some((distance, count, term.to_string()));
+        }
+    }
+    best.map(|(_, _, term)| term)
+}
+
+/// レーベンシュタイン距離 (文字単位)
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+/// "30m" / "24h" / "7d" / "2w" 形式の期間をパース (単位なしは時間)
+pub fn parse_duration(s: &str) -> Option<chrono::Duration> {
+    let s = s.trim().to_ascii_lowercase();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: i64 = num.parse().ok().filter(|&n| n > 0)?;
+    match unit.trim() {
+        "m" | "min" => chrono::Duration::try_minutes(n),
+        "" | "h" => chrono::Duration::try_hours(n),
+        "d" => chrono::Duration::try_days(n),
+        "w" => chrono::Duration::try_weeks(n),
+        _ => None,
+    }
+}
+
+/// /trending の window パラメータ (未指定なら DEFAULT_TRENDING_WINDOW)
+/// Err はそのまま 400 のエラーメッセージ
+pub fn trending_window(path: &mut Path) -> Result<chrono::Duration, String> {
+    let raw = decode_query(path, "window").unwrap_or_else(|| DEFAULT_TRENDING_WINDOW.to_string());
+    parse_duration(&raw).ok_or_else(|| format!("Invalid window: {}", raw))
+}
+
+/// "index_id:doc_id" のリストをパース
+/// 不正な要素があれば Err(その要素)
+pub fn parse_ids<T: AsRef<str>>(ids: &[T]) -> Result<HashSet<(usize, usize)>, String> {
+    ids.iter()
+        .map(|id| {
+            let id = id.as_ref().trim();
+            id.split_once(':')
+                .and_then(|(index_id, doc_id)| Some((index_id.trim().parse().ok()?, doc_id.trim().parse().ok()?)))
+                .ok_or_else(|| id.to_string())
+        })
+        .collect()
+}
+
+/// クエリを通常の語と除外語 ("-" で始まる語) に分ける
+/// "-" だけの語は無視する
+/// # Returns
+/// (除外語を除いたクエリ, 除外語 ("-" なし))
+pub fn split_exclude_terms(query: &str) -> (String, Vec<String>) {
+    let mut terms = Vec::new();
+    let mut exclude = Vec::new();
+    for word in query.split_whitespace() {
+        match word.strip_prefix('-') {
+            Some("") => {}
+            Some(term) => exclude.push(term.to_string()),
+            None => terms.push(word),
+        }
+    }
+    (terms.join(" "), exclude)
+}
+
+/// 指定した (index_id, doc_id) のドキュメントのみ残す
+/// ソート・タグフィルタ前にかけるので range はこの中で数える
+pub fn retain_ids(scored: &mut Vec<ScoredEntry>, ids: &HashSet<(usize, usize)>) {
+    scored.retain(|e| ids.contains(&(e.index_id, e.key)));
+}
+
+/// length が min 以上のドキュメントのみ残す
+/// BM25 の文書長の正規化で短いページ (スタブなど) が上位に来るのを防ぐ
+pub fn retain_min_length(scored: &mut Vec<ScoredEntry>, min: u64) {
+    scored.retain(|e| e.length >= min);
+}
+
+/// sort パラメータ (未指定 / "score" なら None = スコア順)
+/// Err はそのまま 400 のエラーメッセージ
+pub fn parse_sort(s: Option<&str>) -> Result<Option<DateField>, String> {
+    match s.map(|s| s.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("") | Some("score") => Ok(None),
+        Some("published") => Ok(Some(DateField::Published)),
+        Some("modified") => Ok(Some(DateField::Modified)),
+        Some(other) => Err(format!("Unknown sort: {} (score / published / modified)", other)),
+    }
+}
+
+/// collapse_by パラメータ 今は "host" のみ (true ならホストごとにまとめる)
+pub fn parse_collapse_by(s: Option<&str>) -> Result<bool, String> {
+    match s.map(|s| s.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("") => Ok(false),
+        Some("host") => Ok(true),
+        Some(other) => Err(format!("Unknown collapse_by: {} (host)", other)),
+    }
+}
+
+/// published_after / published_before パラメータ (ingest::parse_date の形式)
+fn parse_date_param(name: &str, s: Option<&str>) -> Result<Option<DateTime<Utc>>, String> {
+    match s.map(str::trim).filter(|s| !s.is_empty()) {
+        Some(s) => parse_date(s).map(Some).ok_or_else(|| format!("Invalid {}: {}", name, s)),
+        None => Ok(None),
+    }
+}
+
+/// クエリパラメータを取得して URL デコード (form_decode)
+pub fn decode_query(path: &mut Path, key: &str) -> Option<String> {
+    path.get_query(key).map(|v| form_decode(&v))
+}
+
+/// application/x-www-form-urlencoded としてデコード
+/// `+` は空白にしてから %XX を戻す (`+` そのものは `%2B`)
+/// デコードに失敗したら生の値
+pub fn form_decode(raw: &str) -> String {
+    let plus_decoded = raw.replace('+', " ");
+    percent_decode_str(&plus_decoded)
+        .decode_utf8()
+        .map(|cow| cow.into_owned())
+        .unwrap_or_else(|_| raw.to_string())
+}
+
+/// カンマ区切りのリストを分割 (空要素は除外)
+pub fn split_list(s: &str) -> Vec<String> {
+    s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()
+}
+
+/// "true" / "1" を真とみなす
+pub fn parse_bool(s: &str) -> bool {
+    let v = s.trim().to_ascii_lowercase();
+    v == "true" || v == "1"
+}
+
+/// スコアリング方法
+pub enum Scoring {
+    /// 単一アルゴリズム
+    Single(SimilarityAlgorithm),
+    /// 複数アルゴリズムの重み付き合成 (アルゴリズム, 重み)
+    /// 例: mix(BM25(1.2,0.75):0.7, cosine:0.3)
+    Mix(Vec<(SimilarityAlgorithm, f64)>),
+}
+
+/// algo パラメータをパース
+/// "mix(" で始まれば合成、それ以外は parse_algo
+/// mix の各要素は "アルゴリズム:重み" (重み省略時 1.0)
+pub fn parse_scoring(s: &str) -> Scoring {
+    let trimmed = s.trim();
+    let Some(parts) = mix_parts(trimmed) else {
+        return Scoring::Single(parse_algo(trimmed));
+    };
+    let algos: Vec<(SimilarityAlgorithm, f64)> = parts.iter()
+        .map(|p| match p.rsplit_once(':') {
+            Some((algo, weight)) => (parse_algo(algo), weight.trim().parse::<f64>().unwrap_or(1.0)),
+            None => (parse_algo(p), 1.0),
+        })
+        .collect();
+    if algos.is_empty() {
+        return Scoring::Single(parse_algo(""));
+    }
+    Scoring::Mix(algos)
+}
+
+/// "mix(...)" の要素 (空の要素は除く、mix でなければ None)
+fn mix_parts(trimmed: &str) -> Option<Vec<&str>> {
+    if !trimmed.to_ascii_lowercase().starts_with("mix(") {
+        return None;
+    }
+    let inner = match (trimmed.find('('), trimmed.rfind(')')) {
+        (Some(l), Some(r)) if l < r => &trimmed[l + 1..r],
+        _ => &trimmed[4..],
+    };
+    // 括弧の内側のカンマでは分割しない
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, ch) in inner.char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                parts.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&inner[start..]);
+    Some(parts.into_iter().map(|p| p.trim()).filter(|p| !p.is_empty()).collect())
+}
+
+/// algo の形式を確認 (DEFAULT_ALGORITHM の起動時チェック用)
+/// parse_scoring は読めない部分をデフォルトで補うので、ここで厳密に見る
+pub fn validate_scoring(s: &str) -> Result<(), String> {
+    let Some(parts) = mix_parts(s.trim()) else { return validate_algo(s); };
+    if parts.is_empty() {
+        return Err(format!("Empty mix: {}", s.trim()));
+    }
+    for part in parts {
+        match part.rsplit_once(':') {
+            Some((algo, weight)) => {
+                validate_algo(algo)?;
+                weight.trim().parse::<f64>().map_err(|_| format!("Invalid weight: {}", part))?;
+            }
+            None => validate_algo(part)?,
+        }
+    }
+    Ok(())
+}
+
+fn validate_algo(s: &str) -> Result<(), String> {
+    let s = s.trim();
+    let lower = s.to_ascii_lowercase();
+    if !["dot", "cosine", "bm25"].iter().any(|name| lower.starts_with(name)) {
+        return Err(format!("Unknown algorithm: {}", s));
+    }
+    if let Some(l) = lower.find('(') {
+        let inner = lower[l + 1..].strip_suffix(')').ok_or_else(|| format!("Unclosed parenthesis: {}", s))?;
+        if !inner.trim().is_empty() && inner.split(',').any(|v| v.trim().parse::<f64>().is_err()) {
+            return Err(format!("Invalid parameters: {}", s));
+        }
+    }
+    Ok(())
+}
+
+// 検索アルゴリズムの簡易パーサ
+pub fn parse_algo(s: &str) -> SimilarityAlgorithm {
+    let lower = s.trim().to_ascii_lowercase();
+    // 補助: 引数の括弧内から数値を抽出
+    fn nums(src: &str) -> Vec<f64> {
+        if let (Some(l), Some(r)) = (src.find('('), src.rfind(')')) {
+            let inner = &src[l + 1..r];
+            inner
+                .split(',')
+                .filter_map(|p| p.trim().parse::<f64>().ok())
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    if lower.starts_with("dot") {
+        SimilarityAlgorithm::Dot
+    } else if lower.starts_with("cosine") || lower.starts_with("cosinesimilarity") {
+        SimilarityAlgorithm::CosineSimilarity
+    } else if lower.starts_with("bm25plus") {
+        let v = nums(&lower);
+        let k1 = v.first().copied().unwrap_or(1.2);
+        let b = v.get(1).copied().unwrap_or(0.75);
+        let delta = v.get(2).copied().unwrap_or(0.5);
+        SimilarityAlgorithm::BM25plus(k1, b, delta)
+    } else if lower.starts_with("bm25l") {
+        let v = nums(&lower);
+        let k1 = v.first().copied().unwrap_or(1.2);
+        let b = v.get(1).copied().unwrap_or(0.75);
+        SimilarityAlgorithm::BM25L(k1, b)
+    } else if lower.starts_with("bm25cosinenormalizedlinearcombination") {
+        let v = nums(&lower);
+        let k1 = v.first().copied().unwrap_or(1.2);
+        let b = v.get(1).copied().unwrap_or(0.75);
+        let alpha = v.get(2).copied().unwrap_or(0.5);
+        SimilarityAlgorithm::BM25CosineNormalizedLinearCombination(k1, b, alpha)
+    } else if lower.starts_with("bm25cosinefilter") {
+        let v = nums(&lower);
+        let k1 = v.first().copied().unwrap_or(1.2);
+        let b = v.get(1).copied().unwrap_or(0.75);
+        SimilarityAlgorithm::BM25CosineFilter(k1, b)
+    } else if lower.starts_with("bm25prfcosinesimilarity") {
+        let v = nums(&lower);
+        let k1 = v.first().copied().unwrap_or(1.2);
+        let b = v.get(1).copied().unwrap_or(0.75);
+        let top_n = v.get(2).copied().unwrap_or(10.0) as usize;
+        let alpha = v.get(3).copied().unwrap_or(0.5);
+        SimilarityAlgorithm::BM25PrfCosineSimilarity(k1, b, top_n, alpha)
+    } else if lower.starts_with("bm25") {
+        let v = nums(&lower);
+        let k1 = v.first().copied().unwrap_or(1.2);
+        let b = v.get(1).copied().unwrap_or(0.75);
+        SimilarityAlgorithm::BM25(k1, b)
+    } else {
+        // 既定
+        SimilarityAlgorithm::BM25(1.2, 0.75)
+    }
+}
+
+// range クエリ文字列を正規化して (start, end) (endは排他的) を返す
+// 受け入れる形式:
+//   "a..b"  -> a..b
+//   "..b"   -> 0..b
+//   "a.."   -> a..a+DEFAULT_SEARCH_RESULTS
+//   "v"     -> v..v+DEFAULT_SEARCH_RESULTS
+//   空/None  -> 0..DEFAULT_SEARCH_RESULTS
+// 正規化:
+//   1) 解析失敗はデフォルト
+//   2) end < start の場合 swap (例: 20..10 -> 10..20)
+//   3) 幅 > MAX_SEARCH_RESULTS (MAX_RESULTS_PER_REQUEST) の場合 end = start + その値
+//   4) 加算は saturating_add でオーバーフロー防止
+pub fn parse_range_param(raw: Option<String>) -> ParsedRange {
+    parse_range_param_with(raw, DEFAULT_SEARCH_RESULTS, MAX_SEARCH_RESULTS)
+}
+
+/// 省略時の件数と最大幅を指定する版 (DEFAULT_SEARCH_RESULTS / MAX_RESULTS_PER_REQUEST の設定用)
+/// max_len は MAX_SEARCH_RESULTS を超えない
+pub fn parse_range_param_with(raw: Option<String>, default_len: usize, max_len: usize) -> ParsedRange {
+    let max_len = max_len.min(MAX_SEARCH_RESULTS);
+    let default_end = default_len.min(max_len);
+    let Some(s) = raw else { return ParsedRange::new(0..default_end); };
+    if s.is_empty() { return ParsedRange::new(0..default_end); }
+
+    let mut warnings = Vec::new();
+    let mut parse = |v: &str, fallback: usize| v.parse::<usize>().unwrap_or_else(|_| {
+        warnings.push(format!("Invalid range value {:?}", v));
+        fallback
+    });
+
+    let (mut start, mut end) = if let Some((l, r)) = s.split_once("..") {
+        // a..b / a.. / ..b
+        let start = if l.is_empty() { 0 } else { parse(l, 0) };
+        if r.is_empty() {
+            // a..  -> a..a+DEFAULT
+            let tentative = start.saturating_add(default_len);
+            (start, tentative)
+        } else {
+            // a..b / ..b
+            let end = parse(r, start);
+            let start = if l.is_empty() { 0 } else { start }; // ..b の場合 start=0
+            (start, end)
+        }
+    } else {
+        // 単値 v
+        let v = parse(&s, 0);
+        let end = v.saturating_add(default_len);
+        (v, end)
+    };
+
+    // swap if reversed
+    if end < start {
+        std::mem::swap(&mut start, &mut end);
+        warnings.push(format!("Reversed range, using {}..{}", start, end));
+    }
+
+    // 幅制限
+    let max_end = start.saturating_add(max_len);
+    if end > max_end {
+        end = max_end;
+        warnings.push(format!("Range width limited to {}, using {}..{}", max_len, start, end));
+    }
+
+    if start == end {
+        warnings.push(format!("Empty range {}..{}, no results will be returned", start, end));
+    }
+
+    ParsedRange {
+        range: start..end,
+        warning: if warnings.is_empty() { None } else { Some(warnings.join("; ")) },
+    }
+}
+
+/// 正規化済みの range
+/// 入力を補正した / 空の範囲になった場合は warning に理由を入れる (レスポンスの hint に載せる)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedRange {
+    pub range: Range<usize>,
+    pub warning: Option<String>,
+}
+
+impl ParsedRange {
+    fn new(range: Range<usize>) -> Self {
+        Self { range, warning: None }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{tests::{add_test_docs, test_meta}, IndexMeta};
+    use crate::ingest::title_weighted_tokens;
+
+    fn range(s: &str) -> ParsedRange {
+        parse_range_param(Some(s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_range_param() {
+        assert_eq!(parse_range_param(None), ParsedRange::new(0..DEFAULT_SEARCH_RESULTS));
+        assert_eq!(range("20..40"), ParsedRange::new(20..40));
+        // 空の範囲は hint 付き
+        let empty = range("5..5");
+        assert_eq!(empty.range, 5..5);
+        assert!(empty.warning.unwrap().contains("Empty range"));
+        // a.. は a..a+DEFAULT
+        assert_eq!(range("30.."), ParsedRange::new(30..30 + DEFAULT_SEARCH_RESULTS));
+        // ..b は 0..b、幅は MAX_SEARCH_RESULTS まで
+        assert_eq!(range("..50"), ParsedRange::new(0..50));
+        let wide = range("..999999");
+        assert_eq!(wide.range, 0..MAX_SEARCH_RESULTS);
+        assert!(wide.warning.unwrap().contains("limited"));
+        // 逆順は swap
+        let reversed = range("40..20");
+        assert_eq!(reversed.range, 20..40);
+        assert!(reversed.warning.unwrap().contains("Reversed"));
+        // 単値
+        assert_eq!(range("7"), ParsedRange::new(7..7 + DEFAULT_SEARCH_RESULTS));
+        assert!(range("abc..10").warning.unwrap().contains("Invalid"));
+    }
+
+    #[test]
+    fn test_collapse_by_param() {
+        let req = |collapse_by: &str| SearchReq { query: "rust".to_string(), collapse_by: Some(collapse_by.to_string()), ..Default::default() };
+        assert!(SearchParams::new(req("Host"), &Config::default()).unwrap().collapse_by_host);
+        assert!(!SearchParams::new(req(""), &Config::default()).unwrap().collapse_by_host);
+        assert!(SearchParams::new(req("domain"), &Config::default()).err().unwrap().starts_with("Unknown collapse_by"));
+        let backfill = SearchParams::new(SearchReq { backfill: true, ..req("host") }, &Config::default()).unwrap();
+        assert_eq!(backfill.warnings, vec!["collapse_by ignores backfill"]);
+
+        // total はまとめた後の件数 (search_pool は全て example.com)
+        let (dir, pool) = search_pool("collapse");
+        let params = SearchParams::new(SearchReq { tokens: Some(vec!["天気".to_string()]), ..req("host") }, &Config::default()).unwrap();
+        let SearchRes::Success { results, total, .. } = search(&pool, &Config::default(), &params).1 else { panic!() };
+        assert_eq!((results.len(), total), (1, 1));
+        assert_eq!(results[0].others.as_ref().unwrap().len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_max_results_per_request() {
+        let config = Config::from_lookup(|key| (key == "MAX_RESULTS_PER_REQUEST").then(|| "50".to_string()));
+        assert_eq!(config.max_results_per_request, 50);
+        let params = SearchParams::new(SearchReq { query: "rust".to_string(), range: Some("100..900".to_string()), ..Default::default() }, &config).unwrap();
+        assert_eq!(params.range, 100..150);
+        assert!(params.range_hint.unwrap().contains("limited to 50"));
+        // 省略時の件数も上限まで
+        assert_eq!(parse_range_param_with(None, 80, 50).range, 0..50);
+        // MAX_SEARCH_RESULTS を超える指定は MAX_SEARCH_RESULTS
+        let over = Config::from_lookup(|key| (key == "MAX_RESULTS_PER_REQUEST").then(|| "5000".to_string()));
+        assert_eq!(over.max_results_per_request, MAX_SEARCH_RESULTS);
+        assert_eq!(Config::default().max_results_per_request, MAX_SEARCH_RESULTS);
+
+        // generate_results でも range の幅を上限で切る
+        let (dir, pool) = search_pool("max-results");
+        let sorted = pool.sort_by_score(pool.per_similarity(&TokenFrequency::from(&["東京", "天気"][..]), &SimilarityAlgorithm::CosineSimilarity));
+        assert_eq!(sorted.len(), 3);
+        let options = ResultOptions { max_results: Some(2), ..Default::default() };
+        let results = pool.generate_results_with(sorted, 0..MAX_SEARCH_RESULTS, Tags::new(0), false, Tags::new(0), options);
+        assert_eq!(results.len(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_suggest_query() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("東京", "東京都"), 1);
+        let corpus = Corpus::new();
+        corpus.add_set(&["search", "engine", "rust"]);
+        corpus.add_set(&["search", "rest"]);
+        // 入力ミス -> 近い語 (同距離なら文書頻度の高い方)
+        assert_eq!(suggest_query(&corpus, &["serch", "engin"]).unwrap(), "search engine");
+        assert_eq!(suggest_query(&corpus, &["rast"]).unwrap(), "rest");
+        // 既知の語はそのまま、全て既知なら提案なし
+        assert_eq!(suggest_query(&corpus, &["rust", "enjine"]).unwrap(), "rust engine");
+        assert!(suggest_query(&corpus, &["rust"]).is_none());
+        // 遠すぎる語は置き換えない
+        assert!(suggest_query(&corpus, &["xyzzyq"]).is_none());
+    }
+
+    #[test]
+    fn test_parse_ids() {
+        let ids = parse_ids(&["0:1", " 3:10 "]).unwrap();
+        assert!(ids.contains(&(0, 1)) && ids.contains(&(3, 10)));
+        assert_eq!(parse_ids(&["0:1", "bogus"]).unwrap_err(), "bogus");
+        assert_eq!(parse_ids(&["1:"]).unwrap_err(), "1:");
+    }
+
+    #[test]
+    fn test_retain_ids_scopes_search() {
+        let dir = std::env::temp_dir().join(format!("wk-search-test-ids-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let pool = IndexPool::new(&dir.to_string_lossy());
+        {
+            let shard = pool.shard(0).unwrap();
+            let mut idx = shard.write().unwrap();
+            for i in 0..5 {
+                idx.vectorizer.add_doc(i, &TokenFrequency::from(&["rust", "search"][..]));
+            }
+            idx.vectorizer.update_idf();
+        }
+        let query = TokenFrequency::from(&["rust"][..]);
+        let mut scored = pool.per_similarity(&query, &SimilarityAlgorithm::CosineSimilarity);
+        assert_eq!(scored.len(), 5);
+        let ids = parse_ids(&["0:1", "0:3", "5:1"]).unwrap();
+        retain_ids(&mut scored, &ids);
+        let mut keys: Vec<usize> = scored.iter().map(|e| e.key).collect();
+        keys.sort();
+        assert_eq!(keys, vec![1, 3]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_search_req_from_json() {
+        let req: SearchReq = serde_json::from_str(r#"{
+            "query": "rust",
+            "range": "20..40",
+            "tags": ["wiki", "news"],
+            "tag_exclusive": true,
+            "exclude_tags": ["sns"],
+            "fields": ["url", "title"],
+            "sort": "published"
+        }"#).unwrap();
+        let params = SearchParams::new(req, &Config::default()).unwrap();
+        assert_eq!(params.query, "rust");
+        assert_eq!(params.range, 20..40);
+        assert_eq!(params.algo_str, DEFAULT_ALGORITHM);
+        assert!(params.tags.is_filter_contains(Tags::WIKI | Tags::NEWS));
+        assert!(params.tag_exclusive);
+        assert!(params.exclude_tags.contains(Tags::SNS));
+        assert_eq!(params.fields.unwrap(), vec!["url", "title"]);
+        assert_eq!(params.sort, Some(DateField::Published));
+
+        // 知らないフィールドは無視せず 400
+        let error = crate::context::parse_json_body::<SearchReq>(br#"{ "query": "rust", "filters": { "lang": "en" } }"#).err().unwrap();
+        assert_eq!(error.status, 400);
+        assert!(error.detail.unwrap().contains("unknown field `filters`"));
+    }
+
+    #[test]
+    fn test_default_algorithm() {
+        let config = Config::from_lookup(|key| (key == "DEFAULT_ALGORITHM").then(|| "cosine".to_string()));
+        let params = SearchParams::new(SearchReq { query: "rust".to_string(), ..Default::default() }, &config).unwrap();
+        assert_eq!(params.algo_str, "cosine");
+        assert!(matches!(params.scoring, Scoring::Single(SimilarityAlgorithm::CosineSimilarity)));
+        // algo を指定すればそちら
+        let params = SearchParams::new(SearchReq { query: "rust".to_string(), algo: Some("dot".to_string()), ..Default::default() }, &config).unwrap();
+        assert!(matches!(params.scoring, Scoring::Single(SimilarityAlgorithm::Dot)));
+
+        assert!(validate_scoring(DEFAULT_ALGORITHM).is_ok());
+        assert!(validate_scoring("mix(BM25(1.5,0.5):0.7, cosine:0.3)").is_ok());
+        assert!(validate_scoring("bm25").is_ok());
+        assert!(validate_scoring("tfidf").is_err());
+        assert!(validate_scoring("BM25(1.2,x)").is_err());
+        assert!(validate_scoring("BM25(1.2").is_err());
+        assert!(validate_scoring("mix(cosine:heavy)").is_err());
+        assert!(validate_scoring("mix()").is_err());
+        // 起動時 (と /reload) に弾く
+        let bad = Config::from_lookup(|key| (key == "DEFAULT_ALGORITHM").then(|| "tfidf".to_string()));
+        assert!(bad.install_globals().unwrap_err().starts_with("DEFAULT_ALGORITHM"));
+    }
+
+    #[test]
+    fn test_parse_scoring_mix() {
+        match parse_scoring("mix(BM25(1.5,0.5):0.7, cosine:0.3)") {
+            Scoring::Mix(algos) => {
+                assert_eq!(algos.len(), 2);
+                assert!(matches!(algos[0], (SimilarityAlgorithm::BM25(k1, b), w) if k1 == 1.5 && b == 0.5 && w == 0.7));
+                assert!(matches!(algos[1], (SimilarityAlgorithm::CosineSimilarity, w) if w == 0.3));
+            }
+            Scoring::Single(_) => panic!("expected mix"),
+        }
+        assert!(matches!(parse_scoring("BM25(1.2,0.75)"), Scoring::Single(SimilarityAlgorithm::BM25(_, _))));
+        // 重み省略は 1.0
+        assert!(matches!(parse_scoring("mix(dot)"), Scoring::Mix(a) if matches!(a[0], (SimilarityAlgorithm::Dot, w) if w == 1.0)));
+    }
+
+    #[test]
+    fn test_search_params_validation() {
+        let req = SearchReq { query: "   ".to_string(), ..Default::default() };
+        assert_eq!(SearchParams::new(req, &Config::default()).err().unwrap(), "Missing query");
+        let req = SearchReq { query: "rust".to_string(), fields: vec!["bogus".to_string()], ..Default::default() };
+        assert_eq!(SearchParams::new(req, &Config::default()).err().unwrap(), "Unknown field: bogus");
+    }
+
+    #[test]
+    fn test_exclude_terms() {
+        assert_eq!(split_exclude_terms("東京 -大阪"), ("東京".to_string(), vec!["大阪".to_string()]));
+        assert_eq!(split_exclude_terms("a-b  - c\u{3000}-d"), ("a-b c".to_string(), vec!["d".to_string()]));
+        let req = SearchReq { query: "-大阪 -京都".to_string(), ..Default::default() };
+        assert_eq!(SearchParams::new(req, &Config::default()).err().unwrap(), "Query has only excluded terms");
+
+        let dir = std::env::temp_dir().join(format!("wk-search-test-exclude-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let pool = IndexPool::new(&dir.to_string_lossy());
+        {
+            let shard = pool.shard(0).unwrap();
+            let mut idx = shard.write().unwrap();
+            idx.vectorizer.add_doc(0, &TokenFrequency::from(&["東京", "大阪"][..]));
+            idx.vectorizer.add_doc(1, &TokenFrequency::from(&["東京", "大阪", "府"][..]));
+            idx.vectorizer.add_doc(2, &TokenFrequency::from(&["東京"][..]));
+            idx.vectorizer.update_idf();
+        }
+        let remaining = |exclude: &[Vec<String>]| {
+            let mut scored = pool.per_similarity(&TokenFrequency::from(&["東京"][..]), &SimilarityAlgorithm::CosineSimilarity);
+            pool.retain_without_terms(&mut scored, exclude, &SearchReads::default());
+            let mut keys: Vec<usize> = scored.iter().map(|e| e.key).collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(remaining(&[]), vec![0, 1, 2]);
+        assert_eq!(remaining(&[vec!["大阪".to_string()]]), vec![2]);
+        // 複数トークンの除外語は全て含むものだけ除外
+        assert_eq!(remaining(&[vec!["大阪".to_string(), "府".to_string()]]), vec![0, 2]);
+        // 語彙にない語は何も除外しない
+        assert_eq!(remaining(&[vec!["京都".to_string()]]), vec![0, 1, 2]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn search_pool(name: &str) -> (std::path::PathBuf, IndexPool) {
+        let dir = std::env::temp_dir().join(format!("wk-search-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let pool = IndexPool::new(&dir.to_string_lossy());
+        add_test_docs(&pool, &[
+            ("https://example.com/1", &["東京", "天気", "晴れる"]),
+            ("https://example.com/2", &["東京", "観光"]),
+            ("https://example.com/3", &["大阪", "天気"]),
+        ]);
+        (dir, pool)
+    }
+
+    fn result_urls(res: &SearchRes) -> Vec<String> {
+        match res {
+            SearchRes::Success { results, .. } => results.iter().map(|r| r.url.to_string()).collect(),
+            SearchRes::Failed { error, .. } => panic!("{}", error),
+        }
+    }
+
+    #[test]
+    fn test_search_with_tokens() {
+        let req = |query: &str, tokens: Vec<&str>| SearchReq {
+            query: query.to_string(),
+            tokens: Some(tokens.into_iter().map(|t| t.to_string()).collect()),
+            ..Default::default()
+        };
+        assert_eq!(SearchParams::new(req("東京", vec![" ", ""]), &Config::default()).err().unwrap(), "Empty tokens");
+        // query なしでも良い
+        let params = SearchParams::new(req("-大阪", vec!["天気"]), &Config::default()).unwrap();
+        assert_eq!(params.query, "天気 -大阪");
+        assert_eq!(params.exclude_terms, vec!["大阪"]);
+
+        // sudachi を使わずに検索できる
+        let (dir, pool) = search_pool("tokens");
+        let config = Config::default();
+        let (status, res) = search(&pool, &config, &params);
+        assert_eq!(status, 200);
+        assert_eq!(result_urls(&res), vec!["https://example.com/1"]);
+        let (_, res) = search(&pool, &config, &SearchParams::new(req("", vec!["東京", "天気"]), &Config::default()).unwrap());
+        assert_eq!(result_urls(&res)[0], "https://example.com/1");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_search_lang() {
+        let dir = std::env::temp_dir().join(format!("wk-search-test-lang-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let pool = IndexPool::new(&dir.to_string_lossy());
+        let config = Config::default();
+        let meta = IndexMeta { lang: Some("en".into()), ..test_meta("https://example.com/en") };
+        let tokens = tokenize_lang("Rust Tokio runtime", Some("en"), &config.tokenize).unwrap();
+        pool.add_document(&TokenFrequency::from(tokens.as_slice()), meta);
+
+        let req = |lang: &str| SearchReq { query: "TOKIO runtime -python".to_string(), lang: Some(lang.to_string()), ..Default::default() };
+        let params = SearchParams::new(req(" EN "), &Config::default()).unwrap();
+        assert_eq!(params.lang.as_deref(), Some("en"));
+        assert_eq!(SearchParams::new(req(""), &Config::default()).unwrap().lang, None);
+        // インデックス時と同じ単語分割なので sudachi なしで一致する
+        let (status, res) = search(&pool, &config, &params);
+        assert_eq!(status, 200);
+        assert_eq!(result_urls(&res), vec!["https://example.com/en"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_search_title_weight() {
+        let dir = std::env::temp_dir().join(format!("wk-search-test-title-weight-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let pool = IndexPool::new(&dir.to_string_lossy());
+        let config = Config::default();
+        let no_title: [&str; 0] = [];
+        // 「東京」が本文にあるページ、タイトルにだけあるページ、タイトルだけでインデックスしたページ
+        pool.add_document(&title_weighted_tokens(&["本文", "天気", "東京"], &no_title, 3), test_meta("https://example.com/body"));
+        pool.add_document(&title_weighted_tokens(&["本文", "天気"], &["東京"], 3), test_meta("https://example.com/title"));
+        pool.add_document(&title_weighted_tokens(&no_title, &["大阪"], 1), test_meta("https://example.com/title-only"));
+        pool.add_document(&title_weighted_tokens(&["本文", "天気"], &no_title, 3), test_meta("https://example.com/none"));
+
+        let urls = |token: &str| {
+            let params = SearchParams::new(SearchReq { tokens: Some(vec![token.to_string()]), ..Default::default() }, &Config::default()).unwrap();
+            result_urls(&search(&pool, &config, &params).1)
+        };
+        // タイトルだけの語でもヒットし、重みの分だけ本文の一致より上に来る
+        assert_eq!(urls("東京"), vec!["https://example.com/title", "https://example.com/body"]);
+        assert_eq!(urls("大阪"), vec!["https://example.com/title-only"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_search_suggestion() {
+        let (dir, pool) = search_pool("suggestion");
+        let config = Config::default();
+        let suggestion = |tokens: &[&str], range: Range<usize>| {
+            let tokens = tokens.iter().map(|t| t.to_string()).collect();
+            let params = SearchParams { range, ..SearchParams::new(SearchReq { tokens: Some(tokens), ..Default::default() }, &Config::default()).unwrap() };
+            match search(&pool, &config, &params).1 {
+                SearchRes::Success { suggestion, total, .. } => (suggestion, total),
+                SearchRes::Failed { error, .. } => panic!("{}", error),
+            }
+        };
+        assert_eq!(suggestion(&["天汽"], 0..10), (Some("天気".to_string()), 0));
+        assert_eq!(suggestion(&["天気", "天汽"], 0..10), (None, 2));
+        // 範囲外のページは結果が空でもヒットはあるので候補を出さない
+        assert_eq!(suggestion(&["天気", "天汽"], 10..20), (None, 2));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_early_return_responses() {
+        let params = SearchParams::new(SearchReq { query: "、。".to_string(), ..Default::default() }, &Config::default()).unwrap();
+        // トークナイザのエラーは code とクエリ付き
+        let (status, res) = tokenize_failed(&params, &SudachiError::Exit(1, "bad dict".to_string()));
+        assert_eq!(status, 500);
+        let value = serde_json::to_value(&res).unwrap();
+        assert_eq!(value["success"], "false");
+        assert_eq!(value["code"], "tokenizer_error");
+        assert_eq!(value["query"], "、。");
+        let (status, res) = tokenize_failed(&params, &SudachiError::Spawn(std::io::Error::from(std::io::ErrorKind::NotFound)));
+        assert_eq!(status, 503);
+        assert!(matches!(res, SearchRes::Failed { code: Some(ref c), .. } if c == "tokenizer_unavailable"));
+
+        // 語が残らなかった場合は成功で warnings に載せる
+        match empty_tokens_result(&params) {
+            SearchRes::Success { results, warnings, total, .. } => {
+                assert!(results.is_empty());
+                assert_eq!(total, 0);
+                assert_eq!(warnings, vec![NO_SEARCHABLE_TOKENS]);
+            }
+            SearchRes::Failed { error, .. } => panic!("{}", error),
+        }
+
+        // パラメータの不正
+        let error = SearchParams::new(SearchReq { query: "-大阪".to_string(), ..Default::default() }, &Config::default()).err().unwrap();
+        let value = serde_json::to_value(SearchRes::failed(ERROR_INVALID_REQUEST, error, Some("-大阪".to_string()))).unwrap();
+        assert_eq!(value["code"], "invalid_request");
+        assert_eq!(value["query"], "-大阪");
+    }
+
+    #[test]
+    fn test_min_doc_length() {
+        let dir = std::env::temp_dir().join(format!("wk-search-test-min-length-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let pool = IndexPool::new(&dir.to_string_lossy());
+        let long: Vec<String> = std::iter::once("東京".to_string()).chain((1..300).map(|i| format!("語{}", i))).collect();
+        let long: Vec<&str> = long.iter().map(String::as_str).collect();
+        add_test_docs(&pool, &[
+            ("https://example.com/short", &["東京", "駅", "地図"]),
+            ("https://example.com/long", &long),
+        ]);
+        let req = |min_doc_length| SearchReq { tokens: Some(vec!["東京".to_string()]), min_doc_length, ..Default::default() };
+        let config = Config::default();
+        let (_, res) = search(&pool, &config, &SearchParams::new(req(None), &Config::default()).unwrap());
+        let mut urls = result_urls(&res);
+        urls.sort();
+        assert_eq!(urls, vec!["https://example.com/long", "https://example.com/short"]);
+        let (_, res) = search(&pool, &config, &SearchParams::new(req(Some(100)), &Config::default()).unwrap());
+        assert_eq!(result_urls(&res), vec!["https://example.com/long"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_substring_search() {
+        let dir = std::env::temp_dir().join(format!("wk-search-test-substring-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut pool = IndexPool::new(&dir.to_string_lossy());
+        let config = Config::default();
+        // "京タワ" が "タワー" にトークン化された想定
+        let params = |substring: bool| SearchParams::new(SearchReq {
+            query: "京タワ".to_string(),
+            tokens: Some(vec!["タワー".to_string()]),
+            substring,
+            ..Default::default()
+        }, &Config::default()).unwrap();
+        // 無効なら 400
+        assert_eq!(search(&pool, &config, &params(true)).0, 400);
+
+        // TRIGRAM_INDEX を有効にする前に登録したドキュメント
+        pool.add_document_with_text(&TokenFrequency::from(&["東京", "タワー"][..]), test_meta("https://example.com/old"), "東京タワー");
+        pool.trigram_index = true;
+        pool.add_document_with_text(&TokenFrequency::from(&["東京", "タワー", "展望"][..]), test_meta("https://example.com/tower"), "東京タワーの展望台");
+        pool.add_document_with_text(&TokenFrequency::from(&["京都", "タワー"][..]), test_meta("https://example.com/kyoto"), "京都タワー");
+        // トークンだけでは区別できない
+        let mut urls = result_urls(&search(&pool, &config, &params(false)).1);
+        urls.sort();
+        assert_eq!(urls, vec!["https://example.com/kyoto", "https://example.com/old", "https://example.com/tower"]);
+        // トークンでのヒットのうち部分文字列を含むもの、3-gram のないものは除いて warnings に載せる
+        let (_, res) = search(&pool, &config, &params(true));
+        assert_eq!(result_urls(&res), vec!["https://example.com/tower"]);
+        let SearchRes::Success { warnings, total, .. } = res else { unreachable!() };
+        assert_eq!(total, 1);
+        assert!(warnings.iter().any(|w| w.starts_with("substring: 1 documents")));
+        // トークンでヒットしないものは 3-gram が一致しても返さない
+        let no_token = SearchParams::new(SearchReq {
+            query: "京タワ".to_string(),
+            tokens: Some(vec!["展望台".to_string()]),
+            substring: true,
+            ..Default::default()
+        }, &Config::default()).unwrap();
+        assert!(result_urls(&search(&pool, &config, &no_token).1).is_empty());
+
+        // 書き込み中のシャードは黙って落とさず skipped に載せる
+        let grams = query_trigrams("京タワ").unwrap();
+        let mut scored = pool.per_similarity(&TokenFrequency::from(&["タワー"][..]), &SimilarityAlgorithm::CosineSimilarity);
+        let (shard_id, _) = pool.locate("https://example.com/tower").unwrap();
+        let shard = pool.shard(shard_id).unwrap();
+        let guard = shard.write().unwrap();
+        let reads = SearchReads::default();
+        pool.apply_substring(&mut scored, &grams, &reads);
+        drop(guard);
+        assert_eq!(reads.skipped(), vec![shard_id]);
+        assert!(scored.iter().all(|e| e.index_id != shard_id));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_debug_shards() {
+        let (dir, pool) = search_pool("debug-shards");
+        let config = Config::default();
+        let params = SearchParams::new(SearchReq { tokens: Some(vec!["天気".to_string()]), debug_shards: true, ..Default::default() }, &Config::default()).unwrap();
+        let shards = |res: SearchRes| match res {
+            SearchRes::Success { total, debug_shards, .. } => (total, debug_shards.unwrap()),
+            SearchRes::Failed { error, .. } => panic!("{}", error),
+        };
+        let (total, debug) = shards(search(&pool, &config, &params).1);
+        assert_eq!(total, 2);
+        assert_eq!(debug.len(), pool.indexes.len());
+        assert_eq!(debug.iter().map(|s| s.candidates).sum::<usize>(), total);
+        assert!(debug.iter().all(|s| !s.skipped));
+
+        // 書き込み中のシャードは飛ばして skipped にする
+        let (shard_id, _) = pool.locate("https://example.com/1").unwrap();
+        let shard = pool.shard(shard_id).unwrap();
+        let guard = shard.write().unwrap();
+        let (total, debug) = shards(search(&pool, &config, &params).1);
+        drop(guard);
+        assert_eq!(total, 0);
+        assert_eq!(debug.iter().map(|s| s.candidates).sum::<usize>(), total);
+        assert!(debug.iter().enumerate().all(|(i, s)| s.skipped == (i == shard_id)));
+
+        // 指定しなければ含めない
+        let params = SearchParams::new(SearchReq { tokens: Some(vec!["天気".to_string()]), ..Default::default() }, &Config::default()).unwrap();
+        assert!(matches!(search(&pool, &config, &params).1, SearchRes::Success { debug_shards: None, .. }));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_show_query_tf() {
+        let (dir, pool) = search_pool("query-tf");
+        let config = Config::default();
+        let tokens = ["天気", "東京", "天気"].iter().map(|t| t.to_string()).collect();
+        let params = SearchParams::new(SearchReq { tokens: Some(tokens), show_query_tf: true, ..Default::default() }, &Config::default()).unwrap();
+        let SearchRes::Success { tokenize_query, query_tf, .. } = search(&pool, &config, &params).1 else { panic!() };
+        // 生のトークン列は重複を残し、query_tf はまとめた出現回数
+        assert_eq!(tokenize_query, vec!["天気", "東京", "天気"]);
+        let query_tf = query_tf.unwrap();
+        assert_eq!(query_tf.len(), 2);
+        assert_eq!((query_tf["天気"], query_tf["東京"]), (2, 1));
+
+        let params = SearchParams::new(SearchReq { tokens: Some(vec!["天気".to_string()]), ..Default::default() }, &Config::default()).unwrap();
+        assert!(matches!(search(&pool, &config, &params).1, SearchRes::Success { query_tf: None, .. }));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_normalize_score() {
+        let (dir, pool) = search_pool("normalize-score");
+        let config = Config::default();
+        let req = |normalize_score| SearchReq { tokens: Some(vec!["東京".to_string(), "天気".to_string()]), normalize_score, ..Default::default() };
+        let results = |req| match search(&pool, &config, &SearchParams::new(req, &Config::default()).unwrap()).1 {
+            SearchRes::Success { results, .. } => results,
+            SearchRes::Failed { error, .. } => panic!("{}", error),
+        };
+        let raw = results(req(false));
+        let normalized = results(req(true));
+        assert!(raw.len() >= 2 && raw.iter().all(|r| r.raw_score.is_none()));
+        assert_eq!(normalized[0].score, 1.0);
+        assert!(normalized.last().unwrap().score.abs() < 1e-9);
+        assert!(normalized.iter().all(|r| (0.0..=1.0).contains(&r.score)));
+        // 順位は変わらず、元の値は raw_score に残る
+        for (r, n) in raw.iter().zip(&normalized) {
+            assert_eq!((&r.url, Some(r.score)), (&n.url, n.raw_score));
+        }
+
+        assert_eq!(score_bounds(std::iter::empty()), (0.0, 0.0));
+        let mut same = vec![raw[0].clone()];
+        normalize_result_scores(&mut same, (raw[0].score, raw[0].score));
+        assert_eq!(same[0].score, 1.0);
+        // ヒットが 1 件だけでも 0 にしない
+        let single = results(SearchReq { tokens: Some(vec!["観光".to_string()]), normalize_score: true, ..Default::default() });
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].score, 1.0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_lite_search() {
+        let (dir, pool) = search_pool("lite");
+        let config = Config::default();
+        let req = |lite: bool, fields: Vec<String>| SearchReq { tokens: Some(vec!["天気".to_string()]), lite, fields, snippet_count: Some(1), ..Default::default() };
+        let params = SearchParams::new(req(true, Vec::new()), &Config::default()).unwrap();
+        assert_eq!(params.snippet_count, None);
+        assert_eq!(params.warnings, vec!["lite ignores highlight and snippet_count"]);
+        let (status, res) = search(&pool, &config, &params);
+        assert_eq!(status, 200);
+        // descriptions / favicon は複製せず、JSON にも含めない
+        let SearchRes::Success { results, .. } = &res else { panic!() };
+        assert!(results.iter().all(|r| r.descriptions.is_empty() && r.favicon.is_none()));
+        let value = res.to_value_with_fields(params.fields.as_deref());
+        let entry = value["results"][0].as_object().unwrap();
+        assert!(entry.contains_key("url") && entry.contains_key("score"));
+        assert!(!entry.contains_key("descriptions") && !entry.contains_key("favicon"));
+
+        // fields と併用すると fields から除く
+        let params = SearchParams::new(req(true, vec!["url".to_string(), "descriptions".to_string()]), &Config::default()).unwrap();
+        assert_eq!(params.fields.unwrap(), vec!["url"]);
+        let params = SearchParams::new(req(false, Vec::new()), &Config::default()).unwrap();
+        assert!(params.fields.is_none() && params.snippet_count == Some(1));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_exclude_url() {
+        let (dir, pool) = search_pool("exclude-url");
+        let (shard_id, _) = pool.locate("https://example.com/3").unwrap();
+        pool.shard(shard_id).unwrap().write().unwrap().meta[2].url = "https://Spam.example.net/3".into();
+        let config = Config::default();
+        let req = |exclude: &str| SearchReq {
+            tokens: Some(vec!["天気".to_string()]),
+            exclude_urls: split_list(exclude),
+            ..Default::default()
+        };
+        let urls = |exclude: &str| result_urls(&search(&pool, &config, &SearchParams::new(req(exclude), &Config::default()).unwrap()).1);
+        assert_eq!(urls("").len(), 2);
+        // ホストの大文字小文字は区別しない、他のページは残る
+        assert_eq!(urls("spam.example.net"), vec!["https://example.com/1"]);
+        assert_eq!(urls("nothing, SPAM.EXAMPLE"), vec!["https://example.com/1"]);
+        assert!(urls("example").is_empty());
+
+        let too_many = vec!["a"; MAX_EXCLUDE_URLS + 1].join(",");
+        assert!(SearchParams::new(req(&too_many), &Config::default()).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_search_lock_timeout() {
+        let (dir, mut pool) = search_pool("lock-timeout");
+        let config = Config::default();
+        let params = SearchParams::new(SearchReq { tokens: Some(vec!["天気".to_string()]), ..Default::default() }, &Config::default()).unwrap();
+        // 別スレッドが write lock を hold ms 持ち続ける
+        let (shard_id, _) = pool.locate("https://example.com/1").unwrap();
+        let shard = pool.shard(shard_id).unwrap();
+        let hold_write = |hold: u64| {
+            let index = std::sync::Arc::clone(&shard);
+            let (tx, rx) = std::sync::mpsc::channel();
+            let handle = std::thread::spawn(move || {
+                let _guard = index.write().unwrap();
+                tx.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(hold));
+            });
+            rx.recv().unwrap();
+            handle
+        };
+
+        // 待たない設定ならそのシャードを飛ばして 200 (ヒットが欠ける)
+        let handle = hold_write(200);
+        let (status, res) = search(&pool, &config, &params);
+        assert_eq!(status, 200);
+        assert!(result_urls(&res).is_empty());
+        handle.join().unwrap();
+
+        // タイムアウトより長く持たれていれば 503
+        pool.search_lock_timeout = Some(std::time::Duration::from_millis(20));
+        let handle = hold_write(500);
+        let (status, res) = search(&pool, &config, &params);
+        assert_eq!(status, 503);
+        let SearchRes::Failed { code, .. } = &res else { panic!() };
+        assert_eq!(code.as_deref(), Some(ERROR_SHARD_BUSY));
+        handle.join().unwrap();
+
+        // スコア計算の後に書き込みが始まっても、同じ期限で諦めて 503 (結果の作成で待ち続けない)
+        let snippets = SearchParams::new(SearchReq { tokens: Some(vec!["天気".to_string()]), snippet_count: Some(1), ..Default::default() }, &Config::default()).unwrap();
+        let Ok(scores) = score_query(&pool, &config, &snippets) else { panic!() };
+        assert_eq!(scores.scored.len(), 2);
+        let handle = hold_write(500);
+        let started = std::time::Instant::now();
+        let (status, res) = build_response(&pool, &config, &snippets, scores);
+        assert_eq!(status, 503);
+        let SearchRes::Failed { code, .. } = &res else { panic!() };
+        assert_eq!(code.as_deref(), Some(ERROR_SHARD_BUSY));
+        assert!(started.elapsed() < std::time::Duration::from_millis(400));
+        handle.join().unwrap();
+
+        // タイムアウト内に離されれば待って全件返す
+        pool.search_lock_timeout = Some(std::time::Duration::from_secs(5));
+        let handle = hold_write(30);
+        let (status, res) = search(&pool, &config, &params);
+        assert_eq!(status, 200);
+        assert_eq!(result_urls(&res).len(), 2);
+        handle.join().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sort_by_published() {
+        let (dir, pool) = search_pool("sort-published");
+        {
+            let (shard_id, _) = pool.locate("https://example.com/1").unwrap();
+            let shard = pool.shard(shard_id).unwrap();
+            let mut idx = shard.write().unwrap();
+            idx.meta[0].published = parse_date("2024-01-01");
+            idx.meta[0].modified = parse_date("2025-01-01");
+            idx.meta[2].published = parse_date("2024-06-01");
+        }
+        let config = Config::default();
+        let req = |sort: &str, after: Option<&str>, before: Option<&str>| SearchReq {
+            tokens: Some(vec!["東京".to_string(), "天気".to_string()]),
+            sort: Some(sort.to_string()),
+            published_after: after.map(|s| s.to_string()),
+            published_before: before.map(|s| s.to_string()),
+            ..Default::default()
+        };
+        let urls = |sort: &str, after: Option<&str>, before: Option<&str>| {
+            let params = SearchParams::new(req(sort, after, before), &Config::default()).unwrap();
+            result_urls(&search(&pool, &config, &params).1)
+        };
+        // published がなければインデックスした時刻 (今) で並べる
+        assert_eq!(urls("published", None, None), vec!["https://example.com/2", "https://example.com/3", "https://example.com/1"]);
+        assert_eq!(urls("modified", None, None), vec!["https://example.com/2", "https://example.com/1", "https://example.com/3"]);
+        assert_eq!(urls("published", None, Some("2024-03-01")), vec!["https://example.com/1"]);
+        assert_eq!(urls("published", Some("2024/06/01"), Some("2025-01-01T00:00:00Z")), vec!["https://example.com/3"]);
+        assert_eq!(urls("score", Some("2024-03-01"), None).len(), 2);
+
+        assert!(SearchParams::new(req("date", None, None), &Config::default()).is_err());
+        assert!(SearchParams::new(req("published", Some("last week"), None), &Config::default()).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_search_out_of_range() {
+        let (dir, pool) = search_pool("out-of-range");
+        for index in &pool.shards() {
+            for meta in index.write().unwrap().meta.iter_mut() {
+                if meta.url.as_ref() == "https://example.com/3" {
+                    meta.tags = Tags::new(Tags::NEWS);
+                }
+            }
+        }
+        let search_page = |config: &Config, range: &str, tag: &str| {
+            let req = SearchReq {
+                query: "天気".to_string(),
+                tokens: Some(vec!["天気".to_string()]),
+                range: Some(range.to_string()),
+                tags: split_list(tag),
+                ..Default::default()
+            };
+            match search(&pool, config, &SearchParams::new(req, &Config::default()).unwrap()).1 {
+                SearchRes::Success { total, out_of_range, results, .. } => (total, out_of_range, results.len()),
+                SearchRes::Failed { error, .. } => panic!("{}", error),
+            }
+        };
+        let config = Config::default();
+        let page = |range: &str| search_page(&config, range, "");
+        assert_eq!(page("0..20"), (2, false, 2));
+        assert_eq!(page("1..20"), (2, false, 1));
+        // 結果の末尾を越えたページ
+        assert_eq!(page("500..520"), (2, true, 0));
+        assert!(!is_out_of_range(&(0..20), 0));
+        // total はタグで絞り込んだ後の件数
+        assert_eq!(search_page(&config, "0..20", "news"), (1, false, 1));
+        assert_eq!(search_page(&config, "1..20", "news"), (1, true, 0));
+        // SCORE_TOP_K の上位に入らなかった分も数える
+        for merge in [ScoreMerge::Flat, ScoreMerge::KWay] {
+            let top_k = Config { score_top_k: Some(1), score_merge: merge, ..Config::default() };
+            assert_eq!(search_page(&top_k, "0..20", ""), (2, false, 1));
+            assert_eq!(search_page(&top_k, "0..20", "news"), (1, false, 1));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // 外部コマンド依存のため、デフォルトでは無効化
+    #[ignore]
+    #[test]
+    fn test_search_tokens_matches_tokenized() {
+        let (dir, pool) = search_pool("tokens-compare");
+        let config = Config::default();
+        let query = SearchReq { query: "東京の天気".to_string(), ..Default::default() };
+        let tokenized = crate::tokenize::tokenize(&query.query, &config.tokenize).unwrap();
+        let (_, by_query) = search(&pool, &config, &SearchParams::new(query, &Config::default()).unwrap());
+        let by_tokens = SearchReq { tokens: Some(tokenized), ..Default::default() };
+        let (_, by_tokens) = search(&pool, &config, &SearchParams::new(by_tokens, &Config::default()).unwrap());
+        assert_eq!(result_urls(&by_query), result_urls(&by_tokens));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_query_too_long() {
+        let (dir, pool) = search_pool("too-long");
+        let config = Config { max_query_chars: 10, ..Config::default() };
+        let params = |query: &str| SearchParams::new(SearchReq { query: query.to_string(), tokens: Some(vec!["天気".to_string()]), ..Default::default() }, &Config::default()).unwrap();
+        let (status, res) = search(&pool, &config, &params("東京の天気 -大阪"));
+        assert_eq!(status, 200);
+        assert!(matches!(res, SearchRes::Success { .. }));
+        let (status, res) = search(&pool, &config, &params(&"天".repeat(11)));
+        assert_eq!(status, 400);
+        assert!(matches!(res, SearchRes::Failed { code: Some(code), query: None, .. } if code == ERROR_QUERY_TOO_LONG));
+        assert_eq!(Config::default().max_query_chars, 2000);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_form_decode() {
+        assert_eq!(form_decode("c%2B%2B+rust"), "c++ rust");
+        assert_eq!(form_decode("%FF+a"), "%FF+a");
+        let req = |raw: &str| SearchReq::from_path(&mut Path::new(raw)).unwrap();
+        let plus = req("/search?query=%E6%9D%B1%E4%BA%AC+tower+rust&tag=news+site&algo=BM25(1.2,0.75)");
+        let space = req("/search?query=%E6%9D%B1%E4%BA%AC%20tower%20rust&tag=news%20site&algo=BM25(1.2,0.75)");
+        assert_eq!(plus.query, "東京 tower rust");
+        assert_eq!(plus.query, space.query);
+        assert_eq!(plus.tags, vec!["news site"]);
+        assert_eq!(plus.tags, space.tags);
+        let tokens = |q: &str| crate::tokenize::tokenize_lang(q, Some("en"), &Config::default().tokenize).unwrap();
+        assert_eq!(tokens(&plus.query), tokens(&space.query));
+        assert!(tokens(&plus.query).iter().all(|t| !t.contains('+')));
+    }
+
+    #[test]
+    fn test_from_path_numbers() {
+        let req = |raw: &str| SearchReq::from_path(&mut Path::new(raw));
+        let parsed = req("/search?query=a&snippet_count=3&min_doc_length=%2010").unwrap();
+        assert_eq!((parsed.snippet_count, parsed.min_doc_length), (Some(3), Some(10)));
+        let omitted = req("/search?query=a").unwrap();
+        assert_eq!((omitted.snippet_count, omitted.min_doc_length), (None, None));
+        // 読めない値は無視せず 400 にする
+        assert_eq!(req("/search?query=a&snippet_count=three").err().unwrap(), "Invalid snippet_count: three");
+        assert_eq!(req("/search?query=a&min_doc_length=-1").err().unwrap(), "Invalid min_doc_length: -1");
+    }
+
+    #[test]
+    fn test_trending_window() {
+        assert_eq!(parse_duration("24h"), chrono::Duration::try_hours(24));
+        assert_eq!(parse_duration("7d"), chrono::Duration::try_days(7));
+        assert_eq!(parse_duration("30m"), chrono::Duration::try_minutes(30));
+        assert_eq!(parse_duration("12"), chrono::Duration::try_hours(12));
+        assert!(parse_duration("0h").is_none() && parse_duration("1y").is_none() && parse_duration("h").is_none());
+
+        let (dir, pool) = search_pool("trending");
+        let now = chrono::Utc::now();
+        // 1: 30 日前, 2: 1 時間前, 3: 12 時間前
+        for index in &pool.shards() {
+            for meta in index.write().unwrap().meta.iter_mut() {
+                let hours = match meta.url.as_ref() {
+                    "https://example.com/1" => 24 * 30,
+                    "https://example.com/2" => 1,
+                    _ => 12,
+                };
+                meta.time = now - chrono::Duration::try_hours(hours).unwrap();
+            }
+        }
+        let mut params = SearchParams::new(SearchReq { tokens: Some(vec!["東京".to_string(), "天気".to_string()]), ..Default::default() }, &Config::default()).unwrap();
+        params.window = parse_duration("24h");
+        let (status, res) = search(&pool, &Config::default(), &params);
+        assert_eq!(status, 200);
+        // 30 日前の 1 は関連度が最も高いが除外、新しい 2 が 3 より上
+        assert_eq!(result_urls(&res), vec!["https://example.com/2", "https://example.com/3"]);
+        params.window = parse_duration("60d");
+        let (_, res) = search(&pool, &Config::default(), &params);
+        assert_eq!(result_urls(&res).len(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_unknown_tags() {
+        let req = |strict_tags| SearchReq {
+            query: "rust".to_string(),
+            tags: vec!["news".to_string(), "nws".to_string()],
+            exclude_tags: vec!["snss".to_string()],
+            strict_tags,
+            ..Default::default()
+        };
+        let params = SearchParams::new(req(false), &Config::default()).unwrap();
+        assert!(params.tags.contains(Tags::NEWS));
+        assert!(params.exclude_tags.is_empty());
+        assert_eq!(params.warnings, vec!["unknown tag: nws", "unknown tag: snss"]);
+        let err = SearchParams::new(req(true), &Config::default()).err().unwrap();
+        assert!(err.starts_with("Unknown tag: nws"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_idf_response() {
+        use crate::tokenize::{tests::fake_sudachi, tokenize_lang_batch_with, TokenizeOptions};
+        let (dir, pool) = search_pool("idf");
+        add_test_docs(&pool, &[("https://example.com/4", &["EOS", "R5"])]);
+        let (command, _) = fake_sudachi(&dir.join("sudachi"));
+        let tokenizer = |texts: &[(&str, Option<&str>)]| tokenize_lang_batch_with(&command, texts, &TokenizeOptions::default());
+
+        // sudachi が EOS という表層形のトークンを出しても失敗しない (tokens=EOS R5&tokenize=true)
+        let (status, res) = idf_response(&pool, &["EOS R5".to_string(), "東京".to_string()], true, tokenizer);
+        assert_eq!(status, 200);
+        assert_eq!(res, serde_json::to_value(pool.idf_map(&["EOS", "R5", "東京"])).unwrap());
+        assert!(res["EOS"].is_f64());
+        // tokenize なしなら語のまま引く
+        let (status, res) = idf_response(&pool, &["EOS R5".to_string()], false, tokenizer);
+        assert_eq!(status, 200);
+        assert!(res["EOS R5"].is_null());
+
+        assert_eq!(idf_response(&pool, &[], true, tokenizer).0, 400);
+        let too_many = vec!["東京".to_string(); MAX_BULK_ITEMS + 1];
+        assert_eq!(idf_response(&pool, &too_many, true, tokenizer).0, 400);
+        let unavailable = |_: &[(&str, Option<&str>)]| Err(SudachiError::Spawn(std::io::Error::other("x")));
+        let (status, res) = idf_response(&pool, &["東京".to_string()], true, unavailable);
+        assert_eq!(status, 503);
+        assert_eq!(res["success"], false);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
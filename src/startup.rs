@@ -0,0 +1,141 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use log::{info, warn};
+use reqwest::Client;
+
+use crate::{context::SearchContext, tokenize::{probe_sudachi, sudachi_version}};
+
+/// スクレイパへの疎通確認のタイムアウト
+pub const SCRAPER_PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 自己診断の 1 項目
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, ok: true, detail: detail.into() }
+    }
+
+    fn failed(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, ok: false, detail: detail.into() }
+    }
+}
+
+/// 起動時の自己診断の結果
+#[derive(Debug, Clone, Default)]
+pub struct StartupReport {
+    pub checks: Vec<Check>,
+}
+
+impl StartupReport {
+    pub fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.checks.iter().filter(|c| !c.ok)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// 1 項目 1 行でログに出す (失敗は warn)
+    pub fn log(&self) {
+        info!("Startup check:");
+        for check in &self.checks {
+            if check.ok {
+                info!("  [ok]   {}: {}", check.name, check.detail);
+            } else {
+                warn!("  [fail] {}: {}", check.name, check.detail);
+            }
+        }
+    }
+}
+
+/// 起動時の自己診断
+/// tokenizer / スクレイパの疎通 / インデックスディレクトリの書き込み / 読み込んだインデックス / 実効設定
+/// 失敗を致命的にするかは呼び出し側で決める (STRICT_STARTUP)
+pub async fn startup_check(context: &SearchContext, scraper_api_url: &str) -> StartupReport {
+    let pool = &context.index_pool;
+    let checks = vec![
+        check_tokenizer(),
+        if context.config.scraper_disabled {
+            Check::ok("scraper", "disabled (SCRAPER_DISABLED), /add returns 501")
+        } else {
+            check_scraper(scraper_api_url).await
+        },
+        check_index_dir(&pool.index_dir),
+        Check::ok("index", format!(
+            "{} shards, {} documents",
+            pool.indexes.len(),
+            pool.counter.load(Ordering::SeqCst),
+        )),
+        Check::ok("config", format!("{:?}", context.config)),
+    ];
+    StartupReport { checks }
+}
+
+fn check_tokenizer() -> Check {
+    if let Err(e) = probe_sudachi() {
+        return Check::failed("tokenizer", e.to_string());
+    }
+    match sudachi_version() {
+        Ok(version) => Check::ok("tokenizer", version),
+        // --version のない古い sudachi でもトークン化できれば問題ない
+        Err(e) => Check::ok("tokenizer", format!("version unknown ({})", e)),
+    }
+}
+
+/// HEAD を送って応答があれば到達可能とみなす (ステータスは問わない)
+pub async fn check_scraper(url: &str) -> Check {
+    let client = match Client::builder().timeout(SCRAPER_PING_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => return Check::failed("scraper", e.to_string()),
+    };
+    match client.head(url).send().await {
+        Ok(resp) => Check::ok("scraper", format!("{} ({})", url, resp.status())),
+        Err(e) => Check::failed("scraper", format!("{} unreachable: {}", url, e)),
+    }
+}
+
+/// 一時ファイルを作って消せるか確認する
+pub fn check_index_dir(dir: &str) -> Check {
+    let probe = std::path::Path::new(dir).join(".startup_check");
+    let result = std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(&probe, b""))
+        .and_then(|_| std::fs::remove_file(&probe));
+    match result {
+        Ok(()) => Check::ok("index_dir", format!("{} writable", dir)),
+        Err(e) => Check::failed("index_dir", format!("{} not writable: {}", dir, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_startup_checks() {
+        let dir = std::env::temp_dir().join(format!("startup-check-{}", std::process::id()));
+        let dir_str = dir.to_str().unwrap();
+        assert!(check_index_dir(dir_str).ok);
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+
+        // ディレクトリの代わりにファイルがある
+        let file = dir.join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(!check_index_dir(file.to_str().unwrap()).ok);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // 誰も listen していないポート
+        let scraper = check_scraper("http://127.0.0.1:9/url/").await;
+        assert!(!scraper.ok, "{:?}", scraper);
+
+        let report = StartupReport { checks: vec![scraper, Check::ok("index", "1 shards")] };
+        assert!(!report.is_ok());
+        assert_eq!(report.failures().map(|c| c.name).collect::<Vec<_>>(), vec!["scraper"]);
+    }
+}
@@ -1,5 +1,8 @@
 use std::io::Write;
 use std::process::{Command, Stdio};
+use std::sync::{LazyLock, RwLock};
+
+use serde::{Deserialize, Serialize};
 
 /// Sudachiの分割モード
 #[derive(Clone, Copy, Debug)]
@@ -22,6 +25,7 @@ impl SudachiMode {
 /// 外部コマンド sudachi を使って日本語トークン化する
 /// - 入力: &str
 /// - 出力: Vec<String>（wakati出力を空白区切りで分割）
+///
 /// 失敗時はエラーを返す。
 pub fn sudachi_tokenize(input: &str) -> Result<Vec<String>, SudachiError> {
     sudachi_tokenize_with_mode(input, SudachiMode::A)
@@ -32,21 +36,220 @@ pub fn sudachi_tokenize_with_mode(
     input: &str,
     mode: SudachiMode,
 ) -> Result<Vec<String>, SudachiError> {
-    let mut child = Command::new("sudachi")
-        .arg("-a") // 全情報出力
-        .arg("-m")
-        .arg(mode.as_str())
-        .arg("--split-sentences")
-        .arg("no")
+    let tokens = sudachi_tokenize_detailed(input, mode)?;
+    Ok(tokens.into_iter().map(|t| t.normalized).collect())
+}
+
+/// Sudachi のトークン
+/// -a 出力の 表層形 / 品詞 / 正規化形 を保持
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    /// 表層形 (原文の表記)
+    pub surface: String,
+    /// 正規化形 (インデックスに使う形)
+    pub normalized: String,
+    /// 品詞 (カンマ区切り 例: "名詞,普通名詞,一般,*,*,*")
+    pub pos: String,
+}
+
+impl Token {
+    /// 品詞の大分類 (例: "名詞")
+    pub fn pos_major(&self) -> &str {
+        self.pos.split(',').next().unwrap_or("")
+    }
+}
+
+/// 原文中のトークンの位置 (文字単位)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenSpan {
+    /// 正規化形 (検索クエリのトークンと照合する)
+    pub token: Box<str>,
+    /// 開始位置 (文字数)
+    pub start: u32,
+    /// 表層形の長さ (文字数)
+    pub len: u32,
+}
+
+/// トークン列の表層形を原文から順に探して位置を求める
+/// 原文中に見つからないトークン (Sudachi の置換など) は飛ばす
+pub fn token_spans(text: &str, tokens: &[Token]) -> Vec<TokenSpan> {
+    let mut spans = Vec::with_capacity(tokens.len());
+    let mut byte_cursor = 0;
+    let mut char_cursor = 0;
+    for token in tokens {
+        if token.surface.is_empty() {
+            continue;
+        }
+        let Some(found) = text[byte_cursor..].find(&token.surface) else { continue; };
+        let byte_start = byte_cursor + found;
+        let start = char_cursor + text[byte_cursor..byte_start].chars().count();
+        let len = token.surface.chars().count();
+        spans.push(TokenSpan {
+            token: token.normalized.as_str().into(),
+            start: start as u32,
+            len: len as u32,
+        });
+        byte_cursor = byte_start + token.surface.len();
+        char_cursor = start + len;
+    }
+    spans
+}
+
+/// sudachi の実行ファイル名のデフォルト (PATH から探す)
+pub const DEFAULT_SUDACHI_BIN: &str = "sudachi";
+/// -a 出力で正規化形がある列 (0 始まり) のデフォルト
+pub const DEFAULT_NORMALIZED_COLUMN: usize = 2;
+
+/// sudachi の起動設定
+/// 独自の設定ファイル / 辞書 (ユーザ辞書入りなど) を使う場合に指定
+#[derive(Debug, Clone, PartialEq)]
+pub struct SudachiCommand {
+    /// 実行ファイル
+    pub bin: String,
+    /// 設定ファイル (-r)
+    pub config: Option<String>,
+    /// システム辞書 (-d)
+    pub dict: Option<String>,
+    /// ビルド済みのユーザ辞書 (-u) 製品名などを一語として扱う
+    pub user_dict: Option<String>,
+    /// 正規化形の列 (0 始まり 0: 表層形, 1: 品詞) 列の並びが違うバージョン用
+    pub normalized_column: usize,
+}
+
+static SUDACHI_COMMAND: LazyLock<RwLock<SudachiCommand>> =
+    LazyLock::new(|| RwLock::new(SudachiCommand::default()));
+
+impl Default for SudachiCommand {
+    fn default() -> Self {
+        Self { bin: DEFAULT_SUDACHI_BIN.to_string(), config: None, dict: None, user_dict: None, normalized_column: DEFAULT_NORMALIZED_COLUMN }
+    }
+}
+
+impl SudachiCommand {
+    /// 以後のトークン化で使う設定を差し替える
+    pub fn install(self) {
+        match SUDACHI_COMMAND.write() {
+            Ok(mut command) => *command = self,
+            Err(mut poison) => **poison.get_mut() = self,
+        }
+    }
+
+    /// 現在の設定
+    pub fn current() -> Self {
+        match SUDACHI_COMMAND.read() {
+            Ok(command) => command.clone(),
+            Err(poison) => poison.into_inner().clone(),
+        }
+    }
+
+    /// 指定されたファイルが存在するか / 正規化形の列が表層形・品詞と重ならないか確認 (起動時用)
+    pub fn validate(&self) -> Result<(), String> {
+        if self.normalized_column < 2 {
+            return Err(format!("SUDACHI_NORMALIZED_COLUMN must be 2 or greater (0: surface, 1: part of speech): {}", self.normalized_column));
+        }
+        let files = [("SUDACHI_CONFIG", &self.config), ("SUDACHI_DICT", &self.dict), ("SUDACHI_USER_DICT", &self.user_dict)];
+        for (key, path) in files {
+            if let Some(path) = path && !std::path::Path::new(path).is_file() {
+                return Err(format!("{} not found: {}", key, path));
+            }
+        }
+        Ok(())
+    }
+
+    /// 実行ファイル以外の引数
+    fn args(&self, mode: SudachiMode) -> Vec<&str> {
+        let mut args = vec!["-a", "-m", mode.as_str(), "--split-sentences", "no"];
+        if let Some(config) = &self.config {
+            args.extend(["-r", config.as_str()]);
+        }
+        if let Some(dict) = &self.dict {
+            args.extend(["-d", dict.as_str()]);
+        }
+        if let Some(user_dict) = &self.user_dict {
+            args.extend(["-u", user_dict.as_str()]);
+        }
+        args
+    }
+
+    /// 語彙リスト (Sudachi のユーザ辞書 CSV) からユーザ辞書をビルドする
+    /// `sudachi ubuild -s <システム辞書> -o <出力> <CSV>` を実行
+    pub fn build_user_dict(&self, system_dict: &str, csv: &str, output: &str) -> Result<(), SudachiError> {
+        let output = Command::new(&self.bin)
+            .args(["ubuild", "-s", system_dict, "-o", output, csv])
+            .output()
+            .map_err(SudachiError::Spawn)?;
+        if !output.status.success() {
+            return Err(SudachiError::Exit(
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// 詳細版 表層形 / 正規化形 / 品詞 を返す
+pub fn sudachi_tokenize_detailed(
+    input: &str,
+    mode: SudachiMode,
+) -> Result<Vec<Token>, SudachiError> {
+    run_sudachi(&SudachiCommand::current(), input, mode)
+}
+
+/// `sudachi --version` の出力 (起動時のログ用)
+pub fn sudachi_version() -> Result<String, SudachiError> {
+    let output = Command::new(&SudachiCommand::current().bin)
+        .arg("--version")
+        .output()
+        .map_err(SudachiError::Spawn)?;
+    if !output.status.success() {
+        return Err(SudachiError::Exit(
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(String::from_utf8(output.stdout).map_err(SudachiError::Utf8)?.trim().to_string())
+}
+
+/// 起動時の動作確認 短い文を一度トークン化する
+/// sudachi が見つからなければ SudachiError::Spawn
+pub fn probe_sudachi() -> Result<(), SudachiError> {
+    sudachi_tokenize_detailed("テスト", SudachiMode::A).map(|_| ())
+}
+
+fn run_sudachi(
+    command: &SudachiCommand,
+    input: &str,
+    mode: SudachiMode,
+) -> Result<Vec<Token>, SudachiError> {
+    let text = sudachi_output(command, input, mode)?;
+    Ok(parse_sudachi_columns(&text, command.normalized_column))
+}
+
+/// sudachi を起動して input を流し、-a の出力をそのまま返す
+fn sudachi_output(
+    command: &SudachiCommand,
+    input: &str,
+    mode: SudachiMode,
+) -> Result<String, SudachiError> {
+    let mut child = Command::new(&command.bin)
+        .args(command.args(mode)) // -a: 全情報出力
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()
         .map_err(SudachiError::Spawn)?;
 
-    if let Some(stdin) = child.stdin.as_mut() {
-        stdin.write_all(input.as_bytes()).map_err(SudachiError::Io)?;
-    }
+    // 書き込みを終えてから読むと、出力がパイプのバッファを超えたところで sudachi と互いに待ち合うので別スレッドで書く
+    let writer = child.stdin.take().map(|mut stdin| {
+        let input = input.to_string();
+        std::thread::spawn(move || stdin.write_all(input.as_bytes()))
+    });
     let output = child.wait_with_output().map_err(SudachiError::Io)?;
+    if let Some(writer) = writer {
+        writer.join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            .map_err(SudachiError::Io)?;
+    }
 
     if !output.status.success() {
         return Err(SudachiError::Exit(
@@ -55,14 +258,72 @@ pub fn sudachi_tokenize_with_mode(
         ));
     }
 
-    let text = String::from_utf8(output.stdout).map_err(SudachiError::Utf8)?;
-    let tokens = text
+    String::from_utf8(output.stdout).map_err(SudachiError::Utf8)
+}
+
+/// sudachi -a の出力をパース (正規化形は DEFAULT_NORMALIZED_COLUMN 列目)
+pub fn parse_sudachi_output(text: &str) -> Vec<Token> {
+    parse_sudachi_columns(text, DEFAULT_NORMALIZED_COLUMN)
+}
+
+/// sudachi -a の出力をパース
+/// 列: 表層形 \t 品詞 \t 正規化形 \t 辞書形 \t 読み ... (正規化形の位置は normalized_column)
+/// 正規化形の列が無い / 空なら表層形を使う
+/// 空行 / EOS / 品詞の列が無い行は無視
+pub fn parse_sudachi_columns(text: &str, normalized_column: usize) -> Vec<Token> {
+    let mut missing = 0;
+    let tokens: Vec<Token> = text
         .lines()
-        .filter(|line| !line.trim().is_empty() && !line.starts_with("EOS"))
-        .filter_map(|line| line.split('\t').nth(2))
-        .map(|s| s.to_string())
+        .filter(|line| !line.trim().is_empty() && line.trim_end() != "EOS")
+        .filter_map(|line| {
+            let cols: Vec<&str> = line.split('\t').collect();
+            let (&surface, &pos) = (cols.first()?, cols.get(1)?);
+            let normalized = match cols.get(normalized_column).map(|c| c.trim()) {
+                Some(normalized) if !normalized.is_empty() => normalized,
+                _ => {
+                    missing += 1;
+                    surface
+                }
+            };
+            Some(Token {
+                surface: surface.to_string(),
+                normalized: normalized.to_string(),
+                pos: pos.to_string(),
+            })
+        })
         .collect();
-    Ok(tokens)
+    if missing > 0 && missing == tokens.len() {
+        // 全ての行で無いなら列の位置が出力と合っていない
+        log::warn!("Sudachi output has no normalized form in column {}; using surface forms (check SUDACHI_NORMALIZED_COLUMN)", normalized_column);
+    }
+    tokens
+}
+
+/// sudachi -a の出力を入力の行ごと (EOS 区切り) にパース
+/// 区切りは "EOS" だけの行 (表層形が EOS で始まるトークンの行は区切りではない)
+pub fn parse_sudachi_lines(text: &str, normalized_column: usize) -> Vec<Vec<Token>> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for l in text.lines() {
+        if l.trim_end() == "EOS" {
+            lines.push(parse_sudachi_columns(&line, normalized_column));
+            line.clear();
+        } else {
+            line.push_str(l);
+            line.push('\n');
+        }
+    }
+    lines
+}
+
+/// 品詞の大分類でフィルタし正規化形を返す
+/// allowed は品詞の前方一致 (例: ["名詞", "動詞"])
+pub fn filter_by_pos<T: AsRef<str>>(tokens: Vec<Token>, allowed: &[T]) -> Vec<String> {
+    tokens
+        .into_iter()
+        .filter(|t| allowed.iter().any(|p| t.pos.starts_with(p.as_ref())))
+        .map(|t| t.normalized)
+        .collect()
 }
 
 #[derive(Debug)]
@@ -88,10 +349,36 @@ impl std::fmt::Display for SudachiError {
 
 impl std::error::Error for SudachiError {}
 
+impl SudachiError {
+    /// sudachi を起動できない (未インストールなど)
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, SudachiError::Spawn(_))
+    }
+
+    /// レスポンスの code
+    pub fn code(&self) -> &'static str {
+        if self.is_unavailable() { "tokenizer_unavailable" } else { "tokenizer_error" }
+    }
+
+    /// HTTP レスポンスのステータスとエラーメッセージ
+    /// 起動できないときは 503、それ以外は 500
+    pub fn http_error(&self) -> (u16, String) {
+        if self.is_unavailable() {
+            (503, format!("Tokenizer unavailable: {}", self))
+        } else {
+            (500, format!("Tokenization error: {}", self))
+        }
+    }
+}
+
+/// 強制切りのときに区切りを探す範囲 (文字数)
+pub const SPLIT_LOOKBACK: usize = 32;
+
 /// 長文を Sudachi に渡すために句読点や記号で分割しつつ最大長を超えないチャンクへ分割する
 ///
 /// 分割トリガ: 。！？!?,、, 改行 等
 /// max_len を超えそうな場合は強制切り。UTF-8境界は chars() ベースで安全に。
+/// 強制切りは語の途中で切らないよう、直前 SPLIT_LOOKBACK 文字以内の空白か文字種の変わり目で切る (なければその位置)
 pub fn split_for_sudachi(text: &str, max_len: usize) -> Vec<String> {
     if text.is_empty() { return Vec::new(); }
     let mut chunks = Vec::new();
@@ -105,32 +392,210 @@ pub fn split_for_sudachi(text: &str, max_len: usize) -> Vec<String> {
             continue;
         }
         if buf.len() >= max_len { // 強制切り
-            chunks.push(buf.trim().to_string());
+            let cut = soft_cut_position(&buf).unwrap_or(buf.len());
+            let rest = buf.split_off(cut);
+            if !buf.trim().is_empty() { chunks.push(buf.trim().to_string()); }
             buf = String::with_capacity(max_len.min(4096));
+            buf.push_str(&rest);
         }
     }
     if !buf.trim().is_empty() { chunks.push(buf.trim().to_string()); }
     chunks
 }
 
+/// 末尾 SPLIT_LOOKBACK 文字以内で切ってよい位置 (バイト)
+/// 空白の直後を優先し、なければ文字種の変わり目
+fn soft_cut_position(buf: &str) -> Option<usize> {
+    let tail: Vec<(usize, char)> = buf.char_indices().rev().take(SPLIT_LOOKBACK + 1).collect();
+    // tail は末尾から並ぶ (i + 1 が直前の文字)
+    let whitespace = tail.iter()
+        .skip(1)
+        .find(|(_, c)| c.is_whitespace())
+        .map(|(i, c)| i + c.len_utf8());
+    whitespace.or_else(|| {
+        tail.windows(2)
+            .find(|w| char_class(w[0].1) != char_class(w[1].1))
+            .map(|w| w[0].0)
+    }).filter(|&pos| pos > 0)
+}
+
+/// 文字種 (切れ目の判定用)
+fn char_class(c: char) -> u8 {
+    match c {
+        c if c.is_whitespace() => 0,
+        c if c.is_ascii_alphanumeric() => 1,
+        '\u{3041}'..='\u{309F}' => 2, // ひらがな
+        '\u{30A0}'..='\u{30FF}' => 3, // カタカナ
+        '\u{4E00}'..='\u{9FFF}' => 4, // 漢字
+        _ => 5,
+    }
+}
+
+/// トークン化の設定
+/// インデックス (/add) と検索で同じ設定を使いトークンを揃える
+#[derive(Debug, Clone)]
+pub struct TokenizeOptions {
+    /// 残す品詞 (前方一致) None なら全トークン
+    pub pos_filter: Option<Vec<String>>,
+    /// これより短い (文字数) トークンを捨てる 1 以下ならフィルタしない
+    pub min_token_len: usize,
+}
+
+impl Default for TokenizeOptions {
+    fn default() -> Self {
+        Self { pos_filter: None, min_token_len: 1 }
+    }
+}
+
+/// 言語コードを正規化 (前後の空白を除いて小文字、空なら None)
+pub fn normalize_lang(lang: &str) -> Option<Box<str>> {
+    let lang = lang.trim().to_ascii_lowercase();
+    (!lang.is_empty()).then(|| lang.into_boxed_str())
+}
+
+/// sudachi でトークン化する言語か (未指定と "ja" / "ja-JP" など)
+pub fn uses_sudachi(lang: Option<&str>) -> bool {
+    lang.is_none_or(|l| {
+        let l = l.trim();
+        l.is_empty() || l.eq_ignore_ascii_case("ja") || l.get(..3).is_some_and(|p| p.eq_ignore_ascii_case("ja-") || p.eq_ignore_ascii_case("ja_"))
+    })
+}
+
+/// 日本語以外のテキストの単語分割
+/// 英数字などの連続を 1 語として小文字にし、空白で区切らない文字 (ひらがな・カタカナ・漢字) は 1 文字ずつ 1 語にする
+pub fn split_words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        if matches!(char_class(c), 2..=4) {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            words.push(c.to_string());
+        } else if c.is_alphanumeric() {
+            word.extend(c.to_lowercase());
+        } else if !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// lang に応じたトークン化
+/// 日本語 (未指定を含む) は tokenize、それ以外は split_words (品詞がないので pos_filter は使わない)
+pub fn tokenize_lang(text: &str, lang: Option<&str>, options: &TokenizeOptions) -> Result<Vec<String>, SudachiError> {
+    if uses_sudachi(lang) {
+        return tokenize(text, options);
+    }
+    Ok(filter_by_len(split_words(text), options.min_token_len))
+}
+
+/// 複数のテキストをまとめてトークン化し、テキストごとのトークン列を返す (それぞれ tokenize_lang と同じ)
+/// sudachi でトークン化するテキストはチャンクを 1 行ずつ並べて 1 回の sudachi に流し、EOS で行ごとに分けて戻す
+pub fn tokenize_lang_batch(texts: &[(&str, Option<&str>)], options: &TokenizeOptions) -> Result<Vec<Vec<String>>, SudachiError> {
+    tokenize_lang_batch_with(&SudachiCommand::current(), texts, options)
+}
+
+/// tokenize_lang_batch を指定した sudachi の設定で行う
+pub(crate) fn tokenize_lang_batch_with(command: &SudachiCommand, texts: &[(&str, Option<&str>)], options: &TokenizeOptions) -> Result<Vec<Vec<String>>, SudachiError> {
+    let mut lines: Vec<String> = Vec::new();
+    // テキストごとの sudachi に流す行数 (sudachi を使わない言語は None)
+    let mut line_counts = Vec::with_capacity(texts.len());
+    for (text, lang) in texts {
+        if !uses_sudachi(*lang) {
+            line_counts.push(None);
+            continue;
+        }
+        let before = lines.len();
+        for chunk in split_for_sudachi(text, 2000) {
+            lines.extend(chunk.lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_string));
+        }
+        line_counts.push(Some(lines.len() - before));
+    }
+    let parsed = if lines.is_empty() {
+        Vec::new()
+    } else {
+        let mut input = lines.join("\n");
+        input.push('\n');
+        parse_sudachi_lines(&sudachi_output(command, &input, SudachiMode::A)?, command.normalized_column)
+    };
+    if parsed.len() != lines.len() {
+        let message = format!("sudachi returned {} lines for {} input lines", parsed.len(), lines.len());
+        return Err(SudachiError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, message)));
+    }
+    let mut parsed = parsed.into_iter();
+    Ok(texts.iter().zip(line_counts).map(|((text, _), count)| match count {
+        Some(n) => {
+            let tokens: Vec<Token> = parsed.by_ref().take(n).flatten().collect();
+            let tokens = match &options.pos_filter {
+                Some(pos) => filter_by_pos(tokens, pos),
+                None => tokens.into_iter().map(|t| t.normalized).collect(),
+            };
+            filter_by_len(tokens, options.min_token_len)
+        }
+        None => filter_by_len(split_words(text), options.min_token_len),
+    }).collect())
+}
+
+/// インデックス / 検索共通のトークン化
+pub fn tokenize(text: &str, options: &TokenizeOptions) -> Result<Vec<String>, SudachiError> {
+    let tokens = match &options.pos_filter {
+        Some(pos) => sudachi_tokenize_large_detailed(text, SudachiMode::A, 2000)
+            .map(|t| filter_by_pos(t, pos))?,
+        None => sudachi_tokenize_large(text, SudachiMode::A, 2000)?,
+    };
+    Ok(filter_by_len(tokens, options.min_token_len))
+}
+
+/// min_len 文字未満のトークンを除外
+pub fn filter_by_len(tokens: Vec<String>, min_len: usize) -> Vec<String> {
+    if min_len <= 1 {
+        return tokens;
+    }
+    tokens.into_iter().filter(|t| t.chars().count() >= min_len).collect()
+}
+
 /// 長文を安全にトークン化。内部でチャンク分割し連結。
 pub fn sudachi_tokenize_large(
     text: &str,
     mode: SudachiMode,
     max_chunk: usize,
 ) -> Result<Vec<String>, SudachiError> {
+    let tokens = sudachi_tokenize_large_detailed(text, mode, max_chunk)?;
+    Ok(tokens.into_iter().map(|t| t.normalized).collect())
+}
+
+/// 長文を安全にトークン化 (詳細版)
+pub fn sudachi_tokenize_large_detailed(
+    text: &str,
+    mode: SudachiMode,
+    max_chunk: usize,
+) -> Result<Vec<Token>, SudachiError> {
     let max_chunk = max_chunk.max(64); // 最低サイズ
     let chunks = split_for_sudachi(text, max_chunk);
-    let mut tokens = Vec::new();
-    for c in chunks {
-        let mut part = sudachi_tokenize_with_mode(&c, mode)?;
-        tokens.append(&mut part);
+    run_sudachi_chunks(&SudachiCommand::current(), &chunks, mode)
+}
+
+/// チャンクを改行でつないで 1 回の sudachi でトークン化する
+/// sudachi は行ごとに解析するので、チャンクごとに起動した場合と同じトークンになる
+fn run_sudachi_chunks(
+    command: &SudachiCommand,
+    chunks: &[String],
+    mode: SudachiMode,
+) -> Result<Vec<Token>, SudachiError> {
+    if chunks.is_empty() {
+        return Ok(Vec::new());
     }
-    Ok(tokens)
+    let mut input = chunks.join("\n");
+    input.push('\n');
+    run_sudachi(command, &input, mode)
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     // 外部コマンド依存のため、デフォルトでは無効化
@@ -143,6 +608,291 @@ mod tests {
         assert!(!tokens.is_empty());
     }
 
+    const SAMPLE_OUTPUT: &str = "今日\t名詞,普通名詞,副詞可能,*,*,*\t今日\t今日\tキョウ\t0\t[]
+は\t助詞,係助詞,*,*,*,*\tは\tは\tワ\t0\t[]
+良い\t形容詞,非自立可能,*,*,形容詞,連体形-一般\t良い\t良い\tヨイ\t0\t[]
+天気\t名詞,普通名詞,一般,*,*,*\t天気\t天気\tテンキ\t0\t[]
+です\t助動詞,*,*,*,助動詞-デス,終止形-一般\tです\tです\tデス\t0\t[]
+ね\t助詞,終助詞,*,*,*,*\tね\tね\tネ\t0\t[]
+。\t補助記号,句点,*,*,*,*\t。\t。\t。\t0\t[]
+EOS
+";
+
+    #[test]
+    fn test_parse_sudachi_lines() {
+        let lines = parse_sudachi_lines(&format!("{}EOS\n{}", SAMPLE_OUTPUT, SAMPLE_OUTPUT), DEFAULT_NORMALIZED_COLUMN);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], parse_sudachi_output(SAMPLE_OUTPUT));
+        assert!(lines[1].is_empty());
+        assert_eq!(lines[2].len(), 7);
+
+        // sudachi を使わない言語だけなら起動しない
+        let texts = [("Rust Tokio", Some("en")), ("", Some("en")), ("a hello", Some("en"))];
+        let options = TokenizeOptions { min_token_len: 2, ..Default::default() };
+        let tokens = tokenize_lang_batch(&texts, &options).unwrap();
+        assert_eq!(tokens, vec![vec!["rust", "tokio"], vec![], vec!["hello"]]);
+
+        // 表層形が EOS で始まるトークンの行は区切りではない
+        let eos_token = "Canon\t名詞,固有名詞,一般,*,*,*\tCanon\nEOS\t名詞,固有名詞,一般,*,*,*\tEOS\nEOS\nEOSIO\t名詞,固有名詞,一般,*,*,*\tEOSIO\nEOS\n";
+        let lines = parse_sudachi_lines(eos_token, DEFAULT_NORMALIZED_COLUMN);
+        assert_eq!(lines.len(), 2);
+        let normalized: Vec<&str> = lines[0].iter().map(|t| t.normalized.as_str()).collect();
+        assert_eq!(normalized, vec!["Canon", "EOS"]);
+        assert_eq!(lines[1][0].normalized, "EOSIO");
+        assert_eq!(parse_sudachi_output(eos_token).len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tokenize_lang_batch_eos_token() {
+        let dir = std::env::temp_dir().join(format!("wk-tokenize-test-eos-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (command, count) = fake_sudachi(&dir);
+        let texts = [("Canon EOS R5", None), ("EOSIO", Some("ja")), ("EOS", None), ("Rust", Some("en"))];
+        let tokens = tokenize_lang_batch_with(&command, &texts, &TokenizeOptions::default()).unwrap();
+        assert_eq!(tokens, vec![vec!["Canon", "EOS", "R5"], vec!["EOSIO"], vec!["EOS"], vec!["rust"]]);
+        assert_eq!(std::fs::read_to_string(&count).unwrap().lines().count(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_sudachi_output() {
+        let tokens = parse_sudachi_output(SAMPLE_OUTPUT);
+        assert_eq!(tokens.len(), 7);
+        assert_eq!(tokens[0], Token {
+            surface: "今日".to_string(),
+            normalized: "今日".to_string(),
+            pos: "名詞,普通名詞,副詞可能,*,*,*".to_string(),
+        });
+        assert_eq!(tokens[1].pos_major(), "助詞");
+        let nouns = filter_by_pos(tokens.clone(), &["名詞", "形容詞"]);
+        assert_eq!(nouns, vec!["今日", "良い", "天気"]);
+        // 内容語フィルタでは助詞・助動詞・記号を除外
+        let content = filter_by_pos(tokens, crate::config::CONTENT_POS);
+        assert_eq!(content, vec!["今日", "良い", "天気"]);
+        assert!(!content.contains(&"は".to_string()));
+        assert!(!content.contains(&"です".to_string()));
+    }
+
+    #[test]
+    fn test_parse_sudachi_columns() {
+        // 正規化形が空 / 列が足りない行は表層形、品詞の列が無い行は捨てる
+        let output = "附属\t名詞,普通名詞,一般,*,*,*\t付属\t附属\tフゾク\t0\t[]
+ｶﾞｯｺｳ\t名詞,普通名詞,一般,*,*,*\t\tｶﾞｯｺｳ\tガッコウ\t0\t[]
+ＵＲＬ\t名詞,普通名詞,一般,*,*,*
+壊れた行
+EOS
+";
+        let tokens = parse_sudachi_output(output);
+        let normalized: Vec<&str> = tokens.iter().map(|t| t.normalized.as_str()).collect();
+        assert_eq!(normalized, vec!["付属", "ｶﾞｯｺｳ", "ＵＲＬ"]);
+        assert_eq!(tokens[1].pos_major(), "名詞");
+
+        // 正規化形が別の列にあるバージョン (表層形 / 品詞 / 辞書形 / 正規化形)
+        let reordered = "附属\t名詞,普通名詞,一般,*,*,*\t附属\t付属\tフゾク
+EOS
+";
+        assert_eq!(parse_sudachi_columns(reordered, 3)[0].normalized, "付属");
+        assert_eq!(parse_sudachi_columns(reordered, 2)[0].normalized, "附属");
+        // 範囲外なら全て表層形
+        assert_eq!(parse_sudachi_columns(reordered, 10)[0].normalized, "附属");
+
+        let overlapping = SudachiCommand { normalized_column: 1, ..Default::default() };
+        assert!(overlapping.validate().unwrap_err().starts_with("SUDACHI_NORMALIZED_COLUMN"));
+    }
+
+    #[test]
+    fn test_token_spans() {
+        let text = "今日は 良い天気ですね。";
+        let spans = token_spans(text, &parse_sudachi_output(SAMPLE_OUTPUT));
+        let chars: Vec<char> = text.chars().collect();
+        assert_eq!(spans.len(), 7);
+        for (span, surface) in spans.iter().zip(["今日", "は", "良い", "天気", "です", "ね", "。"]) {
+            let range = span.start as usize..(span.start + span.len) as usize;
+            assert_eq!(chars[range].iter().collect::<String>(), surface);
+        }
+        // 空白の分だけずれる
+        assert_eq!(spans[2].start, 4);
+        // 原文にない表層形は飛ばす
+        let missing = vec![Token { surface: "雨".to_string(), normalized: "雨".to_string(), pos: "名詞".to_string() }];
+        assert!(token_spans(text, &missing).is_empty());
+    }
+
+    #[test]
+    fn test_sudachi_spawn_failure() {
+        let bogus = SudachiCommand { bin: "/nonexistent/sudachi-bogus".to_string(), ..Default::default() };
+        let err = run_sudachi(&bogus, "今日", SudachiMode::A).unwrap_err();
+        assert!(err.is_unavailable());
+        let (status, message) = err.http_error();
+        assert_eq!(status, 503);
+        assert!(message.starts_with("Tokenizer unavailable"));
+        assert_eq!(err.code(), "tokenizer_unavailable");
+        let exit = SudachiError::Exit(1, "bad dict".to_string());
+        assert!(!exit.is_unavailable());
+        assert_eq!(exit.http_error().0, 500);
+        assert_eq!(exit.code(), "tokenizer_error");
+    }
+
+    /// 行ごとに空白区切りの語を 1 トークンとして出す sudachi の代わり
+    /// 起動するたびに count に 1 行追記する
+    #[cfg(unix)]
+    pub(crate) fn fake_sudachi(dir: &std::path::Path) -> (SudachiCommand, std::path::PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::create_dir_all(dir).unwrap();
+        let bin = dir.join("sudachi");
+        let count = dir.join("count");
+        let script = format!(
+            "#!/bin/sh\necho x >> '{}'\nwhile IFS= read -r line || [ -n \"$line\" ]; do\n  for w in $line; do printf '%s\\t名詞,普通名詞\\t%s\\n' \"$w\" \"$w\"; done\n  echo EOS\ndone\n",
+            count.display(),
+        );
+        std::fs::write(&bin, script).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (SudachiCommand { bin: bin.to_string_lossy().into_owned(), ..Default::default() }, count)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_chunks_single_process() {
+        let dir = std::env::temp_dir().join(format!("wk-tokenize-test-chunks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (command, count) = fake_sudachi(&dir);
+        let text = "alpha beta gamma。delta epsilon\nzeta eta theta iota kappa lambda mu nu xi omicron pi rho sigma tau";
+        let chunks = split_for_sudachi(text, 20);
+        assert!(chunks.len() > 2);
+        let spawns = || std::fs::read_to_string(&count).unwrap_or_default().lines().count();
+
+        // 以前のチャンクごとの起動
+        let mut per_chunk = Vec::new();
+        for c in &chunks {
+            per_chunk.extend(run_sudachi(&command, c, SudachiMode::A).unwrap());
+        }
+        assert_eq!(spawns(), chunks.len());
+        assert!(per_chunk.iter().any(|t| t.surface == "omicron"));
+
+        let single = run_sudachi_chunks(&command, &chunks, SudachiMode::A).unwrap();
+        assert_eq!(spawns(), chunks.len() + 1);
+        assert_eq!(single, per_chunk);
+        assert!(run_sudachi_chunks(&command, &[], SudachiMode::A).unwrap().is_empty());
+        assert_eq!(spawns(), chunks.len() + 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sudachi_large_output() {
+        let dir = std::env::temp_dir().join(format!("wk-tokenize-test-large-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (command, _) = fake_sudachi(&dir);
+        // 入力も出力もパイプのバッファ (64KB) より大きい
+        let lines: Vec<String> = (0..5000).map(|i| format!("語{:040}", i)).collect();
+        let mut input = lines.join("\n");
+        input.push('\n');
+        let output = sudachi_output(&command, &input, SudachiMode::A).unwrap();
+        assert!(output.len() > 64 * 1024);
+        let parsed = parse_sudachi_lines(&output, command.normalized_column);
+        assert_eq!(parsed.len(), lines.len());
+        assert_eq!(parsed[4999][0].normalized, lines[4999]);
+        let tokens = run_sudachi_chunks(&command, &lines, SudachiMode::A).unwrap();
+        assert_eq!(tokens.len(), lines.len());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sudachi_command_args() {
+        let default = SudachiCommand::default();
+        assert_eq!(default.bin, "sudachi");
+        assert_eq!(default.args(SudachiMode::A), vec!["-a", "-m", "A", "--split-sentences", "no"]);
+        let custom = SudachiCommand {
+            bin: "/opt/sudachi/bin/sudachi".to_string(),
+            config: Some("sudachi.json".to_string()),
+            dict: Some("system_full.dic".to_string()),
+            user_dict: Some("user.dic".to_string()),
+            normalized_column: DEFAULT_NORMALIZED_COLUMN,
+        };
+        assert_eq!(
+            custom.args(SudachiMode::C),
+            vec!["-a", "-m", "C", "--split-sentences", "no", "-r", "sudachi.json", "-d", "system_full.dic", "-u", "user.dic"],
+        );
+        assert!(default.validate().is_ok());
+        let err = custom.validate().unwrap_err();
+        assert!(err.starts_with("SUDACHI_CONFIG not found"));
+    }
+
+    // sudachi とシステム辞書が必要 SUDACHI_DICT にシステム辞書のパスを指定して実行
+    #[ignore]
+    #[test]
+    fn test_user_dict_term_is_one_token() {
+        let system_dict = std::env::var("SUDACHI_DICT").expect("SUDACHI_DICT is required");
+        let dir = std::env::temp_dir().join(format!("sudachi_user_dict_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let csv = dir.join("user.csv");
+        let dic = dir.join("user.dic");
+        std::fs::write(&csv, "クロサビ検索,4786,4786,5000,クロサビ検索,名詞,固有名詞,一般,*,*,*,クロサビケンサク,クロサビ検索,*,A,*,*,*,*\n").unwrap();
+
+        let base = SudachiCommand { dict: Some(system_dict.clone()), ..Default::default() };
+        base.build_user_dict(&system_dict, csv.to_str().unwrap(), dic.to_str().unwrap()).expect("ubuild failed");
+
+        let text = "クロサビ検索で探す";
+        let without = run_sudachi(&base, text, SudachiMode::A).unwrap();
+        assert!(!without.iter().any(|t| t.surface == "クロサビ検索"));
+        let with = SudachiCommand { user_dict: Some(dic.to_str().unwrap().to_string()), ..base };
+        let tokens = run_sudachi(&with, text, SudachiMode::A).unwrap();
+        assert_eq!(tokens[0].surface, "クロサビ検索");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_tokenize_lang() {
+        // sudachi を起動せずに分割する (sudachi がない環境でも成功する)
+        let options = TokenizeOptions { pos_filter: Some(vec!["名詞".to_string()]), min_token_len: 2 };
+        let tokens = tokenize_lang("Hello, World! Rust's borrow-checker v2", Some("en"), &options).unwrap();
+        assert_eq!(tokens, vec!["hello", "world", "rust", "borrow", "checker", "v2"]);
+        assert_eq!(split_words("Café 北京欢迎你 ok"), vec!["café", "北", "京", "欢", "迎", "你", "ok"]);
+
+        assert!(uses_sudachi(None));
+        assert!(uses_sudachi(Some("JA")));
+        assert!(uses_sudachi(Some("ja-JP")));
+        assert!(!uses_sudachi(Some("en")));
+        assert!(!uses_sudachi(Some("jav")));
+        assert_eq!(normalize_lang(" EN "), Some("en".into()));
+        assert_eq!(normalize_lang(" "), None);
+    }
+
+    #[test]
+    fn test_filter_by_len() {
+        let tokens: Vec<String> = parse_sudachi_output(SAMPLE_OUTPUT).into_iter().map(|t| t.normalized).collect();
+        // デフォルトでは何も落とさない
+        assert_eq!(filter_by_len(tokens.clone(), TokenizeOptions::default().min_token_len), tokens);
+        let filtered = filter_by_len(tokens, 2);
+        assert_eq!(filtered, vec!["今日", "良い", "天気", "です"]);
+        let tf = tf_idf_vectorizer::TokenFrequency::from(&filtered[..]);
+        assert_eq!(tf.token_count("天気"), 1);
+        assert_eq!(tf.token_count("は"), 0);
+        assert_eq!(tf.token_count("。"), 0);
+    }
+
+    #[test]
+    fn test_split_for_sudachi_keeps_words() {
+        // 句読点のない長い英単語列
+        let text = "searchengine ".repeat(40);
+        let parts = split_for_sudachi(&text, 64);
+        assert!(parts.len() > 1);
+        for p in &parts {
+            assert!(p.len() <= 64);
+            assert!(p.split_whitespace().all(|w| w == "searchengine"), "{:?}", p);
+        }
+        // 空白がなければ文字種の変わり目で切る
+        let text = "東京タワーsearchengine".repeat(10);
+        for p in split_for_sudachi(&text, 64) {
+            assert!(!p.starts_with("earch") && !p.starts_with("ワー") && !p.starts_with("京"), "{:?}", p);
+        }
+        // 区切りがなければ強制切り
+        let text = "a".repeat(200);
+        let parts = split_for_sudachi(&text, 64);
+        assert_eq!(parts.concat(), text);
+        assert!(parts.iter().all(|p| p.len() <= 64));
+    }
+
     #[test]
     fn test_split_for_sudachi() {
         let long = "これはテストです。これは二文目です！そして三文目です？改行も\n入ります。";
@@ -0,0 +1,162 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::index::{read_bincode, write_bincode, SaveOptions};
+
+/// 文字 3-gram
+pub type Trigram = [char; 3];
+
+/// テキストの 3-gram の集合
+/// 小文字にそろえ、空白をまたぐものは含めない
+pub fn trigrams(text: &str) -> HashSet<Trigram> {
+    let chars: Vec<char> = text.chars().flat_map(|c| c.to_lowercase()).collect();
+    chars.windows(3)
+        .filter(|w| !w.iter().any(|c| c.is_whitespace()))
+        .map(|w| [w[0], w[1], w[2]])
+        .collect()
+}
+
+/// クエリの 3-gram
+/// 空白区切りの語のうち 3 文字未満のものは使わない (全て 3 文字未満なら None)
+pub fn query_trigrams(query: &str) -> Option<HashSet<Trigram>> {
+    let grams: HashSet<Trigram> = query.split_whitespace()
+        .filter(|w| w.chars().count() >= 3)
+        .flat_map(trigrams)
+        .collect();
+    (!grams.is_empty()).then_some(grams)
+}
+
+/// シャードごとの文字 3-gram インデックス (TRIGRAM_INDEX 有効時)
+/// トークンの境界をまたぐ部分一致の検索に使う
+/// {id}.trigram として保存し、転置リストは読み込み時に作り直す
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrigramIndex {
+    /// ドキュメント ID -> 3-gram
+    docs: HashMap<usize, Box<[Trigram]>>,
+    /// 3-gram -> ドキュメント ID
+    #[serde(skip)]
+    postings: HashMap<Trigram, HashSet<usize>>,
+}
+
+impl TrigramIndex {
+    /// ドキュメントのテキストを登録 (既存なら置き換える)
+    pub fn insert(&mut self, doc_id: usize, text: &str) {
+        self.insert_grams(doc_id, trigrams(text).into_iter().collect());
+    }
+
+    /// 3-gram をそのまま登録 (既存なら置き換える)
+    pub fn insert_grams(&mut self, doc_id: usize, grams: Box<[Trigram]>) {
+        self.remove(doc_id);
+        for gram in grams.iter() {
+            self.postings.entry(*gram).or_default().insert(doc_id);
+        }
+        self.docs.insert(doc_id, grams);
+    }
+
+    pub fn remove(&mut self, doc_id: usize) {
+        self.take(doc_id);
+    }
+
+    /// ドキュメントを消して、その 3-gram を返す (別のシャードへ移すとき用)
+    pub fn take(&mut self, doc_id: usize) -> Option<Box<[Trigram]>> {
+        let grams = self.docs.remove(&doc_id)?;
+        for gram in grams.iter() {
+            if let Some(ids) = self.postings.get_mut(gram) {
+                ids.remove(&doc_id);
+                if ids.is_empty() {
+                    self.postings.remove(gram);
+                }
+            }
+        }
+        Some(grams)
+    }
+
+    /// grams を全て含むドキュメント ID
+    pub fn matching(&self, grams: &HashSet<Trigram>) -> HashSet<usize> {
+        let mut lists: Vec<&HashSet<usize>> = match grams.iter().map(|g| self.postings.get(g)).collect::<Option<Vec<_>>>() {
+            Some(lists) => lists,
+            None => return HashSet::new(),
+        };
+        // 短いリストから絞る
+        lists.sort_by_key(|ids| ids.len());
+        let Some((first, rest)) = lists.split_first() else { return HashSet::new(); };
+        first.iter()
+            .filter(|id| rest.iter().all(|ids| ids.contains(id)))
+            .copied()
+            .collect()
+    }
+
+    /// doc_id が grams を全て含むか (候補を一つずつ確かめる用)
+    pub fn contains_all(&self, doc_id: usize, grams: &HashSet<Trigram>) -> bool {
+        grams.iter().all(|g| self.postings.get(g).is_some_and(|ids| ids.contains(&doc_id)))
+    }
+
+    /// 3-gram を登録済みのドキュメントか
+    pub fn contains(&self, doc_id: usize) -> bool {
+        self.docs.contains_key(&doc_id)
+    }
+
+    /// 登録されているドキュメント数
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    pub fn save(&self, path: &str, shard_id: usize, options: SaveOptions) -> Result<(), Box<dyn std::error::Error>> {
+        let trigram_path = std::path::Path::new(path).join(format!("{}.trigram", shard_id));
+        write_bincode(&trigram_path, self, options)
+    }
+
+    /// 読み込めなければ空
+    pub fn load(path: &str, shard_id: usize) -> Self {
+        let trigram_path = std::path::Path::new(path).join(format!("{}.trigram", shard_id));
+        let Ok(data) = std::fs::read(&trigram_path) else { return Self::default(); };
+        match read_bincode::<Self>(&trigram_path, &data) {
+            Ok(mut index) => {
+                for (doc_id, grams) in &index.docs {
+                    for gram in grams.iter() {
+                        index.postings.entry(*gram).or_default().insert(*doc_id);
+                    }
+                }
+                index
+            }
+            Err(e) => {
+                log::warn!("Failed to deserialize trigram file {:?}: {}", trigram_path, e);
+                Self::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trigram_index() {
+        let mut index = TrigramIndex::default();
+        index.insert(0, "東京タワーの展望台");
+        index.insert(1, "Tokyo Tower");
+        let query = |q: &str| index.matching(&query_trigrams(q).unwrap());
+        assert_eq!(query("京タワ"), HashSet::from([0]));
+        assert_eq!(query("tower"), HashSet::from([1]));
+        assert!(query("京都タワ").is_empty());
+        // 3 文字未満の語だけなら使わない
+        assert!(query_trigrams("東京 a").is_none());
+
+        let dir = std::env::temp_dir().join(format!("wk-trigram-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_str = dir.to_string_lossy();
+        index.save(&dir_str, 3, SaveOptions::default()).unwrap();
+        let mut loaded = TrigramIndex::load(&dir_str, 3);
+        assert_eq!(loaded.matching(&query_trigrams("京タワ").unwrap()), HashSet::from([0]));
+        loaded.remove(0);
+        assert!(loaded.matching(&query_trigrams("京タワ").unwrap()).is_empty());
+        assert_eq!(loaded.len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
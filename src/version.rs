@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// crate のバージョン
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// ビルド時の git コミット (build.rs で設定)
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+/// ビルド時刻 UNIX 秒 (build.rs で設定)
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+/// /version と /status で返すビルド情報
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_time: Option<DateTime<Utc>>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        Self {
+            version: VERSION,
+            git_commit: GIT_COMMIT,
+            build_time: BUILD_TIMESTAMP.parse::<i64>().ok().and_then(|t| DateTime::from_timestamp(t, 0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let info = BuildInfo::current();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_commit.is_empty());
+        assert!(info.build_time.is_some());
+    }
+}